@@ -0,0 +1,85 @@
+//! Micro-benchmark for `Client::start_auth`.
+//!
+//! Run with `cargo bench --bench start_auth`. This compares building the authorization URL from a
+//! cached discovery document, to the case where the document changes on every call, which forces
+//! the client to parse and check it again.
+
+use std::{
+    error::Error,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use bytes::Bytes;
+use portier::{Client, FetchError, Store};
+use url::Url;
+
+type DynFut<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+type DynRes<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+const ITERATIONS: u32 = 100_000;
+
+/// A store that serves a fixed discovery document, optionally alternating between two copies.
+struct BenchStore {
+    documents: [Bytes; 2],
+    alternate: bool,
+    flip: AtomicBool,
+}
+
+impl BenchStore {
+    fn new(alternate: bool) -> Self {
+        let discovery = r#"{"issuer":"https://broker.example","authorization_endpoint":"https://broker.example/auth","jwks_uri":"https://broker.example/jwks.json"}"#;
+        BenchStore {
+            documents: [discovery.into(), format!("{} ", discovery).into()],
+            alternate,
+            flip: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Store for BenchStore {
+    fn fetch(&self, _url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        let idx = self.alternate && self.flip.fetch_xor(true, Ordering::Relaxed);
+        let document = self.documents[idx as usize].clone();
+        Box::pin(async move { Ok(document) })
+    }
+
+    fn new_nonce(&self, _email: String) -> DynFut<DynRes<String>> {
+        Box::pin(async { Ok("bench-nonce".to_owned()) })
+    }
+
+    fn consume_nonce(&self, _nonce: String, _email: String) -> DynFut<DynRes<bool>> {
+        Box::pin(async { Ok(false) })
+    }
+}
+
+async fn run(name: &str, alternate: bool) {
+    let client = Client::builder("https://rp.example/verify".parse().unwrap())
+        .broker("https://broker.example".parse().unwrap())
+        .store(Arc::new(BenchStore::new(alternate)))
+        .build()
+        .unwrap();
+    client.start_auth("user@example.com").await.unwrap();
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        client.start_auth("user@example.com").await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<24} {:>8.0} ns/iter",
+        name,
+        elapsed.as_nanos() as f64 / ITERATIONS as f64
+    );
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    run("cached discovery", false).await;
+    run("changing discovery", true).await;
+}
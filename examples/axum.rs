@@ -0,0 +1,243 @@
+//! Example application for Portier using the axum framework.
+//!
+//! This keeps the verified email address in a server-side session, identified by a cookie, and
+//! has a protected page and a logout button. Run it with:
+//!
+//!     cargo run --example axum --features axum
+//!
+//! The tests at the bottom run a full login against a `MockBroker`, using:
+//!
+//!     cargo test --example axum --features axum,test-utils
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{FromRef, State},
+    http::{header, HeaderMap},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Router,
+};
+use log::error;
+use portier::{
+    axum::{router, VerifiedEmail},
+    Client, MemoryStore, Store,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// Name of the session cookie.
+const SESSION_COOKIE: &str = "session";
+
+/// Application sessions, mapping session IDs to verified email addresses.
+///
+/// A real application would use a session layer backed by a database, so sessions survive
+/// restarts and are shared between workers.
+type Sessions = Arc<Mutex<HashMap<String, String>>>;
+
+/// Router state. The `portier::axum` extractors find the `Client` through `FromRef`.
+#[derive(Clone)]
+struct AppState {
+    client: Arc<Client>,
+    sessions: Sessions,
+}
+
+impl FromRef<AppState> for Arc<Client> {
+    fn from_ref(state: &AppState) -> Self {
+        state.client.clone()
+    }
+}
+
+/// Read the session ID from the request cookies.
+fn session_id(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| pair.trim().strip_prefix(SESSION_COOKIE)?.strip_prefix('='))
+}
+
+/// The email address of the logged in user, if any.
+fn current_user(state: &AppState, headers: &HeaderMap) -> Option<String> {
+    let id = session_id(headers)?;
+    state.sessions.lock().unwrap().get(id).cloned()
+}
+
+/// Render the index page, with a login form or the logged in user.
+async fn index(State(state): State<AppState>, headers: HeaderMap) -> Html<String> {
+    Html(match current_user(&state, &headers) {
+        Some(email) => format!(
+            r#"
+            <p>Logged in as {}. See the <a href="/private">private page</a>.</p>
+            <form method="post" action="/logout"><button type="submit">Logout</button></form>
+            "#,
+            email
+        ),
+        None => r#"
+            <p>Enter your email address:</p>
+            <form method="post" action="/auth">
+              <input name="email" type="email">
+              <button type="submit">Login</button>
+            </form>
+            "#
+        .to_owned(),
+    })
+}
+
+/// Render a page only available to logged in users.
+async fn private(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    match current_user(&state, &headers) {
+        Some(email) => {
+            Html(format!("<p>Hello {}, this page is private.</p>", email)).into_response()
+        }
+        None => Redirect::to("/").into_response(),
+    }
+}
+
+/// Handle the Portier response that arrives as a `POST /verify` request.
+///
+/// The `VerifiedEmail` extractor verifies the token, after which we start a session for the email
+/// address and send the user back to the index page.
+async fn verify(State(state): State<AppState>, VerifiedEmail(email): VerifiedEmail) -> Response {
+    let mut id = [0; 16];
+    if let Err(err) = SystemRandom::new().fill(&mut id) {
+        error!("could not generate session ID: {}", err);
+        return Redirect::to("/").into_response();
+    }
+    let id: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+    state.sessions.lock().unwrap().insert(id.clone(), email);
+
+    let cookie = format!("{}={}; Path=/; HttpOnly; SameSite=Lax", SESSION_COOKIE, id);
+    ([(header::SET_COOKIE, cookie)], Redirect::to("/")).into_response()
+}
+
+/// End the session, and clear the cookie.
+async fn logout(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(id) = session_id(&headers) {
+        state.sessions.lock().unwrap().remove(id);
+    }
+    let cookie = format!("{}=; Path=/; HttpOnly; Max-Age=0", SESSION_COOKIE);
+    ([(header::SET_COOKIE, cookie)], Redirect::to("/")).into_response()
+}
+
+/// Build the application.
+///
+/// The `Store` is created separately, so it can be shared, for example by a `ClientPool` or other
+/// `Client` instances for the same deployment. Replace the `MemoryStore` with a shared store such
+/// as `SqlStore` to run multiple workers.
+fn app(client: Client) -> Router {
+    let state = AppState {
+        client: Arc::new(client),
+        sessions: Default::default(),
+    };
+    router(post(verify))
+        .route("/", get(index))
+        .route("/private", get(private))
+        .route("/logout", post(logout))
+        .with_state(state)
+}
+
+/// Create the Portier client, using the given store.
+fn client(store: Arc<dyn Store>) -> portier::Builder {
+    Client::builder("http://localhost:8000/verify".parse().unwrap()).store(store)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let store: Arc<dyn Store> = Arc::new(MemoryStore::default());
+    let client = client(store)
+        .build()
+        .expect("could not build Portier client");
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:8000")
+        .await
+        .expect("could not bind to port 8000");
+    axum::serve(listener, app(client))
+        .await
+        .expect("server error");
+}
+
+#[cfg(all(test, feature = "test-utils", feature = "simple-store"))]
+mod tests {
+    use super::*;
+
+    use hyper::{body, Body, Method, Request, StatusCode};
+    use portier::test_utils::MockBroker;
+    use url::Url;
+
+    /// Serve the application on a random port, and return its URL.
+    async fn serve(broker: &MockBroker) -> String {
+        let store: Arc<dyn Store> = Arc::new(MemoryStore::default());
+        let client = client(store).broker(broker.url().clone()).build().unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app(client)).await });
+        url
+    }
+
+    /// Send a request, and return the response status, headers and body.
+    async fn send(
+        method: Method,
+        url: &str,
+        cookie: Option<&str>,
+        form: &str,
+    ) -> (StatusCode, hyper::HeaderMap, String) {
+        let mut req = Request::builder().method(method).uri(url);
+        if let Some(cookie) = cookie {
+            req = req.header("cookie", cookie);
+        }
+        let req = req
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(Body::from(form.to_owned()))
+            .unwrap();
+        let res = hyper::Client::new().request(req).await.unwrap();
+        let (parts, body) = res.into_parts();
+        let body = body::to_bytes(body).await.unwrap();
+        (
+            parts.status,
+            parts.headers,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn login_and_logout() {
+        let broker = MockBroker::start().await.unwrap();
+        let app = serve(&broker).await;
+
+        let (status, ..) = send(Method::GET, &format!("{}/private", app), None, "").await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+
+        let (_, headers, _) = send(
+            Method::POST,
+            &format!("{}/auth", app),
+            None,
+            "email=user%40example.com",
+        )
+        .await;
+        let auth_url: Url = headers["location"].to_str().unwrap().parse().unwrap();
+        let token = broker.login(&auth_url).unwrap();
+
+        let (_, headers, _) = send(
+            Method::POST,
+            &format!("{}/verify", app),
+            None,
+            &format!("id_token={}", token),
+        )
+        .await;
+        let cookie = headers["set-cookie"].to_str().unwrap();
+        let cookie = cookie.split(';').next().unwrap().to_owned();
+
+        let (status, _, body) =
+            send(Method::GET, &format!("{}/private", app), Some(&cookie), "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("user@example.com"));
+
+        send(Method::POST, &format!("{}/logout", app), Some(&cookie), "").await;
+        let (status, ..) = send(Method::GET, &format!("{}/private", app), Some(&cookie), "").await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+    }
+}
@@ -0,0 +1,300 @@
+//! Example multi-tenant application for Portier using the axum framework.
+//!
+//! Every tenant is served on its own domain, and needs a `Client` with a redirect URI on that
+//! domain. Tenants using the default broker get their client from a `ClientPool`, while tenants
+//! with their own broker get a client built separately. All clients share one `Store`, so any
+//! worker can complete a login started by another. Run it with:
+//!
+//!     cargo run --example multi_tenant --features axum
+//!
+//! Then visit http://a.localhost:8000/ or http://b.localhost:8000/. The tests at the bottom run
+//! logins for both kinds of tenant against `MockBroker`s, using:
+//!
+//!     cargo test --example multi_tenant --features axum,test-utils
+//!
+//! This crate does not include a Redis store. Multiple workers need a shared store, such as the
+//! `MemcachedStore`, which this example uses if built with the `memcached-store` feature and the
+//! `MEMCACHED_URL` environment variable is set, the `SqlStore`, or a custom Redis-backed `Store`.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, Redirect},
+    routing::{get, post},
+    Form, Router,
+};
+use log::error;
+use portier::{
+    axum::{AuthForm, Callback},
+    BuildError, Client, ClientPool, MemoryStore, Store,
+};
+use url::Url;
+
+/// Error response of the handlers.
+type Rejection = (StatusCode, &'static str);
+
+/// Configuration of a tenant.
+struct Tenant {
+    /// Name shown on pages.
+    name: &'static str,
+    /// Broker of the tenant, or `None` to use the default broker.
+    broker: Option<Url>,
+}
+
+/// Application state, shared by all tenants.
+#[derive(Clone)]
+struct AppState {
+    /// Scheme of tenant domains. This would be `https` in production.
+    scheme: &'static str,
+    /// Tenants, by host name.
+    tenants: Arc<HashMap<String, Tenant>>,
+    /// Clients for tenants using the default broker.
+    pool: Arc<ClientPool>,
+    /// Clients for tenants with their own broker, by host name.
+    custom: Arc<Mutex<HashMap<String, Arc<Client>>>>,
+    /// The store shared by all clients.
+    store: Arc<dyn Store>,
+}
+
+impl AppState {
+    /// Find the tenant and client for a request, based on the `Host` header.
+    ///
+    /// Only configured hosts are accepted, so the `Host` header cannot be used to make the
+    /// application redirect to an arbitrary origin.
+    fn tenant(&self, headers: &HeaderMap) -> Result<(&Tenant, Arc<Client>), Rejection> {
+        let host = headers
+            .get(header::HOST)
+            .and_then(|value| value.to_str().ok())
+            .ok_or((StatusCode::BAD_REQUEST, "missing host"))?;
+        let name = host.split(':').next().unwrap_or_default();
+        let tenant = self
+            .tenants
+            .get(name)
+            .ok_or((StatusCode::NOT_FOUND, "unknown tenant"))?;
+        let origin = format!("{}://{}", self.scheme, host);
+        self.client(name, tenant, &origin)
+            .map(|client| (tenant, client))
+            .map_err(|err| {
+                error!("could not build client for {}: {}", origin, err);
+                (StatusCode::INTERNAL_SERVER_ERROR, "configuration error")
+            })
+    }
+
+    /// The client for `tenant` at `origin`, building it if necessary.
+    fn client(&self, name: &str, tenant: &Tenant, origin: &str) -> Result<Arc<Client>, BuildError> {
+        let broker = match tenant.broker {
+            Some(ref broker) => broker,
+            None => return self.pool.client_for(origin),
+        };
+        if let Some(client) = self.custom.lock().unwrap().get(name) {
+            return Ok(client.clone());
+        }
+        let redirect_uri = format!("{}/verify", origin)
+            .parse()
+            .map_err(|_| BuildError::InvalidRedirectUri)?;
+        let client = Client::builder(redirect_uri)
+            .broker(broker.clone())
+            .store(self.store.clone())
+            .build()?;
+        let mut custom = self.custom.lock().unwrap();
+        Ok(custom
+            .entry(name.to_owned())
+            .or_insert(Arc::new(client))
+            .clone())
+    }
+}
+
+/// Render the index page with a login form.
+async fn index(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Html<String>, Rejection> {
+    let (tenant, _) = state.tenant(&headers)?;
+    Ok(Html(format!(
+        r#"
+        <p>Log in to {}:</p>
+        <form method="post" action="/auth">
+          <input name="email" type="email">
+          <button type="submit">Login</button>
+        </form>
+        "#,
+        tenant.name
+    )))
+}
+
+/// Start a login using the client of the tenant.
+async fn auth(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<AuthForm>,
+) -> Result<Redirect, Rejection> {
+    let (_, client) = state.tenant(&headers)?;
+    let url = client.start_auth(&form.email).await.map_err(|err| {
+        error!("Portier start_auth error: {}", err);
+        (StatusCode::INTERNAL_SERVER_ERROR, "could not start login")
+    })?;
+    Ok(Redirect::to(url.as_str()))
+}
+
+/// Verify the login using the client of the tenant.
+async fn verify(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Callback(params): Callback,
+) -> Result<Html<String>, Rejection> {
+    let (tenant, client) = state.tenant(&headers)?;
+    let email = client.handle_callback(&params).await.map_err(|err| {
+        error!("Portier verify error: {}", err);
+        (StatusCode::BAD_REQUEST, "login failed")
+    })?;
+    Ok(Html(format!(
+        "<p>Verified {} for {}!</p>",
+        email, tenant.name
+    )))
+}
+
+/// Create the store shared by all clients.
+fn store() -> Arc<dyn Store> {
+    #[cfg(feature = "memcached-store")]
+    if let Ok(url) = std::env::var("MEMCACHED_URL") {
+        let memcache = memcache::Client::connect(url).expect("could not connect to memcached");
+        return Arc::new(portier::MemcachedStore::new(
+            memcache,
+            portier::default_http_client(),
+        ));
+    }
+    Arc::new(MemoryStore::default())
+}
+
+/// Build the application.
+fn app(
+    scheme: &'static str,
+    default_broker: Url,
+    tenants: HashMap<String, Tenant>,
+    store: Arc<dyn Store>,
+) -> Router {
+    // The pool replaces the origin of this redirect URI with that of each tenant.
+    let template = Client::builder("https://tenant.invalid/verify".parse().unwrap())
+        .broker(default_broker)
+        .store(store.clone());
+    let state = AppState {
+        scheme,
+        tenants: Arc::new(tenants),
+        pool: Arc::new(ClientPool::new(template)),
+        custom: Default::default(),
+        store,
+    };
+    Router::new()
+        .route("/", get(index))
+        .route("/auth", post(auth))
+        .route("/verify", post(verify))
+        .with_state(state)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let tenants = HashMap::from([
+        (
+            "a.localhost".to_owned(),
+            Tenant {
+                name: "Tenant A",
+                broker: None,
+            },
+        ),
+        (
+            "b.localhost".to_owned(),
+            Tenant {
+                name: "Tenant B",
+                broker: Some("https://broker.portier.io".parse().unwrap()),
+            },
+        ),
+    ]);
+    let default_broker = "https://broker.portier.io".parse().unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:8000")
+        .await
+        .expect("could not bind to port 8000");
+    axum::serve(listener, app("http", default_broker, tenants, store()))
+        .await
+        .expect("server error");
+}
+
+#[cfg(all(test, feature = "test-utils", feature = "simple-store"))]
+mod tests {
+    use super::*;
+
+    use hyper::{body, Body, Method, Request};
+    use portier::test_utils::MockBroker;
+
+    /// Send a form to the application, for the given host, and return the response.
+    async fn send(app: &str, host: &str, path: &str, form: &str) -> hyper::Response<Body> {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}{}", app, path))
+            .header("host", host)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(Body::from(form.to_owned()))
+            .unwrap();
+        hyper::Client::new().request(req).await.unwrap()
+    }
+
+    /// Complete a login for `host`, checking it was started at `broker`.
+    async fn login(app: &str, host: &str, broker: &MockBroker) -> String {
+        let res = send(app, host, "/auth", "email=user%40example.com").await;
+        let auth_url: Url = res.headers()["location"].to_str().unwrap().parse().unwrap();
+        assert_eq!(auth_url.origin(), broker.url().origin());
+        let redirect_uri = auth_url
+            .query_pairs()
+            .find(|(name, _)| name == "redirect_uri")
+            .unwrap()
+            .1
+            .into_owned();
+        assert_eq!(redirect_uri, format!("http://{}/verify", host));
+
+        let token = broker.login(&auth_url).unwrap();
+        let res = send(app, host, "/verify", &format!("id_token={}", token)).await;
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn routes_tenants_to_their_broker() {
+        let default_broker = MockBroker::start().await.unwrap();
+        let custom_broker = MockBroker::start().await.unwrap();
+        let tenants = HashMap::from([
+            (
+                "a.localhost".to_owned(),
+                Tenant {
+                    name: "Tenant A",
+                    broker: None,
+                },
+            ),
+            (
+                "b.localhost".to_owned(),
+                Tenant {
+                    name: "Tenant B",
+                    broker: Some(custom_broker.url().clone()),
+                },
+            ),
+        ]);
+        let store: Arc<dyn Store> = Arc::new(MemoryStore::default());
+        let app = app("http", default_broker.url().clone(), tenants, store);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let body = login(&url, "a.localhost", &default_broker).await;
+        assert!(body.contains("Verified user@example.com for Tenant A"));
+        let body = login(&url, "b.localhost", &custom_broker).await;
+        assert!(body.contains("Verified user@example.com for Tenant B"));
+
+        let res = send(&url, "c.localhost", "/auth", "email=user%40example.com").await;
+        assert_eq!(res.status().as_u16(), 404);
+    }
+}
@@ -1,13 +1,10 @@
 //! Small example application for Portier using the Rocket framework.
 
 use log::error;
+use portier::rocket::{AuthRedirect, Portier, VerifiedEmail};
 use rocket::{
-    form::Form,
-    get,
-    http::{uri::Absolute, Status},
-    launch, post,
-    response::{content::RawHtml, Redirect},
-    routes, FromForm, State,
+    form::Form, get, http::Status, launch, post, response::content::RawHtml, routes, FromForm,
+    State,
 };
 
 /// Struct used to deserialize form data for `POST /auth`.
@@ -16,12 +13,6 @@ struct AuthForm {
     email: String,
 }
 
-/// Struct used to deserialize form data for `POST /verify`.
-#[derive(FromForm)]
-struct VerifyForm {
-    id_token: String,
-}
-
 /// Render a simple index page with a login form.
 #[get("/")]
 fn index() -> RawHtml<&'static str> {
@@ -41,41 +32,39 @@ fn index() -> RawHtml<&'static str> {
 /// This creates a login session using `portier::Client::start_auth`, and redirects the browser to
 /// complete the login.
 #[post("/auth", data = "<form>")]
-async fn auth(form: Form<AuthForm>, client: &State<portier::Client>) -> Result<Redirect, Status> {
+async fn auth(
+    form: Form<AuthForm>,
+    client: &State<portier::Client>,
+) -> Result<AuthRedirect, Status> {
     let url = client.start_auth(&form.email).await.map_err(|err| {
         error!("Portier start_auth error: {}", err);
         Status::InternalServerError
     })?;
 
-    Ok(Redirect::to(Absolute::parse_owned(url.into()).unwrap()))
+    Ok(AuthRedirect(url))
 }
 
 /// Handle the Portier response that arrives as a `POST /verify` request.
 ///
 /// Once the broker has authenticated the user, the user agent is instructed to make this `POST`
-/// request to us. The request contains a token we can verify using `portier::Client::verify`,
-/// which checks that the signature on the token is correct, then extracts the email address
-/// contained within.
+/// request to us. The `VerifiedEmail` data guard verifies the token it contains using the managed
+/// `portier::Client`, which checks that the signature on the token is correct, then extracts the
+/// email address contained within.
 ///
 /// Our example application renders a simple page showing that email address.
-#[post("/verify", data = "<form>")]
-async fn verify(
-    form: Form<VerifyForm>,
-    client: &State<portier::Client>,
-) -> Result<RawHtml<String>, Status> {
-    let email = client.verify(&form.id_token).await.map_err(|err| {
-        error!("Portier verify error: {}", err);
-        Status::InternalServerError
-    })?;
-
-    Ok(RawHtml(format!("<p>Verified email address {}!</p>", email)))
+#[post("/verify", data = "<email>")]
+fn verify(email: VerifiedEmail) -> RawHtml<String> {
+    RawHtml(format!("<p>Verified email address {}!</p>", email.0))
 }
 
 /// Rocket entry-point.
 #[launch]
 fn rocket() -> _ {
-    let redirect_uri = "http://localhost:8000/verify".parse().unwrap();
-    rocket::build()
+    // The `Portier` fairing reads its configuration from the `portier` table, which can also be
+    // set in `Rocket.toml` or using environment variables.
+    let figment =
+        rocket::Config::figment().join(("portier.redirect_uri", "http://localhost:8000/verify"));
+    rocket::custom(figment)
+        .attach(Portier::fairing())
         .mount("/", routes![index, auth, verify])
-        .manage(portier::Client::new(redirect_uri))
 }
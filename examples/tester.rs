@@ -1,7 +1,7 @@
 //! This executable is used to for integration testing the library using:
 //! https://github.com/portier/client-tester
 
-use portier::Client;
+use portier::{AuthOptions, Client};
 use tokio::io::{self, AsyncBufReadExt};
 
 #[tokio::main(flavor = "current_thread")]
@@ -24,15 +24,17 @@ async fn main() {
         let cmd: Vec<_> = line.split('\t').collect();
         match cmd[0] {
             "echo" => println!("ok\t{}", cmd[1]),
-            "auth" => match client.start_auth(&cmd[1]).await {
-                Ok(mut url) => {
-                    url.query_pairs_mut()
-                        .append_pair("state", cmd.get(2).cloned().unwrap_or_default());
-                    println!("ok\t{}", url);
+            "auth" => {
+                let options = AuthOptions {
+                    state: cmd.get(2).map(|state| state.to_string()),
+                    ..Default::default()
+                };
+                match client.start_auth_with_options(cmd[1], options).await {
+                    Ok(url) => println!("ok\t{}", url),
+                    Err(err) => println!("err\t{}", err),
                 }
-                Err(err) => println!("err\t{}", err),
-            },
-            "verify" => match client.verify(&cmd[1]).await {
+            }
+            "verify" => match client.verify(cmd[1]).await {
                 Ok(url) => println!("ok\t{}", url),
                 Err(err) => println!("err\t{}", err),
             },
@@ -0,0 +1,49 @@
+#!/bin/sh
+# Run the integration test in `tests/broker.rs` against a real broker.
+#
+# This starts a Portier broker and a Mailpit instance to capture its email using Docker, then runs
+# the test. The containers are removed afterwards.
+#
+# Usage: scripts/broker-test.sh [broker image]
+set -eu
+
+IMAGE=${1:-portier/broker}
+BROKER_PORT=${BROKER_PORT:-3333}
+MAILPIT_PORT=${MAILPIT_PORT:-8025}
+NETWORK=portier-rs-test
+
+cleanup() {
+    docker rm -f portier-rs-test-broker portier-rs-test-mailpit >/dev/null 2>&1 || true
+    docker network rm "$NETWORK" >/dev/null 2>&1 || true
+}
+trap cleanup EXIT
+cleanup
+
+docker network create "$NETWORK" >/dev/null
+docker run -d --rm --name portier-rs-test-mailpit --network "$NETWORK" \
+    -p "$MAILPIT_PORT:8025" axllent/mailpit >/dev/null
+docker run -d --rm --name portier-rs-test-broker --network "$NETWORK" \
+    -p "$BROKER_PORT:3333" \
+    -e BROKER_LISTEN_IP=0.0.0.0 \
+    -e BROKER_LISTEN_PORT=3333 \
+    -e BROKER_PUBLIC_URL="http://localhost:$BROKER_PORT" \
+    -e BROKER_MEMORY_STORAGE=true \
+    -e BROKER_FROM_ADDRESS=portier@localhost \
+    -e BROKER_SMTP_SERVER=portier-rs-test-mailpit:1025 \
+    "$IMAGE" >/dev/null
+
+# Wait for the broker to come up.
+i=0
+until curl -sf "http://localhost:$BROKER_PORT/.well-known/openid-configuration" >/dev/null; do
+    i=$((i + 1))
+    if [ $i -gt 60 ]; then
+        echo "broker did not start" >&2
+        docker logs portier-rs-test-broker >&2
+        exit 1
+    fi
+    sleep 1
+done
+
+PORTIER_TEST_BROKER="http://localhost:$BROKER_PORT" \
+PORTIER_TEST_MAILPIT="http://localhost:$MAILPIT_PORT" \
+    cargo test --test broker -- --nocapture
@@ -0,0 +1,40 @@
+#!/bin/sh
+# Build and test every combination of the store stack features, then every other optional feature
+# on top of the default features.
+#
+# Usage: scripts/check-features.sh [cargo subcommand] [extra arguments...]
+# The default subcommand is `test`, which also builds the library for each combination.
+set -eu
+
+STACK_FEATURES="client tokio memory-store http-hyper http-reqwest tls-native tls-rustls"
+EXTRA_FEATURES="reqwest-store rustls-store codec-cbor codec-msgpack sql-postgres sql-mysql sql-sqlite memcached-store dynamodb-store sled-store ed448 tracing blocking actix axum rocket"
+
+command=${1:-test}
+[ $# -eq 0 ] || shift
+
+run() {
+    features=$1
+    shift
+    echo "==> features: [$features]" >&2
+    cargo "$command" --lib --no-default-features --features "$features" "$@"
+}
+
+count=$(echo $STACK_FEATURES | wc -w)
+total=$((1 << count))
+i=0
+while [ $i -lt $total ]; do
+    selected=""
+    bit=0
+    for feature in $STACK_FEATURES; do
+        if [ $((i >> bit & 1)) -eq 1 ]; then
+            selected="$selected,$feature"
+        fi
+        bit=$((bit + 1))
+    done
+    run "${selected#,}" "$@"
+    i=$((i + 1))
+done
+
+for feature in $EXTRA_FEATURES; do
+    run "default,$feature" "$@"
+done
@@ -0,0 +1,160 @@
+//! Integration with the [actix-web](https://actix.rs) web framework.
+//!
+//! The extractors in this module expect the `Client` in app data, as `web::Data<Client>`. The
+//! `routes` function configures `POST /auth` and `POST /verify`, where the verify handler is
+//! supplied by the application, so that it can store the verified email address using whatever
+//! session middleware the application uses.
+//!
+//! ```no_run
+//! use actix_web::{web, App, HttpResponse};
+//! use portier::actix::{routes, VerifiedEmail};
+//!
+//! async fn verified(VerifiedEmail(email): VerifiedEmail) -> HttpResponse {
+//!     HttpResponse::Ok().body(format!("Verified email address {}!", email))
+//! }
+//!
+//! let client = portier::Client::new("http://localhost:8000/verify".parse().unwrap());
+//! let client = web::Data::new(client);
+//! let app = App::new()
+//!     .app_data(client.clone())
+//!     .configure(routes(verified));
+//! ```
+//!
+//! Note that actix-web requires a newer Rust version than the rest of this crate.
+
+use std::{future::Future, pin::Pin};
+
+use actix_web::{
+    dev::Payload,
+    http::{header, StatusCode},
+    web::{self, Form, ServiceConfig},
+    FromRequest, Handler, HttpRequest, HttpResponse, Responder, ResponseError,
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{CallbackError, CallbackParams, Client, FragmentPayload, RelayError, StartAuthError};
+
+/// Extracts `CallbackParams` from a form-encoded `POST` body.
+///
+/// This is the body the broker sends to the redirect URI with `ResponseMode::FormPost`.
+#[derive(Clone, Debug)]
+pub struct Callback(pub CallbackParams);
+
+impl FromRequest for Callback {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let form = Form::<Vec<(String, String)>>::from_request(req, payload);
+        Box::pin(async move {
+            let Form(pairs) = form.await?;
+            Ok(Callback(pairs.into_iter().collect()))
+        })
+    }
+}
+
+/// Errors that can result from the `VerifiedEmail` extractor.
+#[derive(Debug, Error)]
+pub enum VerifyRejection {
+    #[error("no `web::Data<Client>` in app data")]
+    MissingClient,
+    #[error("could not read the request body: {0}")]
+    Body(actix_web::Error),
+    #[error(transparent)]
+    Relay(#[from] RelayError),
+    #[error(transparent)]
+    Callback(#[from] CallbackError),
+}
+
+impl ResponseError for VerifyRejection {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            VerifyRejection::MissingClient => StatusCode::INTERNAL_SERVER_ERROR,
+            VerifyRejection::Callback(err) if err.is_server_error() => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+/// Extracts the verified email address from a callback `POST` body.
+///
+/// This uses `Client::handle_callback` with the `Client` in app data. If the body contains a
+/// `redirect_uri`, as sent to the relay endpoint with `ResponseMode::Fragment`, it is checked
+/// using `FragmentRelay::check_redirect_uri`.
+#[derive(Clone, Debug)]
+pub struct VerifiedEmail(pub String);
+
+impl FromRequest for VerifiedEmail {
+    type Error = VerifyRejection;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let client = req.app_data::<web::Data<Client>>().cloned();
+        let callback = Callback::from_request(req, payload);
+        Box::pin(async move {
+            let client = client.ok_or(VerifyRejection::MissingClient)?;
+            let Callback(params) = callback.await.map_err(VerifyRejection::Body)?;
+            let payload = FragmentPayload::from(params);
+            payload.check_redirect_uri(client.fragment_relay())?;
+            Ok(VerifiedEmail(
+                client.handle_callback(&payload.params).await?,
+            ))
+        })
+    }
+}
+
+/// Form body expected by `auth`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct AuthForm {
+    pub email: String,
+}
+
+/// Handler that starts authentication, and redirects to the broker.
+///
+/// Responds with `503 Service Unavailable` if the broker is unavailable, and with
+/// `429 Too Many Requests` if the `RateLimiter` denied the login, passing on the `Retry-After`
+/// delay.
+///
+/// Used for `POST /auth` by `routes`, but can also be mounted separately.
+pub async fn auth(client: web::Data<Client>, form: Form<AuthForm>) -> HttpResponse {
+    match client.start_auth(&form.email).await {
+        Ok(url) => HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, url.as_str()))
+            .finish(),
+        Err(StartAuthError::BrokerUnavailable { retry_after }) => {
+            let mut response = HttpResponse::ServiceUnavailable();
+            if let Some(delay) = retry_after {
+                response.insert_header((header::RETRY_AFTER, delay.as_secs()));
+            }
+            response.body("login is temporarily unavailable")
+        }
+        Err(StartAuthError::RateLimited { retry_after, .. }) => {
+            let mut response = HttpResponse::TooManyRequests();
+            if let Some(delay) = retry_after {
+                response.insert_header((header::RETRY_AFTER, delay.as_secs()));
+            }
+            response.body("too many login attempts")
+        }
+        Err(_) => HttpResponse::InternalServerError().body("could not start login"),
+    }
+}
+
+/// Configure `POST /auth` and `POST /verify` routes, for use with `App::configure`.
+///
+/// The verify route uses the given handler, which will typically use the `VerifiedEmail`
+/// extractor and the application session. The verify path must match the redirect URI of the
+/// `Client`, also when used within a scope.
+pub fn routes<F, Args>(verify: F) -> impl FnOnce(&mut ServiceConfig)
+where
+    F: Handler<Args>,
+    Args: FromRequest + 'static,
+    F::Output: Responder + 'static,
+{
+    move |cfg| {
+        cfg.route("/auth", web::post().to(auth))
+            .route("/verify", web::post().to(verify));
+    }
+}
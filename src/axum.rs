@@ -0,0 +1,154 @@
+//! Integration with the [axum](https://docs.rs/axum) web framework.
+//!
+//! The extractors in this module expect an `Arc<Client>` in the router state, either directly or
+//! through `FromRef`. The `router` function provides routes for `POST /auth` and `POST /verify`,
+//! where the verify handler is supplied by the application, so that it can store the verified
+//! email address using whatever session layer the application uses.
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use axum::{response::Html, routing::post, Router};
+//! use portier::axum::{router, VerifiedEmail};
+//!
+//! async fn verified(VerifiedEmail(email): VerifiedEmail) -> Html<String> {
+//!     Html(format!("<p>Verified email address {}!</p>", email))
+//! }
+//!
+//! let client = portier::Client::new("http://localhost:8000/verify".parse().unwrap());
+//! let app: Router = router(post(verified)).with_state(Arc::new(client));
+//! ```
+//!
+//! Note that axum requires a newer Rust version than the rest of this crate.
+
+use std::sync::Arc;
+
+use ::axum::{
+    extract::{FromRef, FromRequest, Request, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    routing::{post, MethodRouter},
+    Form, Router,
+};
+use serde::Deserialize;
+
+use crate::{CallbackError, CallbackParams, Client, StartAuthError};
+
+/// Extracts `CallbackParams` from a form-encoded `POST` body.
+///
+/// This is the body the broker sends to the redirect URI with `ResponseMode::FormPost`.
+#[derive(Clone, Debug)]
+pub struct Callback(pub CallbackParams);
+
+impl<S: Send + Sync> FromRequest<S> for Callback {
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Form(pairs) = Form::<Vec<(String, String)>>::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        Ok(Callback(pairs.into_iter().collect()))
+    }
+}
+
+/// Extracts the verified email address from a callback `POST` body.
+///
+/// This uses `Client::handle_callback`, and requires an `Arc<Client>` in the router state.
+#[derive(Clone, Debug)]
+pub struct VerifiedEmail(pub String);
+
+impl<S> FromRequest<S> for VerifiedEmail
+where
+    Arc<Client>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = VerifyRejection;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let client = Arc::<Client>::from_ref(state);
+        let Callback(params) = Callback::from_request(req, state)
+            .await
+            .map_err(VerifyRejection::Body)?;
+        client
+            .handle_callback(&params)
+            .await
+            .map(VerifiedEmail)
+            .map_err(VerifyRejection::Callback)
+    }
+}
+
+/// Rejection used by `VerifiedEmail`.
+#[derive(Debug)]
+pub enum VerifyRejection {
+    /// The request body could not be parsed.
+    Body(Response),
+    /// Authentication failed, or the token could not be verified.
+    Callback(CallbackError),
+}
+
+impl IntoResponse for VerifyRejection {
+    fn into_response(self) -> Response {
+        match self {
+            VerifyRejection::Body(res) => res,
+            VerifyRejection::Callback(ref err) if err.is_server_error() => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "could not verify login").into_response()
+            }
+            VerifyRejection::Callback(_) => {
+                (StatusCode::BAD_REQUEST, "login failed").into_response()
+            }
+        }
+    }
+}
+
+/// Form body expected by `auth`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct AuthForm {
+    pub email: String,
+}
+
+/// Handler that starts authentication, and redirects to the broker.
+///
+/// Responds with `503 Service Unavailable` if the broker is unavailable, and with
+/// `429 Too Many Requests` if the `RateLimiter` denied the login, passing on the `Retry-After`
+/// delay.
+///
+/// Used for `POST /auth` by `router`, but can also be mounted separately.
+pub async fn auth(
+    State(client): State<Arc<Client>>,
+    Form(form): Form<AuthForm>,
+) -> Result<Redirect, Response> {
+    let url = client
+        .start_auth(&form.email)
+        .await
+        .map_err(|err| match err {
+            StartAuthError::BrokerUnavailable { retry_after } => {
+                let retry_after = retry_after.map(|delay| delay.as_secs().to_string());
+                let headers = retry_after.map(|delay| [(header::RETRY_AFTER, delay)]);
+                let body = "login is temporarily unavailable";
+                (StatusCode::SERVICE_UNAVAILABLE, headers, body).into_response()
+            }
+            StartAuthError::RateLimited { retry_after, .. } => {
+                let retry_after = retry_after.map(|delay| delay.as_secs().to_string());
+                let headers = retry_after.map(|delay| [(header::RETRY_AFTER, delay)]);
+                let body = "too many login attempts";
+                (StatusCode::TOO_MANY_REQUESTS, headers, body).into_response()
+            }
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, "could not start login").into_response(),
+        })?;
+    Ok(Redirect::to(url.as_str()))
+}
+
+/// Create a router with `POST /auth` and `POST /verify` routes.
+///
+/// The verify route uses the given handler, which will typically use the `VerifiedEmail`
+/// extractor and the application session. The verify path must match the redirect URI of the
+/// `Client`, also when nesting this router.
+pub fn router<S>(verify: MethodRouter<S>) -> Router<S>
+where
+    Arc<Client>: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/auth", post(auth))
+        .route("/verify", verify)
+}
@@ -0,0 +1,111 @@
+use std::net::IpAddr;
+
+use ring::digest;
+use url::Url;
+
+/// Which properties of the user agent to bind login sessions to. See `Builder::session_binding`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SessionBinding {
+    /// Don't bind sessions.
+    #[default]
+    Off,
+    /// Bind sessions to the `User-Agent` header.
+    UserAgent,
+    /// Bind sessions to the client IP address.
+    Ip,
+    /// Bind sessions to both the `User-Agent` header and the client IP address.
+    UserAgentAndIp,
+}
+
+impl SessionBinding {
+    /// The digest of the properties in `info` selected by this mode, or `None` if disabled.
+    pub(crate) fn digest(&self, info: &ClientInfo) -> Option<[u8; DIGEST_LEN]> {
+        let (user_agent, ip) = match self {
+            SessionBinding::Off => return None,
+            SessionBinding::UserAgent => (true, false),
+            SessionBinding::Ip => (false, true),
+            SessionBinding::UserAgentAndIp => (true, true),
+        };
+
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        if user_agent {
+            update_field(
+                &mut ctx,
+                b"ua",
+                info.user_agent.as_deref().map(str::as_bytes),
+            );
+        }
+        if ip {
+            let ip = info.ip.map(|ip| ip.to_canonical().to_string());
+            update_field(&mut ctx, b"ip", ip.as_deref().map(str::as_bytes));
+        }
+        let mut out = [0; DIGEST_LEN];
+        out.copy_from_slice(ctx.finish().as_ref());
+        Some(out)
+    }
+}
+
+/// Length of the binding digest stored with a session.
+pub(crate) const DIGEST_LEN: usize = 32;
+
+/// Add a length-prefixed field to the binding digest, so fields can't run into eachother.
+fn update_field(ctx: &mut digest::Context, name: &[u8], value: Option<&[u8]>) {
+    ctx.update(name);
+    match value {
+        Some(value) => {
+            ctx.update(&[1]);
+            ctx.update(&(value.len() as u64).to_be_bytes());
+            ctx.update(value);
+        }
+        None => ctx.update(&[0]),
+    }
+}
+
+/// Properties of the user agent making a request, used for session binding.
+///
+/// Pass this in `AuthOptions::client_info` when starting a login, and to
+/// `Client::verify_with_info` when verifying it. Only the properties selected using
+/// `Builder::session_binding` are compared.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    user_agent: Option<String>,
+    ip: Option<IpAddr>,
+    redirect_uri: Option<Url>,
+}
+
+impl ClientInfo {
+    /// Create empty client info.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the value of the `User-Agent` request header.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Set the client IP address.
+    ///
+    /// Behind a reverse proxy, this should be the address the proxy reports, not the address of
+    /// the proxy itself.
+    pub fn ip(mut self, ip: IpAddr) -> Self {
+        self.ip = Some(ip);
+        self
+    }
+
+    /// Set the redirect URI the token arrived on, when verifying a login.
+    ///
+    /// This is checked against `AuthOptions::redirect_uri`, or the redirect URI of the `Client`
+    /// if the login did not override it, regardless of `Builder::session_binding`.
+    pub fn redirect_uri(mut self, redirect_uri: Url) -> Self {
+        self.redirect_uri = Some(redirect_uri);
+        self
+    }
+
+    /// The redirect URI set using `ClientInfo::redirect_uri`.
+    pub(crate) fn arrived_on(&self) -> Option<&Url> {
+        self.redirect_uri.as_ref()
+    }
+}
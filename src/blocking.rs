@@ -0,0 +1,701 @@
+//! A synchronous API, for applications that don't use an async runtime.
+//!
+//! The `blocking::Client` mirrors `portier::Client`, but its methods block the calling thread. It
+//! uses a synchronous `blocking::Store`, and by default a `blocking::MemoryStore` that fetches
+//! documents using ureq.
+//!
+//! These methods must not be called from within an async runtime, because they block the thread.
+//!
+//! This module requires the `blocking` crate feature.
+
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::Read,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+use url::Url;
+
+use crate::misc::{self, DynErr, DynFut, DynFutRes};
+use crate::store::sessions::{Pair, Sessions};
+use crate::{
+    jwk, AuthOptions, Broker, BuildError, BuildWarning, CallbackError, CallbackParams, CheckError,
+    CheckReport, ClaimCheck, ClaimPolicy, Clock, DiscoveryDoc, FetchError, FragmentRelay,
+    HttpStatusError, IssuerCheck, NonceGenerator, RandomNonces, ResponseMode, SelfTestReport,
+    SpecVersion, StartAuthError, SystemClock, Unsupported, UriCanonicalization, VerifiedToken,
+    VerifyError,
+};
+
+/// Synchronous counterpart of `portier::Store`, used by `blocking::Client`.
+///
+/// See `portier::Store` for the contract of each method. The store is shared between threads by
+/// reference, and is itself responsible for synchronizing access from different threads.
+pub trait Store: Send + Sync + 'static {
+    /// Requests a document using HTTP GET, and perform caching.
+    ///
+    /// See `blocking::simple_fetch` for a default implementation to use on cache miss.
+    fn fetch(&self, url: Url) -> Result<Bytes, FetchError>;
+
+    /// Generate a random nonce and store the pair nonce/email.
+    fn new_nonce(&self, email: String) -> Result<String, DynErr>;
+
+    /// Like `blocking::Store::new_nonce`, but the pair should expire after `ttl`.
+    ///
+    /// The default implementation ignores `ttl`, and calls `blocking::Store::new_nonce`.
+    fn new_nonce_with_ttl(&self, email: String, ttl: Duration) -> Result<String, DynErr> {
+        let _ = ttl;
+        self.new_nonce(email)
+    }
+
+    /// Check that a nonce/email pair exists and delete it if so.
+    fn consume_nonce(&self, nonce: String, email: String) -> Result<bool, DynErr>;
+
+    /// Store a nonce/email pair that was generated elsewhere, optionally expiring after `ttl`.
+    ///
+    /// See `portier::Store::insert_nonce`. The default implementation returns `Unsupported`.
+    fn insert_nonce(
+        &self,
+        nonce: String,
+        email: String,
+        ttl: Option<Duration>,
+    ) -> Result<(), DynErr> {
+        let _ = (nonce, email, ttl);
+        Err(Box::new(Unsupported("insert_nonce")))
+    }
+}
+
+/// A `blocking::Store` that keeps everything in-memory, and fetches documents using ureq.
+///
+/// This is the synchronous counterpart of `portier::MemoryStore`, and has the same limitations.
+/// Like that store, the cache is bounded to 1000 documents by default, and sessions created using
+/// `blocking::Store::new_nonce_with_ttl` expire.
+pub struct MemoryStore {
+    agent: ureq::Agent,
+    nonce_generator: Arc<dyn NonceGenerator>,
+    clock: Arc<dyn Clock>,
+    cache_capacity: usize,
+    cache: Mutex<Cache>,
+    nonces: Mutex<Sessions>,
+}
+
+impl MemoryStore {
+    /// Create a store with a default ureq agent, with a timeout of 30 seconds.
+    pub fn new() -> Self {
+        Self::with_agent(
+            ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(30))
+                .build(),
+        )
+    }
+
+    /// Create a store using the given ureq agent.
+    ///
+    /// This allows reusing the connection pool, proxy and TLS configuration of an existing agent.
+    pub fn with_agent(agent: ureq::Agent) -> Self {
+        MemoryStore {
+            agent,
+            nonce_generator: Arc::new(RandomNonces::new()),
+            clock: Arc::new(SystemClock),
+            cache_capacity: 1000,
+            cache: Mutex::default(),
+            nonces: Mutex::default(),
+        }
+    }
+
+    /// Use the given `Clock` for cache and session expiry, instead of the system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Generate nonces for new sessions using `generator`, instead of `RandomNonces`.
+    pub fn nonce_generator(mut self, generator: Arc<dyn NonceGenerator>) -> Self {
+        self.nonce_generator = generator;
+        self
+    }
+
+    /// Limit the cache to `capacity` documents. The default is 1000. See
+    /// `portier::MemoryStore::cache_capacity`.
+    pub fn cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache_capacity = capacity.max(1);
+        self
+    }
+
+    /// Generate a nonce and store the pair, optionally expiring after `ttl`.
+    fn create_nonce(&self, email: String, ttl: Option<Duration>) -> Result<String, DynErr> {
+        let nonce = block_on(self.nonce_generator.generate())?;
+        self.store_pair(nonce.clone(), email, ttl);
+        Ok(nonce)
+    }
+
+    /// Store a nonce/email pair, optionally expiring after `ttl`.
+    fn store_pair(&self, nonce: String, email: String, ttl: Option<Duration>) {
+        let now = self.clock.instant();
+        let expires = ttl.map(|ttl| now + ttl);
+        self.nonces
+            .lock()
+            .unwrap()
+            .insert(Pair(nonce, email), Vec::new(), expires, now);
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The document cache of a `blocking::MemoryStore`, bounded to a maximum number of entries.
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<Url, CacheItem>,
+    /// Counter used to track the order in which entries were used.
+    tick: u64,
+}
+
+struct CacheItem {
+    result: Result<Bytes, Arc<DynErr>>,
+    expires: Instant,
+    last_used: u64,
+}
+
+impl Cache {
+    /// The cached result for `url`, if it has not expired.
+    fn get(&mut self, url: &Url, now: Instant) -> Option<Result<Bytes, Arc<DynErr>>> {
+        self.tick += 1;
+        let item = self.entries.get_mut(url)?;
+        if now >= item.expires {
+            return None;
+        }
+        item.last_used = self.tick;
+        Some(item.result.clone())
+    }
+
+    /// Cache `result` for `url`, evicting other entries if the cache is full.
+    ///
+    /// Expired entries are evicted first, then the least recently used.
+    fn insert(
+        &mut self,
+        url: Url,
+        result: Result<Bytes, Arc<DynErr>>,
+        expires: Instant,
+        capacity: usize,
+        now: Instant,
+    ) {
+        self.tick += 1;
+        if !self.entries.contains_key(&url) && self.entries.len() >= capacity {
+            self.entries.retain(|_, item| now < item.expires);
+            while self.entries.len() >= capacity {
+                let oldest = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, item)| item.last_used)
+                    .map(|(url, _)| url.clone());
+                match oldest {
+                    Some(url) => self.entries.remove(&url),
+                    None => break,
+                };
+            }
+        }
+        let item = CacheItem {
+            result,
+            expires,
+            last_used: self.tick,
+        };
+        self.entries.insert(url, item);
+    }
+}
+
+impl Store for MemoryStore {
+    fn fetch(&self, url: Url) -> Result<Bytes, FetchError> {
+        let now = self.clock.instant();
+        if let Some(result) = self.cache.lock().unwrap().get(&url, now) {
+            return result.map_err(FetchError::Fetch);
+        }
+
+        let (result, max_age) = simple_fetch(&self.agent, &url);
+        let result = result.map_err(Arc::new);
+        self.cache.lock().unwrap().insert(
+            url,
+            result.clone(),
+            now + max_age,
+            self.cache_capacity,
+            now,
+        );
+        result.map_err(FetchError::Fetch)
+    }
+
+    fn new_nonce(&self, email: String) -> Result<String, DynErr> {
+        self.create_nonce(email, None)
+    }
+
+    fn new_nonce_with_ttl(&self, email: String, ttl: Duration) -> Result<String, DynErr> {
+        self.create_nonce(email, Some(ttl))
+    }
+
+    fn consume_nonce(&self, nonce: String, email: String) -> Result<bool, DynErr> {
+        let now = self.clock.instant();
+        let res = self.nonces.lock().unwrap().remove(&Pair(nonce, email), now);
+        Ok(res.is_some())
+    }
+
+    fn insert_nonce(
+        &self,
+        nonce: String,
+        email: String,
+        ttl: Option<Duration>,
+    ) -> Result<(), DynErr> {
+        self.store_pair(nonce, email, ttl);
+        Ok(())
+    }
+}
+
+/// Performs a simple GET-request using the given ureq agent, and handles the response.
+///
+/// This is the synchronous counterpart of `portier::simple_fetch`, with the same cache lifespan
+/// rules and default headers. The timeout is configured on the agent.
+pub fn simple_fetch(agent: &ureq::Agent, url: &Url) -> (Result<Bytes, DynErr>, Duration) {
+    // Error-case default cache lifespan.
+    let max_age = Duration::from_secs(3);
+
+    let request = agent
+        .request_url("GET", url)
+        .set("User-Agent", misc::USER_AGENT)
+        .set("Accept", "application/json");
+    let response = match request.call() {
+        Ok(response) if response.status() == 200 => response,
+        Ok(response) => {
+            let retry_after =
+                misc::parse_retry_after(response.header("retry-after")).map(Duration::from_secs);
+            let err = HttpStatusError::new(response.status(), retry_after);
+            return (Err(Box::new(err)), max_age);
+        }
+        Err(err) => return (Err(Box::new(err)), max_age),
+    };
+
+    // Success-case default and minimum cache lifespan.
+    let max_age = misc::parse_max_age(response.header("cache-control"))
+        .map_or(Duration::from_secs(60), |val| {
+            Duration::from_secs(val.max(60))
+        });
+
+    let mut body = Vec::new();
+    if let Err(err) = response.into_reader().read_to_end(&mut body) {
+        return (Err(Box::new(err)), Duration::from_secs(3));
+    }
+    (Ok(body.into()), max_age)
+}
+
+/// A builder to configure a `blocking::Client`.
+///
+/// This mirrors `portier::Builder`. See there for details on each setting.
+#[derive(Clone)]
+pub struct Builder {
+    inner: crate::Builder,
+    store: Option<Arc<dyn Store>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Builder {
+    /// Use the given `blocking::Store` for cache and session storage.
+    ///
+    /// If no store is specified, a default `blocking::MemoryStore` is created.
+    pub fn store(mut self, store: Arc<dyn Store>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Configure the client to use a trusted broker. See `portier::Builder::broker`.
+    pub fn broker(mut self, url: Url) -> Self {
+        self.inner = self.inner.broker(url);
+        self
+    }
+
+    /// Configure the client to use an untrusted identity provider. See `portier::Builder::idp`.
+    pub fn idp(mut self, url: Url) -> Self {
+        self.inner = self.inner.idp(url);
+        self
+    }
+
+    /// Configure the client to use a validated `Broker`. See `portier::Builder::server`.
+    pub fn server(mut self, broker: Broker) -> Self {
+        self.inner = self.inner.server(broker);
+        self
+    }
+
+    /// Configure the response mode to use. The default is `FormPost`.
+    pub fn response_mode(mut self, mode: ResponseMode) -> Self {
+        self.inner = self.inner.response_mode(mode);
+        self
+    }
+
+    /// Configure the leeway to allow for timestamps in tokens. The default is 3 minutes.
+    pub fn leeway(mut self, dur: Duration) -> Self {
+        self.inner = self.inner.leeway(dur);
+        self
+    }
+
+    /// Configure the Portier spec revision to conform to. The default is `SpecVersion::V1`.
+    pub fn spec_version(mut self, version: SpecVersion) -> Self {
+        self.inner = self.inner.spec_version(version);
+        self
+    }
+
+    /// Configure how to handle tokens that fail `check`. See `Validator::claim_policy`.
+    pub fn claim_policy(mut self, check: ClaimCheck, policy: ClaimPolicy) -> Self {
+        self.inner = self.inner.claim_policy(check, policy);
+        self
+    }
+
+    /// Call `f` for every problem with a token accepted using `ClaimPolicy::Warn`.
+    pub fn on_claim_warning(mut self, f: impl Fn(&VerifyError) + Send + Sync + 'static) -> Self {
+        self.inner = self.inner.on_claim_warning(f);
+        self
+    }
+
+    /// Configure whether relative URLs in the discovery document are allowed. The default is
+    /// `true`.
+    pub fn relative_discovery_urls(mut self, enabled: bool) -> Self {
+        self.inner = self.inner.relative_discovery_urls(enabled);
+        self
+    }
+
+    /// Enable strict validation of the discovery document. The default is disabled.
+    pub fn strict_discovery(mut self, enabled: bool) -> Self {
+        self.inner = self.inner.strict_discovery(enabled);
+        self
+    }
+
+    /// Configure how redirect URIs are canonicalized. The default is
+    /// `UriCanonicalization::Parsed`.
+    pub fn uri_canonicalization(mut self, canonicalization: UriCanonicalization) -> Self {
+        self.inner = self.inner.uri_canonicalization(canonicalization);
+        self
+    }
+
+    /// Configure how the `issuer` in the discovery document is checked. The default is
+    /// `IssuerCheck::Verify`.
+    pub fn issuer_check(mut self, check: IssuerCheck) -> Self {
+        self.inner = self.inner.issuer_check(check);
+        self
+    }
+
+    /// Use `keys` to verify tokens, instead of fetching the JWKs document of the broker.
+    pub fn pinned_jwks(mut self, keys: jwk::KeySet) -> Self {
+        self.inner = self.inner.pinned_jwks(keys);
+        self
+    }
+
+    /// Use `document` as the discovery document of the broker, instead of fetching it.
+    pub fn pinned_discovery(mut self, document: impl Into<Bytes>) -> Self {
+        self.inner = self.inner.pinned_discovery(document);
+        self
+    }
+
+    /// Reject tokens longer than `bytes`. See `crate::Builder::max_token_len`.
+    pub fn max_token_len(mut self, bytes: usize) -> Self {
+        self.inner = self.inner.max_token_len(bytes);
+        self
+    }
+
+    /// Reject email addresses longer than `bytes`. See `crate::Builder::max_email_len`.
+    pub fn max_email_len(mut self, bytes: usize) -> Self {
+        self.inner = self.inner.max_email_len(bytes);
+        self
+    }
+
+    /// Use the given `Clock` for token validation, instead of the system clock.
+    ///
+    /// If no store is specified, the clock is also used by the default `blocking::MemoryStore`.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.inner = self.inner.clock(clock.clone());
+        self.clock = clock;
+        self
+    }
+
+    /// Configure the path of the relay endpoint used with `ResponseMode::Fragment`.
+    pub fn fragment_relay_path(mut self, path: impl Into<String>) -> Self {
+        self.inner = self.inner.fragment_relay_path(path);
+        self
+    }
+
+    /// Call `f` for every `BuildWarning` found by `blocking::Builder::build`.
+    pub fn on_warning(mut self, f: impl Fn(&BuildWarning) + Send + Sync + 'static) -> Self {
+        self.inner = self.inner.on_warning(f);
+        self
+    }
+
+    /// Require the endpoints in the discovery document to be on the origin of the server. The
+    /// default is disabled.
+    pub fn same_origin_endpoints(mut self, enabled: bool) -> Self {
+        self.inner = self.inner.same_origin_endpoints(enabled);
+        self
+    }
+
+    /// Apply the strictest settings, for security-conscious deployments.
+    ///
+    /// See `portier::Builder::hardened` for the settings applied.
+    pub fn hardened(mut self) -> Self {
+        self.inner = self.inner.hardened();
+        self
+    }
+
+    /// Apply settings compatible with older releases of the Portier broker.
+    ///
+    /// See `portier::Builder::compat_portier_broker_v0` for the settings applied.
+    pub fn compat_portier_broker_v0(mut self) -> Self {
+        self.inner = self.inner.compat_portier_broker_v0();
+        self
+    }
+
+    /// Make `blocking::Builder::build` fail with `BuildError::Warning` on the first warning.
+    pub fn deny_warnings(mut self, enabled: bool) -> Self {
+        self.inner = self.inner.deny_warnings(enabled);
+        self
+    }
+
+    /// Verify the configuration and build the client.
+    pub fn build(self) -> Result<Client, BuildError> {
+        let store = match self.store {
+            Some(store) => store,
+            None => Arc::new(MemoryStore::new().clock(self.clock)),
+        };
+        let inner = self.inner.store(Arc::new(AsyncStore(store))).build()?;
+        Ok(Client { inner })
+    }
+}
+
+/// A client for performing Portier authentication, with blocking methods.
+///
+/// This mirrors `portier::Client`. See there for details on each method.
+#[derive(Clone)]
+pub struct Client {
+    inner: crate::Client,
+}
+
+impl Client {
+    /// Create a builder-style struct to configure a Client.
+    pub fn builder(redirect_uri: Url) -> Builder {
+        Builder {
+            inner: crate::Client::builder(redirect_uri),
+            store: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Create a client with default settings, using a `blocking::MemoryStore`.
+    pub fn new(redirect_uri: Url) -> Self {
+        Self::builder(redirect_uri).build().unwrap()
+    }
+
+    /// The canonical redirect URI. See `portier::Client::redirect_uri`.
+    pub fn redirect_uri(&self) -> &Url {
+        self.inner.redirect_uri()
+    }
+
+    /// The client ID sent to the broker. See `portier::Client::client_id`.
+    pub fn client_id(&self) -> &str {
+        self.inner.client_id()
+    }
+
+    /// Relay endpoint configuration for use with `ResponseMode::Fragment`.
+    pub fn fragment_relay(&self) -> &FragmentRelay {
+        self.inner.fragment_relay()
+    }
+
+    /// Fetch the discovery document of the broker used for new logins.
+    ///
+    /// See `portier::Client::discovery`.
+    pub fn discovery(&self) -> Result<DiscoveryDoc, StartAuthError> {
+        block_on(self.inner.discovery())
+    }
+
+    /// Fetch and validate the discovery and keys documents of every configured broker endpoint.
+    pub fn check(&self) -> Result<Vec<CheckReport>, CheckError> {
+        block_on(self.inner.check())
+    }
+
+    /// Check that the environment of the client works. See `portier::Client::self_test`.
+    pub fn self_test(&self, check_brokers: bool) -> SelfTestReport {
+        block_on(self.inner.self_test(check_brokers))
+    }
+
+    /// Create a login session for the given email, and return a URL to redirect the user agent
+    /// (browser) to so authentication can continue.
+    pub fn start_auth(&self, email: &str) -> Result<Url, StartAuthError> {
+        block_on(self.inner.start_auth(email))
+    }
+
+    /// Like `blocking::Client::start_auth`, but adds the parameters in `options` to the URL.
+    pub fn start_auth_with_options(
+        &self,
+        email: &str,
+        options: AuthOptions,
+    ) -> Result<Url, StartAuthError> {
+        block_on(self.inner.start_auth_with_options(email, options))
+    }
+
+    /// Verify `token` and return a verified email address.
+    pub fn verify(&self, token: &str) -> Result<String, VerifyError> {
+        block_on(self.inner.verify(token))
+    }
+
+    /// Handle all parameters sent by the broker to the redirect URI, and return a verified email
+    /// address.
+    pub fn handle_callback(&self, params: &CallbackParams) -> Result<String, CallbackError> {
+        block_on(self.inner.handle_callback(params))
+    }
+
+    /// Like `blocking::Client::verify`, but return all validated claims.
+    pub fn verify_full(&self, token: &str) -> Result<VerifiedToken, VerifyError> {
+        block_on(self.inner.verify_full(token))
+    }
+
+    /// Like `blocking::Client::verify_full`, but also deserialize the token payload into `T`.
+    pub fn verify_with_claims<T: DeserializeOwned>(
+        &self,
+        token: &str,
+    ) -> Result<(VerifiedToken, T), VerifyError> {
+        block_on(self.inner.verify_with_claims(token))
+    }
+}
+
+/// Adapts a `blocking::Store` to `portier::Store`, doing the work before returning a future.
+struct AsyncStore(Arc<dyn Store>);
+
+impl crate::Store for AsyncStore {
+    fn fetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        let result = self.0.fetch(url);
+        Box::pin(async move { result })
+    }
+
+    fn new_nonce(&self, email: String) -> DynFutRes<String> {
+        let result = self.0.new_nonce(email);
+        Box::pin(async move { result })
+    }
+
+    fn new_nonce_with_ttl(&self, email: String, ttl: Duration) -> DynFutRes<String> {
+        let result = self.0.new_nonce_with_ttl(email, ttl);
+        Box::pin(async move { result })
+    }
+
+    fn consume_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        let result = self.0.consume_nonce(nonce, email);
+        Box::pin(async move { result })
+    }
+
+    fn insert_nonce(
+        &self,
+        nonce: String,
+        email: String,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> DynFutRes<()> {
+        // `blocking::Store` does not keep session data.
+        let result = if data.is_empty() {
+            self.0.insert_nonce(nonce, email, ttl)
+        } else {
+            Err(Box::new(Unsupported("new_nonce_with_data")) as DynErr)
+        };
+        Box::pin(async move { result })
+    }
+}
+
+/// Wakes a thread parked in `block_on`.
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run a future to completion on the current thread.
+fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = Box::pin(fut);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match Pin::as_mut(&mut fut).poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::ManualClock;
+
+    const EMAIL: &str = "user@example.com";
+
+    fn store() -> (MemoryStore, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new(SystemTime::now()));
+        (MemoryStore::new().clock(clock.clone()), clock)
+    }
+
+    #[test]
+    fn consumes_nonce_once() {
+        let (store, _) = store();
+        let nonce = store.new_nonce(EMAIL.into()).unwrap();
+        assert!(!store
+            .consume_nonce(nonce.clone(), "other@example.com".into())
+            .unwrap());
+        assert!(store.consume_nonce(nonce.clone(), EMAIL.into()).unwrap());
+        assert!(!store.consume_nonce(nonce, EMAIL.into()).unwrap());
+    }
+
+    #[test]
+    fn expires_nonce() {
+        let (store, clock) = store();
+        let ttl = Duration::from_secs(60);
+        let nonce = store.new_nonce_with_ttl(EMAIL.into(), ttl).unwrap();
+        clock.advance(ttl);
+        assert!(!store.consume_nonce(nonce, EMAIL.into()).unwrap());
+    }
+
+    #[test]
+    fn purges_expired_nonces() {
+        let (store, clock) = store();
+        let ttl = Duration::from_secs(60);
+        for _ in 0..10 {
+            store.new_nonce_with_ttl(EMAIL.into(), ttl).unwrap();
+        }
+        clock.advance(Duration::from_secs(120));
+        store.new_nonce_with_ttl(EMAIL.into(), ttl).unwrap();
+        assert_eq!(store.nonces.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn evicts_expired_then_least_recently_used() {
+        let now = Instant::now();
+        let url = |path: &str| Url::parse(&format!("https://broker.example/{path}")).unwrap();
+        let doc = || Ok(Bytes::from_static(b"{}"));
+        let mut cache = Cache::default();
+        let long = now + Duration::from_secs(600);
+        cache.insert(url("a"), doc(), long, 3, now);
+        cache.insert(url("b"), doc(), now + Duration::from_secs(60), 3, now);
+        cache.insert(url("c"), doc(), long, 3, now);
+
+        // Expired entries go first, even if recently used.
+        let later = now + Duration::from_secs(60);
+        assert!(cache.get(&url("a"), later).is_some());
+        cache.insert(url("d"), doc(), long, 3, later);
+        assert!(!cache.entries.contains_key(&url("b")));
+
+        // Then the least recently used.
+        cache.insert(url("e"), doc(), long, 3, later);
+        assert_eq!(cache.entries.len(), 3);
+        assert!(!cache.entries.contains_key(&url("c")));
+        assert!(cache.entries.contains_key(&url("a")));
+    }
+}
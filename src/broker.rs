@@ -0,0 +1,95 @@
+use std::{borrow::Cow, fmt, str::FromStr};
+
+use url::Url;
+
+use crate::BuildError;
+
+/// A broker or identity provider origin, with trust metadata.
+///
+/// Constructing a `Broker` validates that the URL is an origin, so configuration errors can be
+/// caught early, for example while parsing application configuration. Use `Builder::server` to
+/// configure a `Client` with a `Broker`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Broker {
+    origin: Cow<'static, str>,
+    trusted: bool,
+}
+
+impl Broker {
+    /// The public Portier broker at `https://broker.portier.io`. This is the default.
+    pub const PORTIER_IO: Broker = Broker {
+        origin: Cow::Borrowed("https://broker.portier.io"),
+        trusted: true,
+    };
+
+    /// A trusted broker at the origin `url`. See `Builder::broker`.
+    pub fn trusted(url: &Url) -> Result<Self, BuildError> {
+        Ok(Broker {
+            origin: server_origin(url)?.into(),
+            trusted: true,
+        })
+    }
+
+    /// An untrusted identity provider at the origin `url`. See `Builder::idp`.
+    pub fn untrusted(url: &Url) -> Result<Self, BuildError> {
+        Ok(Broker {
+            origin: server_origin(url)?.into(),
+            trusted: false,
+        })
+    }
+
+    /// The origin, in ASCII serialization.
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    /// Whether this is a trusted broker, rather than an untrusted identity provider.
+    pub fn is_trusted(&self) -> bool {
+        self.trusted
+    }
+
+    /// The origin as a URL.
+    pub fn url(&self) -> Url {
+        self.origin
+            .parse()
+            .expect("broker origin is not a valid URL")
+    }
+}
+
+/// Parses a trusted broker origin.
+impl FromStr for Broker {
+    type Err = BuildError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url: Url = s.parse().map_err(|_err| BuildError::InvalidServer)?;
+        Broker::trusted(&url)
+    }
+}
+
+impl fmt::Display for Broker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.origin)
+    }
+}
+
+/// Verify a server URL is an origin, and return its ASCII serialization.
+pub(crate) fn server_origin(server: &Url) -> Result<String, BuildError> {
+    let origin = server.origin();
+    if !origin.is_tuple() {
+        return Err(BuildError::InvalidServer);
+    }
+    let server_id = origin.ascii_serialization();
+
+    // Verify server URL is an origin only. We can compare it with the ASCII origin, because
+    // `Url` is internally ASCII as well. It may contain a `/` path, though.
+    let server_str = server.as_str();
+    if !(server_str == server_id
+        || (server_str.len() == server_id.len() + 1
+            && server_str.starts_with(&server_id)
+            && server_str.ends_with('/')))
+    {
+        return Err(BuildError::ServerNotAnOrigin);
+    }
+
+    Ok(server_id)
+}
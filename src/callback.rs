@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{ErrorCode, ErrorKind, FragmentRelay, RelayError, VerifyError};
+
+/// Errors that can result from `Client::handle_callback`.
+#[derive(Debug, Error)]
+pub enum CallbackError {
+    /// The broker returned an OAuth-style error response instead of a token.
+    #[error(
+        "the broker returned error '{code}': {}",
+        .description.as_deref().unwrap_or("no description")
+    )]
+    Broker {
+        code: String,
+        description: Option<String>,
+    },
+    #[error("the callback contains neither a token nor an error")]
+    MissingToken,
+    #[error(transparent)]
+    Verify(#[from] VerifyError),
+}
+
+impl CallbackError {
+    /// A stable, user-facing classification of the error. See `ErrorCode`.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            CallbackError::Broker { .. } => ErrorCode::BrokerError,
+            CallbackError::MissingToken => ErrorCode::InvalidCallback,
+            CallbackError::Verify(err) => err.code(),
+        }
+    }
+
+    /// An operator-facing classification of the error. See `ErrorKind`.
+    ///
+    /// An error response from the broker is `ErrorKind::UserInput` if the user denied the login,
+    /// and `ErrorKind::Upstream` otherwise.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            CallbackError::Broker { code, .. } if code == "access_denied" => ErrorKind::UserInput,
+            CallbackError::Broker { .. } => ErrorKind::Upstream,
+            CallbackError::MissingToken => ErrorKind::UserInput,
+            CallbackError::Verify(err) => err.kind(),
+        }
+    }
+
+    /// Whether the login may succeed when tried again later. See `ErrorKind::is_retryable`.
+    pub fn is_retryable(&self) -> bool {
+        self.kind().is_retryable()
+    }
+
+    /// Whether the error is caused by the broker or a store, instead of the request.
+    ///
+    /// Web applications can use this to choose between a server error and a client error response.
+    /// Error responses from the broker are never server errors, because they are part of the
+    /// request.
+    pub fn is_server_error(&self) -> bool {
+        match self {
+            CallbackError::Verify(err) => {
+                matches!(err.kind(), ErrorKind::Upstream | ErrorKind::Storage)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parameters sent by the broker to the redirect URI after authentication.
+///
+/// With `ResponseMode::FormPost`, these are in the request body. With `ResponseMode::Fragment`,
+/// they are in the URL fragment, and sent to the relay endpoint by the redirect page.
+///
+/// Parameters not known to this crate are preserved in `extra`, so that applications can adopt
+/// broker extensions without waiting for a crate release. Known parameters may be added as fields
+/// in minor releases, which is why this struct is marked non-exhaustive.
+///
+/// This can also be deserialized using serde, for example by the form extractor of a web
+/// framework. Unlike `CallbackParams::parse`, serde rejects duplicate known parameters.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct CallbackParams {
+    /// The token to pass to `Client::verify`, if authentication succeeded.
+    ///
+    /// `Client::handle_callback` handles both this and error responses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+    /// The `state` passed in `AuthOptions`, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    /// The error code, if authentication failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// A human-readable description of the error, if provided.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_description: Option<String>,
+    /// Any additional parameters.
+    #[serde(flatten)]
+    pub extra: HashMap<String, String>,
+}
+
+impl CallbackParams {
+    /// Parse parameters in `application/x-www-form-urlencoded` format.
+    ///
+    /// This accepts a POST body, a query string or a URL fragment. A leading `?` or `#` is
+    /// ignored.
+    pub fn parse(input: &str) -> Self {
+        let input = input
+            .strip_prefix('?')
+            .or_else(|| input.strip_prefix('#'))
+            .unwrap_or(input);
+        url::form_urlencoded::parse(input.as_bytes())
+            .into_owned()
+            .collect()
+    }
+}
+
+/// Collect parameters from name/value pairs, for example as parsed by a web framework.
+///
+/// If a parameter occurs more than once, the first value is used.
+impl FromIterator<(String, String)> for CallbackParams {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        let mut params = CallbackParams::default();
+        for (name, value) in iter {
+            let field = match name.as_str() {
+                "id_token" => &mut params.id_token,
+                "state" => &mut params.state,
+                "error" => &mut params.error,
+                "error_description" => &mut params.error_description,
+                _ => {
+                    params.extra.entry(name).or_insert(value);
+                    continue;
+                }
+            };
+            field.get_or_insert(value);
+        }
+        params
+    }
+}
+
+/// The body sent to the relay endpoint with `ResponseMode::Fragment`.
+///
+/// This is the `CallbackParams` from the URL fragment, plus the URL of the redirect page in
+/// `redirect_uri`, which is checked using `FragmentPayload::check_redirect_uri`. See
+/// `FragmentRelay`. Like `CallbackParams`, this can be deserialized using serde.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct FragmentPayload {
+    /// The URL of the redirect page, if sent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_uri: Option<String>,
+    /// The parameters from the URL fragment.
+    #[serde(flatten)]
+    pub params: CallbackParams,
+}
+
+impl FragmentPayload {
+    /// Parse the body in `application/x-www-form-urlencoded` format.
+    pub fn parse(input: &str) -> Self {
+        CallbackParams::parse(input).into()
+    }
+
+    /// Check the relayed `redirect_uri`, if any, using `FragmentRelay::check_redirect_uri`.
+    pub fn check_redirect_uri(&self, relay: &FragmentRelay) -> Result<(), RelayError> {
+        match self.redirect_uri {
+            Some(ref relayed) => relay.check_redirect_uri(relayed),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Separate the `redirect_uri` from the other parameters.
+impl From<CallbackParams> for FragmentPayload {
+    fn from(mut params: CallbackParams) -> Self {
+        FragmentPayload {
+            redirect_uri: params.extra.remove("redirect_uri"),
+            params,
+        }
+    }
+}
+
+/// Collect parameters from name/value pairs. See the implementation for `CallbackParams`.
+impl FromIterator<(String, String)> for FragmentPayload {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        CallbackParams::from_iter(iter).into()
+    }
+}
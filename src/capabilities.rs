@@ -0,0 +1,102 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::SpecVersion;
+
+/// Cargo features of this crate, and whether each was enabled at build time.
+const FEATURES: &[(&str, bool)] = &[
+    ("client", cfg!(feature = "client")),
+    ("tokio", cfg!(feature = "tokio")),
+    ("simple-store", cfg!(feature = "simple-store")),
+    ("reqwest-store", cfg!(feature = "reqwest-store")),
+    ("rustls-store", cfg!(feature = "rustls-store")),
+    ("memory-store", cfg!(feature = "memory-store")),
+    ("http-hyper", cfg!(feature = "http-hyper")),
+    ("http-reqwest", cfg!(feature = "http-reqwest")),
+    ("tls-native", cfg!(feature = "tls-native")),
+    ("tls-rustls", cfg!(feature = "tls-rustls")),
+    ("codec-cbor", cfg!(feature = "codec-cbor")),
+    ("codec-msgpack", cfg!(feature = "codec-msgpack")),
+    ("sql-postgres", cfg!(feature = "sql-postgres")),
+    ("sql-mysql", cfg!(feature = "sql-mysql")),
+    ("sql-sqlite", cfg!(feature = "sql-sqlite")),
+    ("ed448", cfg!(feature = "ed448")),
+    ("memcached-store", cfg!(feature = "memcached-store")),
+    ("dynamodb-store", cfg!(feature = "dynamodb-store")),
+    ("sled-store", cfg!(feature = "sled-store")),
+    ("tracing", cfg!(feature = "tracing")),
+    ("actix", cfg!(feature = "actix")),
+    ("axum", cfg!(feature = "axum")),
+    ("rocket", cfg!(feature = "rocket")),
+    ("blocking", cfg!(feature = "blocking")),
+    ("test-utils", cfg!(feature = "test-utils")),
+    ("zeroize", cfg!(feature = "zeroize")),
+];
+
+/// What this build of the crate supports, as returned by `capabilities`.
+///
+/// The `Display` output is a single line, suitable for logging at startup. The struct also
+/// serializes to JSON, for example for a diagnostics page.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct Capabilities {
+    /// The version of this crate.
+    pub version: &'static str,
+    /// The JWS algorithms accepted in token signatures.
+    pub algorithms: Vec<&'static str>,
+    /// The curves accepted with the `EdDSA` algorithm.
+    pub eddsa_curves: Vec<&'static str>,
+    /// The response modes a `Client` can request. Empty without the `client` feature.
+    pub response_modes: Vec<&'static str>,
+    /// The latest revision of the Portier specification supported. See `SpecVersion`.
+    pub spec_version: SpecVersion,
+    /// The Cargo features this crate was built with.
+    pub features: Vec<&'static str>,
+}
+
+/// Report the version of this crate, the protocol features it supports, and the Cargo features it
+/// was built with.
+///
+/// This helps operators confirm what a binary actually supports, for example after changing
+/// feature flags.
+pub fn capabilities() -> Capabilities {
+    let mut eddsa_curves = vec!["Ed25519"];
+    if cfg!(feature = "ed448") {
+        eddsa_curves.push("Ed448");
+    }
+    #[cfg(feature = "client")]
+    let response_modes = vec![
+        crate::ResponseMode::Fragment.as_str(),
+        crate::ResponseMode::FormPost.as_str(),
+    ];
+    #[cfg(not(feature = "client"))]
+    let response_modes = Vec::new();
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        algorithms: vec!["EdDSA", "RS256", "ES256"],
+        eddsa_curves,
+        response_modes,
+        spec_version: SpecVersion::LATEST,
+        features: FEATURES
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| *name)
+            .collect(),
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "portier {} (spec {}; algorithms {}; EdDSA curves {}; response modes {}; features {})",
+            self.version,
+            self.spec_version.as_str(),
+            self.algorithms.join(", "),
+            self.eddsa_curves.join(", "),
+            self.response_modes.join(", "),
+            self.features.join(", "),
+        )
+    }
+}
@@ -0,0 +1,3104 @@
+use bytes::Bytes;
+use ring::{
+    constant_time,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::de::DeserializeOwned;
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    future::Future,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+#[cfg(feature = "tokio")]
+use tokio::sync::broadcast;
+use url::Url;
+
+#[cfg(feature = "memory-store")]
+use crate::EndpointProbe;
+#[cfg(all(
+    feature = "memory-store",
+    any(feature = "http-hyper", feature = "http-reqwest"),
+    any(feature = "tls-native", feature = "tls-rustls")
+))]
+use crate::MemoryStore;
+#[cfg(feature = "tokio")]
+use crate::OwnedLoginEvent;
+use crate::{
+    broker::server_origin,
+    clock,
+    endpoint::{Endpoint, Endpoints, KeyPins, SharedState},
+    events::{correlation_id, random_correlation_id, token_session},
+    jwk, jws,
+    misc::{self, record_span, DiscoveryDoc, DynErr, DynFut, DynFutRes, DynRes},
+    normalize_email,
+    session::SessionEnvelope,
+    validator::{token_header, ClaimWarningHook},
+    AsyncStore, AsyncStoreAdapter, Availability, Broker, CallbackError, CallbackParams, ClaimCheck,
+    ClaimPolicy, ClientInfo, Clock, EmailCase, ErrorCode, ErrorKind, FailureSink, FetchError,
+    FetchPurpose, Fetcher, FragmentRelay, Inspection, KeyInfo, KeySetChange, KeyVerifier,
+    LoginAttempt, LoginEvent, LoginHook, LoginStep, RateLimitDecision, RateLimitScope, RateLimiter,
+    ResponseMode, ReturnTo, SecretProvider, SessionBinding, SessionStore, SpecVersion,
+    StatelessSessions, Store, SystemClock, Unsupported, Validator, VerifiedToken, VerifyError,
+    VerifyFailure,
+};
+#[cfg(feature = "memory-store")]
+use crate::{simple_fetch, HttpClient, NonceGenerator};
+
+/// Errors that can result from `Builder::build`.
+#[derive(Debug, Error)]
+pub enum BuildError {
+    #[error("the configured server URL cannot be used")]
+    InvalidServer,
+    #[error("the configured redirect URI cannot be used")]
+    InvalidRedirectUri,
+    #[error("the configured server is not an origin (contains additional components)")]
+    ServerNotAnOrigin,
+    #[error("the configured fragment relay path is not an absolute path")]
+    InvalidFragmentRelayPath,
+    #[error("the origin is not registered with the client pool")]
+    UnknownOrigin,
+    #[error("could not load a secret: {0}")]
+    Secret(#[source] DynErr),
+    #[error("the pinned discovery document is invalid: {0}")]
+    InvalidPinnedDiscovery(#[source] serde_json::Error),
+    #[error("the pinned keys could not be serialized: {0}")]
+    InvalidPinnedJwks(#[source] serde_json::Error),
+    #[error(transparent)]
+    Warning(BuildWarning),
+    #[cfg(not(all(
+        feature = "memory-store",
+        any(feature = "http-hyper", feature = "http-reqwest"),
+        any(feature = "tls-native", feature = "tls-rustls")
+    )))]
+    #[error("no default store is available")]
+    NoDefaultStore,
+}
+
+/// Likely configuration mistakes detected by `Builder::build`.
+///
+/// These are reported to the callback set using `Builder::on_warning`, or turned into errors
+/// using `Builder::deny_warnings`. Checks may be added in minor releases.
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+#[non_exhaustive]
+pub enum BuildWarning {
+    #[error("a fragment relay path is configured, but the response mode is form_post")]
+    UnusedFragmentRelay,
+    #[error("the fragment relay path is the same as the redirect URI path")]
+    FragmentRelayIsRedirectUri,
+    #[error("the redirect URI contains a fragment, which is not sent to the server")]
+    RedirectUriHasFragment,
+    #[error("the redirect URI does not use https, so tokens are sent unencrypted")]
+    InsecureRedirectUri,
+    #[error("a broker URL does not use https, so its keys are fetched unencrypted")]
+    InsecureServer,
+}
+
+/// Errors that can result from `Client::start_auth`.
+#[derive(Debug, Error)]
+pub enum StartAuthError {
+    #[error("could not fetch discovery document: {0}")]
+    FetchDiscovery(#[source] FetchError),
+    #[error("could not parse discovery document: {0}")]
+    ParseDiscovery(#[source] serde_json::Error),
+    #[error("invalid URL in discovery document: {0}")]
+    InvalidDiscoveryUrl(#[source] url::ParseError),
+    #[error("invalid discovery document: {0}")]
+    InvalidDiscovery(#[source] DiscoveryError),
+    #[error("could not generate nonce: {0}")]
+    GenerateNonce(#[source] DynErr),
+    #[error("the store did not respond in time")]
+    StoreTimeout,
+    /// The broker responded to the discovery request with `503 Service Unavailable`, for example
+    /// during maintenance. `retry_after` is the delay the broker asked for, if any.
+    #[error("the broker is temporarily unavailable")]
+    BrokerUnavailable { retry_after: Option<Duration> },
+    /// `AuthOptions::redirect_uri` does not have the same origin as the redirect URI of the
+    /// `Client`.
+    #[error("the redirect URI must have the same origin as the client")]
+    InvalidRedirectUri,
+    /// The email address is longer than `Builder::max_email_len`.
+    #[error("the email address is longer than {max} bytes")]
+    EmailTooLong { max: usize },
+    /// The `RateLimiter` denied the login. `retry_after` is the delay it suggested, if any.
+    #[error("too many logins were started, as limited by {}", .scope.as_str())]
+    RateLimited {
+        scope: RateLimitScope,
+        retry_after: Option<Duration>,
+    },
+}
+
+impl StartAuthError {
+    /// A stable, user-facing classification of the error. See `ErrorCode`.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            StartAuthError::FetchDiscovery(_)
+            | StartAuthError::ParseDiscovery(_)
+            | StartAuthError::InvalidDiscoveryUrl(_)
+            | StartAuthError::InvalidDiscovery(_)
+            | StartAuthError::BrokerUnavailable { .. } => ErrorCode::BrokerUnavailable,
+            StartAuthError::GenerateNonce(_) | StartAuthError::StoreTimeout => {
+                ErrorCode::StoreUnavailable
+            }
+            StartAuthError::InvalidRedirectUri | StartAuthError::EmailTooLong { .. } => {
+                ErrorCode::LoginRejected
+            }
+            StartAuthError::RateLimited { .. } => ErrorCode::RateLimited,
+        }
+    }
+
+    /// An operator-facing classification of the error. See `ErrorKind`.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            StartAuthError::FetchDiscovery(_)
+            | StartAuthError::ParseDiscovery(_)
+            | StartAuthError::InvalidDiscoveryUrl(_)
+            | StartAuthError::InvalidDiscovery(_)
+            | StartAuthError::BrokerUnavailable { .. } => ErrorKind::Upstream,
+            StartAuthError::GenerateNonce(_) | StartAuthError::StoreTimeout => ErrorKind::Storage,
+            StartAuthError::InvalidRedirectUri
+            | StartAuthError::EmailTooLong { .. }
+            | StartAuthError::RateLimited { .. } => ErrorKind::UserInput,
+        }
+    }
+
+    /// Whether starting the login later may succeed. See `ErrorKind::is_retryable`.
+    pub fn is_retryable(&self) -> bool {
+        self.kind().is_retryable()
+    }
+}
+
+/// Errors that can result from `Client::check`.
+#[derive(Debug, Error)]
+pub enum CheckError {
+    #[error("invalid client configuration: {0}")]
+    Build(#[source] BuildError),
+    #[error("could not fetch discovery document: {0}")]
+    FetchDiscovery(#[source] FetchError),
+    #[error("could not parse discovery document: {0}")]
+    ParseDiscovery(#[source] serde_json::Error),
+    #[error("invalid URL in discovery document: {0}")]
+    InvalidDiscoveryUrl(#[source] url::ParseError),
+    #[error("invalid discovery document: {0}")]
+    InvalidDiscovery(#[source] DiscoveryError),
+    #[error("could not fetch keys document: {0}")]
+    FetchJwks(#[source] FetchError),
+    #[error("could not parse keys document: {0}")]
+    ParseJwks(#[source] serde_json::Error),
+    #[error("the keys document of {0} contains no supported keys")]
+    NoSupportedKeys(String),
+    #[error("the broker keys were rejected: {0}")]
+    KeysRejected(#[source] DynErr),
+    #[error("key continuity check failed: {0}")]
+    KeyContinuity(#[source] KeyContinuityError),
+}
+
+/// Result of checking a broker endpoint, returned by `Client::check`.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CheckReport {
+    /// The origin of the broker.
+    pub origin: String,
+    /// The issuer tokens are expected to have.
+    pub issuer: String,
+    /// The authorization endpoint from the discovery document.
+    pub authorization_endpoint: Url,
+    /// The keys document URL from the discovery document.
+    pub jwks_uri: Url,
+    /// The number of keys in the keys document, including unsupported keys.
+    pub key_count: usize,
+    /// The algorithms of the supported keys, without duplicates.
+    pub algorithms: Vec<&'static str>,
+}
+
+/// Errors that can result from the checks of `Client::self_test`.
+#[derive(Debug, Error)]
+pub enum SelfTestError {
+    #[error("the system random number generator failed")]
+    Rng,
+    #[error("the store failed: {0}")]
+    Store(#[source] DynErr),
+    #[error("the store did not respond in time")]
+    StoreTimeout,
+    #[error("the store did not return the test session")]
+    StoreRoundTrip,
+}
+
+/// Result of `Client::self_test`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct SelfTestReport {
+    /// Whether the system random number generator works.
+    pub rng: Result<(), SelfTestError>,
+    /// Whether a session can be created and consumed.
+    pub store: Result<(), SelfTestError>,
+    /// The result of `Client::check`, if brokers were checked.
+    pub brokers: Option<Result<Vec<CheckReport>, CheckError>>,
+}
+
+impl SelfTestReport {
+    /// Whether all checks passed.
+    pub fn is_ok(&self) -> bool {
+        self.rng.is_ok() && self.store.is_ok() && !matches!(self.brokers, Some(Err(_)))
+    }
+}
+
+/// Problems found in a discovery document.
+///
+/// See `Builder::issuer_check` and `Builder::strict_discovery`.
+#[derive(Debug, Error)]
+pub enum DiscoveryError {
+    #[error("the discovery document has no issuer")]
+    MissingIssuer,
+    #[error("the discovery document issuer {found} does not match {expected}")]
+    IssuerMismatch { expected: String, found: String },
+    #[error("the endpoint {0} does not use https")]
+    InsecureEndpoint(Url),
+    #[error("the endpoint {0} is not on the origin of the server")]
+    CrossOriginEndpoint(Url),
+    #[error("the server does not support response mode {}", .0.as_str())]
+    UnsupportedResponseMode(ResponseMode),
+}
+
+/// How the `issuer` in the discovery document is checked. See `Builder::issuer_check`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IssuerCheck {
+    /// Ignore the `issuer`, and expect tokens to be issued by the configured origin.
+    ///
+    /// This is for legacy brokers that publish an incorrect issuer.
+    Ignore,
+    /// If the document has an `issuer`, verify it and compare it against the token `iss`.
+    #[default]
+    Verify,
+    /// Like `Verify`, but the document must have an `issuer`.
+    Require,
+}
+
+/// How the client checks continuity of broker keys. See `Builder::key_continuity`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyContinuity {
+    /// Don't check key continuity.
+    #[default]
+    Off,
+    /// Report failed checks to `Builder::on_key_continuity`, but accept the keys.
+    Warn,
+    /// Like `Warn`, but also fail with a `KeyContinuity` error.
+    Enforce,
+}
+
+/// How the client canonicalizes redirect URIs. See `Builder::uri_canonicalization`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UriCanonicalization {
+    /// Use redirect URIs as parsed by `Url`, which lowercases the scheme and host, and removes
+    /// the default port.
+    #[default]
+    Parsed,
+    /// Like `Parsed`, but also remove a trailing dot from the host, trailing slashes from the
+    /// path, and an empty query or fragment.
+    Normalized,
+}
+
+impl UriCanonicalization {
+    /// Return the canonical form of `uri`.
+    pub fn apply(self, uri: &Url) -> Url {
+        let mut uri = uri.clone();
+        if self == UriCanonicalization::Parsed {
+            return uri;
+        }
+        if let Some(host) = uri.host_str().and_then(|host| host.strip_suffix('.')) {
+            let host = host.to_owned();
+            // Fails only for URLs that can't have a host, which have no trailing dot either.
+            let _ = uri.set_host(Some(&host));
+        }
+        let path = uri.path().trim_end_matches('/');
+        if path.len() < uri.path().len() {
+            let path = if path.is_empty() { "/" } else { path }.to_owned();
+            uri.set_path(&path);
+        }
+        if uri.query() == Some("") {
+            uri.set_query(None);
+        }
+        if uri.fragment() == Some("") {
+            uri.set_fragment(None);
+        }
+        uri
+    }
+}
+
+/// Errors that can result from a key continuity check. See `Builder::key_continuity`.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum KeyContinuityError {
+    #[error("could not access the key pins: {0}")]
+    Store(#[source] DynErr),
+    #[error("could not parse the key pins: {0}")]
+    Parse(#[source] serde_json::Error),
+    #[error("could not serialize the key pins: {0}")]
+    Serialize(#[source] serde_json::Error),
+    #[error("the keys of {origin} share no keys with the pinned keys")]
+    Disjoint {
+        origin: String,
+        /// Thumbprints of the pinned keys.
+        pinned: Vec<String>,
+        /// Thumbprints of the keys found in the JWKs document.
+        found: Vec<String>,
+    },
+    #[error("the system clock is not reliable")]
+    ClockUnreliable,
+}
+
+/// Additional parameters for `Client::start_auth_with_options`.
+///
+/// Parameters that are `None` or empty are omitted from the authentication URL.
+#[derive(Clone, Debug, Default)]
+pub struct AuthOptions {
+    /// An opaque value that is passed verbatim to the redirect URI after the user returns.
+    ///
+    /// This is typically used to restore application state, such as the page the user was on.
+    /// Check URLs using `Client::return_to` before redirecting to them.
+    pub state: Option<String>,
+    /// Preferred languages for the broker user interface, as BCP 47 language tags, in order of
+    /// preference.
+    pub ui_locales: Vec<String>,
+    /// Whether the broker should prompt the user, as a space-separated list of OpenID Connect
+    /// prompt values, such as `login`.
+    pub prompt: Option<String>,
+    /// How the user prefers to confirm their email address, for brokers that offer a choice.
+    pub confirmation_method: Option<ConfirmationMethod>,
+    /// Additional parameters, for broker extensions not known to this crate.
+    ///
+    /// Parameters with the same name as one already in the URL are skipped, so these can't
+    /// override parameters set by the `Client` or by other fields.
+    pub extra: Vec<(String, String)>,
+    /// Opaque application data to attach to the login session.
+    ///
+    /// This is not sent to the broker, but kept in the store, and returned in
+    /// `VerifiedToken::session_data` by `Client::verify_full`. Use this to remember where the user
+    /// was going, for example. Requires a store that implements `Store::new_nonce_with_data`.
+    pub session_data: Vec<u8>,
+    /// Properties of the user agent starting the login, if sessions are bound to them using
+    /// `Builder::session_binding`.
+    pub client_info: ClientInfo,
+    /// An identifier to trace the login across redirects and workers, such as a request ID.
+    ///
+    /// This is kept in the store, returned in `VerifiedToken::correlation_id`, and included in
+    /// login events and tracing spans. Requires a store that implements
+    /// `Store::new_nonce_with_data`. If not set, a random identifier is generated, and kept in the
+    /// store if it supports that. Otherwise, events after the callback use an identifier derived
+    /// from the session nonce.
+    pub correlation_id: Option<String>,
+    /// The redirect URI to send the user back to, instead of the one of the `Client`.
+    ///
+    /// This must have the same origin as the redirect URI of the `Client`, because the origin is
+    /// the client ID, and thus the audience of the token. The override is kept in the store, so
+    /// `ClientInfo::redirect_uri` can check the token arrived on it. Requires a store that
+    /// implements `Store::new_nonce_with_data`.
+    pub redirect_uri: Option<Url>,
+    /// A key to rate limit the login by, in addition to the email address, such as the client IP
+    /// address. See `Builder::rate_limiter`.
+    pub rate_limit_key: Option<String>,
+}
+
+impl AuthOptions {
+    /// Append the parameters to an authentication URL.
+    fn apply(self, url: &mut Url) {
+        let existing: HashSet<String> = url.query_pairs().map(|(name, _)| name.into()).collect();
+        let mut query = url.query_pairs_mut();
+        if let Some(ref state) = self.state {
+            query.append_pair("state", state);
+        }
+        if !self.ui_locales.is_empty() {
+            query.append_pair("ui_locales", &self.ui_locales.join(" "));
+        }
+        if let Some(ref prompt) = self.prompt {
+            query.append_pair("prompt", prompt);
+        }
+        if let Some(method) = self.confirmation_method {
+            query.append_pair("confirmation_method", method.as_str());
+        }
+        for (name, value) in &self.extra {
+            if !existing.contains(name) && !RESERVED_OPTIONS.contains(&name.as_str()) {
+                query.append_pair(name, value);
+            }
+        }
+    }
+}
+
+/// A check applied to a verified token before its login session is consumed.
+type SessionCheck = fn(&VerifiedToken) -> Result<(), VerifyError>;
+
+/// How often the JWKs document of a broker may be refetched, when a token is signed with a key
+/// that is not in it.
+const KEYS_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Parameters that may be set by `AuthOptions` fields.
+const RESERVED_OPTIONS: &[&str] = &["state", "ui_locales", "prompt", "confirmation_method"];
+
+/// The confirmation method hint in `AuthOptions`.
+///
+/// Brokers that don't support a choice ignore the hint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfirmationMethod {
+    /// Confirm by clicking a link in the email.
+    Link,
+    /// Confirm by entering a code from the email.
+    Code,
+}
+
+impl ConfirmationMethod {
+    /// Convert to the `confirmation_method` query string value.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConfirmationMethod::Link => "link",
+            ConfirmationMethod::Code => "code",
+        }
+    }
+}
+
+/// Callback set using `Builder::on_warning`.
+type WarningHook = Arc<dyn Fn(&BuildWarning) + Send + Sync>;
+
+/// Callback set using `Builder::on_key_change`.
+type KeyChangeHook = Arc<dyn Fn(&KeySetChange) + Send + Sync>;
+
+/// Callback set using `Builder::on_key_continuity`.
+type KeyContinuityHook = Arc<dyn Fn(&KeyContinuityError) + Send + Sync>;
+
+/// Callback set using `Builder::on_login_event`.
+type LoginEventHook = Arc<dyn Fn(&LoginEvent<'_>) + Send + Sync>;
+
+/// A builder to configure a `Client`.
+#[derive(Clone)]
+pub struct Builder {
+    fetcher: Option<Arc<dyn Fetcher>>,
+    sessions: Option<Arc<dyn SessionStore>>,
+    session_secret: Option<Arc<dyn SecretProvider>>,
+    server: Option<Url>,
+    mirrors: Vec<Url>,
+    trusted: bool,
+    redirect_uri: Url,
+    response_mode: ResponseMode,
+    leeway: Duration,
+    session_ttl: Duration,
+    fragment_relay_path: Option<String>,
+    spec_version: SpecVersion,
+    allowed_algs: Option<Vec<String>>,
+    claim_policies: Vec<(ClaimCheck, ClaimPolicy)>,
+    on_claim_warning: Option<ClaimWarningHook>,
+    email_case: EmailCase,
+    session_binding: SessionBinding,
+    relative_discovery_urls: bool,
+    strict_discovery: bool,
+    same_origin_endpoints: bool,
+    issuer_check: IssuerCheck,
+    clock: Arc<dyn Clock>,
+    on_warning: Option<WarningHook>,
+    deny_warnings: bool,
+    on_key_change: Option<KeyChangeHook>,
+    on_login_event: Option<LoginEventHook>,
+    key_continuity: KeyContinuity,
+    rotation_overlap: Duration,
+    on_key_continuity: Option<KeyContinuityHook>,
+    shared_endpoint_state: bool,
+    uri_canonicalization: UriCanonicalization,
+    key_verifier: Option<Arc<dyn KeyVerifier>>,
+    login_hook: Option<Arc<dyn LoginHook>>,
+    failure_sink: Option<Arc<dyn FailureSink>>,
+    rate_limiter: Option<Arc<dyn RateLimiter>>,
+    pinned_discovery: Option<Bytes>,
+    pinned_jwks: Option<Arc<jwk::KeySet>>,
+    max_token_len: usize,
+    max_email_len: usize,
+    #[cfg(feature = "tokio")]
+    store_timeout: Option<Duration>,
+    #[cfg(feature = "tokio")]
+    offload_rsa: bool,
+    #[cfg(feature = "tokio")]
+    event_capacity: usize,
+    #[cfg(feature = "memory-store")]
+    fetch_fallback: Option<FetchFallback>,
+    #[cfg(feature = "memory-store")]
+    nonce_generator: Option<Arc<dyn NonceGenerator>>,
+    #[cfg(feature = "memory-store")]
+    fetch_timeout: Duration,
+    #[cfg(feature = "memory-store")]
+    connect_timeout: Option<Duration>,
+    #[cfg(feature = "memory-store")]
+    max_response_size: Option<usize>,
+}
+
+impl Builder {
+    fn new(redirect_uri: Url) -> Self {
+        Builder {
+            fetcher: None,
+            sessions: None,
+            session_secret: None,
+            server: None,
+            mirrors: Vec::new(),
+            trusted: true,
+            redirect_uri,
+            response_mode: ResponseMode::default(),
+            leeway: Duration::from_secs(180),
+            session_ttl: Duration::from_secs(15 * 60),
+            fragment_relay_path: None,
+            spec_version: SpecVersion::default(),
+            allowed_algs: None,
+            claim_policies: Vec::new(),
+            on_claim_warning: None,
+            email_case: EmailCase::default(),
+            session_binding: SessionBinding::default(),
+            relative_discovery_urls: true,
+            strict_discovery: false,
+            same_origin_endpoints: false,
+            issuer_check: IssuerCheck::default(),
+            clock: Arc::new(SystemClock),
+            on_warning: None,
+            deny_warnings: false,
+            on_key_change: None,
+            on_login_event: None,
+            key_continuity: KeyContinuity::default(),
+            rotation_overlap: Duration::ZERO,
+            on_key_continuity: None,
+            shared_endpoint_state: false,
+            uri_canonicalization: UriCanonicalization::default(),
+            key_verifier: None,
+            login_hook: None,
+            failure_sink: None,
+            rate_limiter: None,
+            pinned_discovery: None,
+            pinned_jwks: None,
+            max_token_len: 16 * 1024,
+            max_email_len: 254,
+            #[cfg(feature = "tokio")]
+            store_timeout: None,
+            #[cfg(feature = "tokio")]
+            offload_rsa: false,
+            #[cfg(feature = "tokio")]
+            event_capacity: 256,
+            #[cfg(feature = "memory-store")]
+            fetch_fallback: None,
+            #[cfg(feature = "memory-store")]
+            nonce_generator: None,
+            #[cfg(feature = "memory-store")]
+            fetch_timeout: Duration::from_secs(30),
+            #[cfg(feature = "memory-store")]
+            connect_timeout: None,
+            #[cfg(feature = "memory-store")]
+            max_response_size: None,
+        }
+    }
+
+    /// Use the given `Store` for cache and session storage.
+    ///
+    /// If no store is specified, a default `MemoryStore` is created. This type of store has some
+    /// limitations. See the documentation for `MemoryStore` for details.
+    pub fn store(mut self, store: Arc<dyn Store>) -> Self {
+        self.fetcher = Some(Arc::new(store.clone()));
+        self.sessions = Some(Arc::new(store));
+        self.session_secret = None;
+        self
+    }
+
+    /// Use the given `AsyncStore` for both fetching documents and session storage.
+    ///
+    /// This is shorthand for `Builder::store` with an `AsyncStoreAdapter`.
+    pub fn async_store(self, store: impl AsyncStore) -> Self {
+        self.store(Arc::new(AsyncStoreAdapter::new(store)))
+    }
+
+    /// Use the given `Fetcher` for fetching documents, overriding the `Store`.
+    ///
+    /// If only one of `Builder::fetcher` and `Builder::session_store` is used, the other half is
+    /// provided by the configured `Store`, or the default `MemoryStore`.
+    pub fn fetcher(mut self, fetcher: Arc<dyn Fetcher>) -> Self {
+        self.fetcher = Some(fetcher);
+        self
+    }
+
+    /// Use the given `SessionStore` for session storage, overriding the `Store`.
+    ///
+    /// If only one of `Builder::fetcher` and `Builder::session_store` is used, the other half is
+    /// provided by the configured `Store`, or the default `MemoryStore`.
+    pub fn session_store(mut self, sessions: Arc<dyn SessionStore>) -> Self {
+        self.sessions = Some(sessions);
+        self.session_secret = None;
+        self
+    }
+
+    /// Use `StatelessSessions` with the given secret for session storage.
+    ///
+    /// This is shorthand for `Builder::session_store` with `StatelessSessions::new`, for
+    /// deployments that can't run a shared store. Note that this allows a token to be verified
+    /// more than once, until the session expires. See `StatelessSessions` for details.
+    pub fn session_secret(self, secret: &[u8]) -> Self {
+        self.session_store(Arc::new(StatelessSessions::new(secret)))
+    }
+
+    /// Like `Builder::session_secret`, but load the secret from `provider` in `Builder::build`.
+    ///
+    /// See `SecretProvider`. If loading fails, `Builder::build` fails with `BuildError::Secret`.
+    pub fn session_secret_from(mut self, provider: impl SecretProvider) -> Self {
+        self.sessions = None;
+        self.session_secret = Some(Arc::new(provider));
+        self
+    }
+
+    /// Configure the client to use a trusted broker.
+    ///
+    /// This allows you to override the default broker `https://broker.portier.io` with your own.
+    /// The `url` must be an origin only. (Only scheme, host, and optionally port. No path, query
+    /// string, etc.)
+    pub fn broker(mut self, url: Url) -> Self {
+        self.server = Some(url);
+        self.trusted = true;
+        self
+    }
+
+    /// Configure the client to use an untrusted identity provider.
+    ///
+    /// This is usually only used when implementing a broker. For configuring a relying party to
+    /// use a custom broker, see `Builder::broker` instead.
+    pub fn idp(mut self, url: Url) -> Self {
+        self.server = Some(url);
+        self.trusted = false;
+        self
+    }
+
+    /// Configure the client to use a validated `Broker`.
+    ///
+    /// This is equivalent to `Builder::broker` or `Builder::idp`, depending on whether the broker
+    /// is trusted.
+    pub fn server(mut self, broker: Broker) -> Self {
+        self.server = Some(broker.url());
+        self.trusted = broker.is_trusted();
+        self
+    }
+
+    /// Add a mirror of the configured broker, such as a regional endpoint.
+    ///
+    /// Like the broker, the `url` must be an origin only. Mirrors share the trust setting of the
+    /// broker. Until `Client::probe_endpoints` is called, logins start at the broker itself.
+    /// Afterwards, the fastest healthy endpoint is used. Tokens are verified against the endpoint
+    /// they were issued by.
+    pub fn broker_mirror(mut self, url: Url) -> Self {
+        self.mirrors.push(url);
+        self
+    }
+
+    /// Configure the response mode to use. The default is `FormPost`.
+    ///
+    /// If the broker publishes `response_modes_supported` in its discovery document, and the mode
+    /// is not listed, `Client::start_auth` fails with `DiscoveryError::UnsupportedResponseMode`.
+    pub fn response_mode(mut self, mode: ResponseMode) -> Self {
+        self.response_mode = mode;
+        self
+    }
+
+    /// Configure the leeway to allow for timestamps in tokens. The default is 3 minutes.
+    pub fn leeway(mut self, dur: Duration) -> Self {
+        self.leeway = dur;
+        self
+    }
+
+    /// Configure how long a login session remains valid. The default is 15 minutes.
+    ///
+    /// This is passed to `Store::new_nonce_with_ttl`, so abandoned logins can't accumulate in the
+    /// store or be completed much later. Stores that don't support expiry ignore it.
+    pub fn session_ttl(mut self, dur: Duration) -> Self {
+        self.session_ttl = dur;
+        self
+    }
+
+    /// Configure the Portier spec revision to conform to. The default is `SpecVersion::V1`.
+    ///
+    /// See `SpecVersion` for the differences between revisions.
+    pub fn spec_version(mut self, version: SpecVersion) -> Self {
+        self.spec_version = version;
+        self
+    }
+
+    /// Only accept tokens signed with one of the given JWS algorithms, such as `"EdDSA"`.
+    ///
+    /// By default, any algorithm supported by this crate is accepted. Regardless of this setting,
+    /// `none` is always rejected, and the algorithm must match the key used. Tokens with other
+    /// algorithms fail with `VerifyError::Signature`.
+    pub fn allowed_algs<I, A>(mut self, algs: I) -> Self
+    where
+        I: IntoIterator<Item = A>,
+        A: Into<String>,
+    {
+        self.allowed_algs = Some(algs.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Configure how to handle tokens that fail `check`. See `Validator::claim_policy`.
+    ///
+    /// This allows accepting tokens from brokers in transition between spec revisions, without
+    /// changing application code once they conform.
+    pub fn claim_policy(mut self, check: ClaimCheck, policy: ClaimPolicy) -> Self {
+        self.claim_policies.push((check, policy));
+        self
+    }
+
+    /// Call `f` for every problem with a token accepted using `ClaimPolicy::Warn`.
+    pub fn on_claim_warning(mut self, f: impl Fn(&VerifyError) + Send + Sync + 'static) -> Self {
+        self.on_claim_warning = Some(Arc::new(f));
+        self
+    }
+
+    /// Configure which form of the email address `Client::verify` returns. The default is
+    /// `EmailCase::Normalized`.
+    ///
+    /// See `EmailCase` for how to compare addresses. `Client::verify_full` returns both forms.
+    pub fn email_case(mut self, case: EmailCase) -> Self {
+        self.email_case = case;
+        self
+    }
+
+    /// Bind login sessions to properties of the user agent. The default is
+    /// `SessionBinding::Off`.
+    ///
+    /// When enabled, a digest of the selected properties in `AuthOptions::client_info` is stored
+    /// with the session, and `Client::verify_with_info` fails with
+    /// `VerifyError::SessionBindingMismatch` if they differ when the login completes. This makes a
+    /// token intercepted on its way to the redirect URI harder to use from another device.
+    ///
+    /// Binding to the IP address rejects users whose address changes during login, for example on
+    /// mobile networks. This requires a store that implements `Store::new_nonce_with_data`, and
+    /// `Store::peek_nonce_with_data` for `Client::verify_pending_with_info`. Methods that don't
+    /// take a `ClientInfo`, such as `Client::verify`, compare against empty info.
+    pub fn session_binding(mut self, binding: SessionBinding) -> Self {
+        self.session_binding = binding;
+        self
+    }
+
+    /// Configure a timeout for store session operations. The default is no timeout.
+    ///
+    /// This applies to `Store::new_nonce` and `Store::consume_nonce`, so that a hung store backend
+    /// results in a `StoreTimeout` error, instead of hanging the request indefinitely. Document
+    /// fetches are not covered, because the store is expected to apply its own HTTP timeouts.
+    ///
+    /// An operation that times out is not aborted, but continues in the background.
+    ///
+    /// This requires the Tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn store_timeout(mut self, dur: Duration) -> Self {
+        self.store_timeout = Some(dur);
+        self
+    }
+
+    /// Verify tokens signed with RSA keys on the blocking thread pool. The default is `false`.
+    ///
+    /// RSA verification is comparatively expensive, so a burst of logins with RSA-signed tokens can
+    /// stall other tasks on the async runtime. With this option, those tokens are verified using
+    /// `tokio::task::spawn_blocking`. Tokens signed with EdDSA or ECDSA are cheap to verify, and are
+    /// always verified inline.
+    ///
+    /// This requires the Tokio runtime. Without a runtime, all tokens are verified inline.
+    #[cfg(feature = "tokio")]
+    pub fn offload_rsa_verification(mut self, enable: bool) -> Self {
+        self.offload_rsa = enable;
+        self
+    }
+
+    /// Configure how many events `Client::events` buffers for each subscriber. The default is 256.
+    ///
+    /// Subscribers that fall further behind miss the oldest events.
+    #[cfg(feature = "tokio")]
+    pub fn event_capacity(mut self, events: usize) -> Self {
+        self.event_capacity = events;
+        self
+    }
+
+    /// Fall back to an uncached fetch if the store fails to fetch a document.
+    ///
+    /// When `Store::fetch` returns `FetchError::Store`, indicating a problem with the store itself
+    /// rather than the HTTP request, the document is fetched directly using `simple_fetch` with the
+    /// given HTTP client and timeout. This allows logins to keep working while a remote store is
+    /// briefly unavailable. Session operations do not fall back, and still fail when the store is
+    /// unavailable.
+    #[cfg(feature = "memory-store")]
+    pub fn fetch_fallback(mut self, client: Arc<dyn HttpClient>, timeout: Duration) -> Self {
+        self.fetch_fallback = Some(FetchFallback { client, timeout });
+        self
+    }
+
+    /// Generate nonces for new login sessions using `generator`.
+    ///
+    /// This is only used by the default `MemoryStore`, like `Builder::clock`. Configure other
+    /// stores directly, for example using `MemoryStore::nonce_generator`.
+    #[cfg(feature = "memory-store")]
+    pub fn nonce_generator(mut self, generator: Arc<dyn NonceGenerator>) -> Self {
+        self.nonce_generator = Some(generator);
+        self
+    }
+
+    /// Configure the timeout for each document fetch. The default is 30 seconds.
+    ///
+    /// This and the other network settings are only used by the default `MemoryStore`, like
+    /// `Builder::clock`. Configure other stores directly, for example using
+    /// `MemoryStore::with_http_client`.
+    #[cfg(feature = "memory-store")]
+    pub fn fetch_timeout(mut self, dur: Duration) -> Self {
+        self.fetch_timeout = dur;
+        self
+    }
+
+    /// Fail connections to the broker that are not established within `dur`. By default, only
+    /// `Builder::fetch_timeout` applies. See `HttpClientConfig::connect_timeout`.
+    #[cfg(feature = "memory-store")]
+    pub fn connect_timeout(mut self, dur: Duration) -> Self {
+        self.connect_timeout = Some(dur);
+        self
+    }
+
+    /// Reject documents larger than `bytes`. By default, the size is not limited. See
+    /// `MemoryStore::max_response_size`.
+    #[cfg(feature = "memory-store")]
+    pub fn max_response_size(mut self, bytes: usize) -> Self {
+        self.max_response_size = Some(bytes);
+        self
+    }
+
+    /// Reject tokens longer than `bytes` with `VerifyError::TokenTooLong`, before decoding them.
+    /// The default is 16 KiB, which is well above the size of tokens issued by a broker.
+    pub fn max_token_len(mut self, bytes: usize) -> Self {
+        self.max_token_len = bytes;
+        self
+    }
+
+    /// Reject email addresses longer than `bytes` in `Client::start_auth` with
+    /// `StartAuthError::EmailTooLong`. The default is 254, the maximum length of an address that
+    /// can be delivered to.
+    pub fn max_email_len(mut self, bytes: usize) -> Self {
+        self.max_email_len = bytes;
+        self
+    }
+
+    /// Configure whether relative URLs in the discovery document are allowed. The default is
+    /// `true`.
+    ///
+    /// Some minimal identity providers publish a relative `jwks_uri` or `authorization_endpoint`.
+    /// These are resolved against the discovery document URL, like other OpenID Connect clients
+    /// do. When disabled, relative URLs result in an `InvalidDiscoveryUrl` error.
+    pub fn relative_discovery_urls(mut self, enabled: bool) -> Self {
+        self.relative_discovery_urls = enabled;
+        self
+    }
+
+    /// Enable strict validation of the discovery document. The default is disabled.
+    ///
+    /// In strict mode, the discovery document must have an `issuer` (unless `IssuerCheck::Ignore`
+    /// is configured), and the endpoints it references must use https (unless the server itself
+    /// is configured with plain http, for development). This catches misconfigured brokers in
+    /// `Client::start_auth`, instead of failing verification after the user completed the login.
+    pub fn strict_discovery(mut self, enabled: bool) -> Self {
+        self.strict_discovery = enabled;
+        self
+    }
+
+    /// Require the endpoints in the discovery document to be on the origin of the server. The
+    /// default is disabled.
+    ///
+    /// Portier brokers serve all their endpoints from one origin. With this enabled, a tampered
+    /// discovery document can't make the client fetch keys from arbitrary hosts, such as services
+    /// on an internal network. Endpoints on other origins result in
+    /// `DiscoveryError::CrossOriginEndpoint`.
+    pub fn same_origin_endpoints(mut self, enabled: bool) -> Self {
+        self.same_origin_endpoints = enabled;
+        self
+    }
+
+    /// Configure how the `issuer` in the discovery document is checked. The default is
+    /// `IssuerCheck::Verify`.
+    ///
+    /// Per OpenID Connect Discovery, the `issuer` must match the URL the document was fetched
+    /// from, and is the value tokens are expected to have in their `iss` claim. Because servers
+    /// are configured as origins, the only difference allowed is a trailing slash.
+    pub fn issuer_check(mut self, check: IssuerCheck) -> Self {
+        self.issuer_check = check;
+        self
+    }
+
+    /// Use the given `Clock` for token validation, instead of the system clock.
+    ///
+    /// If no store is specified, the clock is also used by the default `MemoryStore`.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Configure the path of the relay endpoint used with `ResponseMode::Fragment`.
+    ///
+    /// The path must be absolute. The default is the redirect URI path with `/relay` appended. See
+    /// `FragmentRelay` for details.
+    pub fn fragment_relay_path(mut self, path: impl Into<String>) -> Self {
+        self.fragment_relay_path = Some(path.into());
+        self
+    }
+
+    /// Call `f` for every `BuildWarning` found by `Builder::build`.
+    ///
+    /// This can be used to log likely configuration mistakes, such as a redirect URI that doesn't
+    /// suit the response mode. By default, warnings are ignored.
+    pub fn on_warning(mut self, f: impl Fn(&BuildWarning) + Send + Sync + 'static) -> Self {
+        self.on_warning = Some(Arc::new(f));
+        self
+    }
+
+    /// Call `f` when the keys published by a broker change.
+    ///
+    /// The keys are recorded whenever the client reads the JWKs document, and the current keys are
+    /// available from `Client::keys`. Operators can use this to alert on unexpected key rotations,
+    /// or keys with a different algorithm. The first key set seen for each broker is not reported.
+    pub fn on_key_change(mut self, f: impl Fn(&KeySetChange) + Send + Sync + 'static) -> Self {
+        self.on_key_change = Some(Arc::new(f));
+        self
+    }
+
+    /// Set a callback to receive an event for every step of every login. See `LoginStep`.
+    ///
+    /// This can be used to count logins per step and failure reason, for example as Prometheus
+    /// counters, and build a login funnel dashboard from them. Events of the same login share a
+    /// correlation ID. The callback is called inline, so should not block.
+    pub fn on_login_event(mut self, f: impl Fn(&LoginEvent<'_>) + Send + Sync + 'static) -> Self {
+        self.on_login_event = Some(Arc::new(f));
+        self
+    }
+
+    /// Use the given `KeyVerifier` to verify broker keys out-of-band, before they are used.
+    ///
+    /// By default, keys are accepted as published in the JWKs document of the broker.
+    pub fn key_verifier(mut self, verifier: Arc<dyn KeyVerifier>) -> Self {
+        self.key_verifier = Some(verifier);
+        self
+    }
+
+    /// Use the given `LoginHook` to inspect every login verification, and reject suspicious logins.
+    ///
+    /// By default, all logins that pass verification are accepted.
+    pub fn login_hook(mut self, hook: Arc<dyn LoginHook>) -> Self {
+        self.login_hook = Some(hook);
+        self
+    }
+
+    /// Use the given `FailureSink` to record failed verifications, for investigating attacks.
+    ///
+    /// By default, failures are only reported as login events. See `StoreFailureSink` to record
+    /// them in a store.
+    pub fn failure_sink(mut self, sink: Arc<dyn FailureSink>) -> Self {
+        self.failure_sink = Some(sink);
+        self
+    }
+
+    /// Use the given `RateLimiter` to limit how often logins can be started.
+    ///
+    /// By default, logins are not limited. See `MemoryRateLimiter` for a limiter that works
+    /// within a single process.
+    pub fn rate_limiter(mut self, limiter: Arc<dyn RateLimiter>) -> Self {
+        self.rate_limiter = Some(limiter);
+        self
+    }
+
+    /// Check that the keys published by a broker overlap with keys seen before. The default is
+    /// `KeyContinuity::Off`.
+    ///
+    /// Thumbprints of the broker keys are pinned in the session store, using
+    /// `Store::save_key_pins`, and kept until they have not been seen for `overlap`. A key set that
+    /// shares no keys with the pinned keys may indicate a compromised broker or DNS hijack, and
+    /// fails the check. Brokers should therefore publish new keys alongside the old keys for at
+    /// least `overlap` when rotating. The first key set seen for a broker is trusted.
+    ///
+    /// Keys are checked when the JWKs document changes, and at least every half `overlap` to
+    /// refresh the pins. If the store cannot be accessed, the check also fails.
+    pub fn key_continuity(mut self, mode: KeyContinuity, overlap: Duration) -> Self {
+        self.key_continuity = mode;
+        self.rotation_overlap = overlap;
+        self
+    }
+
+    /// Call `f` when a key continuity check fails. See `Builder::key_continuity`.
+    pub fn on_key_continuity(
+        mut self,
+        f: impl Fn(&KeyContinuityError) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_key_continuity = Some(Arc::new(f));
+        self
+    }
+
+    /// Share broker health and the rate limit on key refetches between workers. The default is
+    /// disabled.
+    ///
+    /// By default, each client process tracks failed document fetches for `Client::availability`,
+    /// and limits how often the JWKs document is refetched after a key rotation, on its own. With
+    /// this enabled, the state is loaded from the session store around document fetches using
+    /// `Store::load_endpoint_state`, and saved when it changes, so the whole fleet shares it.
+    /// Errors accessing the store are ignored, and fall back to the local state.
+    pub fn shared_endpoint_state(mut self, enabled: bool) -> Self {
+        self.shared_endpoint_state = enabled;
+        self
+    }
+
+    /// Configure how redirect URIs are canonicalized. The default is
+    /// `UriCanonicalization::Parsed`.
+    ///
+    /// The redirect URI is canonicalized before deriving the client ID, which is its origin, and
+    /// so are `AuthOptions::redirect_uri` and `ClientInfo::redirect_uri`. This prevents
+    /// `VerifyError::AudienceInvalid` and `VerifyError::RedirectUriMismatch` failures when parts
+    /// of an application construct the URI slightly differently. The application must accept
+    /// logins on the canonical URI, which is available as `Client::redirect_uri`.
+    pub fn uri_canonicalization(mut self, canonicalization: UriCanonicalization) -> Self {
+        self.uri_canonicalization = canonicalization;
+        self
+    }
+
+    /// Use `keys` to verify tokens, instead of fetching the JWKs document of the broker.
+    ///
+    /// This is for air-gapped deployments, and deployments that can't afford a fetch on the
+    /// verification path. The keys apply to all broker endpoints. Tokens signed with a key that is
+    /// not in `keys` fail with `VerifyError::KeyNotPinned`, so keys must be updated here before the
+    /// broker rotates them. To also avoid fetching the discovery document, use
+    /// `Builder::pinned_discovery`. If the keys can't be serialized, `Builder::build` fails with
+    /// `BuildError::InvalidPinnedJwks`.
+    pub fn pinned_jwks(mut self, keys: jwk::KeySet) -> Self {
+        self.pinned_jwks = Some(Arc::new(keys));
+        self
+    }
+
+    /// Use `document` as the discovery document of the broker, instead of fetching it.
+    ///
+    /// The document is checked like a fetched document. Together with `Builder::pinned_jwks`,
+    /// logins can be started and verified without any requests to the broker. If the document
+    /// can't be parsed, `Builder::build` fails with `BuildError::InvalidPinnedDiscovery`.
+    pub fn pinned_discovery(mut self, document: impl Into<Bytes>) -> Self {
+        self.pinned_discovery = Some(document.into());
+        self
+    }
+
+    /// Make `Builder::build` fail with `BuildError::Warning` on the first `BuildWarning`.
+    pub fn deny_warnings(mut self, enabled: bool) -> Self {
+        self.deny_warnings = enabled;
+        self
+    }
+
+    /// Apply the strictest settings, for security-conscious deployments.
+    ///
+    /// This is shorthand for:
+    ///
+    /// - `Builder::deny_warnings`, which makes plain http redirect URIs and brokers an error,
+    ///   except on loopback addresses.
+    /// - `Builder::strict_discovery`, `Builder::relative_discovery_urls` disabled,
+    ///   `Builder::same_origin_endpoints` and `IssuerCheck::Require`.
+    /// - `Builder::allowed_algs` with only `EdDSA`.
+    /// - `Builder::spec_version` with `SpecVersion::LATEST`.
+    /// - A leeway of 30 seconds, and a session TTL of 5 minutes.
+    ///
+    /// The broker must sign tokens using EdDSA, and publish an `issuer` in its discovery document.
+    /// Settings can be relaxed by calling their methods after this one. Settings may become
+    /// stricter in minor releases.
+    pub fn hardened(self) -> Self {
+        self.deny_warnings(true)
+            .strict_discovery(true)
+            .relative_discovery_urls(false)
+            .same_origin_endpoints(true)
+            .issuer_check(IssuerCheck::Require)
+            .allowed_algs(["EdDSA"])
+            .spec_version(SpecVersion::LATEST)
+            .leeway(Duration::from_secs(30))
+            .session_ttl(Duration::from_secs(5 * 60))
+    }
+
+    /// Build the client, then check the broker configuration using `Client::check`.
+    ///
+    /// This is useful to fail fast at application startup, if the broker is misconfigured or
+    /// unreachable.
+    pub async fn build_checked(self) -> Result<Client, CheckError> {
+        let client = self.build().map_err(CheckError::Build)?;
+        client.check().await?;
+        Ok(client)
+    }
+
+    /// Apply settings compatible with older releases of the Portier broker, for operators running
+    /// pinned self-hosted brokers.
+    ///
+    /// These releases only sign tokens using RS256, may publish an incorrect `issuer` in their
+    /// discovery document, and don't include `email_original` in tokens. This is shorthand for:
+    ///
+    /// - `Builder::allowed_algs` with only `RS256`.
+    /// - `IssuerCheck::Ignore`, with `Builder::strict_discovery` disabled.
+    /// - `Builder::spec_version` with `SpecVersion::V1`.
+    /// - A leeway of 5 minutes, for brokers with poorly synchronized clocks.
+    ///
+    /// Upgrade the broker and remove this preset when possible, because it disables checks that
+    /// protect against misconfiguration.
+    pub fn compat_portier_broker_v0(self) -> Self {
+        self.allowed_algs(["RS256"])
+            .issuer_check(IssuerCheck::Ignore)
+            .strict_discovery(false)
+            .spec_version(SpecVersion::V1)
+            .leeway(Duration::from_secs(5 * 60))
+    }
+
+    /// Create the default `MemoryStore` now if no store is configured, so it is shared between
+    /// clones of this builder.
+    pub(crate) fn with_default_store(self) -> Self {
+        #[cfg(all(
+            feature = "memory-store",
+            any(feature = "http-hyper", feature = "http-reqwest"),
+            any(feature = "tls-native", feature = "tls-rustls")
+        ))]
+        if self.fetcher.is_none() || self.sessions.is_none() {
+            let store = Arc::new(self.default_store());
+            return Builder {
+                fetcher: Some(self.fetcher.clone().unwrap_or_else(|| store.clone())),
+                sessions: Some(self.sessions.clone().unwrap_or(store)),
+                ..self
+            };
+        }
+        self
+    }
+
+    /// Create the default `MemoryStore`, using the configured clock, nonce generator and network
+    /// settings.
+    #[cfg(all(
+        feature = "memory-store",
+        any(feature = "http-hyper", feature = "http-reqwest"),
+        any(feature = "tls-native", feature = "tls-rustls")
+    ))]
+    fn default_store(&self) -> MemoryStore<crate::DefaultHttpClient> {
+        let mut config = crate::HttpClientConfig::new();
+        if let Some(dur) = self.connect_timeout {
+            config = config.connect_timeout(dur);
+        }
+        let client = crate::default_http_client_with_config(config);
+        let mut store =
+            MemoryStore::with_http_client(client, self.fetch_timeout).clock(self.clock.clone());
+        if let Some(ref generator) = self.nonce_generator {
+            store = store.nonce_generator(generator.clone());
+        }
+        if let Some(bytes) = self.max_response_size {
+            store = store.max_response_size(bytes);
+        }
+        store
+    }
+
+    /// A copy of this builder, with the origin of the redirect URI replaced by `origin`.
+    pub(crate) fn for_origin(&self, origin: &Url) -> Self {
+        let mut builder = self.clone();
+        let mut redirect_uri = origin.clone();
+        redirect_uri.set_path(self.redirect_uri.path());
+        redirect_uri.set_query(self.redirect_uri.query());
+        redirect_uri.set_fragment(self.redirect_uri.fragment());
+        builder.redirect_uri = redirect_uri;
+        builder
+    }
+
+    /// A copy of this builder, with the redirect URI replaced by `redirect_uri`.
+    pub(crate) fn with_redirect_uri(&self, redirect_uri: Url) -> Self {
+        let mut builder = self.clone();
+        builder.redirect_uri = redirect_uri;
+        builder
+    }
+
+    /// The configured redirect URI.
+    pub(crate) fn redirect_uri(&self) -> &Url {
+        &self.redirect_uri
+    }
+
+    /// Verify the configuration and build the client.
+    pub fn build(mut self) -> Result<Client, BuildError> {
+        if let Some(provider) = self.session_secret.take() {
+            let mut secret = provider.load_secret().map_err(BuildError::Secret)?;
+            self.sessions = Some(Arc::new(StatelessSessions::new(&secret)));
+            misc::wipe(&mut secret);
+        }
+        let (fetcher, sessions) = match (self.fetcher.take(), self.sessions.take()) {
+            (Some(fetcher), Some(sessions)) => (fetcher, sessions),
+            #[cfg(all(
+                feature = "memory-store",
+                any(feature = "http-hyper", feature = "http-reqwest"),
+                any(feature = "tls-native", feature = "tls-rustls")
+            ))]
+            (fetcher, sessions) => {
+                let store = Arc::new(self.default_store());
+                (
+                    fetcher.unwrap_or_else(|| store.clone()),
+                    sessions.unwrap_or(store),
+                )
+            }
+            #[cfg(not(all(
+                feature = "memory-store",
+                any(feature = "http-hyper", feature = "http-reqwest"),
+                any(feature = "tls-native", feature = "tls-rustls")
+            )))]
+            _ => return Err(BuildError::NoDefaultStore),
+        };
+        self.build_with_store(DynStore { fetcher, sessions })
+    }
+
+    /// Verify the configuration and build a client that uses `store` directly.
+    ///
+    /// Unlike `Builder::store`, the store is not put behind a trait object, so calls to it can be
+    /// inlined, and stores with native async methods don't need to box their futures. The store
+    /// is cloned for each store operation, so should be a cheap handle, such as an `Arc` or a
+    /// connection pool. Any `Builder::store`, `Builder::fetcher` or `Builder::session_store` is
+    /// ignored.
+    pub fn build_with_store<S: AsyncStore + Clone>(
+        mut self,
+        store: S,
+    ) -> Result<Client<S>, BuildError> {
+        let server = self.server.unwrap_or_else(|| Broker::PORTIER_IO.url());
+
+        self.redirect_uri = self.uri_canonicalization.apply(&self.redirect_uri);
+        let client_origin = self.redirect_uri.origin();
+        if !client_origin.is_tuple() {
+            return Err(BuildError::InvalidRedirectUri);
+        }
+
+        let client_id = client_origin.ascii_serialization();
+
+        if let Some(ref document) = self.pinned_discovery {
+            serde_json::from_slice::<DiscoveryDoc>(document)
+                .map_err(BuildError::InvalidPinnedDiscovery)?;
+        }
+        let pinned_jwks = match self.pinned_jwks {
+            Some(ref keys) => Some(Bytes::from(
+                serde_json::to_vec(&**keys).map_err(BuildError::InvalidPinnedJwks)?,
+            )),
+            None => None,
+        };
+
+        if let Some(ref path) = self.fragment_relay_path {
+            if !path.starts_with('/') {
+                return Err(BuildError::InvalidFragmentRelayPath);
+            }
+        }
+        let fragment_relay =
+            FragmentRelay::new(&self.redirect_uri, self.fragment_relay_path.as_deref());
+
+        let mut warnings = Vec::new();
+        match self.response_mode {
+            ResponseMode::FormPost if self.fragment_relay_path.is_some() => {
+                warnings.push(BuildWarning::UnusedFragmentRelay);
+            }
+            ResponseMode::Fragment
+                if fragment_relay.relay_path() == fragment_relay.redirect_path() =>
+            {
+                warnings.push(BuildWarning::FragmentRelayIsRedirectUri);
+            }
+            _ => {}
+        }
+        if self.redirect_uri.fragment().is_some() {
+            warnings.push(BuildWarning::RedirectUriHasFragment);
+        }
+        if self.redirect_uri.scheme() != "https" && !is_loopback(&self.redirect_uri) {
+            warnings.push(BuildWarning::InsecureRedirectUri);
+        }
+        if std::iter::once(&server)
+            .chain(&self.mirrors)
+            .any(|url| url.scheme() != "https" && !is_loopback(url))
+        {
+            warnings.push(BuildWarning::InsecureServer);
+        }
+        for warning in warnings {
+            if self.deny_warnings {
+                return Err(BuildError::Warning(warning));
+            }
+            if let Some(ref on_warning) = self.on_warning {
+                on_warning(&warning);
+            }
+        }
+
+        let endpoints = std::iter::once(server)
+            .chain(self.mirrors)
+            .map(|server| {
+                let server_id = server_origin(&server)?;
+
+                let mut discovery_url = server;
+                discovery_url.set_path("/.well-known/openid-configuration");
+
+                let mut validator = Validator::new(server_id, client_id.clone())
+                    .leeway(self.leeway)
+                    .spec_version(self.spec_version)
+                    .clock(self.clock.clone())
+                    .with_claim_warning_hook(self.on_claim_warning.clone());
+                for &(check, policy) in &self.claim_policies {
+                    validator = validator.claim_policy(check, policy);
+                }
+                if !self.trusted {
+                    validator = validator.untrusted();
+                }
+                if let Some(ref algs) = self.allowed_algs {
+                    validator = validator.allowed_algs(algs.iter().cloned());
+                }
+
+                Ok(Endpoint::new(discovery_url, validator))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Client {
+            store,
+            inner: Arc::new(ClientInner {
+                endpoints: Endpoints::new(endpoints),
+                redirect_uri: self.redirect_uri,
+                client_id,
+                response_mode: self.response_mode,
+                session_ttl: self.session_ttl,
+                email_case: self.email_case,
+                session_binding: self.session_binding,
+                relative_discovery_urls: self.relative_discovery_urls,
+                strict_discovery: self.strict_discovery,
+                same_origin_endpoints: self.same_origin_endpoints,
+                issuer_check: self.issuer_check,
+                fragment_relay,
+                clock: self.clock,
+                on_key_change: self.on_key_change,
+                on_login_event: self.on_login_event,
+                key_continuity: self.key_continuity,
+                rotation_overlap: self.rotation_overlap,
+                on_key_continuity: self.on_key_continuity,
+                shared_endpoint_state: self.shared_endpoint_state,
+                uri_canonicalization: self.uri_canonicalization,
+                key_verifier: self.key_verifier,
+                login_hook: self.login_hook,
+                failure_sink: self.failure_sink,
+                rate_limiter: self.rate_limiter,
+                pinned_discovery: self.pinned_discovery,
+                pinned_jwks,
+                max_token_len: self.max_token_len,
+                max_email_len: self.max_email_len,
+                #[cfg(feature = "tokio")]
+                store_timeout: self.store_timeout,
+                #[cfg(feature = "tokio")]
+                offload_rsa: self.offload_rsa,
+                #[cfg(feature = "tokio")]
+                events: broadcast::channel(self.event_capacity.max(1)).0,
+                #[cfg(feature = "memory-store")]
+                fetch_fallback: self.fetch_fallback,
+            }),
+        })
+    }
+}
+
+/// A client for performing Portier authentication.
+///
+/// Create a client using either `Client::builder` or `Client::new`. Sharing a client can be done
+/// simply by reference, even across threads. All methods take an immutable reference to `self`
+/// only.
+///
+/// A client can also be cloned cheaply, for example to move it into request handlers. Clones share
+/// the configuration, the store, and cached broker state.
+///
+/// The store type `S` is `DynStore` by default, which holds the store configured on the `Builder`
+/// as a trait object. Use `Builder::build_with_store` to use a concrete store type instead.
+#[derive(Clone)]
+pub struct Client<S = DynStore> {
+    store: S,
+    inner: Arc<ClientInner>,
+}
+
+/// The configuration of a `Client`, shared between clones.
+struct ClientInner {
+    endpoints: Endpoints,
+    redirect_uri: Url,
+    client_id: String,
+    response_mode: ResponseMode,
+    session_ttl: Duration,
+    email_case: EmailCase,
+    session_binding: SessionBinding,
+    relative_discovery_urls: bool,
+    strict_discovery: bool,
+    same_origin_endpoints: bool,
+    issuer_check: IssuerCheck,
+    fragment_relay: FragmentRelay,
+    clock: Arc<dyn Clock>,
+    on_key_change: Option<KeyChangeHook>,
+    on_login_event: Option<LoginEventHook>,
+    key_continuity: KeyContinuity,
+    rotation_overlap: Duration,
+    on_key_continuity: Option<KeyContinuityHook>,
+    shared_endpoint_state: bool,
+    uri_canonicalization: UriCanonicalization,
+    key_verifier: Option<Arc<dyn KeyVerifier>>,
+    login_hook: Option<Arc<dyn LoginHook>>,
+    failure_sink: Option<Arc<dyn FailureSink>>,
+    rate_limiter: Option<Arc<dyn RateLimiter>>,
+    pinned_discovery: Option<Bytes>,
+    pinned_jwks: Option<Bytes>,
+    max_token_len: usize,
+    max_email_len: usize,
+    #[cfg(feature = "tokio")]
+    store_timeout: Option<Duration>,
+    #[cfg(feature = "tokio")]
+    offload_rsa: bool,
+    #[cfg(feature = "tokio")]
+    events: broadcast::Sender<OwnedLoginEvent>,
+    #[cfg(feature = "memory-store")]
+    fetch_fallback: Option<FetchFallback>,
+}
+
+impl Client {
+    /// Create a builder-style struct to configure a Client.
+    pub fn builder(redirect_uri: Url) -> Builder {
+        Builder::new(redirect_uri)
+    }
+
+    /// Create a client with default settings.
+    ///
+    /// This uses a `MemoryStore`, which has some limitations. See the documentation for
+    /// `MemoryStore` for details.
+    #[cfg(all(
+        feature = "memory-store",
+        any(feature = "http-hyper", feature = "http-reqwest"),
+        any(feature = "tls-native", feature = "tls-rustls")
+    ))]
+    pub fn new(redirect_uri: Url) -> Self {
+        Builder::new(redirect_uri).build().unwrap()
+    }
+}
+
+impl<S: AsyncStore + Clone> Client<S> {
+    /// The store used by this client.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// The canonical redirect URI. See `Builder::uri_canonicalization`.
+    pub fn redirect_uri(&self) -> &Url {
+        &self.inner.redirect_uri
+    }
+
+    /// The client ID sent to the broker, which is the origin of the redirect URI.
+    pub fn client_id(&self) -> &str {
+        &self.inner.client_id
+    }
+
+    /// Relay endpoint configuration for use with `ResponseMode::Fragment`.
+    pub fn fragment_relay(&self) -> &FragmentRelay {
+        &self.inner.fragment_relay
+    }
+
+    /// The HTML page to serve at the redirect URI with `ResponseMode::Fragment`.
+    ///
+    /// Shorthand for `FragmentRelay::page_html` on `Client::fragment_relay`.
+    pub fn fragment_callback_html(&self, script_nonce: Option<&str>) -> String {
+        self.inner.fragment_relay.page_html(script_nonce)
+    }
+
+    /// Subscribe to the login events of this client, and its clones.
+    ///
+    /// The receiver gets the same events as `Builder::on_login_event`, from the moment it
+    /// subscribes, which is useful for a real-time view of login activity in an admin dashboard.
+    /// Subscribers that fall behind by more than `Builder::event_capacity` events receive
+    /// `RecvError::Lagged`, and miss the oldest events. Events are not buffered while there are no
+    /// subscribers.
+    #[cfg(feature = "tokio")]
+    pub fn events(&self) -> broadcast::Receiver<OwnedLoginEvent> {
+        self.inner.events.subscribe()
+    }
+
+    /// Measure the latency of the broker and its mirrors, and prefer the fastest for new logins.
+    ///
+    /// Each endpoint is probed by fetching its discovery document directly with the given HTTP
+    /// client, bypassing the store. Endpoints that fail or exceed `timeout` are considered
+    /// unhealthy, and are avoided until the next probe. Applications can call this periodically.
+    /// Measurements are shared between clones of the client.
+    #[cfg(feature = "memory-store")]
+    pub async fn probe_endpoints(
+        &self,
+        client: &dyn HttpClient,
+        timeout: Duration,
+    ) -> Vec<EndpointProbe> {
+        self.inner.endpoints.probe(client, timeout).await
+    }
+
+    /// The broker signing keys last seen by this client, for all endpoints.
+    ///
+    /// Keys are recorded when the client reads the keys document, during `Client::verify` or
+    /// `Client::check`. This is empty until then. See also `Builder::on_key_change`.
+    pub fn keys(&self) -> Vec<KeyInfo> {
+        self.inner
+            .endpoints
+            .all()
+            .iter()
+            .flat_map(Endpoint::keys)
+            .collect()
+    }
+
+    /// Fetch and validate the discovery and keys documents of every configured broker endpoint.
+    ///
+    /// This performs the same checks as `Client::start_auth` and `Client::verify`, and also
+    /// verifies that the keys document contains at least one supported key. Documents are fetched
+    /// using the store, so this also warms the cache.
+    pub async fn check(&self) -> Result<Vec<CheckReport>, CheckError> {
+        let mut reports = Vec::with_capacity(self.inner.endpoints.all().len());
+        for endpoint in self.inner.endpoints.all() {
+            reports.push(self.check_endpoint(endpoint).await?);
+        }
+        Ok(reports)
+    }
+
+    /// Check that the environment of the client works, and report the outcome of each check.
+    ///
+    /// This checks that the system random number generator produces output, and that the store
+    /// can create and consume a session. With `check_brokers`, this also runs `Client::check`,
+    /// which makes requests to every broker endpoint. Call this at application startup, or from a
+    /// diagnostics endpoint. Unlike a login, this does not call any `LoginHook` or emit events.
+    pub async fn self_test(&self, check_brokers: bool) -> SelfTestReport {
+        let rng = {
+            let mut buf = [0u8; 32];
+            match SystemRandom::new().fill(&mut buf) {
+                Ok(()) if buf != [0u8; 32] => Ok(()),
+                _ => Err(SelfTestError::Rng),
+            }
+        };
+        let store = self.self_test_store().await;
+        let brokers = if check_brokers {
+            Some(self.check().await)
+        } else {
+            None
+        };
+        SelfTestReport {
+            rng,
+            store,
+            brokers,
+        }
+    }
+
+    /// Create and consume a session with a placeholder email address.
+    async fn self_test_store(&self) -> Result<(), SelfTestError> {
+        const EMAIL: &str = "self-test@portier.invalid";
+        let ttl = self.inner.session_ttl;
+        let nonce = self
+            .store_op(|store| async move { store.new_nonce_with_ttl(EMAIL.to_owned(), ttl).await })
+            .await
+            .ok_or(SelfTestError::StoreTimeout)?
+            .map_err(SelfTestError::Store)?;
+        let consumed = self
+            .store_op(|store| async move { store.consume_nonce(nonce, EMAIL.to_owned()).await })
+            .await
+            .ok_or(SelfTestError::StoreTimeout)?
+            .map_err(SelfTestError::Store)?;
+        if consumed {
+            Ok(())
+        } else {
+            Err(SelfTestError::StoreRoundTrip)
+        }
+    }
+
+    /// Summarize recent broker health, to indicate whether logins are likely to work.
+    ///
+    /// This does not perform any requests, but is based on the outcome of recent document fetches
+    /// and `Client::probe_endpoints`. Frontends can use it to hide or annotate the login form
+    /// during a broker outage, instead of failing after the user submits it. A broker is
+    /// considered unavailable for a minute after several consecutive failed fetches, or until the
+    /// time it asked to be retried.
+    pub fn availability(&self) -> Availability {
+        self.inner.endpoints.availability(self.inner.clock.now())
+    }
+
+    /// Create a `ReturnTo` validator that accepts URLs on the origin of the redirect URI.
+    ///
+    /// Use this to check where to send the user after login, before redirecting there.
+    pub fn return_to(&self) -> ReturnTo {
+        ReturnTo::new(self.inner.redirect_uri.clone())
+    }
+
+    /// Fetch the discovery and keys documents of every configured broker endpoint ahead of time.
+    ///
+    /// This warms the cache, so that the first login after startup doesn't have to wait for the
+    /// documents to be fetched. The documents are validated like in `Client::check`.
+    ///
+    /// Returns the shortest remaining cache lifetime of the documents, which can be used to
+    /// schedule the next prefetch. This is `None` if the store does not report cache lifetimes.
+    /// See `Store::cache_lifetime`.
+    pub async fn prefetch(&self) -> Result<Option<Duration>, CheckError> {
+        let reports = self.check().await?;
+        let mut shortest: Option<Duration> = None;
+        for (endpoint, report) in self.inner.endpoints.all().iter().zip(reports) {
+            for url in [endpoint.discovery_url.clone(), report.jwks_uri] {
+                let lifetime = match self.store.cache_lifetime(url).await {
+                    Ok(Some(lifetime)) => lifetime,
+                    _ => return Ok(None),
+                };
+                shortest = Some(shortest.map_or(lifetime, |shortest| shortest.min(lifetime)));
+            }
+        }
+        Ok(shortest)
+    }
+
+    /// Create a login session for the given email, and return a URL to redirect the user agent
+    /// (browser) to so authentication can continue.
+    ///
+    /// If performing the redirect in the HTTP response, the recommended method is to send a 303
+    /// HTTP status code with the `Location` header set to the URL. But other solutions are
+    /// possible, such as fetching this URL using a request from client-side JavaScript.
+    ///
+    /// To pass additional parameters, such as `state`, use `Client::start_auth_with_options`.
+    ///
+    /// This method is cancellation-safe. If the future is dropped, at worst an unused session
+    /// remains in the store, which expires like any abandoned login.
+    pub async fn start_auth(&self, email: &str) -> Result<Url, StartAuthError> {
+        self.start_auth_with_options(email, AuthOptions::default())
+            .await
+    }
+
+    /// Like `Client::start_auth`, but adds the parameters in `options` to the returned URL.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "start_auth",
+            level = "debug",
+            skip_all,
+            fields(
+                broker = tracing::field::Empty,
+                correlation_id = tracing::field::Empty,
+            ),
+            err(level = "debug")
+        )
+    )]
+    pub async fn start_auth_with_options(
+        &self,
+        email: &str,
+        mut options: AuthOptions,
+    ) -> Result<Url, StartAuthError> {
+        let endpoint = self.inner.endpoints.select();
+        let broker = endpoint.validator.issuer();
+        record_span!("broker", broker);
+        let generated = options.correlation_id.is_none();
+        let correlation_id = options
+            .correlation_id
+            .get_or_insert_with(random_correlation_id)
+            .clone();
+        record_span!("correlation_id", correlation_id.as_str());
+        self.emit(LoginStep::AuthStarted, Some(&correlation_id), Some(broker));
+        let result = self
+            .start_auth_inner(endpoint, email, options, generated)
+            .await;
+        let step = match result {
+            Ok(_) => LoginStep::Redirected,
+            Err(ref err) => LoginStep::Failed(err.code()),
+        };
+        self.emit(step, Some(&correlation_id), Some(broker));
+        result
+    }
+
+    /// Fetch the discovery document of the broker used for new logins.
+    ///
+    /// Applications can use this to inspect the metadata published by the broker, such as the
+    /// supported response modes. The document is cached like any other document fetch, and is
+    /// not checked against the client configuration; use `Client::check` for that.
+    pub async fn discovery(&self) -> Result<DiscoveryDoc, StartAuthError> {
+        let discovery = self.fetch_discovery(self.inner.endpoints.select()).await?;
+        serde_json::from_slice(&discovery).map_err(StartAuthError::ParseDiscovery)
+    }
+
+    /// Fetch the discovery document of `endpoint`, for starting a login.
+    async fn fetch_discovery(&self, endpoint: &Endpoint) -> Result<Bytes, StartAuthError> {
+        self.fetch(
+            endpoint,
+            FetchPurpose::Discovery,
+            endpoint.discovery_url.clone(),
+        )
+        .await
+        .map_err(|err| match err.status_error() {
+            Some(status) if status.status == 503 => StartAuthError::BrokerUnavailable {
+                retry_after: status.retry_after,
+            },
+            _ => StartAuthError::FetchDiscovery(err),
+        })
+    }
+
+    /// Start a login using `endpoint`, for `Client::start_auth_with_options`.
+    ///
+    /// With `generated_id`, the correlation ID was not set by the caller, and is only kept if the
+    /// store supports session data.
+    async fn start_auth_inner(
+        &self,
+        endpoint: &Endpoint,
+        email: &str,
+        mut options: AuthOptions,
+        generated_id: bool,
+    ) -> Result<Url, StartAuthError> {
+        if email.len() > self.inner.max_email_len {
+            return Err(StartAuthError::EmailTooLong {
+                max: self.inner.max_email_len,
+            });
+        }
+        self.check_rate_limit(email, options.rate_limit_key.as_deref())
+            .await?;
+        let discovery = self.fetch_discovery(endpoint).await?;
+        // Parsing and checking the discovery document is only necessary when it changes.
+        let mut auth_url = match endpoint.cached_auth_url(&discovery) {
+            Some(auth_url) => auth_url,
+            None => {
+                let auth_url = self.auth_url_prefix(endpoint, &discovery)?;
+                endpoint.cache_auth_url(discovery, auth_url.clone());
+                auth_url
+            }
+        };
+
+        if let Some(ref mut redirect_uri) = options.redirect_uri {
+            *redirect_uri = self.inner.uri_canonicalization.apply(redirect_uri);
+            if redirect_uri.origin() != self.inner.redirect_uri.origin() {
+                return Err(StartAuthError::InvalidRedirectUri);
+            }
+            let pairs: Vec<(String, String)> = auth_url
+                .query_pairs()
+                .map(|(name, value)| match name.as_ref() {
+                    "redirect_uri" => (name.into_owned(), redirect_uri.to_string()),
+                    _ => (name.into_owned(), value.into_owned()),
+                })
+                .collect();
+            auth_url.query_pairs_mut().clear().extend_pairs(pairs);
+        }
+
+        let envelope = SessionEnvelope {
+            binding: self.inner.session_binding.digest(&options.client_info),
+            correlation_id: options.correlation_id.take(),
+            redirect_uri: options.redirect_uri.take().map(String::from),
+            data: std::mem::take(&mut options.session_data),
+        };
+        let nonce = self.new_session(email, &envelope, generated_id).await?;
+        auth_url
+            .query_pairs_mut()
+            .append_pair("login_hint", email)
+            .append_pair("nonce", &nonce);
+        options.apply(&mut auth_url);
+        Ok(auth_url)
+    }
+
+    /// Verify `token` and return a verified email address.
+    ///
+    /// The token is delivered by the user agent (browser) directly according to the `redirect_uri`
+    /// and `response_mode` configured when the `Client` was created.
+    ///
+    /// The address is normalized, unless configured otherwise using `Builder::email_case`.
+    ///
+    /// This method is cancellation-safe when running on a Tokio runtime with the `tokio` feature
+    /// enabled. Once the session is being consumed, the store operation runs to completion as a
+    /// separate task, even if the future is dropped. The session is then either left untouched, or
+    /// fully consumed, in which case the login has to be restarted. Without a Tokio runtime,
+    /// dropping the future may interrupt the store operation, and cancellation safety depends on
+    /// the `Store` implementation.
+    pub async fn verify(&self, token: &str) -> Result<String, VerifyError> {
+        let token = self.verify_full(token).await?;
+        Ok(token.email_with_case(self.inner.email_case).to_owned())
+    }
+
+    /// Handle all parameters sent by the broker to the redirect URI, and return a verified email
+    /// address.
+    ///
+    /// Unlike `Client::verify`, this detects error responses from the broker, and returns them as
+    /// `CallbackError::Broker`, instead of failing to verify a missing token. Otherwise, this is
+    /// the same as calling `Client::verify` with the `id_token` parameter.
+    pub async fn handle_callback(&self, params: &CallbackParams) -> Result<String, CallbackError> {
+        let token = params.id_token.as_deref().unwrap_or_default();
+        let mut session_id = None;
+        let result = match (&params.error, &params.id_token) {
+            (Some(code), _) => Err(CallbackError::Broker {
+                code: code.clone(),
+                description: params.error_description.clone(),
+            }),
+            (None, None) => Err(CallbackError::MissingToken),
+            (None, Some(_)) => self
+                .verify_checked(token, &ClientInfo::default(), |_| Ok(()), &mut session_id)
+                .await
+                .map_err(CallbackError::from),
+        };
+        // Both events are reported once the session is consumed, so that they have its
+        // correlation ID.
+        let outcome = match result {
+            Ok(_) => LoginStep::Verified,
+            Err(ref err) => LoginStep::Failed(err.code()),
+        };
+        self.emit_for_token(&[LoginStep::CallbackReceived, outcome], token, session_id)
+            .await;
+        Ok(result?.email_with_case(self.inner.email_case).to_owned())
+    }
+
+    /// Like `Client::verify`, but return all validated claims instead of only the email address.
+    ///
+    /// This is useful to log the original email address, or to base the lifetime of an
+    /// application session on the token expiry.
+    pub async fn verify_full(&self, token: &str) -> Result<VerifiedToken, VerifyError> {
+        self.verify_with_info(token, &ClientInfo::default()).await
+    }
+
+    /// Like `Client::verify_full`, but also deserialize the token payload into `T`.
+    ///
+    /// See `VerifiedToken::claims_as`. The raw payload remains available in
+    /// `VerifiedToken::payload`. If the payload does not deserialize into `T`, this fails with
+    /// `VerifyError::InvalidPayload`, before the session is consumed.
+    pub async fn verify_with_claims<T: DeserializeOwned>(
+        &self,
+        token: &str,
+    ) -> Result<(VerifiedToken, T), VerifyError> {
+        let token = self
+            .verify_with_check(token, &ClientInfo::default(), |token| {
+                token.claims_as::<T>().map(drop)
+            })
+            .await?;
+        let claims = token.claims_as()?;
+        Ok((token, claims))
+    }
+
+    /// Like `Client::verify`, but fail with `VerifyError::DeadlineExceeded` if verification takes
+    /// longer than `deadline` in total.
+    ///
+    /// This bounds the time spent fetching documents and consuming the session, regardless of
+    /// `Builder::store_timeout`, so a web handler can respond in time even if the broker or store
+    /// is slow. The session is consumed as in `Client::verify`, so an expired deadline may still
+    /// leave it consumed, in which case the login has to be restarted.
+    #[cfg(feature = "tokio")]
+    pub async fn verify_with_deadline(
+        &self,
+        token: &str,
+        deadline: Duration,
+    ) -> Result<String, VerifyError> {
+        tokio::time::timeout(deadline, self.verify(token))
+            .await
+            .unwrap_or(Err(VerifyError::DeadlineExceeded(deadline)))
+    }
+
+    /// Like `Client::verify_full`, but check that the login completes in the same user agent that
+    /// started it. See `Builder::session_binding`.
+    pub async fn verify_with_info(
+        &self,
+        token: &str,
+        info: &ClientInfo,
+    ) -> Result<VerifiedToken, VerifyError> {
+        self.verify_with_check(token, info, |_| Ok(())).await
+    }
+
+    /// Like `Client::verify_with_info`, but apply `check` to the verified token before consuming
+    /// its login session.
+    async fn verify_with_check(
+        &self,
+        token: &str,
+        info: &ClientInfo,
+        check: SessionCheck,
+    ) -> Result<VerifiedToken, VerifyError> {
+        let mut session_id = None;
+        let result = self
+            .verify_checked(token, info, check, &mut session_id)
+            .await;
+        let step = match result {
+            Ok(_) => LoginStep::Verified,
+            Err(ref err) => LoginStep::Failed(err.code()),
+        };
+        self.emit_for_token(&[step], token, session_id).await;
+        result
+    }
+
+    /// Verify `token`, apply `check`, consume its login session, and apply the `LoginHook`,
+    /// without reporting login events.
+    ///
+    /// `session_id` is set to the correlation ID of the session once it is consumed, so that it
+    /// can be reported even if verification fails afterwards.
+    async fn verify_checked(
+        &self,
+        token: &str,
+        info: &ClientInfo,
+        check: SessionCheck,
+        session_id: &mut Option<String>,
+    ) -> Result<VerifiedToken, VerifyError> {
+        let result = self
+            .verify_and_consume(token, info, check, session_id)
+            .await;
+        self.check_login(token, info, result).await
+    }
+
+    /// Verify `token` and consume its login session, for `Client::verify_checked`.
+    async fn verify_and_consume(
+        &self,
+        token: &str,
+        info: &ClientInfo,
+        check: SessionCheck,
+        session_id: &mut Option<String>,
+    ) -> Result<VerifiedToken, VerifyError> {
+        let mut token = self.verify_claims(token).await?;
+        check(&token)?;
+
+        // Check the pair (nonce, email_original) exists in the store.
+        let session = self
+            .consume_session(
+                token.claims.nonce.clone(),
+                token.email_original().to_owned(),
+            )
+            .await?;
+        let id = session
+            .correlation_id
+            .clone()
+            .unwrap_or_else(|| correlation_id(&token.claims.nonce));
+        *session_id = Some(id.clone());
+        self.check_session(&session, info)?;
+        token.session_data = session.data;
+        token.correlation_id = Some(id);
+
+        Ok(token)
+    }
+
+    /// Validate `token` for debugging, and report all problems found.
+    ///
+    /// This performs the same signature and claim checks as `Client::verify`, but doesn't stop at
+    /// the first problem, and doesn't check or consume the login session. Support tooling can use
+    /// this to see what's inside a token a user reports as failing. The token is not accepted as a
+    /// login, so the `LoginHook` and login events are not involved.
+    ///
+    /// If the broker documents can't be loaded, the claims are decoded without verifying the
+    /// signature, and the error is the first finding.
+    pub async fn inspect(&self, token: &str) -> Inspection {
+        let endpoint = match self.inner.endpoints.for_token(token) {
+            Ok(endpoint) => endpoint,
+            Err(err) => return Inspection::unverified(token, err),
+        };
+        match self.load_validator(endpoint).await {
+            Ok((validator, _, keys)) => validator.inspect(token, &keys),
+            Err(err) => endpoint.validator.inspect_unverified(token, err),
+        }
+    }
+
+    /// Verify `token`, but don't consume the login session yet.
+    ///
+    /// This is a two-phase alternative to `Client::verify`, for applications that need to do
+    /// additional work before completing the login, such as creating an application session. The
+    /// login only completes when `PendingLogin::commit` succeeds, and may fail at that point if the
+    /// token was used concurrently. Until then, the user's login is not burned if the application
+    /// encounters an error, and it can use `PendingLogin::rollback` instead.
+    ///
+    /// This requires a store that implements `Store::peek_nonce`. To use session binding, use
+    /// `Client::verify_pending_with_info` instead.
+    pub async fn verify_pending(&self, token: &str) -> Result<PendingLogin<'_, S>, VerifyError> {
+        self.verify_pending_with_info(token, ClientInfo::default())
+            .await
+    }
+
+    /// Like `Client::verify_pending`, but check that the login completes in the same user agent
+    /// that started it. See `Builder::session_binding`.
+    ///
+    /// The session binding is checked both now and when committing. With session binding
+    /// enabled, this requires a store that implements `Store::peek_nonce_with_data`.
+    pub async fn verify_pending_with_info(
+        &self,
+        token: &str,
+        info: ClientInfo,
+    ) -> Result<PendingLogin<'_, S>, VerifyError> {
+        let mut session_id = None;
+        let result = self.verify_and_peek(token, &info, &mut session_id).await;
+        let result = self.check_login(token, &info, result).await;
+        if let Err(ref err) = result {
+            self.emit_for_token(&[LoginStep::Failed(err.code())], token, session_id)
+                .await;
+        }
+        let token = result?;
+        Ok(PendingLogin {
+            client: self,
+            token,
+            client_info: info,
+        })
+    }
+
+    /// Verify `token` and check its login session exists, for `Client::verify_pending_with_info`.
+    ///
+    /// `session_id` is set to the correlation ID of the session if it is read, as for
+    /// `Client::verify_checked`.
+    async fn verify_and_peek(
+        &self,
+        token: &str,
+        info: &ClientInfo,
+        session_id: &mut Option<String>,
+    ) -> Result<VerifiedToken, VerifyError> {
+        let token = self.verify_claims(token).await?;
+
+        let nonce = token.claims.nonce.clone();
+        let email_original = token.email_original().to_owned();
+        if self.inner.session_binding == SessionBinding::Off {
+            if !self
+                .store_op(|store| async move { store.peek_nonce(nonce, email_original).await })
+                .await
+                .ok_or(VerifyError::StoreTimeout)?
+                .map_err(VerifyError::VerifySession)?
+            {
+                return Err(VerifyError::InvalidSession);
+            }
+            return Ok(token);
+        }
+
+        // Check the binding now, so a mismatch doesn't surface only after the application did its
+        // work, and committing consumed the session.
+        let data = self
+            .store_op(
+                |store| async move { store.peek_nonce_with_data(nonce, email_original).await },
+            )
+            .await
+            .ok_or(VerifyError::StoreTimeout)?
+            .map_err(VerifyError::VerifySession)?
+            .ok_or(VerifyError::InvalidSession)?;
+        let envelope = SessionEnvelope::decode(data).ok_or(VerifyError::InvalidSession)?;
+        *session_id = envelope.correlation_id.clone();
+        self.check_session(&envelope, info)?;
+        Ok(token)
+    }
+
+    /// The issuer of the endpoint that handles `token`, without verifying the token.
+    fn token_broker(&self, token: &str) -> Option<&str> {
+        self.inner
+            .endpoints
+            .for_token(token)
+            .ok()
+            .map(|endpoint| endpoint.validator.issuer())
+    }
+
+    /// Whether login events are reported anywhere.
+    fn has_event_listeners(&self) -> bool {
+        #[cfg(feature = "tokio")]
+        if self.inner.events.receiver_count() > 0 {
+            return true;
+        }
+        self.inner.on_login_event.is_some()
+    }
+
+    /// Report `step` to the `Builder::on_login_event` callback, if configured, and to subscribers
+    /// of `Client::events`.
+    fn emit(&self, step: LoginStep, correlation_id: Option<&str>, broker: Option<&str>) {
+        let event = LoginEvent {
+            step,
+            correlation_id,
+            broker,
+        };
+        if let Some(ref on_login_event) = self.inner.on_login_event {
+            on_login_event(&event);
+        }
+        #[cfg(feature = "tokio")]
+        if self.inner.events.receiver_count() > 0 {
+            // Sending only fails if all subscribers were dropped in the meantime.
+            let _ = self
+                .inner
+                .events
+                .send(event.to_owned_at(self.inner.clock.now()));
+        }
+    }
+
+    /// Like `Client::emit`, for each of `steps`, but read the broker from `token`, without
+    /// verifying it.
+    ///
+    /// `session_id` is the correlation ID of the login session, if it was read. If not, because
+    /// verification failed before that, it is read from the session without consuming it. See
+    /// `Client::session_correlation_id`.
+    async fn emit_for_token(&self, steps: &[LoginStep], token: &str, session_id: Option<String>) {
+        if !self.has_event_listeners() {
+            return;
+        }
+        let session_id = match session_id {
+            Some(session_id) => Some(session_id),
+            // Don't wait for the store again if it just failed.
+            None if steps.contains(&LoginStep::Failed(ErrorCode::StoreUnavailable)) => {
+                token_session(token).map(|(nonce, _)| correlation_id(&nonce))
+            }
+            None => self.session_correlation_id(token).await,
+        };
+        let broker = self.token_broker(token);
+        for step in steps {
+            self.emit(*step, session_id.as_deref(), broker);
+        }
+    }
+
+    /// The correlation ID of the login session of `token`, without verifying the token or
+    /// consuming the session.
+    ///
+    /// This is read from the session if the store implements `Store::peek_nonce_with_data`.
+    /// Otherwise, or if the session is not found, it is derived from the nonce in the token.
+    async fn session_correlation_id(&self, token: &str) -> Option<String> {
+        let (nonce, email) = token_session(token)?;
+        let derived = correlation_id(&nonce);
+        let data = self
+            .store_op(|store| async move { store.peek_nonce_with_data(nonce, email).await })
+            .await;
+        let stored = match data {
+            Some(Ok(Some(data))) => {
+                SessionEnvelope::decode(data).and_then(|envelope| envelope.correlation_id)
+            }
+            _ => None,
+        };
+        Some(stored.unwrap_or(derived))
+    }
+
+    /// Report the verification `result` of `token` to the `LoginHook`, if configured, and apply
+    /// its verdict. A failed verification is then recorded by the `FailureSink`, if configured.
+    async fn check_login(
+        &self,
+        token: &str,
+        info: &ClientInfo,
+        result: Result<VerifiedToken, VerifyError>,
+    ) -> Result<VerifiedToken, VerifyError> {
+        let result = match self.inner.login_hook {
+            Some(ref login_hook) => {
+                let header = token_header(token);
+                let verdict = login_hook
+                    .check_login(&LoginAttempt {
+                        email: result.as_ref().ok().map(VerifiedToken::email),
+                        broker: self.token_broker(token),
+                        kid: header.as_ref().and_then(|header| header.kid.as_deref()),
+                        client_info: info,
+                        outcome: result.as_ref(),
+                    })
+                    .await;
+                match (result, verdict) {
+                    (Ok(_), Err(err)) => Err(VerifyError::LoginRejected(err)),
+                    (result, _) => result,
+                }
+            }
+            None => result,
+        };
+        if let Err(ref err) = result {
+            self.record_failure(token, err).await;
+        }
+        result
+    }
+
+    /// Record the failed verification of `token` with the `FailureSink`, if configured.
+    async fn record_failure(&self, token: &str, err: &VerifyError) {
+        let Some(ref sink) = self.inner.failure_sink else {
+            return;
+        };
+        let time = clock::unix_now(&*self.inner.clock).unwrap_or(0);
+        match sink.record(&VerifyFailure::new(token, err, time)).await {
+            Ok(()) => {}
+            // Ignore errors, so an outage of the sink doesn't prevent logins.
+            #[cfg(feature = "tracing")]
+            Err(err) => tracing::warn!(error = %err, "failure sink failed"),
+            #[cfg(not(feature = "tracing"))]
+            Err(_) => {}
+        }
+    }
+
+    /// Check a single endpoint for `Client::check`.
+    async fn check_endpoint(&self, endpoint: &Endpoint) -> Result<CheckReport, CheckError> {
+        let discovery = self
+            .fetch(
+                endpoint,
+                FetchPurpose::Discovery,
+                endpoint.discovery_url.clone(),
+            )
+            .await
+            .map_err(CheckError::FetchDiscovery)?;
+        let discovery: DiscoveryDoc =
+            serde_json::from_slice(&discovery).map_err(CheckError::ParseDiscovery)?;
+        let base = self.discovery_base(endpoint);
+        let authorization_endpoint =
+            DiscoveryDoc::parse_url(&discovery.authorization_endpoint, base)
+                .map_err(CheckError::InvalidDiscoveryUrl)?;
+        let jwks_uri = DiscoveryDoc::parse_url(&discovery.jwks_uri, base)
+            .map_err(CheckError::InvalidDiscoveryUrl)?;
+        self.check_discovery(endpoint, &discovery, &authorization_endpoint)
+            .and_then(|_| self.check_response_mode(&discovery))
+            .map_err(CheckError::InvalidDiscovery)?;
+        let issuer = self
+            .check_discovery(endpoint, &discovery, &jwks_uri)
+            .map_err(CheckError::InvalidDiscovery)?
+            .to_owned();
+
+        let jwks = self
+            .fetch(endpoint, FetchPurpose::Keys, jwks_uri.clone())
+            .await
+            .map_err(CheckError::FetchJwks)?;
+        let keys: jwk::KeySet = serde_json::from_slice(&jwks).map_err(CheckError::ParseJwks)?;
+        self.verify_keys(endpoint, &jwks, &keys)
+            .await
+            .map_err(CheckError::KeysRejected)?;
+        self.observe_keys(endpoint, &jwks, &keys);
+        self.check_key_continuity(endpoint, &jwks, &keys)
+            .await
+            .map_err(CheckError::KeyContinuity)?;
+        let mut algorithms = Vec::new();
+        for alg in keys.keys.iter().filter_map(jwk::Key::supported_alg) {
+            if !algorithms.contains(&alg) {
+                algorithms.push(alg);
+            }
+        }
+
+        let origin = endpoint.validator.issuer().to_owned();
+        if algorithms.is_empty() {
+            return Err(CheckError::NoSupportedKeys(origin));
+        }
+        Ok(CheckReport {
+            origin,
+            issuer,
+            authorization_endpoint,
+            jwks_uri,
+            key_count: keys.keys.len(),
+            algorithms,
+        })
+    }
+
+    /// Verify the token signature and claims, without checking the session.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "verify",
+            level = "debug",
+            skip_all,
+            fields(
+                broker = tracing::field::Empty,
+                kid = tracing::field::Empty,
+                alg = tracing::field::Empty,
+            ),
+            err(level = "debug")
+        )
+    )]
+    async fn verify_claims(&self, token: &str) -> Result<VerifiedToken, VerifyError> {
+        if token.len() > self.inner.max_token_len {
+            return Err(VerifyError::TokenTooLong {
+                max: self.inner.max_token_len,
+            });
+        }
+        // The unverified header is only used for tracing.
+        #[cfg(feature = "tracing")]
+        if let Some(ref header) = token_header(token) {
+            let span = tracing::Span::current();
+            span.record("alg", header.alg.as_str());
+            if let Some(ref kid) = header.kid {
+                span.record("kid", kid.as_str());
+            }
+        }
+        let endpoint = self.inner.endpoints.for_token(token)?;
+        record_span!("broker", endpoint.validator.issuer());
+        let (validator, jwks_uri, keys) = self.load_validator(endpoint).await?;
+
+        // Basic token signature verification, parsing, and claim validation.
+        match self.validate(&validator, token, keys).await {
+            // The broker may have rotated its keys since the JWKs document was cached.
+            Err(VerifyError::Signature(jws::VerifyError::KidNotMatched { kid }))
+                if self.inner.pinned_jwks.is_some() =>
+            {
+                Err(VerifyError::KeyNotPinned(kid))
+            }
+            Err(err @ VerifyError::Signature(jws::VerifyError::KidNotMatched { .. })) => {
+                if !self.try_refetch_keys(endpoint).await {
+                    return Err(err);
+                }
+                #[cfg(feature = "tracing")]
+                tracing::debug!("token key not found, refetching keys");
+                match self.load_keys(endpoint, jwks_uri, true).await {
+                    Ok(keys) => self.validate(&validator, token, keys).await,
+                    // Report the original error if the store doesn't support refetching.
+                    Err(VerifyError::FetchJwks(_)) => Err(err),
+                    Err(refetch_err) => Err(refetch_err),
+                }
+            }
+            result => result,
+        }
+    }
+
+    /// Fetch the documents of `endpoint`, and return the validator to use for its tokens, together
+    /// with the keys and where they were fetched from.
+    async fn load_validator<'e>(
+        &self,
+        endpoint: &'e Endpoint,
+    ) -> Result<(Cow<'e, Validator>, Url, jwk::KeySet), VerifyError> {
+        let discovery = self
+            .fetch(
+                endpoint,
+                FetchPurpose::Discovery,
+                endpoint.discovery_url.clone(),
+            )
+            .await
+            .map_err(VerifyError::FetchDiscovery)?;
+        let discovery: DiscoveryDoc =
+            serde_json::from_slice(&discovery).map_err(VerifyError::ParseDiscovery)?;
+        let jwks_uri = DiscoveryDoc::parse_url(&discovery.jwks_uri, self.discovery_base(endpoint))
+            .map_err(VerifyError::InvalidDiscoveryUrl)?;
+        let issuer = self
+            .check_discovery(endpoint, &discovery, &jwks_uri)
+            .map_err(VerifyError::InvalidDiscovery)?;
+
+        let keys = self.load_keys(endpoint, jwks_uri.clone(), false).await?;
+
+        let validator = if issuer == endpoint.validator.issuer() {
+            Cow::Borrowed(&endpoint.validator)
+        } else {
+            Cow::Owned(endpoint.validator.clone().with_issuer(issuer))
+        };
+        Ok((validator, jwks_uri, keys))
+    }
+
+    /// Fetch and parse the JWKs document of `endpoint`, and check the keys.
+    ///
+    /// With `refetch`, the document is fetched bypassing the store cache.
+    async fn load_keys(
+        &self,
+        endpoint: &Endpoint,
+        jwks_uri: Url,
+        refetch: bool,
+    ) -> Result<jwk::KeySet, VerifyError> {
+        let jwks = if refetch {
+            self.refetch(endpoint, FetchPurpose::Keys, jwks_uri).await
+        } else {
+            self.fetch(endpoint, FetchPurpose::Keys, jwks_uri).await
+        }
+        .map_err(VerifyError::FetchJwks)?;
+        let keys: jwk::KeySet = serde_json::from_slice(&jwks).map_err(VerifyError::ParseJwks)?;
+        self.verify_keys(endpoint, &jwks, &keys)
+            .await
+            .map_err(VerifyError::KeysRejected)?;
+        self.observe_keys(endpoint, &jwks, &keys);
+        self.check_key_continuity(endpoint, &jwks, &keys)
+            .await
+            .map_err(VerifyError::KeyContinuity)?;
+        Ok(keys)
+    }
+
+    /// Verify the signature and claims of `token` using `keys`.
+    async fn validate(
+        &self,
+        validator: &Validator,
+        token: &str,
+        keys: jwk::KeySet,
+    ) -> Result<VerifiedToken, VerifyError> {
+        #[cfg(feature = "tokio")]
+        if self.inner.offload_rsa
+            && token_header(token).is_some_and(|header| header.alg.starts_with("RS"))
+        {
+            if let Ok(handle) = tokio::runtime::Handle::try_current() {
+                let validator = validator.clone();
+                let token = token.to_owned();
+                let task = handle.spawn_blocking(move || validator.verify_full(&token, &keys));
+                return match task.await {
+                    Ok(result) => result,
+                    Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+                    Err(_) => Err(VerifyError::Cancelled),
+                };
+            }
+        }
+
+        validator.verify_full(token, &keys)
+    }
+
+    /// Record the keys in a fetched JWKs document, and report changes.
+    fn observe_keys(&self, endpoint: &Endpoint, jwks: &Bytes, key_set: &jwk::KeySet) {
+        let change = endpoint.observe_keys(jwks, key_set, self.inner.clock.now());
+        if let (Some(change), Some(on_key_change)) = (change, &self.inner.on_key_change) {
+            on_key_change(&change);
+        }
+    }
+
+    /// Verify the keys of `endpoint` using the `KeyVerifier`, if configured.
+    ///
+    /// The result is only remembered if the keys are accepted, so rejected keys are verified again
+    /// on the next attempt.
+    async fn verify_keys(
+        &self,
+        endpoint: &Endpoint,
+        jwks: &Bytes,
+        keys: &jwk::KeySet,
+    ) -> Result<(), DynErr> {
+        let key_verifier = match self.inner.key_verifier {
+            Some(ref key_verifier) => key_verifier,
+            None => return Ok(()),
+        };
+        if endpoint.is_verified_jwks(jwks) {
+            return Ok(());
+        }
+        key_verifier
+            .verify_keys(endpoint.validator.issuer(), keys)
+            .await?;
+        endpoint.set_verified_jwks(jwks.clone());
+        Ok(())
+    }
+
+    /// Check the keys of `endpoint` against the pinned keys, if enabled.
+    ///
+    /// See `Builder::key_continuity`. Only fails in `KeyContinuity::Enforce` mode.
+    async fn check_key_continuity(
+        &self,
+        endpoint: &Endpoint,
+        jwks: &Bytes,
+        keys: &jwk::KeySet,
+    ) -> Result<(), KeyContinuityError> {
+        if self.inner.key_continuity == KeyContinuity::Off {
+            return Ok(());
+        }
+        let now = self.inner.clock.now();
+        if endpoint.continuity_checked(jwks, now, self.inner.rotation_overlap / 2) {
+            return Ok(());
+        }
+
+        let result = self.update_key_pins(endpoint, keys, now).await;
+        // In warn mode, report a failure only once for each document.
+        if result.is_ok() || self.inner.key_continuity == KeyContinuity::Warn {
+            endpoint.set_continuity_checked(jwks.clone(), now);
+        }
+        match result {
+            Err(err) => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(error = %err, "key continuity check failed");
+                if let Some(ref on_key_continuity) = self.inner.on_key_continuity {
+                    on_key_continuity(&err);
+                }
+                match self.inner.key_continuity {
+                    KeyContinuity::Enforce => Err(err),
+                    _ => Ok(()),
+                }
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// Load the pins for `endpoint`, update them with `keys`, and save them.
+    ///
+    /// In `KeyContinuity::Enforce` mode, pins are not saved if the key set is disjoint.
+    async fn update_key_pins(
+        &self,
+        endpoint: &Endpoint,
+        keys: &jwk::KeySet,
+        now: SystemTime,
+    ) -> Result<(), KeyContinuityError> {
+        let origin = endpoint.validator.issuer().to_owned();
+        let found: Vec<String> = keys.keys.iter().filter_map(jwk::Key::thumbprint).collect();
+        let mut pins: KeyPins = match self
+            .key_pins_op({
+                let origin = origin.clone();
+                |store| async move { store.load_key_pins(origin).await }
+            })
+            .await?
+        {
+            Some(pins) => serde_json::from_str(&pins).map_err(KeyContinuityError::Parse)?,
+            None => KeyPins::default(),
+        };
+
+        let now = match now.duration_since(UNIX_EPOCH) {
+            Ok(elapsed) if self.inner.clock.is_reliable(now) => elapsed.as_secs(),
+            _ => return Err(KeyContinuityError::ClockUnreliable),
+        };
+        let disjoint = pins.update(&found, now, self.inner.rotation_overlap);
+        if disjoint.is_none() || self.inner.key_continuity != KeyContinuity::Enforce {
+            let pins = serde_json::to_string(&pins).map_err(KeyContinuityError::Serialize)?;
+            let origin = origin.clone();
+            self.key_pins_op(|store| async move { store.save_key_pins(origin, pins).await })
+                .await?;
+        }
+
+        match disjoint {
+            Some(pinned) => Err(KeyContinuityError::Disjoint {
+                origin,
+                pinned,
+                found,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Run a key pins operation on the session store, using `Client::store_op`.
+    async fn key_pins_op<T, F, Fut>(&self, op: F) -> Result<T, KeyContinuityError>
+    where
+        T: Send + 'static,
+        F: FnOnce(S) -> Fut,
+        Fut: Future<Output = DynRes<T>> + Send + 'static,
+    {
+        match self.store_op(op).await {
+            Some(result) => result.map_err(KeyContinuityError::Store),
+            None => Err(KeyContinuityError::Store(
+                "the store did not respond in time".into(),
+            )),
+        }
+    }
+
+    /// Consult the `RateLimiter`, if any, for `email` and the caller-supplied `key`.
+    async fn check_rate_limit(&self, email: &str, key: Option<&str>) -> Result<(), StartAuthError> {
+        let Some(ref limiter) = self.inner.rate_limiter else {
+            return Ok(());
+        };
+        let email = normalize_email(email).unwrap_or_else(|_| email.to_lowercase());
+        let checks = std::iter::once((RateLimitScope::Email, email.as_str()))
+            .chain(key.map(|key| (RateLimitScope::Caller, key)));
+        for (scope, key) in checks {
+            match limiter.check(scope, key).await {
+                Ok(RateLimitDecision::Allow) => {}
+                Ok(RateLimitDecision::Deny { retry_after }) => {
+                    return Err(StartAuthError::RateLimited { scope, retry_after });
+                }
+                // Fail open, so an outage of the limiter doesn't prevent logins.
+                #[cfg(feature = "tracing")]
+                Err(err) => tracing::warn!(error = %err, "rate limiter failed"),
+                #[cfg(not(feature = "tracing"))]
+                Err(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a session for `email`, and return the nonce.
+    ///
+    /// With `generated_id`, the correlation ID in `envelope` is dropped if the store doesn't
+    /// support session data, and it is all there is to keep.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "new_nonce", level = "debug", skip_all, err(level = "debug"))
+    )]
+    async fn new_session(
+        &self,
+        email: &str,
+        envelope: &SessionEnvelope,
+        generated_id: bool,
+    ) -> Result<String, StartAuthError> {
+        let email = email.to_owned();
+        let ttl = self.inner.session_ttl;
+        let data = (!envelope.is_empty()).then(|| envelope.encode());
+        let optional = generated_id
+            && envelope.binding.is_none()
+            && envelope.redirect_uri.is_none()
+            && envelope.data.is_empty();
+        self.store_op(|store| async move {
+            match data {
+                Some(data) => match store.new_nonce_with_data(email.clone(), data, ttl).await {
+                    Err(err) if optional && err.is::<Unsupported>() => {
+                        store.new_nonce_with_ttl(email, ttl).await
+                    }
+                    result => result,
+                },
+                None => store.new_nonce_with_ttl(email, ttl).await,
+            }
+        })
+        .await
+        .ok_or(StartAuthError::StoreTimeout)?
+        .map_err(StartAuthError::GenerateNonce)
+    }
+
+    /// Consume the session for the pair (nonce, email_original), and return its contents, to be
+    /// checked using `Client::check_session`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "consume_nonce",
+            level = "debug",
+            skip_all,
+            fields(correlation_id = tracing::field::Empty),
+            err(level = "debug")
+        )
+    )]
+    async fn consume_session(
+        &self,
+        nonce: String,
+        email: String,
+    ) -> Result<SessionEnvelope, VerifyError> {
+        #[cfg(feature = "tracing")]
+        let default_correlation_id = correlation_id(&nonce);
+        let data = self
+            .store_op(|store| async move { store.consume_nonce_with_data(nonce, email).await })
+            .await
+            .ok_or(VerifyError::StoreTimeout)?
+            .map_err(VerifyError::VerifySession)?
+            .ok_or(VerifyError::InvalidSession)?;
+        let envelope = SessionEnvelope::decode(data).ok_or(VerifyError::InvalidSession)?;
+        record_span!(
+            "correlation_id",
+            envelope
+                .correlation_id
+                .as_deref()
+                .unwrap_or(&default_correlation_id)
+        );
+        Ok(envelope)
+    }
+
+    /// Check the session binding and redirect URI of a login session against `info`.
+    fn check_session(
+        &self,
+        envelope: &SessionEnvelope,
+        info: &ClientInfo,
+    ) -> Result<(), VerifyError> {
+        if let Some(expected) = self.inner.session_binding.digest(info) {
+            let matches = envelope.binding.is_some_and(|binding| {
+                constant_time::verify_slices_are_equal(&binding, &expected).is_ok()
+            });
+            if !matches {
+                return Err(VerifyError::SessionBindingMismatch);
+            }
+        }
+        if let Some(arrived_on) = info.arrived_on() {
+            let arrived_on = self.inner.uri_canonicalization.apply(arrived_on);
+            let expected = envelope
+                .redirect_uri
+                .as_deref()
+                .unwrap_or(self.inner.redirect_uri.as_str());
+            if arrived_on.as_str() != expected {
+                return Err(VerifyError::RedirectUriMismatch);
+            }
+        }
+        Ok(())
+    }
+
+    /// The base URL for relative URLs in the discovery document of `endpoint`, if allowed.
+    fn discovery_base<'a>(&self, endpoint: &'a Endpoint) -> Option<&'a Url> {
+        if self.inner.relative_discovery_urls {
+            Some(&endpoint.discovery_url)
+        } else {
+            None
+        }
+    }
+
+    /// Build the authorization URL from a discovery document, with the parameters that are the
+    /// same for every login.
+    fn auth_url_prefix(
+        &self,
+        endpoint: &Endpoint,
+        discovery: &[u8],
+    ) -> Result<Url, StartAuthError> {
+        let discovery: DiscoveryDoc =
+            serde_json::from_slice(discovery).map_err(StartAuthError::ParseDiscovery)?;
+        let mut auth_url = DiscoveryDoc::parse_url(
+            &discovery.authorization_endpoint,
+            self.discovery_base(endpoint),
+        )
+        .map_err(StartAuthError::InvalidDiscoveryUrl)?;
+        self.check_discovery(endpoint, &discovery, &auth_url)
+            .and_then(|_| self.check_response_mode(&discovery))
+            .map_err(StartAuthError::InvalidDiscovery)?;
+        auth_url
+            .query_pairs_mut()
+            .append_pair("scope", "openid email")
+            .append_pair("response_type", "id_token")
+            .append_pair("response_mode", self.inner.response_mode.as_str())
+            .append_pair("client_id", &self.inner.client_id)
+            .append_pair("redirect_uri", self.inner.redirect_uri.as_str());
+        Ok(auth_url)
+    }
+
+    /// Check that the broker supports the configured `Builder::response_mode`.
+    fn check_response_mode(&self, discovery: &DiscoveryDoc) -> Result<(), DiscoveryError> {
+        if discovery.supports_response_mode(self.inner.response_mode) {
+            Ok(())
+        } else {
+            Err(DiscoveryError::UnsupportedResponseMode(
+                self.inner.response_mode,
+            ))
+        }
+    }
+
+    /// Check a discovery document and the endpoint `url` from it.
+    ///
+    /// Returns the issuer to expect in tokens, according to `Builder::issuer_check`.
+    fn check_discovery<'a>(
+        &self,
+        endpoint: &'a Endpoint,
+        discovery: &'a DiscoveryDoc,
+        url: &Url,
+    ) -> Result<&'a str, DiscoveryError> {
+        let issuer = self.discovery_issuer(endpoint, discovery)?;
+
+        if self.inner.strict_discovery
+            && url.scheme() != "https"
+            && endpoint.discovery_url.scheme() == "https"
+        {
+            return Err(DiscoveryError::InsecureEndpoint(url.clone()));
+        }
+        if self.inner.same_origin_endpoints && url.origin() != endpoint.discovery_url.origin() {
+            return Err(DiscoveryError::CrossOriginEndpoint(url.clone()));
+        }
+
+        Ok(issuer)
+    }
+
+    /// Verify the `issuer` of a discovery document, and return the issuer to expect in tokens.
+    fn discovery_issuer<'a>(
+        &self,
+        endpoint: &'a Endpoint,
+        discovery: &'a DiscoveryDoc,
+    ) -> Result<&'a str, DiscoveryError> {
+        let configured = endpoint.validator.issuer();
+        let require = match self.inner.issuer_check {
+            IssuerCheck::Ignore => return Ok(configured),
+            IssuerCheck::Verify => self.inner.strict_discovery,
+            IssuerCheck::Require => true,
+        };
+
+        let found = match discovery.issuer.as_deref() {
+            Some(found) => found,
+            None if require => return Err(DiscoveryError::MissingIssuer),
+            None => return Ok(configured),
+        };
+        if found.strip_suffix('/').unwrap_or(found) != configured {
+            return Err(DiscoveryError::IssuerMismatch {
+                expected: configured.to_owned(),
+                found: found.to_owned(),
+            });
+        }
+
+        Ok(found)
+    }
+
+    /// Fetch a document of `endpoint` using the store, falling back to a direct fetch if
+    /// configured.
+    ///
+    /// The outcome is recorded for `Client::availability`. Errors are annotated with the URL and
+    /// purpose of the document.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(purpose = %purpose, url = %url),
+            err(level = "debug")
+        )
+    )]
+    async fn fetch(
+        &self,
+        endpoint: &Endpoint,
+        purpose: FetchPurpose,
+        url: Url,
+    ) -> Result<Bytes, FetchError> {
+        if let Some(document) = self.pinned(purpose) {
+            return Ok(document);
+        }
+        let result = self.fetch_inner(url.clone()).await;
+        let loaded = self.load_endpoint_state(endpoint).await;
+        endpoint.record_fetch(&result, self.inner.clock.now());
+        self.save_endpoint_state(endpoint, loaded).await;
+        result.map_err(|err| FetchError::Context {
+            purpose,
+            url,
+            source: Box::new(err),
+        })
+    }
+
+    /// Like `Client::fetch`, but bypass the store cache.
+    ///
+    /// This is a best-effort retry, so failures are not recorded in the endpoint health.
+    async fn refetch(
+        &self,
+        endpoint: &Endpoint,
+        purpose: FetchPurpose,
+        url: Url,
+    ) -> Result<Bytes, FetchError> {
+        if let Some(document) = self.pinned(purpose) {
+            return Ok(document);
+        }
+        let result = self.store.refetch(url.clone()).await;
+        if result.is_ok() {
+            let loaded = self.load_endpoint_state(endpoint).await;
+            endpoint.record_fetch(&result, self.inner.clock.now());
+            self.save_endpoint_state(endpoint, loaded).await;
+        }
+        result.map_err(|err| FetchError::Context {
+            purpose,
+            url,
+            source: Box::new(err),
+        })
+    }
+
+    /// The document configured using `Builder::pinned_discovery` or `Builder::pinned_jwks`.
+    fn pinned(&self, purpose: FetchPurpose) -> Option<Bytes> {
+        match purpose {
+            FetchPurpose::Discovery => self.inner.pinned_discovery.clone(),
+            FetchPurpose::Keys => self.inner.pinned_jwks.clone(),
+        }
+    }
+
+    /// Whether the JWKs document of `endpoint` may be refetched now. Records the attempt if so.
+    ///
+    /// See `Endpoint::try_refetch_keys`. With `Builder::shared_endpoint_state`, the limit applies
+    /// to all workers sharing the store.
+    async fn try_refetch_keys(&self, endpoint: &Endpoint) -> bool {
+        let loaded = self.load_endpoint_state(endpoint).await;
+        let allowed = endpoint.try_refetch_keys(self.inner.clock.now(), KEYS_REFETCH_INTERVAL);
+        self.save_endpoint_state(endpoint, loaded).await;
+        allowed
+    }
+
+    /// Load the state of `endpoint` saved by other workers, and merge it into the local state.
+    ///
+    /// Returns the merged state, or `None` if `Builder::shared_endpoint_state` is disabled or the
+    /// store failed.
+    async fn load_endpoint_state(&self, endpoint: &Endpoint) -> Option<SharedState> {
+        if !self.inner.shared_endpoint_state {
+            return None;
+        }
+        let origin = endpoint.validator.issuer().to_owned();
+        let result = self
+            .store_op(|store| async move { store.load_endpoint_state(origin).await })
+            .await;
+        match result {
+            Some(Ok(Some(state))) => match serde_json::from_str(&state) {
+                Ok(state) => endpoint.merge_shared_state(&state),
+                Err(_err) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::debug!(error = %_err, "could not parse shared endpoint state");
+                }
+            },
+            Some(Ok(None)) => {}
+            Some(Err(_err)) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(error = %_err, "could not load shared endpoint state");
+                return None;
+            }
+            None => return None,
+        }
+        Some(endpoint.shared_state())
+    }
+
+    /// Save the state of `endpoint` for other workers, if it changed since it was `loaded`.
+    async fn save_endpoint_state(&self, endpoint: &Endpoint, loaded: Option<SharedState>) {
+        let Some(loaded) = loaded else {
+            return;
+        };
+        let state = endpoint.shared_state();
+        if state == loaded {
+            return;
+        }
+        let origin = endpoint.validator.issuer().to_owned();
+        let ttl = state.ttl(self.inner.clock.now(), KEYS_REFETCH_INTERVAL);
+        let state = match serde_json::to_string(&state) {
+            Ok(state) => state,
+            Err(_err) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(error = %_err, "could not serialize shared endpoint state");
+                return;
+            }
+        };
+        let result = self
+            .store_op(|store| async move { store.save_endpoint_state(origin, state, ttl).await })
+            .await;
+        if let Some(Err(_err)) = result {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(error = %_err, "could not save shared endpoint state");
+        }
+    }
+
+    async fn fetch_inner(&self, url: Url) -> Result<Bytes, FetchError> {
+        #[cfg(feature = "memory-store")]
+        if let Some(ref fallback) = self.inner.fetch_fallback {
+            return match self.store.fetch(url.clone()).await {
+                Err(FetchError::Store(_)) => {
+                    let (result, _) = simple_fetch(&*fallback.client, fallback.timeout, url).await;
+                    result.map_err(|err| FetchError::Fetch(Arc::new(err)))
+                }
+                result => result,
+            };
+        }
+        self.store.fetch(url).await
+    }
+
+    /// Await a store session operation, applying the configured timeout.
+    ///
+    /// With the `tokio` feature, the operation is spawned as a task if a runtime is available, so
+    /// that it runs to completion even if the caller is cancelled or times out.
+    ///
+    /// Returns `None` if the operation timed out.
+    async fn store_op<T, F, Fut>(&self, op: F) -> Option<DynRes<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(S) -> Fut,
+        Fut: Future<Output = DynRes<T>> + Send + 'static,
+    {
+        let op = op(self.store.clone());
+        #[cfg(feature = "tokio")]
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let task = handle.spawn(op);
+            let op = async move {
+                task.await
+                    .unwrap_or_else(|err| Err(Box::new(err) as DynErr))
+            };
+            return self.with_store_timeout(op).await;
+        }
+        self.with_store_timeout(op).await
+    }
+
+    /// Await a store operation, applying the configured timeout.
+    async fn with_store_timeout<T>(
+        &self,
+        op: impl Future<Output = DynRes<T>>,
+    ) -> Option<DynRes<T>> {
+        #[cfg(feature = "tokio")]
+        if let Some(timeout) = self.inner.store_timeout {
+            return tokio::time::timeout(timeout, op).await.ok();
+        }
+        Some(op.await)
+    }
+}
+
+/// The default store type of a `Client`.
+///
+/// This holds the `Fetcher` and `SessionStore` configured using `Builder::store`,
+/// `Builder::fetcher` and `Builder::session_store` as trait objects, or the default `MemoryStore`.
+#[derive(Clone)]
+pub struct DynStore {
+    fetcher: Arc<dyn Fetcher>,
+    sessions: Arc<dyn SessionStore>,
+}
+
+impl Store for DynStore {
+    fn fetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        self.fetcher.fetch(url)
+    }
+
+    fn refetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        self.fetcher.refetch(url)
+    }
+
+    fn cache_lifetime(&self, url: Url) -> DynFutRes<Option<Duration>> {
+        self.fetcher.cache_lifetime(url)
+    }
+
+    fn new_nonce(&self, email: String) -> DynFutRes<String> {
+        self.sessions.new_nonce(email)
+    }
+
+    fn new_nonce_with_ttl(&self, email: String, ttl: Duration) -> DynFutRes<String> {
+        self.sessions.new_nonce_with_ttl(email, ttl)
+    }
+
+    fn consume_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        self.sessions.consume_nonce(nonce, email)
+    }
+
+    fn new_nonce_with_data(
+        &self,
+        email: String,
+        data: Vec<u8>,
+        ttl: Duration,
+    ) -> DynFutRes<String> {
+        self.sessions.new_nonce_with_data(email, data, ttl)
+    }
+
+    fn consume_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        self.sessions.consume_nonce_with_data(nonce, email)
+    }
+
+    fn insert_nonce(
+        &self,
+        nonce: String,
+        email: String,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> DynFutRes<()> {
+        self.sessions.insert_nonce(nonce, email, data, ttl)
+    }
+
+    fn peek_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        self.sessions.peek_nonce(nonce, email)
+    }
+
+    fn peek_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        self.sessions.peek_nonce_with_data(nonce, email)
+    }
+
+    fn load_key_pins(&self, origin: String) -> DynFutRes<Option<String>> {
+        self.sessions.load_key_pins(origin)
+    }
+
+    fn save_key_pins(&self, origin: String, pins: String) -> DynFutRes<()> {
+        self.sessions.save_key_pins(origin, pins)
+    }
+
+    fn load_endpoint_state(&self, origin: String) -> DynFutRes<Option<String>> {
+        self.sessions.load_endpoint_state(origin)
+    }
+
+    fn save_endpoint_state(&self, origin: String, state: String, ttl: Duration) -> DynFutRes<()> {
+        self.sessions.save_endpoint_state(origin, state, ttl)
+    }
+
+    fn record_failure(&self, record: String, retention: Duration) -> DynFutRes<()> {
+        self.sessions.record_failure(record, retention)
+    }
+
+    fn load_failures(&self) -> DynFutRes<Vec<String>> {
+        self.sessions.load_failures()
+    }
+}
+
+/// A verified login that has not been completed yet, returned by `Client::verify_pending`.
+#[must_use = "the login is only completed by `PendingLogin::commit`"]
+pub struct PendingLogin<'a, S = DynStore> {
+    client: &'a Client<S>,
+    token: VerifiedToken,
+    client_info: ClientInfo,
+}
+
+impl<S: AsyncStore + Clone> PendingLogin<'_, S> {
+    /// The verified email address.
+    pub fn email(&self) -> &str {
+        self.token.email_with_case(self.client.inner.email_case)
+    }
+
+    /// The verified token.
+    pub fn token(&self) -> &VerifiedToken {
+        &self.token
+    }
+
+    /// Check the session binding against `info` when committing, instead of the `ClientInfo`
+    /// given to `Client::verify_pending_with_info`. See `Builder::session_binding`.
+    pub fn client_info(mut self, info: ClientInfo) -> Self {
+        self.client_info = info;
+        self
+    }
+
+    /// Consume the login session, and return the verified email address.
+    ///
+    /// Fails with `VerifyError::InvalidSession` if the session was consumed in the meantime, in
+    /// which case the application must not complete the login.
+    pub async fn commit(self) -> Result<String, VerifyError> {
+        let email_case = self.client.inner.email_case;
+        let token = self.commit_full().await?;
+        Ok(token.email_with_case(email_case).to_owned())
+    }
+
+    /// Like `PendingLogin::commit`, but return the verified token, including its session data.
+    pub async fn commit_full(mut self) -> Result<VerifiedToken, VerifyError> {
+        let nonce = self.token.claims.nonce.clone();
+        let email_original = self.token.email_original().to_owned();
+        let result = self.client.consume_session(nonce, email_original).await;
+        if let Ok(ref session) = result {
+            self.token.correlation_id = Some(
+                session
+                    .correlation_id
+                    .clone()
+                    .unwrap_or_else(|| correlation_id(&self.token.claims.nonce)),
+            );
+        }
+        let result = result.and_then(|session| {
+            self.client.check_session(&session, &self.client_info)?;
+            Ok(session)
+        });
+        let step = match result {
+            Ok(_) => LoginStep::Verified,
+            Err(ref err) => LoginStep::Failed(err.code()),
+        };
+        if self.client.has_event_listeners() {
+            let correlation_id = self
+                .token
+                .correlation_id
+                .clone()
+                .unwrap_or_else(|| correlation_id(&self.token.claims.nonce));
+            self.client
+                .emit(step, Some(&correlation_id), Some(&self.token.claims.iss));
+        }
+        self.token.session_data = result?.data;
+        Ok(self.token)
+    }
+
+    /// Abandon the login, leaving the session in place so the token can be verified again.
+    pub fn rollback(self) {}
+}
+
+/// Whether the host of `url` is a loopback address, used during development.
+fn is_loopback(url: &Url) -> bool {
+    match url.host() {
+        Some(url::Host::Domain(domain)) => domain == "localhost",
+        Some(url::Host::Ipv4(addr)) => addr.is_loopback(),
+        Some(url::Host::Ipv6(addr)) => addr.is_loopback(),
+        None => false,
+    }
+}
+
+/// HTTP client configuration used by `Builder::fetch_fallback`.
+#[cfg(feature = "memory-store")]
+#[derive(Clone)]
+struct FetchFallback {
+    client: Arc<dyn HttpClient>,
+    timeout: Duration,
+}
@@ -0,0 +1,104 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// The earliest plausible wall-clock time, 2020-01-01T00:00:00Z.
+const PLAUSIBLE_SINCE: u64 = 1_577_836_800;
+
+/// The latest plausible wall-clock time, 2100-01-01T00:00:00Z.
+const PLAUSIBLE_UNTIL: u64 = 4_102_444_800;
+
+/// A source of the current time.
+///
+/// The clock is used by `Validator` to check token timestamps, and by `MemoryStore` to expire
+/// cached documents. Tests can substitute a `ManualClock` to control time deterministically.
+pub trait Clock: Send + Sync + 'static {
+    /// The current wall-clock time, used for token validation.
+    fn now(&self) -> SystemTime;
+
+    /// The current monotonic time, used for cache expiry.
+    fn instant(&self) -> Instant;
+
+    /// Whether `now`, as returned by `Clock::now`, can be trusted for token validation.
+    ///
+    /// If not, validation fails with `VerifyError::ClockUnreliable`, instead of with confusing
+    /// expiry errors. The default accepts times between the years 2020 and 2100, which catches
+    /// devices that boot with their clock reset to 1970. Clocks backed by a trusted time source
+    /// can override this.
+    fn is_reliable(&self, now: SystemTime) -> bool {
+        now.duration_since(UNIX_EPOCH)
+            .is_ok_and(|elapsed| (PLAUSIBLE_SINCE..PLAUSIBLE_UNTIL).contains(&elapsed.as_secs()))
+    }
+}
+
+/// The current time of `clock` as a Unix timestamp, or `None` if it is not reliable.
+pub(crate) fn unix_now(clock: &dyn Clock) -> Option<u64> {
+    let now = clock.now();
+    if !clock.is_reliable(now) {
+        return None;
+    }
+    now.duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|elapsed| elapsed.as_secs())
+}
+
+/// The default `Clock`, which uses the system clock.
+///
+/// When the `memory-store` feature is enabled, monotonic time is read from Tokio, so that it is
+/// affected by `tokio::time::pause` and `tokio::time::advance` in tests.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    #[cfg(feature = "memory-store")]
+    fn instant(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    #[cfg(not(feature = "memory-store"))]
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A `Clock` that only advances when told to, for use in tests.
+#[derive(Debug)]
+pub struct ManualClock {
+    state: Mutex<(SystemTime, Instant)>,
+}
+
+impl ManualClock {
+    /// Create a clock that is stopped at the given wall-clock time.
+    pub fn new(now: SystemTime) -> Self {
+        ManualClock {
+            state: Mutex::new((now, Instant::now())),
+        }
+    }
+
+    /// Move the clock forward by the given duration.
+    pub fn advance(&self, dur: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.0 += dur;
+        state.1 += dur;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        self.state.lock().unwrap().0
+    }
+
+    fn instant(&self) -> Instant {
+        self.state.lock().unwrap().1
+    }
+
+    /// Tests may set any time, so every time is accepted.
+    fn is_reliable(&self, _now: SystemTime) -> bool {
+        true
+    }
+}
@@ -0,0 +1,35 @@
+use thiserror::Error;
+use url::Host;
+
+/// Errors that can result from `normalize_email`.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum EmailError {
+    #[error("the email address does not contain an @")]
+    MissingAt,
+    #[error("the email address has an empty local part")]
+    EmptyLocalPart,
+    #[error("the email address does not have a valid domain")]
+    InvalidDomain,
+}
+
+/// Normalize an email address, the way Portier brokers do.
+///
+/// The local part is lowercased, and the domain is converted to lowercase ASCII using IDNA, so
+/// `Jane@Exämple.com` becomes `jane@xn--exmple-cua.com`. Addresses with an IP address as domain
+/// are rejected.
+///
+/// Applications can use this to identify users before a login completes, for example to look up
+/// an account, with the same result as `VerifiedToken::email` afterwards. Pass the address as
+/// entered by the user to `Client::start_auth`, so the broker can show it as typed.
+pub fn normalize_email(email: &str) -> Result<String, EmailError> {
+    let (local, domain) = email.rsplit_once('@').ok_or(EmailError::MissingAt)?;
+    if local.is_empty() {
+        return Err(EmailError::EmptyLocalPart);
+    }
+    let domain = match Host::parse(domain) {
+        Ok(Host::Domain(domain)) if !domain.is_empty() => domain,
+        _ => return Err(EmailError::InvalidDomain),
+    };
+    Ok(format!("{}@{}", local.to_lowercase(), domain))
+}
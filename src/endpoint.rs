@@ -0,0 +1,626 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use url::Url;
+
+use crate::{jwk, misc::base64url, FetchError, Validator, VerifyError};
+
+/// Result of probing a broker endpoint, returned by `Client::probe_endpoints`.
+#[derive(Clone, Debug)]
+pub struct EndpointProbe {
+    /// The origin of the endpoint.
+    pub origin: String,
+    /// The measured latency, or `None` if the endpoint is unhealthy.
+    pub latency: Option<Duration>,
+}
+
+/// A broker signing key, returned by `Client::keys`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct KeyInfo {
+    /// The origin of the endpoint that published the key.
+    pub origin: String,
+    /// The key ID.
+    pub kid: String,
+    /// The JWS algorithm, if the key is supported.
+    pub alg: Option<&'static str>,
+    /// The RFC 7638 thumbprint, if the key type is known. See `jwk::Key::thumbprint`.
+    pub thumbprint: Option<String>,
+    /// When the client first saw the key.
+    pub first_seen: SystemTime,
+}
+
+/// A change in the keys published by an endpoint, reported to `Builder::on_key_change`.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct KeySetChange {
+    /// The origin of the endpoint.
+    pub origin: String,
+    /// Keys that were not in the previous key set.
+    pub added: Vec<KeyInfo>,
+    /// Keys that are no longer in the key set.
+    pub removed: Vec<KeyInfo>,
+}
+
+/// The keys last seen for an endpoint.
+#[derive(Default)]
+struct KeyState {
+    /// The raw JWKs document the keys were read from.
+    jwks: Option<Bytes>,
+    keys: Vec<KeyInfo>,
+}
+
+/// Summary of recent broker health, returned by `Client::availability`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Availability {
+    /// No recent failures.
+    Available,
+    /// Recent requests to a broker failed, so logins may fail.
+    Degraded,
+    /// Requests to all brokers are failing.
+    ///
+    /// `retry_after` is the earliest time a broker asked to be retried, using `Retry-After`.
+    Unavailable { retry_after: Option<SystemTime> },
+}
+
+/// Number of consecutive failed fetches after which an endpoint is considered unavailable.
+const FAILURE_THRESHOLD: u32 = 3;
+
+/// How long an endpoint is considered unavailable after the last of consecutive failed fetches.
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Recent fetch outcomes of an endpoint.
+#[derive(Default)]
+struct FetchHealth {
+    consecutive_failures: u32,
+    last_failure: Option<SystemTime>,
+    /// The time until which the broker asked not to be retried.
+    retry_until: Option<SystemTime>,
+}
+
+impl FetchHealth {
+    fn availability(&self, now: SystemTime) -> Availability {
+        if let Some(retry_until) = self.retry_until {
+            if now < retry_until {
+                return Availability::Unavailable {
+                    retry_after: Some(retry_until),
+                };
+            }
+        }
+        let recent = self.last_failure.is_some_and(|at| {
+            now.duration_since(at)
+                .map_or(true, |elapsed| elapsed < FAILURE_WINDOW)
+        });
+        match self.consecutive_failures {
+            0 => Availability::Available,
+            failures if failures >= FAILURE_THRESHOLD && recent => {
+                Availability::Unavailable { retry_after: None }
+            }
+            _ => Availability::Degraded,
+        }
+    }
+}
+
+/// Endpoint state shared between workers, as persisted using `Store::save_endpoint_state`.
+///
+/// Times are Unix timestamps.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct SharedState {
+    consecutive_failures: u32,
+    last_failure: Option<u64>,
+    retry_until: Option<u64>,
+    keys_refetched: Option<u64>,
+}
+
+impl SharedState {
+    /// How long the state is relevant, given the interval between key refetches.
+    pub fn ttl(&self, now: SystemTime, refetch_interval: Duration) -> Duration {
+        let retry = self
+            .retry_until
+            .map(|until| until.saturating_sub(unix_secs(now)))
+            .map_or(Duration::ZERO, Duration::from_secs);
+        FAILURE_WINDOW.max(refetch_interval).max(retry)
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+fn from_unix_secs(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Key pins for a broker, as persisted using `Store::save_key_pins`.
+#[derive(Default, Deserialize, Serialize)]
+pub struct KeyPins {
+    keys: Vec<KeyPin>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct KeyPin {
+    thumbprint: String,
+    /// Unix timestamp at which the key was last seen.
+    last_seen: u64,
+}
+
+impl KeyPins {
+    /// Pin the `found` thumbprints, and drop pins that were not seen within `overlap`.
+    ///
+    /// Returns the previously pinned thumbprints if `found` shares none of them. Pins are updated
+    /// regardless, so the caller decides whether to save them.
+    pub fn update(&mut self, found: &[String], now: u64, overlap: Duration) -> Option<Vec<String>> {
+        let cutoff = now.saturating_sub(overlap.as_secs());
+        self.keys.retain(|pin| pin.last_seen >= cutoff);
+        let disjoint =
+            !self.keys.is_empty() && !self.keys.iter().any(|pin| found.contains(&pin.thumbprint));
+        let pinned = if disjoint {
+            Some(self.keys.iter().map(|pin| pin.thumbprint.clone()).collect())
+        } else {
+            None
+        };
+
+        for thumbprint in found {
+            match self
+                .keys
+                .iter_mut()
+                .find(|pin| &pin.thumbprint == thumbprint)
+            {
+                Some(pin) => pin.last_seen = now,
+                None => self.keys.push(KeyPin {
+                    thumbprint: thumbprint.clone(),
+                    last_seen: now,
+                }),
+            }
+        }
+        pinned
+    }
+}
+
+/// A broker origin the client can talk to.
+#[derive(Clone)]
+pub(crate) struct Endpoint {
+    pub discovery_url: Url,
+    pub validator: Validator,
+    /// Cache for `Endpoint::cached_auth_url`, shared between clones of the client.
+    pub auth_url: Arc<Mutex<Option<(Bytes, Url)>>>,
+    /// Keys recorded by `Endpoint::observe_keys`, shared between clones of the client.
+    keys: Arc<Mutex<KeyState>>,
+    /// Recent fetch outcomes recorded by `Endpoint::record_fetch`.
+    fetch_health: Arc<Mutex<FetchHealth>>,
+    /// The JWKs document last accepted by the `KeyVerifier`.
+    verified_jwks: Arc<Mutex<Option<Bytes>>>,
+    /// The JWKs document last checked by `Client::check_key_continuity`, and when.
+    continuity: Arc<Mutex<Option<(Bytes, SystemTime)>>>,
+    /// When the JWKs document was last refetched by `Endpoint::try_refetch_keys`.
+    keys_refetched: Arc<Mutex<Option<SystemTime>>>,
+}
+
+impl Endpoint {
+    pub fn new(discovery_url: Url, validator: Validator) -> Self {
+        Endpoint {
+            discovery_url,
+            validator,
+            auth_url: Arc::default(),
+            keys: Arc::default(),
+            fetch_health: Arc::default(),
+            verified_jwks: Arc::default(),
+            continuity: Arc::default(),
+            keys_refetched: Arc::default(),
+        }
+    }
+
+    /// The authorization URL with fixed parameters, if it was built from the same `discovery`
+    /// document as cached using `Endpoint::cache_auth_url`.
+    pub fn cached_auth_url(&self, discovery: &Bytes) -> Option<Url> {
+        match *self.auth_url.lock().unwrap() {
+            Some((ref cached, ref url)) if cached == discovery => Some(url.clone()),
+            _ => None,
+        }
+    }
+
+    /// Cache the authorization URL with fixed parameters built from `discovery`.
+    pub fn cache_auth_url(&self, discovery: Bytes, url: Url) {
+        *self.auth_url.lock().unwrap() = Some((discovery, url));
+    }
+
+    /// Record the outcome of fetching a document of this endpoint, for `Client::availability`.
+    pub fn record_fetch(&self, result: &Result<Bytes, FetchError>, now: SystemTime) {
+        let mut health = self.fetch_health.lock().unwrap();
+        match result {
+            Ok(_) => *health = FetchHealth::default(),
+            Err(err) => {
+                health.consecutive_failures = health.consecutive_failures.saturating_add(1);
+                health.last_failure = Some(now);
+                health.retry_until = err
+                    .status_error()
+                    .and_then(|status| status.retry_after)
+                    .map(|retry_after| now + retry_after);
+            }
+        }
+    }
+
+    /// The fetch health and last key refetch of this endpoint, to share with other workers.
+    pub fn shared_state(&self) -> SharedState {
+        let health = self.fetch_health.lock().unwrap();
+        SharedState {
+            consecutive_failures: health.consecutive_failures,
+            last_failure: health.last_failure.map(unix_secs),
+            retry_until: health.retry_until.map(unix_secs),
+            keys_refetched: self.keys_refetched.lock().unwrap().map(unix_secs),
+        }
+    }
+
+    /// Adopt the state saved by another worker.
+    ///
+    /// The fetch health is replaced, because the saved state is the most recent view of the
+    /// fleet. Of the key refetch times, the most recent one is kept.
+    pub fn merge_shared_state(&self, state: &SharedState) {
+        *self.fetch_health.lock().unwrap() = FetchHealth {
+            consecutive_failures: state.consecutive_failures,
+            last_failure: state.last_failure.map(from_unix_secs),
+            retry_until: state.retry_until.map(from_unix_secs),
+        };
+        if let Some(refetched) = state.keys_refetched.map(from_unix_secs) {
+            let mut last = self.keys_refetched.lock().unwrap();
+            *last = Some(last.map_or(refetched, |last| last.max(refetched)));
+        }
+    }
+
+    /// Whether `jwks` is the document last accepted by the `KeyVerifier`.
+    pub fn is_verified_jwks(&self, jwks: &Bytes) -> bool {
+        self.verified_jwks.lock().unwrap().as_ref() == Some(jwks)
+    }
+
+    /// Whether the JWKs document may be refetched, bypassing the cache, at most once per
+    /// `interval`. Records the attempt if so.
+    pub fn try_refetch_keys(&self, now: SystemTime, interval: Duration) -> bool {
+        let mut last = self.keys_refetched.lock().unwrap();
+        let allowed = last.map_or(true, |last| {
+            now.duration_since(last)
+                .is_ok_and(|elapsed| elapsed >= interval)
+        });
+        if allowed {
+            *last = Some(now);
+        }
+        allowed
+    }
+
+    /// Record that `jwks` was accepted by the `KeyVerifier`.
+    pub fn set_verified_jwks(&self, jwks: Bytes) {
+        *self.verified_jwks.lock().unwrap() = Some(jwks);
+    }
+
+    /// Whether key continuity was checked for `jwks` less than `interval` ago.
+    pub fn continuity_checked(&self, jwks: &Bytes, now: SystemTime, interval: Duration) -> bool {
+        match *self.continuity.lock().unwrap() {
+            Some((ref checked, at)) if checked == jwks => now
+                .duration_since(at)
+                .map_or(true, |elapsed| elapsed < interval),
+            _ => false,
+        }
+    }
+
+    /// Record that key continuity was checked for `jwks`.
+    pub fn set_continuity_checked(&self, jwks: Bytes, now: SystemTime) {
+        *self.continuity.lock().unwrap() = Some((jwks, now));
+    }
+
+    /// The keys last seen for this endpoint.
+    pub fn keys(&self) -> Vec<KeyInfo> {
+        self.keys.lock().unwrap().keys.clone()
+    }
+
+    /// Record the keys in a fetched JWKs document, parsed as `key_set`.
+    ///
+    /// Returns the change if the set of keys differs from the previously recorded set. The first
+    /// recorded set is not reported as a change.
+    pub fn observe_keys(
+        &self,
+        jwks: &Bytes,
+        key_set: &jwk::KeySet,
+        now: SystemTime,
+    ) -> Option<KeySetChange> {
+        let mut state = self.keys.lock().unwrap();
+        if state.jwks.as_ref() == Some(jwks) {
+            return None;
+        }
+
+        let origin = self.validator.issuer();
+        let keys: Vec<KeyInfo> = key_set
+            .keys
+            .iter()
+            .map(|key| {
+                let thumbprint = key.thumbprint();
+                let first_seen = state
+                    .keys
+                    .iter()
+                    .find(|old| old.kid == key.kid && old.thumbprint == thumbprint)
+                    .map_or(now, |old| old.first_seen);
+                KeyInfo {
+                    origin: origin.to_owned(),
+                    kid: key.kid.clone(),
+                    alg: key.supported_alg(),
+                    thumbprint,
+                    first_seen,
+                }
+            })
+            .collect();
+
+        let is_same = |a: &KeyInfo, b: &KeyInfo| a.kid == b.kid && a.thumbprint == b.thumbprint;
+        let added: Vec<KeyInfo> = keys
+            .iter()
+            .filter(|key| !state.keys.iter().any(|old| is_same(old, key)))
+            .cloned()
+            .collect();
+        let removed: Vec<KeyInfo> = state
+            .keys
+            .iter()
+            .filter(|old| !keys.iter().any(|key| is_same(old, key)))
+            .cloned()
+            .collect();
+
+        let first = state.jwks.is_none();
+        state.jwks = Some(jwks.clone());
+        state.keys = keys;
+        if first || (added.is_empty() && removed.is_empty()) {
+            return None;
+        }
+        Some(KeySetChange {
+            origin: origin.to_owned(),
+            added,
+            removed,
+        })
+    }
+}
+
+/// Latency state of an endpoint.
+///
+/// Only probing changes the state, which requires `memory-store`.
+#[cfg_attr(not(feature = "memory-store"), allow(dead_code))]
+#[derive(Clone, Copy)]
+enum Health {
+    Unknown,
+    Healthy(Duration),
+    Unhealthy,
+}
+
+/// The set of broker endpoints configured on a `Client`, with latency measurements.
+///
+/// The first endpoint is the primary one, which is used until probing has found a faster one.
+/// Measurements are shared between clones of the client.
+#[derive(Clone)]
+pub(crate) struct Endpoints {
+    list: Vec<Endpoint>,
+    health: Arc<Mutex<Vec<Health>>>,
+}
+
+impl Endpoints {
+    pub fn new(list: Vec<Endpoint>) -> Self {
+        let health = vec![Health::Unknown; list.len()];
+        Endpoints {
+            list,
+            health: Arc::new(Mutex::new(health)),
+        }
+    }
+
+    /// All endpoints, starting with the primary one.
+    pub fn all(&self) -> &[Endpoint] {
+        &self.list
+    }
+
+    /// The endpoint to use for new logins: the fastest healthy endpoint, if any were probed.
+    pub fn select(&self) -> &Endpoint {
+        let health = self.health.lock().unwrap();
+        let fastest = health
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, health)| match *health {
+                Health::Healthy(latency) => Some((latency, idx)),
+                _ => None,
+            })
+            .min();
+        let idx = match fastest {
+            Some((_, idx)) => idx,
+            None => health
+                .iter()
+                .position(|health| !matches!(health, Health::Unhealthy))
+                .unwrap_or(0),
+        };
+        &self.list[idx]
+    }
+
+    /// Summarize the availability of all endpoints.
+    ///
+    /// An endpoint that failed its last probe is considered degraded.
+    pub fn availability(&self, now: SystemTime) -> Availability {
+        let health = self.health.lock().unwrap();
+        let mut all_unavailable = true;
+        let mut any_degraded = false;
+        let mut earliest_retry: Option<SystemTime> = None;
+        for (endpoint, health) in self.list.iter().zip(health.iter()) {
+            match endpoint.fetch_health.lock().unwrap().availability(now) {
+                Availability::Unavailable { retry_after } => {
+                    if let Some(retry_after) = retry_after {
+                        earliest_retry = Some(
+                            earliest_retry
+                                .map_or(retry_after, |earliest| earliest.min(retry_after)),
+                        );
+                    }
+                    continue;
+                }
+                Availability::Degraded => any_degraded = true,
+                Availability::Available => {}
+            }
+            all_unavailable = false;
+            any_degraded |= matches!(health, Health::Unhealthy);
+        }
+
+        if all_unavailable {
+            Availability::Unavailable {
+                retry_after: earliest_retry,
+            }
+        } else if any_degraded || earliest_retry.is_some() {
+            Availability::Degraded
+        } else {
+            Availability::Available
+        }
+    }
+
+    /// The endpoint that issued `token`, based on the unverified `iss` claim.
+    pub fn for_token(&self, token: &str) -> Result<&Endpoint, VerifyError> {
+        if self.list.len() == 1 {
+            // The validator reports a mismatched issuer.
+            return Ok(&self.list[0]);
+        }
+        let issuer = unverified_issuer(token).ok_or(VerifyError::IssuerInvalid)?;
+        // The discovery document may specify the issuer with a trailing slash.
+        let issuer = issuer.strip_suffix('/').unwrap_or(&issuer);
+        self.list
+            .iter()
+            .find(|endpoint| endpoint.validator.issuer() == issuer)
+            .ok_or(VerifyError::IssuerInvalid)
+    }
+
+    /// Probe all endpoints by fetching their discovery documents, and record latencies.
+    #[cfg(feature = "memory-store")]
+    pub async fn probe(
+        &self,
+        client: &dyn crate::HttpClient,
+        timeout: Duration,
+    ) -> Vec<EndpointProbe> {
+        let mut probes = Vec::with_capacity(self.list.len());
+        for (idx, endpoint) in self.list.iter().enumerate() {
+            let request = crate::HttpRequest::get(endpoint.discovery_url.as_str())
+                .body(())
+                .unwrap();
+            let start = std::time::Instant::now();
+            let latency = match tokio::time::timeout(timeout, client.request(request)).await {
+                Ok(Ok(response)) if response.status() == http::StatusCode::OK => {
+                    Some(start.elapsed())
+                }
+                _ => None,
+            };
+            self.health.lock().unwrap()[idx] = match latency {
+                Some(latency) => Health::Healthy(latency),
+                None => Health::Unhealthy,
+            };
+            probes.push(EndpointProbe {
+                origin: endpoint.validator.issuer().to_owned(),
+                latency,
+            });
+        }
+        probes
+    }
+}
+
+/// Extract the `iss` claim from a token without verifying it.
+///
+/// This is only used to select the endpoint whose keys should verify the token.
+pub(crate) fn unverified_issuer(token: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Payload {
+        iss: String,
+    }
+    let payload = token.split('.').nth(1)?;
+    let payload = base64url::decode(payload).ok()?;
+    let payload: Payload = serde_json::from_slice(&payload).ok()?;
+    Some(payload.iss)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OVERLAP: Duration = Duration::from_secs(3600);
+    const NOW: u64 = 1_600_000_000;
+
+    fn thumbprints(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn pinned(pins: &KeyPins) -> Vec<&str> {
+        pins.keys
+            .iter()
+            .map(|pin| pin.thumbprint.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn trusts_first_key_set() {
+        let mut pins = KeyPins::default();
+        assert_eq!(pins.update(&thumbprints(&["a", "b"]), NOW, OVERLAP), None);
+        assert_eq!(pinned(&pins), ["a", "b"]);
+    }
+
+    #[test]
+    fn accepts_overlapping_key_set() {
+        let mut pins = KeyPins::default();
+        pins.update(&thumbprints(&["a", "b"]), NOW, OVERLAP);
+        assert_eq!(
+            pins.update(&thumbprints(&["b", "c"]), NOW + 1, OVERLAP),
+            None
+        );
+        assert_eq!(pinned(&pins), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn rejects_disjoint_key_set() {
+        let mut pins = KeyPins::default();
+        pins.update(&thumbprints(&["a", "b"]), NOW, OVERLAP);
+        let disjoint = pins.update(&thumbprints(&["c"]), NOW + 1, OVERLAP);
+        assert_eq!(disjoint, Some(thumbprints(&["a", "b"])));
+        // Pins are updated regardless; the caller decides whether to save them.
+        assert_eq!(pinned(&pins), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn rejects_empty_key_set() {
+        let mut pins = KeyPins::default();
+        pins.update(&thumbprints(&["a"]), NOW, OVERLAP);
+        let disjoint = pins.update(&[], NOW + 1, OVERLAP);
+        assert_eq!(disjoint, Some(thumbprints(&["a"])));
+    }
+
+    #[test]
+    fn drops_pins_after_overlap() {
+        let mut pins = KeyPins::default();
+        pins.update(&thumbprints(&["a"]), NOW, OVERLAP);
+        pins.update(&thumbprints(&["a", "b"]), NOW + 1, OVERLAP);
+        let later = NOW + OVERLAP.as_secs() + 1;
+        assert_eq!(pins.update(&thumbprints(&["b"]), later, OVERLAP), None);
+        assert_eq!(pinned(&pins), ["a", "b"]);
+        // Key "a" was last seen more than `overlap` ago.
+        let later = NOW + OVERLAP.as_secs() + 2;
+        assert_eq!(
+            pins.update(&thumbprints(&["c"]), later, OVERLAP),
+            Some(thumbprints(&["b"]))
+        );
+    }
+
+    #[test]
+    fn trusts_any_key_set_once_pins_expire() {
+        let mut pins = KeyPins::default();
+        pins.update(&thumbprints(&["a"]), NOW, OVERLAP);
+        let later = NOW + OVERLAP.as_secs() + 1;
+        assert_eq!(pins.update(&thumbprints(&["b"]), later, OVERLAP), None);
+        assert_eq!(pinned(&pins), ["b"]);
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let mut pins = KeyPins::default();
+        pins.update(&thumbprints(&["a"]), NOW, OVERLAP);
+        let mut pins: KeyPins =
+            serde_json::from_str(&serde_json::to_string(&pins).unwrap()).unwrap();
+        assert_eq!(
+            pins.update(&thumbprints(&["b"]), NOW + 1, OVERLAP),
+            Some(thumbprints(&["a"]))
+        );
+    }
+}
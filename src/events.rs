@@ -0,0 +1,129 @@
+#[cfg(feature = "tokio")]
+use std::time::SystemTime;
+
+use ring::{
+    digest,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::Deserialize;
+
+use crate::{misc::base64url, ErrorCode};
+
+/// A step in the login flow, reported to `Builder::on_login_event`.
+///
+/// Counting events per step gives a login funnel: the drop-off between `LoginStep::Redirected` and
+/// `LoginStep::CallbackReceived`, for example, is the share of users that never completed the login
+/// at the broker. The strings returned by `LoginStep::as_str` are stable, and suitable as metric
+/// labels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum LoginStep {
+    /// `Client::start_auth` was called.
+    AuthStarted,
+    /// `Client::start_auth` returned the URL to redirect the user agent to.
+    Redirected,
+    /// `Client::handle_callback` was called with the parameters sent by the broker.
+    CallbackReceived,
+    /// The token was verified, and the login completed.
+    Verified,
+    /// The login failed at any step, for the given reason.
+    Failed(ErrorCode),
+}
+
+impl LoginStep {
+    /// The stable string form of the step. Use `LoginStep::reason` for the reason of a failure.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginStep::AuthStarted => "auth_started",
+            LoginStep::Redirected => "redirected",
+            LoginStep::CallbackReceived => "callback_received",
+            LoginStep::Verified => "verified",
+            LoginStep::Failed(_) => "failed",
+        }
+    }
+
+    /// The reason of a `LoginStep::Failed` step.
+    pub fn reason(&self) -> Option<ErrorCode> {
+        match self {
+            LoginStep::Failed(code) => Some(*code),
+            _ => None,
+        }
+    }
+}
+
+/// An event in the login flow, reported to `Builder::on_login_event`.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct LoginEvent<'a> {
+    /// The step that was reached.
+    pub step: LoginStep,
+    /// An identifier of the login attempt, the same for all of its events, if known.
+    ///
+    /// This is `AuthOptions::correlation_id` if set, or a random identifier generated when the
+    /// login starts, which is safe to log. It is kept in the login session, and read from there
+    /// when verifying the token. If the session can't be found, for example because it expired,
+    /// an identifier derived from the session nonce is used instead, which does not reveal the
+    /// nonce. It is not known if the token is unreadable.
+    pub correlation_id: Option<&'a str>,
+    /// The issuer of the broker handling the login, if known.
+    pub broker: Option<&'a str>,
+}
+
+impl LoginEvent<'_> {
+    /// An owned copy of the event, that happened at `at`.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn to_owned_at(&self, at: SystemTime) -> OwnedLoginEvent {
+        OwnedLoginEvent {
+            step: self.step,
+            correlation_id: self.correlation_id.map(Into::into),
+            broker: self.broker.map(Into::into),
+            at,
+        }
+    }
+}
+
+/// An owned `LoginEvent`, received from `Client::events`.
+#[cfg(feature = "tokio")]
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct OwnedLoginEvent {
+    /// The step that was reached.
+    pub step: LoginStep,
+    /// An identifier of the login attempt, if known. See `LoginEvent::correlation_id`.
+    pub correlation_id: Option<String>,
+    /// The issuer of the broker handling the login, if known.
+    pub broker: Option<String>,
+    /// When the step was reached, according to the `Clock` of the client.
+    pub at: SystemTime,
+}
+
+/// The correlation ID of the login session with the given nonce.
+pub(crate) fn correlation_id(nonce: &str) -> String {
+    let hash = digest::digest(&digest::SHA256, nonce.as_bytes());
+    base64url::encode(&hash.as_ref()[..12])
+}
+
+/// A random correlation ID, for a login started without `AuthOptions::correlation_id`.
+pub(crate) fn random_correlation_id() -> String {
+    let mut bytes = [0; 12];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("secure random number generator failed");
+    base64url::encode(&bytes)
+}
+
+/// The nonce and original email address of the login session of `token`, without verifying the
+/// token.
+pub(crate) fn token_session(token: &str) -> Option<(String, String)> {
+    #[derive(Deserialize)]
+    struct Payload {
+        nonce: String,
+        email: String,
+        email_original: Option<String>,
+    }
+    let payload = token.split('.').nth(1)?;
+    let payload = base64url::decode(payload).ok()?;
+    let payload: Payload = serde_json::from_slice(&payload).ok()?;
+    let email = payload.email_original.unwrap_or(payload.email);
+    Some((payload.nonce, email))
+}
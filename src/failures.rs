@@ -0,0 +1,109 @@
+use std::{sync::Arc, time::Duration};
+
+use ring::digest;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    endpoint::unverified_issuer,
+    misc::{base64url, DynErr, DynFutRes},
+    validator::token_header,
+    ErrorCode, Store, VerifyError,
+};
+
+/// How long a `StoreFailureSink` keeps records by default: one week.
+const DEFAULT_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// A failed verification, as recorded by a `FailureSink`.
+///
+/// The token itself is not included, because it may still be valid. Instead, `token_hash` can be
+/// matched against the hash of tokens found elsewhere, such as in access logs.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct VerifyFailure {
+    /// When verification failed, in seconds since the Unix epoch, or 0 if the clock is not
+    /// reliable.
+    pub time: u64,
+    /// The classification of the error.
+    pub code: ErrorCode,
+    /// The error message, for operators.
+    pub error: String,
+    /// The `kid` from the token header, without verifying the token.
+    pub kid: Option<String>,
+    /// The `iss` claim of the token, without verifying the token.
+    pub issuer: Option<String>,
+    /// The first 12 bytes of the SHA-256 hash of the token, encoded as base64url.
+    pub token_hash: String,
+}
+
+impl VerifyFailure {
+    /// Describe the failed verification of `token` with `err` at `time`.
+    pub(crate) fn new(token: &str, err: &VerifyError, time: u64) -> Self {
+        let hash = digest::digest(&digest::SHA256, token.as_bytes());
+        VerifyFailure {
+            time,
+            code: err.code(),
+            error: err.to_string(),
+            kid: token_header(token).and_then(|header| header.kid),
+            issuer: unverified_issuer(token),
+            token_hash: base64url::encode(&hash.as_ref()[..12]),
+        }
+    }
+}
+
+/// Records failed verifications, for investigating attacks afterwards.
+///
+/// Configure a sink using `Builder::failure_sink`. It is called once by every `Client::verify`
+/// and `Client::verify_pending` that fails, including when rejected by the `LoginHook`. Errors
+/// returned by the sink are ignored, so an outage of the sink doesn't affect logins.
+///
+/// `StoreFailureSink` records failures in a `Store`. This is also implemented for closures of the
+/// form `Fn(&VerifyFailure)`, for example to write failures to a log.
+pub trait FailureSink: Send + Sync + 'static {
+    /// Record `failure`.
+    fn record(&self, failure: &VerifyFailure) -> DynFutRes<()>;
+}
+
+impl<F> FailureSink for F
+where
+    F: Fn(&VerifyFailure) + Send + Sync + 'static,
+{
+    fn record(&self, failure: &VerifyFailure) -> DynFutRes<()> {
+        self(failure);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// A `FailureSink` that records failures as JSON using `Store::record_failure`.
+///
+/// Records can be read back using `Store::load_failures`, and parsed as `VerifyFailure`. The store
+/// must support these methods; `MemoryStore` does.
+#[derive(Clone)]
+pub struct StoreFailureSink {
+    store: Arc<dyn Store>,
+    retention: Duration,
+}
+
+impl StoreFailureSink {
+    /// Create a sink that records failures in `store`.
+    pub fn new(store: Arc<dyn Store>) -> Self {
+        StoreFailureSink {
+            store,
+            retention: DEFAULT_RETENTION,
+        }
+    }
+
+    /// Ask the store to keep records for `dur`. The default is one week.
+    pub fn retention(mut self, dur: Duration) -> Self {
+        self.retention = dur;
+        self
+    }
+}
+
+impl FailureSink for StoreFailureSink {
+    fn record(&self, failure: &VerifyFailure) -> DynFutRes<()> {
+        match serde_json::to_string(failure) {
+            Ok(record) => self.store.record_failure(record, self.retention),
+            Err(err) => Box::pin(async move { Err(Box::new(err) as DynErr) }),
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use thiserror::Error;
+use url::Url;
+
+/// Errors that can result from `FragmentRelay::check_redirect_uri`.
+#[derive(Debug, Error)]
+pub enum RelayError {
+    #[error("the relayed redirect URI could not be parsed: {0}")]
+    InvalidRedirectUri(#[source] url::ParseError),
+    #[error("the relayed redirect URI does not match the configured redirect URI")]
+    RedirectUriMismatch,
+}
+
+/// Endpoint configuration for use with `ResponseMode::Fragment`.
+///
+/// In fragment mode, the broker sends the user agent to the redirect URI with the token in the URL
+/// fragment, which is not sent to the server. The page at the redirect URI must then use
+/// client-side JavaScript to send the token to a second endpoint, the relay endpoint, which calls
+/// `Client::verify`.
+///
+/// This helper derives the relay endpoint from the client configuration, so both endpoints stay in
+/// sync. By default, the relay path is the redirect URI path with `/relay` appended. This can be
+/// changed using `Builder::fragment_relay_path`.
+///
+/// The page at the redirect URI should send its own URL (without the fragment) along with the
+/// token, so the relay endpoint can use `check_redirect_uri` to catch mismatched configuration.
+/// `page_html` generates such a page.
+#[derive(Clone)]
+pub struct FragmentRelay {
+    redirect_uri: Url,
+    relay_url: Url,
+}
+
+impl FragmentRelay {
+    pub(crate) fn new(redirect_uri: &Url, relay_path: Option<&str>) -> Self {
+        let mut redirect_uri = redirect_uri.clone();
+        redirect_uri.set_fragment(None);
+
+        let mut relay_url = redirect_uri.clone();
+        relay_url.set_query(None);
+        match relay_path {
+            Some(path) => relay_url.set_path(path),
+            None => {
+                let path = format!("{}/relay", redirect_uri.path().trim_end_matches('/'));
+                relay_url.set_path(&path);
+            }
+        }
+
+        FragmentRelay {
+            redirect_uri,
+            relay_url,
+        }
+    }
+
+    /// The path of the page that receives the fragment, to use when registering routes.
+    pub fn redirect_path(&self) -> &str {
+        self.redirect_uri.path()
+    }
+
+    /// The path of the relay endpoint, to use when registering routes.
+    pub fn relay_path(&self) -> &str {
+        self.relay_url.path()
+    }
+
+    /// The full URL of the relay endpoint, to which the redirect page should send the token.
+    pub fn relay_url(&self) -> &Url {
+        &self.relay_url
+    }
+
+    /// Generate the HTML page to serve at the redirect URI.
+    ///
+    /// The page uses JavaScript to send the parameters in the URL fragment to the relay endpoint,
+    /// as a form `POST`, along with the `redirect_uri` of the page. The body can be parsed using
+    /// `FragmentPayload`.
+    ///
+    /// The script is inline, so a Content Security Policy must allow it. Pass the nonce of a
+    /// `script-src 'nonce-...'` directive in `script_nonce` to add it to the script element.
+    pub fn page_html(&self, script_nonce: Option<&str>) -> String {
+        page_html(&self.relay_url, script_nonce)
+    }
+
+    /// Check that the redirect URI relayed by the redirect page matches the configuration.
+    ///
+    /// Any fragment in `relayed` is ignored, so the page can simply send `location.href`.
+    pub fn check_redirect_uri(&self, relayed: &str) -> Result<(), RelayError> {
+        let mut relayed: Url = relayed.parse().map_err(RelayError::InvalidRedirectUri)?;
+        relayed.set_fragment(None);
+        if relayed != self.redirect_uri {
+            return Err(RelayError::RedirectUriMismatch);
+        }
+        Ok(())
+    }
+}
+
+/// Generate the page for `FragmentRelay::page_html`.
+pub(crate) fn page_html(relay_url: &Url, script_nonce: Option<&str>) -> String {
+    let nonce_attr = match script_nonce {
+        Some(nonce) => format!(r#" nonce="{}""#, escape_attr(nonce)),
+        None => String::new(),
+    };
+    format!(
+        r##"<!DOCTYPE html>
+<meta charset="utf-8">
+<title>Logging in</title>
+<form method="post" action="{}"></form>
+<script{}>
+  var form = document.forms[0];
+  var params = new URLSearchParams(location.hash.slice(1));
+  params.append("redirect_uri", location.href.split("#")[0]);
+  params.forEach(function (value, name) {{
+    var input = document.createElement("input");
+    input.type = "hidden";
+    input.name = name;
+    input.value = value;
+    form.appendChild(input);
+  }});
+  form.submit();
+</script>
+"##,
+        escape_attr(relay_url.as_str()),
+        nonce_attr
+    )
+}
+
+/// Escape `value` for use in a double-quoted HTML attribute.
+fn escape_attr(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relay(redirect_uri: &str, relay_path: Option<&str>) -> FragmentRelay {
+        FragmentRelay::new(&redirect_uri.parse().unwrap(), relay_path)
+    }
+
+    #[test]
+    fn derives_relay_path() {
+        let relay = relay("https://rp.example/callback?tenant=a#frag", None);
+        assert_eq!(relay.redirect_path(), "/callback");
+        assert_eq!(
+            relay.relay_url().as_str(),
+            "https://rp.example/callback/relay"
+        );
+    }
+
+    #[test]
+    fn derives_relay_path_with_trailing_slash() {
+        assert_eq!(
+            relay("https://rp.example/callback/", None).relay_path(),
+            "/callback/relay"
+        );
+        assert_eq!(relay("https://rp.example/", None).relay_path(), "/relay");
+    }
+
+    #[test]
+    fn uses_configured_relay_path() {
+        let relay = relay("https://rp.example/callback", Some("/auth/relay"));
+        assert_eq!(relay.relay_url().as_str(), "https://rp.example/auth/relay");
+    }
+
+    #[test]
+    fn accepts_matching_redirect_uri() {
+        let relay = relay("https://rp.example/callback?tenant=a", None);
+        relay
+            .check_redirect_uri("https://rp.example/callback?tenant=a")
+            .unwrap();
+        relay
+            .check_redirect_uri("https://rp.example/callback?tenant=a#id_token=x")
+            .unwrap();
+    }
+
+    #[test]
+    fn rejects_mismatched_redirect_uri() {
+        let relay = relay("https://rp.example/callback", None);
+        for relayed in [
+            "https://rp.example/other",
+            "https://rp.example/callback?tenant=a",
+            "http://rp.example/callback",
+            "https://evil.example/callback",
+        ] {
+            assert!(matches!(
+                relay.check_redirect_uri(relayed),
+                Err(RelayError::RedirectUriMismatch)
+            ));
+        }
+        assert!(matches!(
+            relay.check_redirect_uri("/callback"),
+            Err(RelayError::InvalidRedirectUri(_))
+        ));
+    }
+
+    #[test]
+    fn escapes_attributes() {
+        assert_eq!(escape_attr(r#"a&b"c<d>e"#), "a&amp;b&quot;c&lt;d&gt;e");
+    }
+
+    #[test]
+    fn escapes_page_attributes() {
+        let relay = relay("https://rp.example/callback", Some("/relay?a=1&b=\"2\""));
+        let html = relay.page_html(Some(r#""><script>"#));
+        assert!(html.contains(r#"<script nonce="&quot;&gt;&lt;script&gt;">"#));
+        assert!(html.contains(r#"action="https://rp.example/relay%3Fa=1&amp;b=%222%22""#));
+        assert!(!html.contains(r#""><script>"#));
+    }
+}
@@ -1,40 +1,104 @@
-use serde::{de::Error, Deserialize};
+use serde::{de::Error, Deserialize, Serialize};
 
-use crate::misc::base64url;
+use ring::digest;
+
+use crate::misc::base64url::{self, Engine, BASE64_URL_SAFE_NO_PAD};
 
 /// Document containing a set of JWKs.
 ///
-/// Deserializes RFC 7517, Section 5.
-#[derive(Deserialize)]
+/// Serializes and deserializes RFC 7517, Section 5.
+#[derive(Deserialize, Serialize)]
 pub struct KeySet {
     pub keys: Vec<Key>,
 }
 
 /// A single JWK.
 ///
-/// Deserializes RFC 7517, Section 4.
-#[derive(Deserialize)]
+/// Serializes and deserializes RFC 7517, Section 4.
+#[derive(Deserialize, Serialize)]
 pub struct Key {
     pub kid: String,
     #[serde(flatten)]
     pub data: KeyData,
 }
 
+impl Key {
+    /// The JWS algorithm this key is used with, if it is supported by `jws::verify`.
+    pub fn supported_alg(&self) -> Option<&'static str> {
+        match self.data {
+            KeyData::Okp(OkpKey {
+                alg: OkpAlg::EdDsa,
+                crv: OkpCurve::Ed25519,
+                ..
+            }) => Some("EdDSA"),
+            #[cfg(feature = "ed448")]
+            KeyData::Okp(OkpKey {
+                alg: OkpAlg::EdDsa,
+                crv: OkpCurve::Ed448,
+                ..
+            }) => Some("EdDSA"),
+            KeyData::Rsa(RsaKey {
+                alg: RsaAlg::Rs256, ..
+            }) => Some("RS256"),
+            KeyData::Ec(EcKey {
+                alg: EcAlg::Es256,
+                crv: EcCurve::P256,
+                ..
+            }) => Some("ES256"),
+            _ => None,
+        }
+    }
+
+    /// The RFC 7638 thumbprint of this key, using SHA-256, in URL-safe base64.
+    ///
+    /// Returns `None` for key types and curves this crate does not know.
+    pub fn thumbprint(&self) -> Option<String> {
+        // Members must be in lexicographic order. The values are base64 or fixed names, which
+        // don't need escaping.
+        let canonical = match self.data {
+            KeyData::Rsa(RsaKey { ref n, ref e, .. }) => format!(
+                r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#,
+                BASE64_URL_SAFE_NO_PAD.encode(e),
+                BASE64_URL_SAFE_NO_PAD.encode(n)
+            ),
+            KeyData::Okp(OkpKey { crv, ref x, .. }) => format!(
+                r#"{{"crv":"{}","kty":"OKP","x":"{}"}}"#,
+                crv.name()?,
+                BASE64_URL_SAFE_NO_PAD.encode(x)
+            ),
+            KeyData::Ec(EcKey {
+                crv, ref x, ref y, ..
+            }) => format!(
+                r#"{{"crv":"{}","kty":"EC","x":"{}","y":"{}"}}"#,
+                crv.name()?,
+                BASE64_URL_SAFE_NO_PAD.encode(x),
+                BASE64_URL_SAFE_NO_PAD.encode(y)
+            ),
+            KeyData::Unknown => return None,
+        };
+        let digest = digest::digest(&digest::SHA256, canonical.as_bytes());
+        Some(BASE64_URL_SAFE_NO_PAD.encode(digest))
+    }
+}
+
 /// The type of key and inner data, based on the `kty` field.
 ///
-/// Deserializes RFC 7517, Section 4.1.
-#[derive(Deserialize)]
+/// Serializes and deserializes RFC 7517, Section 4.1.
+#[derive(Deserialize, Serialize)]
 #[serde(tag = "kty")]
+#[non_exhaustive]
 pub enum KeyData {
     #[serde(rename = "RSA")]
     Rsa(RsaKey),
     #[serde(rename = "OKP")]
     Okp(OkpKey),
-    #[serde(other)]
+    #[serde(rename = "EC")]
+    Ec(EcKey),
+    #[serde(other, skip_serializing)]
     Unknown,
 }
 
-/// Deserializes URL-safe base64, often used in JWK fields.
+/// Serializes and deserializes URL-safe base64, often used in JWK fields.
 #[derive(Debug)]
 pub struct Binary(Vec<u8>);
 
@@ -48,6 +112,21 @@ impl<'de> Deserialize<'de> for Binary {
     }
 }
 
+impl Serialize for Binary {
+    fn serialize<S>(&self, ser: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        ser.serialize_str(&BASE64_URL_SAFE_NO_PAD.encode(&self.0))
+    }
+}
+
+impl From<Vec<u8>> for Binary {
+    fn from(data: Vec<u8>) -> Self {
+        Self(data)
+    }
+}
+
 impl AsRef<[u8]> for Binary {
     fn as_ref(&self) -> &[u8] {
         &self.0
@@ -56,8 +135,8 @@ impl AsRef<[u8]> for Binary {
 
 /// RSA-specific fields of a JWK.
 ///
-/// Deserializes RFC 7518, Section 6.3.
-#[derive(Deserialize)]
+/// Serializes and deserializes RFC 7518, Section 6.3.
+#[derive(Deserialize, Serialize)]
 pub struct RsaKey {
     pub alg: RsaAlg,
     pub n: Binary,
@@ -65,37 +144,94 @@ pub struct RsaKey {
 }
 
 /// JWS algorithm types for RSA keys.
-#[derive(Clone, Copy, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum RsaAlg {
     #[serde(rename = "RS256")]
     Rs256,
-    #[serde(other)]
+    #[serde(other, skip_serializing)]
     Unknown,
 }
 
 /// Octet Key Pair (OKP) specific fields of a JWK. Used by Ed25519 and Ed448.
 ///
-/// Deserializes RFC 8037, Section 2.
-#[derive(Deserialize)]
+/// Serializes and deserializes RFC 8037, Section 2.
+#[derive(Deserialize, Serialize)]
 pub struct OkpKey {
     pub alg: OkpAlg,
     pub crv: OkpCurve,
     pub x: Binary,
 }
 
-/// JWS algorithm types for RSA keys.
-#[derive(Clone, Copy, Deserialize, PartialEq, Eq)]
+/// JWS algorithm types for OKP keys.
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum OkpAlg {
     #[serde(rename = "EdDSA")]
     EdDsa,
-    #[serde(other)]
+    #[serde(other, skip_serializing)]
     Unknown,
 }
 
 /// OKP curve types.
-#[derive(Clone, Copy, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum OkpCurve {
     Ed25519,
-    #[serde(other)]
+    /// Ed448 keys are only verified with the `ed448` crate feature.
+    Ed448,
+    #[serde(other, skip_serializing)]
     Unknown,
 }
+
+impl OkpCurve {
+    /// The `crv` value, if known.
+    fn name(self) -> Option<&'static str> {
+        match self {
+            OkpCurve::Ed25519 => Some("Ed25519"),
+            OkpCurve::Ed448 => Some("Ed448"),
+            OkpCurve::Unknown => None,
+        }
+    }
+}
+
+/// Elliptic Curve specific fields of a JWK.
+///
+/// Serializes and deserializes RFC 7518, Section 6.2.
+#[derive(Deserialize, Serialize)]
+pub struct EcKey {
+    pub alg: EcAlg,
+    pub crv: EcCurve,
+    pub x: Binary,
+    pub y: Binary,
+}
+
+/// JWS algorithm types for EC keys.
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EcAlg {
+    #[serde(rename = "ES256")]
+    Es256,
+    #[serde(other, skip_serializing)]
+    Unknown,
+}
+
+/// EC curve types.
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EcCurve {
+    #[serde(rename = "P-256")]
+    P256,
+    #[serde(other, skip_serializing)]
+    Unknown,
+}
+
+impl EcCurve {
+    /// The `crv` value, if known.
+    fn name(self) -> Option<&'static str> {
+        match self {
+            EcCurve::P256 => Some("P-256"),
+            EcCurve::Unknown => None,
+        }
+    }
+}
@@ -1,10 +1,23 @@
-use ring::signature;
-use serde::Deserialize;
+use std::{borrow::Cow, error::Error, future::Future, pin::Pin};
+
+use ring::{
+    error::KeyRejected,
+    rand::SystemRandom,
+    signature::{self, Ed25519KeyPair, KeyPair as _, RsaKeyPair},
+};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::{jwk, misc::base64url};
+use crate::{
+    jwk,
+    misc::{
+        base64url::{self, Engine, BASE64_URL_SAFE_NO_PAD},
+        wipe,
+    },
+};
 
 #[derive(Debug, Error)]
+#[non_exhaustive]
 pub enum VerifyError {
     #[error("the token must consist of three dot-separated parts")]
     IncorrectFormat,
@@ -17,16 +30,31 @@ pub enum VerifyError {
     InvalidHeaderJson(serde_json::Error),
     #[error("the token 'kid' could not be found in the JWKs document: {kid}")]
     KidNotMatched { kid: String },
+    #[error("the token 'alg' is not allowed: {alg}")]
+    AlgNotAllowed { alg: String },
+    #[error("the token 'alg' does not match the algorithm of the matching JWK: {alg}")]
+    AlgMismatch { alg: String },
     #[error("the matching JWK is of an unsupported type")]
     UnsupportedKeyType,
     #[error("the token signature did not validate using the matching JWK")]
     BadSignature,
 }
 
-/// Verify a JWS signature, returning the payload as a `Value` if successful.
+/// Verify a JWS signature, returning the raw payload if successful.
+///
+/// The `alg` in the header must be the algorithm of the matching key.
 pub fn verify<'a>(
     input: &'a str,
     keys: impl IntoIterator<Item = &'a jwk::Key>,
+) -> Result<Vec<u8>, VerifyError> {
+    verify_with_algs(input, keys, None)
+}
+
+/// Like `verify`, but only accept tokens with an `alg` in `allowed`, if set.
+pub fn verify_with_algs<'a>(
+    input: &'a str,
+    keys: impl IntoIterator<Item = &'a jwk::Key>,
+    allowed: Option<&[String]>,
 ) -> Result<Vec<u8>, VerifyError> {
     // Split the token up in parts.
     let mut parts = input.split('.');
@@ -39,58 +67,326 @@ pub fn verify<'a>(
 
     // Slice the signed part of the message, before we start decoding parts.
     let message_len = header.len() + payload.len() + 1;
-    let message = input[..message_len].as_bytes();
+    let message = &input.as_bytes()[..message_len];
 
-    // Decode all parts.
-    let header = base64url::decode(header)
-        .map_err(|reason| VerifyError::InvalidPartBase64 { index: 1, reason })?;
-    let payload = base64url::decode(payload)
-        .map_err(|reason| VerifyError::InvalidPartBase64 { index: 2, reason })?;
-    let signature = base64url::decode(signature)
-        .map_err(|reason| VerifyError::InvalidPartBase64 { index: 3, reason })?;
-
-    // Parse the header and find the key ID.
+    // Parse the header and find the key ID. The header and signature are only needed during
+    // verification, so are decoded into a reused buffer.
     #[derive(Deserialize)]
-    struct Header {
-        kid: String,
+    struct Header<'a> {
+        #[serde(borrow)]
+        alg: Cow<'a, str>,
+        #[serde(borrow)]
+        kid: Cow<'a, str>,
     }
-    let header: Header = serde_json::from_slice(&header).map_err(VerifyError::InvalidHeaderJson)?;
+    let key = base64url::with_decoded(header, |header| {
+        let header: Header =
+            serde_json::from_slice(header).map_err(VerifyError::InvalidHeaderJson)?;
 
-    // Look for they key ID in the JWKs.
-    let matched_keys: Vec<&jwk::Key> = keys
-        .into_iter()
-        .filter(|key| key.kid == header.kid)
-        .collect();
+        // Never accept unsigned tokens, and only accept allowed algorithms.
+        let alg_allowed = match allowed {
+            _ if header.alg == "none" => false,
+            Some(allowed) => allowed.iter().any(|alg| *alg == header.alg),
+            None => true,
+        };
+        if !alg_allowed {
+            return Err(VerifyError::AlgNotAllowed {
+                alg: header.alg.into_owned(),
+            });
+        }
 
-    // Verify that we found exactly one key matching the key ID.
-    if matched_keys.len() != 1 {
-        return Err(VerifyError::KidNotMatched { kid: header.kid });
-    }
-    let key = matched_keys.first().unwrap();
+        // Verify that we find exactly one key matching the key ID.
+        let mut matched_keys = keys.into_iter().filter(|key| key.kid == header.kid);
+        let key = match (matched_keys.next(), matched_keys.next()) {
+            (Some(key), None) => key,
+            _ => {
+                return Err(VerifyError::KidNotMatched {
+                    kid: header.kid.into_owned(),
+                })
+            }
+        };
+
+        // Verify the key is used with the algorithm in the header. Keys of unsupported types are
+        // rejected with `UnsupportedKeyType` below.
+        match key.supported_alg() {
+            Some(alg) if alg != header.alg => Err(VerifyError::AlgMismatch {
+                alg: header.alg.into_owned(),
+            }),
+            _ => Ok(key),
+        }
+    })
+    .map_err(|reason| VerifyError::InvalidPartBase64 { index: 1, reason })??;
+
+    let payload = base64url::decode(payload)
+        .map_err(|reason| VerifyError::InvalidPartBase64 { index: 2, reason })?;
+
+    base64url::with_decoded(signature, |signature| {
+        verify_signature(key, message, signature)
+    })
+    .map_err(|reason| VerifyError::InvalidPartBase64 { index: 3, reason })??;
 
-    // Verify the signature.
+    // Return the payload.
+    Ok(payload)
+}
+
+/// Verify `signature` over `message` using `key`.
+fn verify_signature(key: &jwk::Key, message: &[u8], signature: &[u8]) -> Result<(), VerifyError> {
     match key.data {
         jwk::KeyData::Okp(jwk::OkpKey {
             alg: jwk::OkpAlg::EdDsa,
             crv: jwk::OkpCurve::Ed25519,
             ref x,
-        }) => {
-            signature::UnparsedPublicKey::new(&signature::ED25519, x)
-                .verify(message, &signature)
-                .map_err(|_err| VerifyError::BadSignature)?;
-        }
+        }) => signature::UnparsedPublicKey::new(&signature::ED25519, x)
+            .verify(message, signature)
+            .map_err(|_err| VerifyError::BadSignature),
+        #[cfg(feature = "ed448")]
+        jwk::KeyData::Okp(jwk::OkpKey {
+            alg: jwk::OkpAlg::EdDsa,
+            crv: jwk::OkpCurve::Ed448,
+            ref x,
+        }) => verify_ed448(x.as_ref(), message, signature),
         jwk::KeyData::Rsa(jwk::RsaKey {
             alg: jwk::RsaAlg::Rs256,
             ref n,
             ref e,
+        }) => signature::RsaPublicKeyComponents { n, e }
+            .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature)
+            .map_err(|_err| VerifyError::BadSignature),
+        jwk::KeyData::Ec(jwk::EcKey {
+            alg: jwk::EcAlg::Es256,
+            crv: jwk::EcCurve::P256,
+            ref x,
+            ref y,
         }) => {
-            signature::RsaPublicKeyComponents { n, e }
-                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, &signature)
-                .map_err(|_err| VerifyError::BadSignature)?;
+            // Ring expects an uncompressed point.
+            let (x, y) = (x.as_ref(), y.as_ref());
+            if x.len() != 32 || y.len() != 32 {
+                return Err(VerifyError::BadSignature);
+            }
+            let mut point = [0x04; 65];
+            point[1..33].copy_from_slice(x);
+            point[33..].copy_from_slice(y);
+            signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                .verify(message, signature)
+                .map_err(|_err| VerifyError::BadSignature)
         }
-        _ => return Err(VerifyError::UnsupportedKeyType),
+        _ => Err(VerifyError::UnsupportedKeyType),
     }
+}
 
-    // Return the payload.
-    Ok(payload)
+/// Verify an Ed448 signature. Ring doesn't support Ed448, so this uses a separate crate.
+#[cfg(feature = "ed448")]
+fn verify_ed448(key: &[u8], message: &[u8], signature: &[u8]) -> Result<(), VerifyError> {
+    use ed448_goldilocks_plus::{Signature, VerifyingKey};
+
+    let key = key.try_into().map_err(|_err| VerifyError::BadSignature)?;
+    let key = VerifyingKey::from_bytes(key).map_err(|_err| VerifyError::BadSignature)?;
+    let signature = Signature::try_from(signature).map_err(|_err| VerifyError::BadSignature)?;
+    key.verify_raw(&signature, message)
+        .map_err(|_err| VerifyError::BadSignature)
+}
+
+/// Errors that can result from `jws::sign`, `jws::sign_with` and creating a `KeyPair`.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum SignError {
+    #[error("the key could not be generated")]
+    GenerateKey,
+    #[error("the key was rejected: {0}")]
+    InvalidKey(KeyRejected),
+    #[error("the signature could not be created")]
+    Sign,
+    #[error("the signer failed: {0}")]
+    Signer(#[source] Box<dyn Error + Send + Sync>),
+}
+
+/// A private key for signing tokens using `jws::sign`.
+///
+/// This is intended for brokers and identity providers. Relying parties only verify tokens, and
+/// don't need this.
+pub struct KeyPair {
+    kid: String,
+    pkcs8: Vec<u8>,
+    inner: KeyPairInner,
+}
+
+enum KeyPairInner {
+    Ed25519(Ed25519KeyPair),
+    Rsa(RsaKeyPair),
+}
+
+impl KeyPair {
+    /// Generate a new Ed25519 key pair with the given key ID.
+    ///
+    /// Store the result of `KeyPair::pkcs8` to load the key again using
+    /// `KeyPair::ed25519_from_pkcs8`. Ring does not support generating RSA keys, so generate those
+    /// using other tools, and load them using `KeyPair::rsa_from_pkcs8`.
+    pub fn generate_ed25519(kid: impl Into<String>) -> Result<Self, SignError> {
+        let rng = SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).map_err(|_err| SignError::GenerateKey)?;
+        Self::ed25519_from_pkcs8(kid, pkcs8.as_ref())
+    }
+
+    /// Load an Ed25519 key pair from a PKCS#8 document, and use it with the given key ID.
+    pub fn ed25519_from_pkcs8(kid: impl Into<String>, pkcs8: &[u8]) -> Result<Self, SignError> {
+        let key = Ed25519KeyPair::from_pkcs8(pkcs8).map_err(SignError::InvalidKey)?;
+        Ok(KeyPair {
+            kid: kid.into(),
+            pkcs8: pkcs8.to_vec(),
+            inner: KeyPairInner::Ed25519(key),
+        })
+    }
+
+    /// Load an RSA key pair from a PKCS#8 document, and use it with the given key ID.
+    ///
+    /// The key must be between 2048 and 8192 bits.
+    pub fn rsa_from_pkcs8(kid: impl Into<String>, pkcs8: &[u8]) -> Result<Self, SignError> {
+        let key = RsaKeyPair::from_pkcs8(pkcs8).map_err(SignError::InvalidKey)?;
+        Ok(KeyPair {
+            kid: kid.into(),
+            pkcs8: pkcs8.to_vec(),
+            inner: KeyPairInner::Rsa(key),
+        })
+    }
+
+    /// The key ID, used as the `kid` of tokens and the public JWK.
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    /// The JWS algorithm of signatures made with this key.
+    pub fn alg(&self) -> &'static str {
+        match self.inner {
+            KeyPairInner::Ed25519(_) => "EdDSA",
+            KeyPairInner::Rsa(_) => "RS256",
+        }
+    }
+
+    /// The private key, as a PKCS#8 document.
+    pub fn pkcs8(&self) -> &[u8] {
+        &self.pkcs8
+    }
+
+    /// The public key, as a JWK to publish in the keys document.
+    pub fn public_jwk(&self) -> jwk::Key {
+        let data = match self.inner {
+            KeyPairInner::Ed25519(ref key) => jwk::KeyData::Okp(jwk::OkpKey {
+                alg: jwk::OkpAlg::EdDsa,
+                crv: jwk::OkpCurve::Ed25519,
+                x: key.public_key().as_ref().to_vec().into(),
+            }),
+            KeyPairInner::Rsa(ref key) => {
+                let public = signature::RsaPublicKeyComponents::<Vec<u8>>::from(key.public());
+                jwk::KeyData::Rsa(jwk::RsaKey {
+                    alg: jwk::RsaAlg::Rs256,
+                    n: public.n.into(),
+                    e: public.e.into(),
+                })
+            }
+        };
+        jwk::Key {
+            kid: self.kid.clone(),
+            data,
+        }
+    }
+}
+
+impl Drop for KeyPair {
+    fn drop(&mut self) {
+        wipe(&mut self.pkcs8);
+    }
+}
+
+/// A future returned by `Signer::sign`.
+pub type SignFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Vec<u8>, Box<dyn Error + Send + Sync>>> + Send + 'a>>;
+
+/// A private key for signing tokens using `jws::sign_with`.
+///
+/// Implement this for keys held in a KMS or HSM, so the private key never needs to be in memory.
+/// `KeyPair` implements this for local keys.
+pub trait Signer: Send + Sync {
+    /// The key ID, used as the `kid` of tokens.
+    fn kid(&self) -> &str;
+
+    /// The JWS algorithm of signatures made with this key.
+    fn alg(&self) -> &str;
+
+    /// Sign `message`, returning the signature in the encoding of the JWS algorithm.
+    ///
+    /// For ECDSA, this is the fixed-length concatenation of `r` and `s`, not ASN.1 DER, which
+    /// some KMS APIs return instead.
+    fn sign<'a>(&'a self, message: &'a [u8]) -> SignFuture<'a>;
+}
+
+impl Signer for KeyPair {
+    fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    fn alg(&self) -> &str {
+        KeyPair::alg(self)
+    }
+
+    fn sign<'a>(&'a self, message: &'a [u8]) -> SignFuture<'a> {
+        let result = self.sign_message(message).map_err(Into::into);
+        Box::pin(std::future::ready(result))
+    }
+}
+
+impl KeyPair {
+    fn sign_message(&self, message: &[u8]) -> Result<Vec<u8>, SignError> {
+        match self.inner {
+            KeyPairInner::Ed25519(ref inner) => Ok(inner.sign(message).as_ref().to_vec()),
+            KeyPairInner::Rsa(ref inner) => {
+                let mut signature = vec![0; inner.public().modulus_len()];
+                inner
+                    .sign(
+                        &signature::RSA_PKCS1_SHA256,
+                        &SystemRandom::new(),
+                        message,
+                        &mut signature,
+                    )
+                    .map_err(|_err| SignError::Sign)?;
+                Ok(signature)
+            }
+        }
+    }
+}
+
+/// Sign `payload` using `key`, returning a token in JWS compact serialization.
+///
+/// The header contains the `alg` and `kid` of the key. For a token, the payload is the JSON
+/// serialized claims.
+pub fn sign(payload: &[u8], key: &KeyPair) -> Result<String, SignError> {
+    let mut output = signing_input(payload, key.alg(), &key.kid)?;
+    let signature = key.sign_message(output.as_bytes())?;
+    output.push('.');
+    BASE64_URL_SAFE_NO_PAD.encode_string(signature, &mut output);
+    Ok(output)
+}
+
+/// Like `jws::sign`, but sign using a `Signer`, such as a key in a KMS.
+pub async fn sign_with(payload: &[u8], signer: &dyn Signer) -> Result<String, SignError> {
+    let mut output = signing_input(payload, signer.alg(), signer.kid())?;
+    let signature = signer
+        .sign(output.as_bytes())
+        .await
+        .map_err(SignError::Signer)?;
+    output.push('.');
+    BASE64_URL_SAFE_NO_PAD.encode_string(signature, &mut output);
+    Ok(output)
+}
+
+/// Encode the header and payload, which are the input to the signature.
+fn signing_input(payload: &[u8], alg: &str, kid: &str) -> Result<String, SignError> {
+    #[derive(Serialize)]
+    struct Header<'a> {
+        alg: &'a str,
+        kid: &'a str,
+    }
+    let header = serde_json::to_vec(&Header { alg, kid }).map_err(|_err| SignError::Sign)?;
+    let mut output = BASE64_URL_SAFE_NO_PAD.encode(header);
+    output.push('.');
+    BASE64_URL_SAFE_NO_PAD.encode_string(payload, &mut output);
+    Ok(output)
 }
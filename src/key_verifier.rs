@@ -0,0 +1,32 @@
+use crate::{
+    jwk::KeySet,
+    misc::{DynErr, DynFutRes},
+};
+
+/// Verifies the keys published by a broker out-of-band, before they are used.
+///
+/// Deployments can use this to compare broker keys against keys distributed through another
+/// channel, such as configuration management or DNSSEC/DANE records. Configure a verifier using
+/// `Builder::key_verifier`.
+///
+/// The verifier is called when the client reads a JWKs document it has not verified before, so not
+/// on every `Client::verify`. If it returns an error, the keys are not used, and verification fails
+/// with `VerifyError::KeysRejected`.
+///
+/// This is implemented for closures of the form
+/// `Fn(&str, &KeySet) -> Result<(), Box<dyn Error + Send + Sync>>`, for verifiers that don't need
+/// to perform I/O.
+pub trait KeyVerifier: Send + Sync + 'static {
+    /// Verify the `keys` published by the broker at `origin`.
+    fn verify_keys(&self, origin: &str, keys: &KeySet) -> DynFutRes<()>;
+}
+
+impl<F> KeyVerifier for F
+where
+    F: Fn(&str, &KeySet) -> Result<(), DynErr> + Send + Sync + 'static,
+{
+    fn verify_keys(&self, origin: &str, keys: &KeySet) -> DynFutRes<()> {
+        let result = self(origin, keys);
+        Box::pin(async move { result })
+    }
+}
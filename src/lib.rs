@@ -2,77 +2,215 @@
 //!
 //! The primary interface of this package is the `Client`. Construct one using `Client::builder` or
 //! `Client::new`. See also the short example using the Rocket framework in
-//! [`example/src/main.rs`](https://github.com/portier/portier-rs/blob/main/example/src/main.rs).
+//! [`example/src/main.rs`](https://github.com/portier/portier-rs/blob/main/example/src/main.rs),
+//! and the complete axum application with sessions and logout in
+//! [`examples/axum.rs`](https://github.com/portier/portier-rs/blob/main/examples/axum.rs).
 //!
 //! Some data storage is needed to implement the protocol. This is used for tracking short-lived
 //! login sessions, and caching of basic HTTP GET requests. The `Store` trait facilitates this, and
 //! by default, an in-memory store is used. This will work fine for simple single-process
-//! applications, but if you intend to run multiple workers, an alternative Store must be
-//! implemented. (In the future, we may offer some alternatives for common databases.
-//! Contributions are welcome!)
+//! applications, but if you intend to run multiple workers, an alternative Store must be used.
+//! The `SqlStore` supports PostgreSQL, MySQL and SQLite through the `sql-postgres`, `sql-mysql`
+//! and `sql-sqlite` crate features, the `MemcachedStore` through the `memcached-store` feature,
+//! and the `DynamoStore` through the `dynamodb-store` feature. Single-process applications that
+//! restart often can use the `SledStore` through the `sled-store` feature, which keeps sessions on
+//! disk. (In the future, we may offer more alternatives. Contributions are welcome!)
+//!
+//! The two concerns of a store can also be configured independently, using the narrower `Fetcher`
+//! and `SessionStore` traits. Every `Store` implements both. Deployments that can't run a shared
+//! store at all can use `StatelessSessions`, which signs nonces instead of storing them.
+//!
+//! Custom stores can also implement `AsyncStore`, which has the same methods as `Store`, but can
+//! be implemented using `async fn`. Configure one using `Builder::async_store`, or use
+//! `Builder::build_with_store` to build a `Client` that calls the store without a trait object.
 //!
 //! Some applications may need multiple configurations and `Client` instances, for example because
-//! they serve multiple domains. In this case, we recommended creating short-lived `Client`s and
-//! sharing the `Store` between them.
+//! they serve multiple domains. In this case, a `ClientPool` builds and caches a `Client` per
+//! origin from a single configuration, sharing the `Store` between them. It can also be limited to
+//! a set of registered redirect URIs, and select the client by request host. See
+//! [`examples/multi_tenant.rs`](https://github.com/portier/portier-rs/blob/main/examples/multi_tenant.rs)
+//! for an application that also configures a broker per tenant.
 //!
-//! The crate feature `simple-store` is enabled by default, but can be disabled to remove the Tokio
-//! and Hyper dependencies. When disabled, the default `MemoryStore` will also not be available,
-//! and a custom `Store` implementation must be provided.
+//! The crate features select which parts of the default store stack are compiled:
 //!
-//! The minimum required Rust version is 1.46.
-
-mod jwk;
-mod jws;
+//! - `memory-store`: the in-memory `MemoryStore` implementation.
+//! - `http-hyper`, `http-reqwest`: `HttpClient` implementations for Hyper and reqwest.
+//! - `tls-native`, `tls-rustls`: TLS support for the HTTP clients, using native-tls or rustls.
+//!
+//! The `simple-store` feature is enabled by default, and is shorthand for `memory-store`,
+//! `http-hyper` and `tls-native`. A default `MemoryStore` is only available when `memory-store`,
+//! at least one HTTP client and at least one TLS backend are enabled. Otherwise, a custom `Store`
+//! implementation must be provided. The default store connects through the proxies set in the
+//! `http_proxy`, `https_proxy` and `no_proxy` environment variables. Use `MemoryStore::with_proxy`
+//! to configure a `ProxyConfig` explicitly.
+//!
+//! Applications that already use reqwest can avoid pulling in a second HTTP stack by disabling
+//! default features and enabling `reqwest-store` instead, which is shorthand for `memory-store`,
+//! `http-reqwest` and `tls-native`. An existing `reqwest::Client` can be shared with the store
+//! using `MemoryStore::with_http_client`, to reuse its connection pool, proxy and TLS
+//! configuration.
+//!
+//! Builds that can't link native-tls, such as static musl builds, can disable default features
+//! and enable `rustls-store`, which is shorthand for `memory-store`, `http-hyper` and
+//! `tls-rustls`. This uses rustls with the webpki root certificates, instead of the certificate
+//! store of the system.
+//!
+//! The `Client`, `Builder` and `Store` are part of the `client` feature, which is enabled by
+//! `memory-store`. The `tokio` feature (also enabled by `memory-store`) adds Tokio-specific
+//! functionality to the `Client`, such as `Builder::store_timeout`.
+//!
+//! Disabling default features without enabling `client` results in a minimal build that only
+//! contains token verification, through `Validator`, `jwk` and `jws`. This has no dependency on
+//! Tokio or any HTTP stack, and is intended for API gateways and edge filters that receive tokens
+//! and keys out-of-band. Brokers and identity providers can also sign tokens using `jws::sign`
+//! with a `jws::KeyPair`, and publish its public key by serializing a `jwk::Key`. Keys held in a
+//! KMS or HSM can implement `jws::Signer` for use with `jws::sign_with`.
+//!
+//! The `ed448` feature adds support for Ed448 keys. This uses a separate crate, because ring does
+//! not implement Ed448.
+//!
+//! The `test-utils` feature adds the `test_utils` module, with a `MockBroker` and `TokenMint` for
+//! testing applications end-to-end without a real broker.
+//!
+//! The `zeroize` feature overwrites nonces and decoded token parts in memory before they are
+//! freed, for deployments with strict memory-hygiene requirements. This is best-effort: copies
+//! held by the application, stores or other crates are not affected.
+//!
+//! The `tracing` feature adds spans and events for `Client` operations and document fetches,
+//! using the tracing crate. Failures are recorded at debug level.
+//!
+//! The `blocking` feature adds the `blocking` module, with a synchronous `blocking::Client` for
+//! applications that don't use an async runtime. Its default store fetches documents using ureq.
+//!
+//! The `actix` feature adds the `actix` module, with extractors and routes for the actix-web
+//! framework.
+//!
+//! The `axum` feature adds the `axum` module, with extractors and routes for the axum web
+//! framework.
+//!
+//! The `rocket` feature adds the `rocket` module, with a fairing, request guards and responders
+//! for the Rocket web framework.
+//!
+//! Errors can be presented to users without matching on `Display` output, using the stable
+//! `ErrorCode` returned by `VerifyError::code` and friends. Implement `MessageCatalog` to map codes
+//! to localized messages, or use the English messages in `EnglishCatalog`.
+//!
+//! The `replay` module can record login flows through a `Client`, and replay them offline, to
+//! reproduce login failures reported by users.
+//!
+//! The `prelude` module re-exports the most commonly needed types, and is the recommended import
+//! surface for applications and framework integrations.
+//!
+//! Use `capabilities` to report the algorithms, response modes and Cargo features of a build, for
+//! example in startup logs.
+//!
+//! The minimum required Rust version is 1.75.
+
+#[cfg(feature = "actix")]
+pub mod actix;
+#[cfg(feature = "axum")]
+pub mod axum;
+#[cfg(feature = "client")]
+mod binding;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "client")]
+mod broker;
+#[cfg(feature = "client")]
+mod callback;
+mod capabilities;
+#[cfg(feature = "client")]
+mod client;
+mod clock;
+#[cfg(feature = "client")]
+mod email;
+#[cfg(feature = "client")]
+mod endpoint;
+#[cfg(feature = "client")]
+mod events;
+#[cfg(feature = "client")]
+mod failures;
+#[cfg(feature = "client")]
+mod fragment;
+pub mod jwk;
+pub mod jws;
+#[cfg(feature = "client")]
+mod key_verifier;
+#[cfg(feature = "client")]
+mod login_hook;
+mod messages;
 mod misc;
+#[cfg(feature = "client")]
+mod pool;
+pub mod prelude;
+#[cfg(feature = "client")]
+mod rate_limit;
+#[cfg(feature = "client")]
+pub mod replay;
+#[cfg(feature = "client")]
+mod return_to;
+#[cfg(feature = "rocket")]
+pub mod rocket;
+#[cfg(feature = "client")]
+mod secret;
+#[cfg(feature = "client")]
+mod session;
+#[cfg(any(feature = "client", feature = "http-hyper", feature = "http-reqwest"))]
 mod store;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
+mod validator;
 
-use misc::DynErr;
-use serde::Deserialize;
-use std::{
-    sync::Arc,
-    time::{Duration, SystemTime, UNIX_EPOCH},
-};
-use thiserror::Error;
-use url::Url;
-
-use crate::misc::DiscoveryDoc;
+#[cfg(feature = "tokio")]
+use std::time::Duration;
 
-pub use crate::{misc::ResponseMode, store::*};
-
-/// Errors that can result from `Builder::build`.
-#[derive(Debug, Error)]
-pub enum BuildError {
-    #[error("the configured server URL cannot be used")]
-    InvalidServer,
-    #[error("the configured redirect URI cannot be used")]
-    InvalidRedirectUri,
-    #[error("the configured server is not an origin (contains additional components)")]
-    ServerNotAnOrigin,
-    #[cfg(not(feature = "simple-store"))]
-    #[error("no default store is available")]
-    NoDefaultStore,
-}
+use thiserror::Error;
 
-/// Errors that can result from `Client::start_auth`.
-#[derive(Debug, Error)]
-pub enum StartAuthError {
-    #[error("could not fetch discovery document: {0}")]
-    FetchDiscovery(#[source] FetchError),
-    #[error("could not parse discovery document: {0}")]
-    ParseDiscovery(#[source] serde_json::Error),
-    #[error("could not generate nonce: {0}")]
-    GenerateNonce(#[source] DynErr),
-}
+#[cfg(feature = "client")]
+use crate::misc::DynErr;
+
+#[cfg(any(feature = "client", feature = "http-hyper", feature = "http-reqwest"))]
+pub use crate::store::*;
+#[cfg(feature = "client")]
+pub use crate::{
+    binding::*,
+    broker::*,
+    callback::*,
+    client::*,
+    email::*,
+    endpoint::{Availability, EndpointProbe, KeyInfo, KeySetChange},
+    events::*,
+    failures::*,
+    fragment::*,
+    key_verifier::*,
+    login_hook::*,
+    misc::{DiscoveryDoc, ResponseMode},
+    pool::*,
+    rate_limit::*,
+    return_to::*,
+    secret::*,
+};
+pub use crate::{capabilities::*, clock::*, messages::*, validator::*};
 
 /// Errors that can result from `Client::verify`.
 #[derive(Debug, Error)]
 pub enum VerifyError {
+    #[cfg(feature = "client")]
     #[error("could not fetch discovery document: {0}")]
     FetchDiscovery(#[source] FetchError),
+    #[cfg(feature = "client")]
     #[error("could not parse discovery document: {0}")]
     ParseDiscovery(#[source] serde_json::Error),
+    #[cfg(feature = "client")]
+    #[error("invalid URL in discovery document: {0}")]
+    InvalidDiscoveryUrl(#[source] url::ParseError),
+    #[cfg(feature = "client")]
+    #[error("invalid discovery document: {0}")]
+    InvalidDiscovery(#[source] DiscoveryError),
+    #[cfg(feature = "client")]
     #[error("could not fetch keys document: {0}")]
     FetchJwks(#[source] FetchError),
+    #[cfg(feature = "client")]
     #[error("could not parse keys document: {0}")]
     ParseJwks(#[source] serde_json::Error),
     #[error("could not verify token signature: {0}")]
@@ -87,292 +225,154 @@ pub enum VerifyError {
     TokenExpired,
     #[error("the token issue time is in the future")]
     IssuedInTheFuture,
+    #[error("the system clock is not reliable")]
+    ClockUnreliable,
+    #[error("the token is missing the required claim '{0}'")]
+    MissingClaim(&'static str),
+    #[error("the token has the unexpected claim '{0}'")]
+    UnexpectedClaim(String),
+    #[error("the token email address is not normalized")]
+    EmailNotNormalized,
     #[error("the server changed the email address, but is not trusted")]
     UntrustedServerChangedEmail,
+    #[error("the token '{0}' claim did not match")]
+    HashMismatch(&'static str),
+    #[error("cannot verify token hash claims for algorithm '{0}'")]
+    UnsupportedHashAlg(String),
+    #[cfg(feature = "client")]
     #[error("could not verify the session: {0}")]
     VerifySession(#[source] DynErr),
+    #[cfg(feature = "client")]
     #[error("the session is invalid or has expired")]
     InvalidSession,
+    #[cfg(feature = "client")]
+    #[error("the session was started by a different user agent")]
+    SessionBindingMismatch,
+    #[cfg(feature = "client")]
+    #[error("the token arrived on a different redirect URI than the login was started with")]
+    RedirectUriMismatch,
+    #[cfg(feature = "client")]
+    #[error("the store did not respond in time")]
+    StoreTimeout,
+    /// The deadline passed to `Client::verify_with_deadline` expired.
+    #[cfg(feature = "tokio")]
+    #[error("verification did not complete within {0:?}")]
+    DeadlineExceeded(Duration),
+    /// The blocking task verifying the signature was cancelled, because the runtime is shutting
+    /// down. See `Builder::offload_rsa_verification`.
+    #[cfg(feature = "tokio")]
+    #[error("signature verification was cancelled")]
+    Cancelled,
+    #[cfg(feature = "client")]
+    #[error("the broker keys were rejected: {0}")]
+    KeysRejected(#[source] DynErr),
+    #[cfg(feature = "client")]
+    #[error("key continuity check failed: {0}")]
+    KeyContinuity(#[source] KeyContinuityError),
+    #[cfg(feature = "client")]
+    #[error("the login was rejected: {0}")]
+    LoginRejected(#[source] DynErr),
+    #[cfg(feature = "client")]
+    #[error("the token key is not pinned: {0}")]
+    KeyNotPinned(String),
+    /// The token is longer than `Builder::max_token_len`.
+    #[cfg(feature = "client")]
+    #[error("the token is longer than {max} bytes")]
+    TokenTooLong { max: usize },
 }
 
-/// A builder to configure a `Client`.
-#[derive(Clone)]
-pub struct Builder {
-    store: Option<Arc<dyn Store>>,
-    server: Option<Url>,
-    trusted: bool,
-    redirect_uri: Url,
-    response_mode: ResponseMode,
-    leeway: Duration,
-}
-
-impl Builder {
-    fn new(redirect_uri: Url) -> Self {
-        Builder {
-            store: None,
-            server: None,
-            trusted: true,
-            redirect_uri,
-            response_mode: ResponseMode::default(),
-            leeway: Duration::from_secs(180),
+impl VerifyError {
+    /// A stable, user-facing classification of the error. See `ErrorCode`.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            #[cfg(feature = "client")]
+            VerifyError::FetchDiscovery(_)
+            | VerifyError::ParseDiscovery(_)
+            | VerifyError::InvalidDiscoveryUrl(_)
+            | VerifyError::InvalidDiscovery(_)
+            | VerifyError::FetchJwks(_)
+            | VerifyError::ParseJwks(_)
+            | VerifyError::KeysRejected(_)
+            | VerifyError::KeyContinuity(_) => ErrorCode::BrokerUnavailable,
+            #[cfg(feature = "client")]
+            VerifyError::VerifySession(_) | VerifyError::StoreTimeout => {
+                ErrorCode::StoreUnavailable
+            }
+            #[cfg(feature = "tokio")]
+            VerifyError::DeadlineExceeded(_) => ErrorCode::BrokerUnavailable,
+            #[cfg(feature = "tokio")]
+            VerifyError::Cancelled => ErrorCode::BrokerUnavailable,
+            #[cfg(feature = "client")]
+            VerifyError::InvalidSession => ErrorCode::LoginExpired,
+            #[cfg(feature = "client")]
+            VerifyError::SessionBindingMismatch
+            | VerifyError::RedirectUriMismatch
+            | VerifyError::KeyNotPinned(_)
+            | VerifyError::TokenTooLong { .. } => ErrorCode::InvalidToken,
+            #[cfg(feature = "client")]
+            VerifyError::LoginRejected(_) => ErrorCode::LoginRejected,
+            VerifyError::ClockUnreliable => ErrorCode::ServiceUnavailable,
+            VerifyError::TokenExpired => ErrorCode::LoginExpired,
+            VerifyError::Signature(_)
+            | VerifyError::InvalidPayload(_)
+            | VerifyError::IssuerInvalid
+            | VerifyError::AudienceInvalid
+            | VerifyError::IssuedInTheFuture
+            | VerifyError::MissingClaim(_)
+            | VerifyError::UnexpectedClaim(_)
+            | VerifyError::EmailNotNormalized
+            | VerifyError::UntrustedServerChangedEmail
+            | VerifyError::HashMismatch(_)
+            | VerifyError::UnsupportedHashAlg(_) => ErrorCode::InvalidToken,
         }
     }
 
-    /// Use the given `Store` for cache and session storage.
-    ///
-    /// If no store is specified, a default `MemoryStore` is created. This type of store has some
-    /// limitations. See the documentation for `MemoryStore` for details.
-    pub fn store(mut self, store: Arc<dyn Store>) -> Self {
-        self.store = Some(store);
-        self
-    }
-
-    /// Configure the client to use a trusted broker.
-    ///
-    /// This allows you to override the default broker `https://broker.portier.io` with your own.
-    /// The `url` must be an origin only. (Only scheme, host, and optionally port. No path, query
-    /// string, etc.)
-    pub fn broker(mut self, url: Url) -> Self {
-        self.server = Some(url);
-        self.trusted = true;
-        self
-    }
-
-    /// Configure the client to use an untrusted identity provider.
-    ///
-    /// This is usually only used when implementing a broker. For configuring a relying party to
-    /// use a custom broker, see `Builder::broker` instead.
-    pub fn idp(mut self, url: Url) -> Self {
-        self.server = Some(url);
-        self.trusted = false;
-        self
-    }
-
-    /// Configure the response mode to use. The default is `FormPost`.
-    pub fn response_mode(mut self, mode: ResponseMode) -> Self {
-        self.response_mode = mode;
-        self
-    }
-
-    /// Configure the leeway to allow for timestamps in tokens. The default is 3 minutes.
-    pub fn leeway(mut self, dur: Duration) -> Self {
-        self.leeway = dur;
-        self
-    }
-
-    /// Verify the configuration and build the client.
-    pub fn build(self) -> Result<Client, BuildError> {
-        let store = match self.store {
-            Some(store) => store,
-            #[cfg(feature = "simple-store")]
-            None => Arc::new(MemoryStore::default()),
-            #[cfg(not(feature = "simple-store"))]
-            None => return Err(BuildError::NoDefaultStore),
-        };
-
-        let server = self
-            .server
-            .unwrap_or_else(|| "https://broker.portier.io".parse().unwrap());
-
-        let server_origin = server.origin();
-        if !server_origin.is_tuple() {
-            return Err(BuildError::InvalidServer);
+    /// An operator-facing classification of the error. See `ErrorKind`.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            #[cfg(feature = "client")]
+            VerifyError::FetchDiscovery(_)
+            | VerifyError::ParseDiscovery(_)
+            | VerifyError::InvalidDiscoveryUrl(_)
+            | VerifyError::InvalidDiscovery(_)
+            | VerifyError::FetchJwks(_)
+            | VerifyError::ParseJwks(_) => ErrorKind::Upstream,
+            #[cfg(feature = "client")]
+            VerifyError::KeysRejected(_) | VerifyError::KeyContinuity(_) => ErrorKind::Protocol,
+            #[cfg(feature = "tokio")]
+            VerifyError::DeadlineExceeded(_) => ErrorKind::Upstream,
+            #[cfg(feature = "tokio")]
+            VerifyError::Cancelled => ErrorKind::Environment,
+            #[cfg(feature = "client")]
+            VerifyError::VerifySession(_) | VerifyError::StoreTimeout => ErrorKind::Storage,
+            #[cfg(feature = "client")]
+            VerifyError::InvalidSession => ErrorKind::Expired,
+            #[cfg(feature = "client")]
+            VerifyError::KeyNotPinned(_) => ErrorKind::Protocol,
+            #[cfg(feature = "client")]
+            VerifyError::SessionBindingMismatch
+            | VerifyError::RedirectUriMismatch
+            | VerifyError::TokenTooLong { .. } => ErrorKind::UserInput,
+            #[cfg(feature = "client")]
+            VerifyError::LoginRejected(_) => ErrorKind::Policy,
+            VerifyError::ClockUnreliable => ErrorKind::Environment,
+            VerifyError::TokenExpired => ErrorKind::Expired,
+            VerifyError::Signature(_)
+            | VerifyError::InvalidPayload(_)
+            | VerifyError::IssuerInvalid
+            | VerifyError::AudienceInvalid
+            | VerifyError::IssuedInTheFuture
+            | VerifyError::MissingClaim(_)
+            | VerifyError::UnexpectedClaim(_)
+            | VerifyError::EmailNotNormalized
+            | VerifyError::UntrustedServerChangedEmail
+            | VerifyError::HashMismatch(_)
+            | VerifyError::UnsupportedHashAlg(_) => ErrorKind::Protocol,
         }
-
-        let client_origin = self.redirect_uri.origin();
-        if !client_origin.is_tuple() {
-            return Err(BuildError::InvalidRedirectUri);
-        }
-
-        let client_id = client_origin.ascii_serialization();
-        let server_id = server_origin.ascii_serialization();
-
-        // Verify server URL is an origin only. We can compare it with the ASCII origin, because
-        // `Url` is internally ASCII as well. It may contain a `/` path, though.
-        let server_str = server.as_str();
-        if !(server_str == server_id
-            || (server_str.len() == server_id.len() + 1
-                && server_str.starts_with(&server_id)
-                && server_str.ends_with('/')))
-        {
-            return Err(BuildError::ServerNotAnOrigin);
-        }
-
-        let mut discovery_url = server;
-        discovery_url.set_path("/.well-known/openid-configuration");
-
-        Ok(Client {
-            store,
-            server_id,
-            discovery_url,
-            trusted: self.trusted,
-            redirect_uri: self.redirect_uri,
-            client_id,
-            response_mode: self.response_mode,
-            leeway: self.leeway,
-        })
-    }
-}
-
-/// A client for performing Portier authentication.
-///
-/// Create a client using either `Client::builder` or `Client::new`. Sharing a client can be done
-/// simply by reference, even across threads. All methods take an immutable reference to `self`
-/// only.
-///
-/// If necessary, a client can also be cloned. This is not cheap, however, because settings within
-/// are also cloned. The exception is the store, which is shared between clones.
-#[derive(Clone)]
-pub struct Client {
-    store: Arc<dyn Store>,
-    server_id: String,
-    discovery_url: Url,
-    trusted: bool,
-    redirect_uri: Url,
-    client_id: String,
-    response_mode: ResponseMode,
-    leeway: Duration,
-}
-
-impl Client {
-    /// Create a builder-style struct to configure a Client.
-    pub fn builder(redirect_uri: Url) -> Builder {
-        Builder::new(redirect_uri)
     }
 
-    /// Create a client with default settings.
-    ///
-    /// This uses a `MemoryStore`, which has some limitations. See the documentation for
-    /// `MemoryStore` for details.
-    #[cfg(feature = "simple-store")]
-    pub fn new(redirect_uri: Url) -> Self {
-        Builder::new(redirect_uri).build().unwrap()
-    }
-
-    /// Create a login session for the given email, and return a URL to redirect the user agent
-    /// (browser) to so authentication can continue.
-    ///
-    /// If performing the redirect in the HTTP response, the recommended method is to send a 303
-    /// HTTP status code with the `Location` header set to the URL. But other solutions are
-    /// possible, such as fetching this URL using a request from client-side JavaScript.
-    ///
-    /// The caller may add a `state` query parameter to the returned URL, which is passed verbatim
-    /// to the redirect URI after the user returns.
-    pub async fn start_auth(&self, email: &str) -> Result<Url, StartAuthError> {
-        let discovery = self
-            .store
-            .fetch(self.discovery_url.clone())
-            .await
-            .map_err(StartAuthError::FetchDiscovery)?;
-        let discovery: DiscoveryDoc =
-            serde_json::from_slice(&discovery).map_err(StartAuthError::ParseDiscovery)?;
-
-        let nonce = self
-            .store
-            .new_nonce(email.to_owned())
-            .await
-            .map_err(StartAuthError::GenerateNonce)?;
-        let mut auth_url = discovery.authorization_endpoint;
-        auth_url
-            .query_pairs_mut()
-            .append_pair("login_hint", email)
-            .append_pair("scope", "openid email")
-            .append_pair("nonce", &nonce)
-            .append_pair("response_type", "id_token")
-            .append_pair("response_mode", self.response_mode.as_str())
-            .append_pair("client_id", &self.client_id)
-            .append_pair("redirect_uri", self.redirect_uri.as_str());
-        Ok(auth_url)
-    }
-
-    /// Verify `token` and return a verified email address.
-    ///
-    /// The token is delivered by the user agent (browser) directly according to the `redirect_uri`
-    /// and `response_mode` configured when the `Client` was created.
-    pub async fn verify(&self, token: &str) -> Result<String, VerifyError> {
-        let discovery = self
-            .store
-            .fetch(self.discovery_url.clone())
-            .await
-            .map_err(VerifyError::FetchDiscovery)?;
-        let discovery: DiscoveryDoc =
-            serde_json::from_slice(&discovery).map_err(VerifyError::ParseDiscovery)?;
-
-        let jwks = self
-            .store
-            .fetch(discovery.jwks_uri)
-            .await
-            .map_err(VerifyError::FetchJwks)?;
-        let jwks: jwk::KeySet = serde_json::from_slice(&jwks).map_err(VerifyError::ParseJwks)?;
-
-        // Basic token signature verification, parsing, and claim validation.
-        #[derive(Deserialize)]
-        struct Payload {
-            iss: String,
-            aud: String,
-            email: String,
-            email_original: Option<String>,
-            #[serde(deserialize_with = "misc::deserialize_timestamp")]
-            iat: u64,
-            #[serde(deserialize_with = "misc::deserialize_timestamp")]
-            exp: u64,
-            nonce: String,
-        }
-        let payload = jws::verify(token, &jwks.keys)?;
-        let payload: Payload =
-            serde_json::from_slice(&payload).map_err(VerifyError::InvalidPayload)?;
-        if payload.iss != self.server_id {
-            return Err(VerifyError::IssuerInvalid);
-        }
-        if payload.aud != self.client_id {
-            return Err(VerifyError::AudienceInvalid);
-        }
-
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("current system time is before Unix epoch")
-            .as_secs();
-
-        let exp_stretched = payload
-            .exp
-            .checked_add(self.leeway.as_secs())
-            .unwrap_or(u64::MIN);
-        if exp_stretched < now {
-            return Err(VerifyError::TokenExpired);
-        }
-
-        let iat_stretched = payload
-            .iat
-            .checked_sub(self.leeway.as_secs())
-            .unwrap_or(u64::MAX);
-        if now < iat_stretched {
-            return Err(VerifyError::IssuedInTheFuture);
-        }
-
-        // If verifying an IdP token, it can't change the email address per spec. The spec assumes
-        // the client is a Broker, in this case, and has already done normalization.
-        if !self.trusted {
-            match payload.email_original {
-                None => {}
-                Some(ref orig) if orig == &payload.email => {}
-                Some(_) => return Err(VerifyError::UntrustedServerChangedEmail),
-            }
-        }
-
-        // Check the pair (nonce, email_original) exists in the store.
-        let email_original = match payload.email_original {
-            Some(email) => email,
-            None => payload.email.clone(),
-        };
-        if !self
-            .store
-            .consume_nonce(payload.nonce, email_original)
-            .await
-            .map_err(VerifyError::VerifySession)?
-        {
-            return Err(VerifyError::InvalidSession);
-        }
-
-        Ok(payload.email)
+    /// Whether verifying may succeed when tried again later. See `ErrorKind::is_retryable`.
+    pub fn is_retryable(&self) -> bool {
+        self.kind().is_retryable()
     }
 }
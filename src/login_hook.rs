@@ -0,0 +1,48 @@
+use crate::{
+    misc::{DynErr, DynFutRes},
+    ClientInfo, VerifiedToken, VerifyError,
+};
+
+/// A login verification, as reported to a `LoginHook`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct LoginAttempt<'a> {
+    /// The verified (normalized) email address, or `None` if verification failed.
+    pub email: Option<&'a str>,
+    /// The issuer of the broker the token claims to be from, or `None` if it is not configured.
+    pub broker: Option<&'a str>,
+    /// The `kid` from the token header, without verifying the token.
+    pub kid: Option<&'a str>,
+    /// Properties of the user agent completing the login, as passed to `Client::verify_with_info`.
+    pub client_info: &'a ClientInfo,
+    /// The result of verification.
+    pub outcome: Result<&'a VerifiedToken, &'a VerifyError>,
+}
+
+/// Inspects login verifications, and can reject suspicious logins.
+///
+/// Deployments can use this to feed fraud or abuse detection systems, and block logins they flag.
+/// Configure a hook using `Builder::login_hook`.
+///
+/// The hook is called once by every `Client::verify` and `Client::verify_pending`, including
+/// when verification fails. If verification succeeded and the hook returns an error, verification
+/// fails with `VerifyError::LoginRejected`. Note that the login session is consumed at that point,
+/// so the user has to start a new login. Errors returned for failed verifications are ignored.
+///
+/// This is implemented for closures of the form
+/// `Fn(&LoginAttempt) -> Result<(), Box<dyn Error + Send + Sync>>`, for hooks that don't need to
+/// perform I/O.
+pub trait LoginHook: Send + Sync + 'static {
+    /// Inspect `attempt`, and return an error to reject the login.
+    fn check_login(&self, attempt: &LoginAttempt<'_>) -> DynFutRes<()>;
+}
+
+impl<F> LoginHook for F
+where
+    F: Fn(&LoginAttempt<'_>) -> Result<(), DynErr> + Send + Sync + 'static,
+{
+    fn check_login(&self, attempt: &LoginAttempt<'_>) -> DynFutRes<()> {
+        let result = self(attempt);
+        Box::pin(async move { result })
+    }
+}
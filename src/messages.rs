@@ -0,0 +1,207 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use serde::{Deserialize, Serialize};
+
+/// A stable, user-facing classification of errors.
+///
+/// Errors such as `VerifyError` distinguish many causes that are only relevant to operators, and
+/// their `Display` output is meant for logs. Use the `code` method of an error to get one of these
+/// codes instead, and a `MessageCatalog` to turn it into a message for the user.
+///
+/// The strings returned by `ErrorCode::as_str` are stable, and can be used as keys in translation
+/// files. New codes may be added in minor releases.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ErrorCode {
+    /// The broker could not be reached, or its configuration could not be used.
+    BrokerUnavailable,
+    /// The broker reported an error, for example because the user cancelled the login.
+    BrokerError,
+    /// The session store could not be reached.
+    StoreUnavailable,
+    /// The login took too long, or was already completed.
+    LoginExpired,
+    /// The token is not valid.
+    InvalidToken,
+    /// The request to the redirect URI is missing the expected parameters.
+    InvalidCallback,
+    /// The login was verified, but rejected by the application.
+    LoginRejected,
+    /// Too many logins were started, and the user should wait before trying again.
+    RateLimited,
+    /// The service can't verify logins right now, for example because its system clock is not set.
+    ServiceUnavailable,
+}
+
+impl ErrorCode {
+    /// Every code, for example to check that a catalog is complete.
+    pub const ALL: &'static [ErrorCode] = &[
+        ErrorCode::BrokerUnavailable,
+        ErrorCode::BrokerError,
+        ErrorCode::StoreUnavailable,
+        ErrorCode::LoginExpired,
+        ErrorCode::InvalidToken,
+        ErrorCode::InvalidCallback,
+        ErrorCode::LoginRejected,
+        ErrorCode::RateLimited,
+        ErrorCode::ServiceUnavailable,
+    ];
+
+    /// The stable string form of the code.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::BrokerUnavailable => "broker_unavailable",
+            ErrorCode::BrokerError => "broker_error",
+            ErrorCode::StoreUnavailable => "store_unavailable",
+            ErrorCode::LoginExpired => "login_expired",
+            ErrorCode::InvalidToken => "invalid_token",
+            ErrorCode::InvalidCallback => "invalid_callback",
+            ErrorCode::LoginRejected => "login_rejected",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::ServiceUnavailable => "service_unavailable",
+        }
+    }
+
+    /// The message for this code from `catalog`, or the message from `EnglishCatalog` if the
+    /// catalog doesn't have one.
+    pub fn message(&self, catalog: &dyn MessageCatalog) -> Cow<'static, str> {
+        match catalog.message(*self) {
+            Some(message) => Cow::Owned(message),
+            None => Cow::Borrowed(EnglishCatalog::text(*self)),
+        }
+    }
+}
+
+/// An operator-facing classification of errors, to decide how to handle them.
+///
+/// Where `ErrorCode` chooses a message for the user, this tells whether the problem lies with the
+/// user, the broker, or the store. Use the `kind` method of an error to get one. New kinds may be
+/// added in minor releases.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The request or the login was not acceptable, for example because the user cancelled.
+    UserInput,
+    /// The login took too long, or was already completed. The user can start a new login.
+    Expired,
+    /// The broker could not be reached, or its documents could not be used.
+    Upstream,
+    /// The session store failed or did not respond in time.
+    Storage,
+    /// The token or a broker response violates the protocol, which may indicate an attack or a
+    /// configuration mistake.
+    Protocol,
+    /// The local environment can't be relied on, for example because the system clock is not set.
+    Environment,
+    /// The login was verified, but rejected by the application.
+    Policy,
+}
+
+impl ErrorKind {
+    /// Whether retrying the operation later may succeed, because the cause is likely temporary.
+    ///
+    /// This is the case for `ErrorKind::Upstream`, `ErrorKind::Storage` and
+    /// `ErrorKind::Environment`, as the system clock may be set once the device syncs its time.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ErrorKind::Upstream | ErrorKind::Storage | ErrorKind::Environment
+        )
+    }
+}
+
+/// Maps error codes to messages for users, for example in a specific language.
+///
+/// This is implemented for `HashMap<String, String>`, keyed by `ErrorCode::as_str`, so that
+/// catalogs can be loaded from translation files, and for closures of the form
+/// `Fn(ErrorCode) -> Option<String>`. `EnglishCatalog` provides default messages.
+pub trait MessageCatalog: Send + Sync {
+    /// The message for `code`, or `None` if the catalog has no message for it.
+    fn message(&self, code: ErrorCode) -> Option<String>;
+}
+
+impl MessageCatalog for HashMap<String, String> {
+    fn message(&self, code: ErrorCode) -> Option<String> {
+        self.get(code.as_str()).cloned()
+    }
+}
+
+impl<F> MessageCatalog for F
+where
+    F: Fn(ErrorCode) -> Option<String> + Send + Sync,
+{
+    fn message(&self, code: ErrorCode) -> Option<String> {
+        self(code)
+    }
+}
+
+/// A `MessageCatalog` with English messages for every code.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EnglishCatalog;
+
+impl EnglishCatalog {
+    fn text(code: ErrorCode) -> &'static str {
+        match code {
+            ErrorCode::BrokerUnavailable => {
+                "The login service is temporarily unavailable. Please try again later."
+            }
+            ErrorCode::BrokerError => "The login was not completed. Please try again.",
+            ErrorCode::StoreUnavailable => {
+                "Logging in is temporarily unavailable. Please try again later."
+            }
+            ErrorCode::LoginExpired => {
+                "The login link has expired or was already used. Please log in again."
+            }
+            ErrorCode::InvalidToken => "The login could not be verified. Please log in again.",
+            ErrorCode::InvalidCallback => "The login response was incomplete. Please log in again.",
+            ErrorCode::LoginRejected => "The login was not allowed.",
+            ErrorCode::RateLimited => "Too many login attempts. Please try again later.",
+            ErrorCode::ServiceUnavailable => {
+                "Logging in is temporarily unavailable. Please try again later."
+            }
+        }
+    }
+}
+
+impl MessageCatalog for EnglishCatalog {
+    fn message(&self, code: ErrorCode) -> Option<String> {
+        Some(EnglishCatalog::text(code).to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn has_unique_codes() {
+        let mut seen = std::collections::HashSet::new();
+        for code in ErrorCode::ALL {
+            assert!(seen.insert(code.as_str()), "{code:?}");
+            let json = serde_json::to_value(code).unwrap();
+            assert_eq!(json, code.as_str());
+        }
+    }
+
+    #[test]
+    fn falls_back_to_english() {
+        let mut catalog = HashMap::new();
+        catalog.insert(
+            "service_unavailable".to_owned(),
+            "Inloggen is tijdelijk niet mogelijk.".to_owned(),
+        );
+        assert_eq!(
+            ErrorCode::ServiceUnavailable.message(&catalog),
+            "Inloggen is tijdelijk niet mogelijk."
+        );
+        assert_eq!(
+            ErrorCode::ServiceUnavailable.message(&EnglishCatalog),
+            "Logging in is temporarily unavailable. Please try again later."
+        );
+        assert_eq!(
+            ErrorCode::RateLimited.message(&catalog),
+            EnglishCatalog::text(ErrorCode::RateLimited)
+        );
+    }
+}
@@ -1,28 +1,50 @@
-use serde::{de::Visitor, Deserialize};
-use std::{fmt, future::Future, pin::Pin};
+use serde::de::Visitor;
+#[cfg(feature = "client")]
+use serde::Deserialize;
+use std::fmt;
+#[cfg(any(feature = "client", feature = "http-hyper", feature = "http-reqwest"))]
+use std::{future::Future, pin::Pin};
+#[cfg(feature = "client")]
 use url::Url;
 
+#[cfg(any(feature = "client", feature = "http-hyper", feature = "http-reqwest"))]
 pub type DynErr = Box<dyn std::error::Error + Send + Sync>;
+#[cfg(any(feature = "client", feature = "http-hyper", feature = "http-reqwest"))]
 pub type DynFut<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+#[cfg(any(feature = "client", feature = "http-hyper", feature = "http-reqwest"))]
 pub type DynRes<T> = Result<T, DynErr>;
+#[cfg(any(feature = "client", feature = "http-hyper", feature = "http-reqwest"))]
 pub type DynFutRes<T> = DynFut<DynRes<T>>;
 
+/// Record a value for a field of the current span, if the `tracing` feature is enabled.
+#[cfg(feature = "client")]
+macro_rules! record_span {
+    ($field:literal, $value:expr) => {
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record($field, $value);
+    };
+}
+#[cfg(feature = "client")]
+pub(crate) use record_span;
+
 /// Supported response modes.
 ///
 /// The response mode specifies how the server instructs the user agent to return a response to the
 /// `redirect_uri` of the client.
-#[derive(Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[cfg(feature = "client")]
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
 pub enum ResponseMode {
     /// Send the response data in the URL fragment.
     ///
     /// Additional client-side JavaScript is required to use this mode, because the URL fragment is
-    /// not sent to the server.
+    /// not sent to the server. See `Client::fragment_relay` for help setting up the endpoints.
     Fragment,
     /// Send the response data in a POST request with an `application/x-www-form-urlencoded` body.
     #[default]
     FormPost,
 }
 
+#[cfg(feature = "client")]
 impl ResponseMode {
     /// Convert to the `response_mode` query string value.
     pub fn as_str(&self) -> &'static str {
@@ -33,11 +55,77 @@ impl ResponseMode {
     }
 }
 
-/// OpenID Connect discovery document.
-#[derive(Deserialize)]
+/// OpenID Connect discovery document of a broker. See `Client::discovery`.
+///
+/// URLs are as published by the broker, and may be relative if `Builder::relative_discovery_urls`
+/// is enabled. Optional metadata the broker does not publish is `None`.
+#[cfg(feature = "client")]
+#[derive(Clone, Debug, Deserialize)]
+#[non_exhaustive]
 pub struct DiscoveryDoc {
-    pub jwks_uri: Url,
-    pub authorization_endpoint: Url,
+    /// The issuer identifier of the broker.
+    pub issuer: Option<String>,
+    /// URL of the keys document.
+    pub jwks_uri: String,
+    /// URL of the authorization endpoint.
+    pub authorization_endpoint: String,
+    /// Supported `response_mode` values.
+    #[serde(default)]
+    pub response_modes_supported: Option<Vec<String>>,
+    /// Supported `response_type` values.
+    #[serde(default)]
+    pub response_types_supported: Option<Vec<String>>,
+    /// Supported `scope` values.
+    #[serde(default)]
+    pub scopes_supported: Option<Vec<String>>,
+    /// Supported token signing algorithms.
+    #[serde(default)]
+    pub id_token_signing_alg_values_supported: Option<Vec<String>>,
+    /// Supported claims.
+    #[serde(default)]
+    pub claims_supported: Option<Vec<String>>,
+}
+
+#[cfg(feature = "client")]
+impl DiscoveryDoc {
+    /// Parse a URL from the document, resolving relative URLs against `base` if given.
+    pub(crate) fn parse_url(value: &str, base: Option<&Url>) -> Result<Url, url::ParseError> {
+        match base {
+            Some(base) => base.join(value),
+            None => Url::parse(value),
+        }
+    }
+
+    /// Whether the broker supports `mode`.
+    ///
+    /// Brokers that do not publish `response_modes_supported` are assumed to support all modes.
+    pub fn supports_response_mode(&self, mode: ResponseMode) -> bool {
+        self.response_modes_supported
+            .as_ref()
+            .map_or(true, |modes| modes.iter().any(|m| m == mode.as_str()))
+    }
+}
+
+/// The `User-Agent` sent with document fetches.
+#[cfg(any(feature = "memory-store", feature = "blocking"))]
+pub const USER_AGENT: &str = concat!("portier-rs/", env!("CARGO_PKG_VERSION"));
+
+/// Parse the `max-age` directive from a `Cache-Control` header value.
+#[cfg(any(feature = "memory-store", feature = "blocking"))]
+pub fn parse_max_age(cache_control: Option<&str>) -> Option<u64> {
+    cache_control?
+        .split(',')
+        .find_map(|s| s.trim().strip_prefix("max-age="))?
+        .parse()
+        .ok()
+}
+
+/// Parse a `Retry-After` header value in delay-seconds format.
+///
+/// The HTTP-date format is not supported, and results in `None`.
+#[cfg(any(feature = "memory-store", feature = "blocking"))]
+pub fn parse_retry_after(retry_after: Option<&str>) -> Option<u64> {
+    retry_after?.trim().parse().ok()
 }
 
 /// Function used to deserialize Unix timestamps in a JWT.
@@ -74,9 +162,36 @@ where
     deserializer.deserialize_any(TimestampVisitor)
 }
 
+/// Overwrite sensitive data in `buf` before it is freed, if the `zeroize` feature is enabled.
+///
+/// This also clears `buf`, but keeps its capacity.
+pub fn wipe(buf: &mut Vec<u8>) {
+    #[cfg(feature = "zeroize")]
+    zeroize::Zeroize::zeroize(buf);
+    buf.clear();
+}
+
+/// Like `wipe`, for strings.
+#[cfg(any(feature = "memory-store", feature = "blocking"))]
+pub fn wipe_str(buf: &mut String) {
+    #[cfg(feature = "zeroize")]
+    zeroize::Zeroize::zeroize(buf);
+    buf.clear();
+}
+
 pub mod base64url {
+    use std::cell::RefCell;
+
     pub use base64::prelude::*;
 
+    /// Buffers that grew larger than this are not kept for reuse.
+    const MAX_REUSED_CAPACITY: usize = 4096;
+
+    thread_local! {
+        static BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    }
+
+    #[cfg(feature = "client")]
     #[inline]
     pub fn encode<T: ?Sized + AsRef<[u8]>>(data: &T) -> String {
         BASE64_URL_SAFE_NO_PAD.encode(data)
@@ -86,4 +201,33 @@ pub mod base64url {
     pub fn decode<T: ?Sized + AsRef<[u8]>>(data: &T) -> Result<Vec<u8>, base64::DecodeError> {
         BASE64_URL_SAFE_NO_PAD.decode(data)
     }
+
+    /// Decode into a thread-local buffer, and pass the result to `f`.
+    ///
+    /// This avoids an allocation for data that is only needed temporarily. If the buffer is
+    /// already in use, for example because `f` calls this function again, a new buffer is used.
+    /// The decoded data is wiped afterwards. See `wipe`.
+    pub fn with_decoded<T: ?Sized + AsRef<[u8]>, R>(
+        data: &T,
+        f: impl FnOnce(&[u8]) -> R,
+    ) -> Result<R, base64::DecodeError> {
+        BUFFER.with(|buffer| match buffer.try_borrow_mut() {
+            Ok(mut buffer) => {
+                buffer.clear();
+                let result = BASE64_URL_SAFE_NO_PAD
+                    .decode_vec(data, &mut buffer)
+                    .map(|()| f(&buffer));
+                super::wipe(&mut buffer);
+                if buffer.capacity() > MAX_REUSED_CAPACITY {
+                    *buffer = Vec::new();
+                }
+                result
+            }
+            Err(_) => decode(data).map(|mut buffer| {
+                let result = f(&buffer);
+                super::wipe(&mut buffer);
+                result
+            }),
+        })
+    }
 }
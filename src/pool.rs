@@ -0,0 +1,144 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use url::Url;
+
+use crate::{BuildError, Builder, Client};
+
+/// A set of `Client`s for different origins, sharing one configuration.
+///
+/// Applications serving many domains need a `Client` per origin, because the redirect URI is part
+/// of the client configuration. This pool builds clients from a template `Builder` on first use,
+/// and caches them. The redirect URI of each client is that of the template, with the origin
+/// replaced.
+///
+/// All clients share the store of the template. If the template has no store, a single default
+/// `MemoryStore` is created for the pool.
+///
+/// A pool created using `ClientPool::with_redirect_uris` only serves a fixed set of origins,
+/// which is useful for an application serving both `example.com` and `www.example.com`. Use
+/// `ClientPool::client_for_host` to select the client for the `Host` of a request.
+pub struct ClientPool {
+    template: Builder,
+    /// Redirect URIs registered using `ClientPool::with_redirect_uris`, keyed by origin. Empty if
+    /// any origin is served.
+    registered: HashMap<String, Url>,
+    clients: Mutex<HashMap<String, Arc<Client>>>,
+}
+
+impl ClientPool {
+    /// Create a pool that builds clients from `template`.
+    pub fn new(template: Builder) -> Self {
+        ClientPool {
+            template: template.with_default_store(),
+            registered: HashMap::new(),
+            clients: Mutex::default(),
+        }
+    }
+
+    /// Create a pool that only serves the origins of `redirect_uris`.
+    ///
+    /// Each client uses the redirect URI registered for its origin, so paths may differ between
+    /// origins. The redirect URI of the template is only used for its scheme in
+    /// `ClientPool::client_for_host`. Clients are built immediately, so configuration errors are
+    /// reported here.
+    pub fn with_redirect_uris(
+        template: Builder,
+        redirect_uris: impl IntoIterator<Item = Url>,
+    ) -> Result<Self, BuildError> {
+        let mut pool = ClientPool::new(template);
+        let mut clients = HashMap::new();
+        for redirect_uri in redirect_uris {
+            let client = Arc::new(pool.template.with_redirect_uri(redirect_uri).build()?);
+            let key = client.redirect_uri().origin().ascii_serialization();
+            pool.registered
+                .insert(key.clone(), client.redirect_uri().clone());
+            clients.insert(key, client);
+        }
+        pool.clients = Mutex::new(clients);
+        Ok(pool)
+    }
+
+    /// The client for `origin`, such as `https://example.com`, building it if necessary.
+    ///
+    /// Returns `BuildError::InvalidRedirectUri` if `origin` is not an origin, and
+    /// `BuildError::UnknownOrigin` if the pool has registered redirect URIs, but none for `origin`.
+    pub fn client_for(&self, origin: &str) -> Result<Arc<Client>, BuildError> {
+        let origin = parse_origin(origin)?;
+        let key = origin.origin().ascii_serialization();
+        if let Some(client) = self.clients.lock().unwrap().get(&key) {
+            return Ok(client.clone());
+        }
+
+        let builder = match self.registered.get(&key) {
+            Some(redirect_uri) => self.template.with_redirect_uri(redirect_uri.clone()),
+            None if self.registered.is_empty() => self.template.for_origin(&origin),
+            None => return Err(BuildError::UnknownOrigin),
+        };
+        // Build outside the lock. If another thread won the race, its client is used.
+        let client = Arc::new(builder.build()?);
+        let mut clients = self.clients.lock().unwrap();
+        Ok(clients.entry(key).or_insert(client).clone())
+    }
+
+    /// The client for a request `host`, such as `www.example.com` or `localhost:8000`.
+    ///
+    /// This is typically the value of the `Host` header. For a pool with registered redirect URIs,
+    /// the client registered with the same host and port is returned, regardless of its scheme.
+    /// Otherwise, the scheme of the template redirect URI is used. Returns
+    /// `BuildError::UnknownOrigin` if no client matches.
+    ///
+    /// The `Host` header is controlled by the user agent, so only use this with a pool with
+    /// registered redirect URIs, or behind a proxy that checks the host.
+    pub fn client_for_host(&self, host: &str) -> Result<Arc<Client>, BuildError> {
+        let scheme = self.template.redirect_uri().scheme();
+        let url = parse_origin(&format!("{}://{}", scheme, host))?;
+        if self.registered.is_empty() {
+            return self.client_for(url.as_str());
+        }
+        let origin = self
+            .registered
+            .values()
+            .find(|uri| uri.host_str() == url.host_str() && uri.port() == url.port())
+            .ok_or(BuildError::UnknownOrigin)?
+            .origin()
+            .ascii_serialization();
+        self.client_for(&origin)
+    }
+
+    /// Remove the cached client for `origin`, for example when a tenant is removed.
+    ///
+    /// Logins already started using the client can still be verified using a new client from
+    /// `ClientPool::client_for`, because the store is shared.
+    pub fn remove(&self, origin: &str) -> Option<Arc<Client>> {
+        let key = parse_origin(origin).ok()?.origin().ascii_serialization();
+        self.clients.lock().unwrap().remove(&key)
+    }
+
+    /// The number of cached clients.
+    pub fn len(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// Whether no clients are cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Parse an origin, rejecting URLs with additional components.
+fn parse_origin(origin: &str) -> Result<Url, BuildError> {
+    let url: Url = origin.parse().map_err(|_| BuildError::InvalidRedirectUri)?;
+    if !url.origin().is_tuple()
+        || url.path() != "/"
+        || url.query().is_some()
+        || url.fragment().is_some()
+        || !url.username().is_empty()
+        || url.password().is_some()
+    {
+        return Err(BuildError::InvalidRedirectUri);
+    }
+    Ok(url)
+}
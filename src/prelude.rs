@@ -0,0 +1,18 @@
+//! Commonly needed types, for glob importing.
+//!
+//! ```
+//! use portier::prelude::*;
+//! ```
+//!
+//! Stability guarantees: items are only ever added to the prelude in minor releases, never
+//! removed or renamed outside of a major release (or a `0.x` minor release, before 1.0). Names
+//! are chosen to be unlikely to conflict with other glob imports, but note that adding an item may
+//! still cause ambiguity with a glob import from another crate. Applications that need full
+//! control should import items individually.
+
+#[cfg(feature = "client")]
+pub use crate::{
+    AuthOptions, Broker, BuildError, Builder, Client, ClientInfo, FetchError, PendingLogin,
+    ResponseMode, StartAuthError, Store,
+};
+pub use crate::{Claims, EmailCase, ErrorCode, SpecVersion, Validator, VerifiedToken, VerifyError};
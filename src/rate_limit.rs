@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{misc::DynFutRes, Clock, SystemClock};
+
+/// The number of buckets a `MemoryRateLimiter` holds before it first forgets refilled buckets.
+const PURGE_MIN: usize = 1024;
+
+/// What a rate limit applies to, as passed to `RateLimiter::check`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum RateLimitScope {
+    /// The key is the normalized email address of the login.
+    Email,
+    /// The key is `AuthOptions::rate_limit_key`, such as the client IP address.
+    Caller,
+}
+
+impl RateLimitScope {
+    /// The name of the scope, for logs and metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RateLimitScope::Email => "email",
+            RateLimitScope::Caller => "caller",
+        }
+    }
+}
+
+/// The outcome of `RateLimiter::check`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitDecision {
+    /// The login may start.
+    Allow,
+    /// The login may not start. `retry_after` is when another attempt may succeed, if known.
+    Deny { retry_after: Option<Duration> },
+}
+
+/// Limits how often logins can be started, to prevent flooding users with emails and filling the
+/// session store.
+///
+/// Configure a limiter using `Builder::rate_limiter`. `Client::start_auth` checks it before
+/// creating the session, once for the normalized email address, and once for
+/// `AuthOptions::rate_limit_key` if set. If either check denies the login, it fails with
+/// `StartAuthError::RateLimited`. If the limiter returns an error, the login is allowed, so an
+/// outage of a shared limiter doesn't block all logins.
+///
+/// `MemoryRateLimiter` is a limiter for a single process. This is also implemented for closures
+/// of the form `Fn(RateLimitScope, &str) -> RateLimitDecision`.
+pub trait RateLimiter: Send + Sync + 'static {
+    /// Record an attempt for `key` in `scope`, and decide whether it may proceed.
+    fn check(&self, scope: RateLimitScope, key: &str) -> DynFutRes<RateLimitDecision>;
+}
+
+impl<F> RateLimiter for F
+where
+    F: Fn(RateLimitScope, &str) -> RateLimitDecision + Send + Sync + 'static,
+{
+    fn check(&self, scope: RateLimitScope, key: &str) -> DynFutRes<RateLimitDecision> {
+        let decision = self(scope, key);
+        Box::pin(async move { Ok(decision) })
+    }
+}
+
+/// A `RateLimiter` that keeps a token bucket per key in memory.
+///
+/// Each key can start `burst` logins at once, and regains one every `refill`. Keys of different
+/// scopes are counted separately. Buckets that have fully refilled are forgotten, so memory use is
+/// bounded by the number of recently active keys.
+///
+/// State is not shared between processes. For a fleet of workers, implement `RateLimiter` on a
+/// shared store instead.
+#[derive(Clone)]
+pub struct MemoryRateLimiter {
+    burst: u32,
+    refill: Duration,
+    clock: Arc<dyn Clock>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+/// The buckets of a `MemoryRateLimiter`, with the size at which to forget refilled buckets.
+#[derive(Default)]
+struct Buckets {
+    map: HashMap<(RateLimitScope, String), Bucket>,
+    purge_at: usize,
+}
+
+/// The state of one key of a `MemoryRateLimiter`.
+#[derive(Clone, Copy)]
+struct Bucket {
+    tokens: u32,
+    updated: Instant,
+}
+
+impl MemoryRateLimiter {
+    /// Create a limiter that allows `burst` logins per key, regaining one every `refill`.
+    pub fn new(burst: u32, refill: Duration) -> Self {
+        MemoryRateLimiter {
+            burst,
+            refill,
+            clock: Arc::new(SystemClock),
+            buckets: Default::default(),
+        }
+    }
+
+    /// Use the given `Clock` instead of the system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Decide whether an attempt for `key` in `scope` may proceed, and record it if so.
+    fn take(&self, scope: RateLimitScope, key: &str) -> RateLimitDecision {
+        let now = self.clock.instant();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.map.len() >= buckets.purge_at {
+            buckets
+                .map
+                .retain(|_, bucket| self.refilled(bucket, now).tokens < self.burst);
+            buckets.purge_at = (buckets.map.len() * 2).max(PURGE_MIN);
+        }
+
+        let full = Bucket {
+            tokens: self.burst,
+            updated: now,
+        };
+        let bucket = buckets.map.entry((scope, key.to_owned())).or_insert(full);
+        *bucket = self.refilled(bucket, now);
+        if bucket.tokens == 0 {
+            let elapsed = now.saturating_duration_since(bucket.updated);
+            return RateLimitDecision::Deny {
+                retry_after: Some(self.refill.saturating_sub(elapsed)),
+            };
+        }
+        bucket.tokens -= 1;
+        RateLimitDecision::Allow
+    }
+
+    /// The state of `bucket` at `now`, after adding the tokens regained since it was updated.
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> Bucket {
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        let regained = match elapsed.as_nanos().checked_div(self.refill.as_nanos()) {
+            Some(0) => return *bucket,
+            Some(regained) => regained,
+            None => u128::MAX,
+        };
+        let tokens = u128::from(bucket.tokens).saturating_add(regained);
+        if tokens >= u128::from(self.burst) {
+            return Bucket {
+                tokens: self.burst,
+                updated: now,
+            };
+        }
+        Bucket {
+            tokens: tokens as u32,
+            // Keep the remainder, so partial progress towards the next token isn't lost.
+            updated: bucket.updated + self.refill * regained as u32,
+        }
+    }
+}
+
+impl RateLimiter for MemoryRateLimiter {
+    fn check(&self, scope: RateLimitScope, key: &str) -> DynFutRes<RateLimitDecision> {
+        let decision = self.take(scope, key);
+        Box::pin(async move { Ok(decision) })
+    }
+}
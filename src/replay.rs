@@ -0,0 +1,360 @@
+//! Recording and replay of login flows, for debugging.
+//!
+//! A `FlowRecorder` wraps the `Fetcher` of a `Client`, and remembers the documents it fetches.
+//! Together with the authentication URL and callback parameters of a failing login, these form a
+//! `FlowRecording`, which can be serialized and sent to a support engineer. `FlowRecording::replay`
+//! then verifies the token again using a `Validator`, at the time the flow was recorded, without
+//! access to the broker or the session store of the original deployment.
+//!
+//! Recordings are sanitized: only the authentication URL parameters and callback parameters used
+//! by the protocol are kept, and the `state`, `login_hint` and any application parameters are
+//! dropped. The token itself is kept, because it is needed for replay. It contains the email
+//! address of the user, so recordings should still be handled with care.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use url::Url;
+
+use crate::{
+    jwk::KeySet,
+    misc::{DiscoveryDoc, DynFut, DynFutRes},
+    CallbackError, CallbackParams, FetchError, Fetcher, ManualClock, Validator, VerifiedToken,
+    VerifyError,
+};
+
+/// Authentication URL parameters kept in a recording.
+const AUTH_PARAMS: &[&str] = &[
+    "client_id",
+    "redirect_uri",
+    "response_type",
+    "response_mode",
+    "scope",
+    "nonce",
+];
+
+/// Errors that can result from `FlowRecording::replay`.
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    #[error("the recording has an invalid authentication URL")]
+    InvalidAuthUrl,
+    #[error("the recording does not contain the document at {0}")]
+    MissingDocument(String),
+    #[error(transparent)]
+    Callback(#[from] CallbackError),
+}
+
+impl From<VerifyError> for ReplayError {
+    fn from(err: VerifyError) -> Self {
+        ReplayError::Callback(err.into())
+    }
+}
+
+/// A `Fetcher` that remembers the documents fetched through it.
+///
+/// Configure a `Client` with the recorder using `Builder::fetcher`, and create recordings of
+/// failing logins using `FlowRecorder::record`. Only the most recent version of each document is
+/// remembered.
+#[derive(Clone)]
+pub struct FlowRecorder {
+    inner: Arc<dyn Fetcher>,
+    documents: Arc<Mutex<BTreeMap<String, String>>>,
+}
+
+impl FlowRecorder {
+    /// Create a recorder that fetches documents using `inner`.
+    pub fn new(inner: Arc<dyn Fetcher>) -> Self {
+        FlowRecorder {
+            inner,
+            documents: Default::default(),
+        }
+    }
+
+    /// Create a sanitized recording of a login flow.
+    ///
+    /// The `auth_url` is the URL returned by `Client::start_auth`, and `callback` contains the
+    /// parameters passed to `Client::handle_callback`.
+    pub fn record(&self, auth_url: &Url, callback: &CallbackParams) -> FlowRecording {
+        let mut auth_url = auth_url.clone();
+        let query: Vec<(String, String)> = auth_url
+            .query_pairs()
+            .filter(|(name, _)| AUTH_PARAMS.contains(&name.as_ref()))
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect();
+        auth_url.query_pairs_mut().clear().extend_pairs(query);
+
+        let mut params = BTreeMap::new();
+        let fields = [
+            ("id_token", &callback.id_token),
+            ("error", &callback.error),
+            ("error_description", &callback.error_description),
+        ];
+        for (name, value) in fields {
+            if let Some(value) = value {
+                params.insert(name.to_owned(), value.clone());
+            }
+        }
+
+        FlowRecording {
+            auth_url: auth_url.into(),
+            callback: params,
+            documents: self.documents.lock().unwrap().clone(),
+            recorded_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+        }
+    }
+
+    /// Remember a successfully fetched document.
+    fn observe(
+        &self,
+        url: Url,
+        fut: DynFut<Result<Bytes, FetchError>>,
+    ) -> DynFut<Result<Bytes, FetchError>> {
+        let documents = self.documents.clone();
+        Box::pin(async move {
+            let result = fut.await;
+            if let Ok(ref body) = result {
+                let body = String::from_utf8_lossy(body).into_owned();
+                documents.lock().unwrap().insert(url.into(), body);
+            }
+            result
+        })
+    }
+}
+
+impl Fetcher for FlowRecorder {
+    fn fetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        self.observe(url.clone(), self.inner.fetch(url))
+    }
+
+    fn refetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        self.observe(url.clone(), self.inner.refetch(url))
+    }
+
+    fn cache_lifetime(&self, url: Url) -> DynFutRes<Option<Duration>> {
+        self.inner.cache_lifetime(url)
+    }
+}
+
+/// A sanitized recording of a login flow, created by `FlowRecorder::record`.
+///
+/// This can be serialized using serde, for example to JSON.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct FlowRecording {
+    /// The authentication URL the user was redirected to.
+    pub auth_url: String,
+    /// The callback parameters sent by the broker.
+    pub callback: BTreeMap<String, String>,
+    /// Bodies of the documents fetched, by URL.
+    pub documents: BTreeMap<String, String>,
+    /// Unix timestamp at which the recording was made.
+    pub recorded_at: u64,
+}
+
+impl FlowRecording {
+    /// Replay the recording, verifying the token as it would have been at the time of recording.
+    ///
+    /// The broker is taken to be the origin of the authentication URL, and the audience its
+    /// `client_id` parameter. This uses the defaults of `Validator`, so use
+    /// `FlowRecording::replay_with` to replay against a different configuration.
+    ///
+    /// Like `Validator`, this does not check the session.
+    pub fn replay(&self) -> Result<VerifiedToken, ReplayError> {
+        let auth_url: Url = self
+            .auth_url
+            .parse()
+            .map_err(|_err| ReplayError::InvalidAuthUrl)?;
+        let issuer = auth_url.origin();
+        if !issuer.is_tuple() {
+            return Err(ReplayError::InvalidAuthUrl);
+        }
+        let audience = auth_url
+            .query_pairs()
+            .find(|(name, _)| name == "client_id")
+            .ok_or(ReplayError::InvalidAuthUrl)?
+            .1;
+
+        let time = UNIX_EPOCH + Duration::from_secs(self.recorded_at);
+        let validator = Validator::new(issuer.ascii_serialization(), audience)
+            .clock(Arc::new(ManualClock::new(time)));
+        self.replay_with(&validator)
+    }
+
+    /// Replay the recording using the given `Validator`.
+    ///
+    /// The caller is responsible for configuring the validator, including a `Clock` set to the
+    /// time of recording.
+    pub fn replay_with(&self, validator: &Validator) -> Result<VerifiedToken, ReplayError> {
+        let params: CallbackParams = self
+            .callback
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        if let Some(code) = params.error {
+            return Err(CallbackError::Broker {
+                code,
+                description: params.error_description,
+            }
+            .into());
+        }
+        let token = params.id_token.ok_or(CallbackError::MissingToken)?;
+
+        let discovery_url = format!("{}/.well-known/openid-configuration", validator.issuer());
+        let discovery: DiscoveryDoc = serde_json::from_str(self.document(&discovery_url)?)
+            .map_err(VerifyError::ParseDiscovery)?;
+        let base: Url = discovery_url
+            .parse()
+            .map_err(VerifyError::InvalidDiscoveryUrl)?;
+        let jwks_uri = DiscoveryDoc::parse_url(&discovery.jwks_uri, Some(&base))
+            .map_err(VerifyError::InvalidDiscoveryUrl)?;
+        let keys: KeySet = serde_json::from_str(self.document(jwks_uri.as_str())?)
+            .map_err(VerifyError::ParseJwks)?;
+
+        Ok(validator.verify_full(&token, &keys)?)
+    }
+
+    /// The recorded body of the document at `url`.
+    fn document(&self, url: &str) -> Result<&str, ReplayError> {
+        self.documents
+            .get(url)
+            .map(String::as_str)
+            .ok_or_else(|| ReplayError::MissingDocument(url.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::jws::{self, KeyPair};
+
+    const BROKER: &str = "https://broker.example";
+
+    /// A `Fetcher` that serves fixed documents.
+    struct StaticFetcher(BTreeMap<String, String>);
+
+    impl Fetcher for StaticFetcher {
+        fn fetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+            let result = match self.0.get(url.as_str()) {
+                Some(body) => Ok(Bytes::from(body.clone())),
+                None => Err(FetchError::Store(format!("no document at {url}").into())),
+            };
+            Box::pin(async move { result })
+        }
+
+        fn refetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+            self.fetch(url)
+        }
+
+        fn cache_lifetime(&self, _url: Url) -> DynFutRes<Option<Duration>> {
+            Box::pin(async { Ok(None) })
+        }
+    }
+
+    /// Record a login, fetching the broker documents through the recorder like a `Client` does.
+    async fn record() -> FlowRecording {
+        let key = KeyPair::generate_ed25519("key-1").unwrap();
+        let discovery_url = format!("{BROKER}/.well-known/openid-configuration");
+        let jwks_url = format!("{BROKER}/jwks.json");
+        let documents = BTreeMap::from([
+            (
+                discovery_url.clone(),
+                json!({
+                    "issuer": BROKER,
+                    "jwks_uri": jwks_url,
+                    "authorization_endpoint": format!("{BROKER}/auth"),
+                })
+                .to_string(),
+            ),
+            (
+                jwks_url.clone(),
+                json!({ "keys": [key.public_jwk()] }).to_string(),
+            ),
+        ]);
+        let recorder = FlowRecorder::new(Arc::new(StaticFetcher(documents)));
+        recorder
+            .fetch(discovery_url.parse().unwrap())
+            .await
+            .unwrap();
+        recorder.fetch(jwks_url.parse().unwrap()).await.unwrap();
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let claims = json!({
+            "iss": BROKER,
+            "aud": "https://rp.example",
+            "email": "user@example.com",
+            "email_original": "User@example.com",
+            "iat": now,
+            "exp": now + 600,
+            "nonce": "nonce-1",
+        });
+        let token = jws::sign(claims.to_string().as_bytes(), &key).unwrap();
+
+        let auth_url: Url = format!(
+            "{BROKER}/auth?login_hint=User%40example.com&scope=openid%20email\
+             &nonce=nonce-1&response_type=id_token&client_id=https%3A%2F%2Frp.example\
+             &redirect_uri=https%3A%2F%2Frp.example%2Fverify&state=secret-state&tab=billing"
+        )
+        .parse()
+        .unwrap();
+        let callback = CallbackParams::parse(&format!(
+            "id_token={token}&state=secret-state&session=app-secret"
+        ));
+        recorder.record(&auth_url, &callback)
+    }
+
+    #[tokio::test]
+    async fn replays_recording() {
+        let recording = record().await;
+        let json = serde_json::to_string(&recording).unwrap();
+        let recording: FlowRecording = serde_json::from_str(&json).unwrap();
+        let token = recording.replay().unwrap();
+        assert_eq!(token.email(), "user@example.com");
+        assert_eq!(token.claims.nonce, "nonce-1");
+    }
+
+    #[tokio::test]
+    async fn sanitizes_recording() {
+        let json = serde_json::to_string(&record().await).unwrap();
+        for secret in [
+            "secret-state",
+            "login_hint",
+            "User%40example",
+            "billing",
+            "app-secret",
+        ] {
+            assert!(
+                !json.contains(secret),
+                "recording contains {secret:?}: {json}"
+            );
+        }
+        assert!(json.contains("nonce-1"));
+
+        let recording: FlowRecording = serde_json::from_str(&json).unwrap();
+        let auth_url: Url = recording.auth_url.parse().unwrap();
+        let names: Vec<_> = auth_url.query_pairs().map(|(name, _)| name).collect();
+        assert_eq!(
+            names,
+            [
+                "scope",
+                "nonce",
+                "response_type",
+                "client_id",
+                "redirect_uri"
+            ]
+        );
+        assert_eq!(recording.callback.keys().collect::<Vec<_>>(), ["id_token"]);
+    }
+}
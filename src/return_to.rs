@@ -0,0 +1,192 @@
+use thiserror::Error;
+use url::Url;
+
+/// Errors that can result from `ReturnTo::validate`.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ReturnToError {
+    #[error("the return-to URL is invalid: {0}")]
+    Invalid(#[source] url::ParseError),
+    #[error("the return-to URL contains characters browsers may interpret differently")]
+    UnsafeCharacters,
+    #[error("the return-to URL has an unsupported scheme")]
+    UnsupportedScheme,
+    #[error("the return-to URL points to an origin that is not allowed")]
+    OriginNotAllowed,
+    #[error("the return-to URL points to a path that is not allowed")]
+    PathNotAllowed,
+}
+
+/// Validates URLs to redirect the user to after login, to prevent open redirects.
+///
+/// Applications often remember where the user was going, using `AuthOptions::state` or
+/// `AuthOptions::session_data`, and redirect there once the login completes. If an attacker can
+/// influence that value, for example through a query parameter on the login form, redirecting to
+/// it unchecked turns the login into an open redirect. Check it with this validator first, both
+/// when starting the login and after the callback.
+///
+/// By default, only URLs on the same origin as the base URL are accepted. Relative URLs are
+/// resolved against the base URL. Use `Client::return_to` to create a validator using the redirect
+/// URI as the base.
+#[derive(Clone, Debug)]
+pub struct ReturnTo {
+    base: Url,
+    origins: Vec<String>,
+    path_prefixes: Vec<String>,
+}
+
+impl ReturnTo {
+    /// Create a validator that accepts URLs on the same origin as `base`.
+    pub fn new(base: Url) -> Self {
+        let origin = base.origin().ascii_serialization();
+        ReturnTo {
+            base,
+            origins: vec![origin],
+            path_prefixes: Vec::new(),
+        }
+    }
+
+    /// Also accept URLs on `origin`, in ASCII serialization, such as `https://app.example.com`.
+    pub fn allow_origin(mut self, origin: impl Into<String>) -> Self {
+        self.origins.push(origin.into());
+        self
+    }
+
+    /// Only accept URLs with a path below `prefix`, such as `/app`.
+    ///
+    /// The prefix matches whole path segments, so `/app` accepts `/app` and `/app/settings`, but
+    /// not `/apple`. If called multiple times, paths below any of the prefixes are accepted. By
+    /// default, all paths are accepted.
+    pub fn allow_path(mut self, prefix: impl Into<String>) -> Self {
+        self.path_prefixes.push(prefix.into());
+        self
+    }
+
+    /// Check `return_to`, and return it as an absolute URL if it is safe to redirect to.
+    pub fn validate(&self, return_to: &str) -> Result<Url, ReturnToError> {
+        // Browsers treat a backslash like a slash, so `/\example.com` is protocol-relative to
+        // them, and strip tabs and newlines. Reject these instead of guessing.
+        if return_to
+            .chars()
+            .any(|c| c == '\\' || c.is_control() || c.is_whitespace())
+        {
+            return Err(ReturnToError::UnsafeCharacters);
+        }
+
+        let url = self.base.join(return_to).map_err(ReturnToError::Invalid)?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(ReturnToError::UnsupportedScheme);
+        }
+        let origin = url.origin().ascii_serialization();
+        if !self.origins.contains(&origin) {
+            return Err(ReturnToError::OriginNotAllowed);
+        }
+        if !self.path_prefixes.is_empty()
+            && !self
+                .path_prefixes
+                .iter()
+                .any(|prefix| path_below(url.path(), prefix))
+        {
+            return Err(ReturnToError::PathNotAllowed);
+        }
+        Ok(url)
+    }
+}
+
+/// Whether `path` is `prefix` or below it, matching whole path segments.
+fn path_below(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validator() -> ReturnTo {
+        ReturnTo::new("https://rp.example/login".parse().unwrap())
+    }
+
+    #[test]
+    fn accepts_same_origin() {
+        let url = validator().validate("/app/settings?tab=1").unwrap();
+        assert_eq!(url.as_str(), "https://rp.example/app/settings?tab=1");
+        let url = validator().validate("https://rp.example/other").unwrap();
+        assert_eq!(url.as_str(), "https://rp.example/other");
+    }
+
+    #[test]
+    fn rejects_protocol_relative() {
+        assert!(matches!(
+            validator().validate("//evil.example"),
+            Err(ReturnToError::OriginNotAllowed)
+        ));
+        assert!(matches!(
+            validator().validate("/\\evil.example"),
+            Err(ReturnToError::UnsafeCharacters)
+        ));
+    }
+
+    #[test]
+    fn rejects_other_origin() {
+        assert!(matches!(
+            validator().validate("https://evil.example"),
+            Err(ReturnToError::OriginNotAllowed)
+        ));
+        assert!(matches!(
+            validator().validate("http://rp.example/"),
+            Err(ReturnToError::OriginNotAllowed)
+        ));
+    }
+
+    #[test]
+    fn rejects_other_schemes() {
+        assert!(matches!(
+            validator().validate("javascript:alert(1)"),
+            Err(ReturnToError::UnsupportedScheme)
+        ));
+    }
+
+    #[test]
+    fn rejects_control_characters() {
+        for return_to in ["/\t/evil.example", "/app\n", "/app\r/x", "/ app"] {
+            assert!(
+                matches!(
+                    validator().validate(return_to),
+                    Err(ReturnToError::UnsafeCharacters)
+                ),
+                "{return_to:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn matches_whole_path_segments() {
+        let validator = validator().allow_path("/app");
+        assert!(validator.validate("/app").is_ok());
+        assert!(validator.validate("/app/settings").is_ok());
+        assert!(matches!(
+            validator.validate("/apple"),
+            Err(ReturnToError::PathNotAllowed)
+        ));
+        assert!(matches!(
+            validator.validate("/app/../admin"),
+            Err(ReturnToError::PathNotAllowed)
+        ));
+    }
+
+    #[test]
+    fn accepts_allowed_origin() {
+        let validator = validator().allow_origin("https://app.example");
+        let url = validator.validate("https://app.example/home").unwrap();
+        assert_eq!(url.as_str(), "https://app.example/home");
+        assert!(validator.validate("/home").is_ok());
+        assert!(matches!(
+            validator.validate("https://evil.example/home"),
+            Err(ReturnToError::OriginNotAllowed)
+        ));
+    }
+}
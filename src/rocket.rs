@@ -0,0 +1,237 @@
+//! Integration with the [Rocket](https://rocket.rs) web framework.
+//!
+//! The `Portier` fairing builds a `Client` when Rocket ignites, and adds it to managed state. It
+//! is configured using the `portier` table in the Rocket configuration:
+//!
+//! ```toml
+//! [default.portier]
+//! redirect_uri = "http://localhost:8000/verify"
+//! # Optional, defaults to the public Portier broker.
+//! broker = "https://broker.portier.io"
+//! ```
+//!
+//! Routes can then use `&State<Client>` to start logins, and the `VerifiedEmail` data guard to
+//! complete them:
+//!
+//! ```no_run
+//! use portier::rocket::{AuthRedirect, Portier, VerifiedEmail};
+//! use rocket::{http::Status, post, routes, State};
+//!
+//! #[post("/auth?<email>")]
+//! async fn auth(email: &str, client: &State<portier::Client>) -> Result<AuthRedirect, Status> {
+//!     let url = client
+//!         .start_auth(email)
+//!         .await
+//!         .map_err(|_| Status::InternalServerError)?;
+//!     Ok(AuthRedirect(url))
+//! }
+//!
+//! #[post("/verify", data = "<email>")]
+//! fn verify(email: VerifiedEmail) -> String {
+//!     format!("Verified email address {}!", email.0)
+//! }
+//!
+//! let rocket = rocket::build()
+//!     .attach(Portier::fairing())
+//!     .mount("/", routes![auth, verify]);
+//! ```
+//!
+//! Note that Rocket requires a newer Rust version than the rest of this crate.
+
+use ::rocket::{
+    data::{self, Data, FromData, Limits},
+    fairing::{self, Fairing, Info, Kind},
+    http::Status,
+    outcome::Outcome,
+    response::{self, content::RawHtml, Redirect, Responder},
+    Build, Request, Rocket,
+};
+use serde::Deserialize;
+use thiserror::Error;
+use url::Url;
+
+use crate::{
+    fragment, Broker, Builder, CallbackError, Client, FragmentPayload, FragmentRelay, RelayError,
+};
+
+/// The `portier` table in the Rocket configuration.
+#[derive(Deserialize)]
+struct Config {
+    redirect_uri: String,
+    broker: Option<String>,
+}
+
+/// A fairing that builds a `Client` from the Rocket configuration, and manages it as state.
+///
+/// See the module documentation for the configuration keys.
+pub struct Portier {
+    configure: Box<dyn Fn(Builder) -> Builder + Send + Sync>,
+}
+
+impl Portier {
+    /// Create a fairing that builds the `Client` with only the configured settings.
+    pub fn fairing() -> Self {
+        Portier {
+            configure: Box::new(|builder| builder),
+        }
+    }
+
+    /// Create a fairing that additionally applies `configure` to the `Builder`.
+    pub fn custom(configure: impl Fn(Builder) -> Builder + Send + Sync + 'static) -> Self {
+        Portier {
+            configure: Box::new(configure),
+        }
+    }
+
+    fn build(&self, rocket: &Rocket<Build>) -> Result<Client, String> {
+        let config: Config = rocket
+            .figment()
+            .extract_inner("portier")
+            .map_err(|err| format!("invalid configuration: {}", err))?;
+        let redirect_uri = config
+            .redirect_uri
+            .parse()
+            .map_err(|err| format!("invalid redirect_uri: {}", err))?;
+        let mut builder = Client::builder(redirect_uri);
+        if let Some(broker) = config.broker {
+            let broker: Broker = broker
+                .parse()
+                .map_err(|err| format!("invalid broker: {}", err))?;
+            builder = builder.server(broker);
+        }
+        (self.configure)(builder)
+            .build()
+            .map_err(|err| err.to_string())
+    }
+}
+
+#[::rocket::async_trait]
+impl Fairing for Portier {
+    fn info(&self) -> Info {
+        Info {
+            name: "Portier",
+            kind: Kind::Ignite,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        match self.build(&rocket) {
+            Ok(client) => Ok(rocket.manage(client)),
+            Err(err) => {
+                ::rocket::error!("Portier: {}", err);
+                Err(rocket)
+            }
+        }
+    }
+}
+
+/// Errors that can result from the `VerifiedEmail` data guard.
+#[derive(Debug, Error)]
+pub enum VerifiedEmailError {
+    #[error("no `Client` in managed state")]
+    MissingClient,
+    #[error("could not read the request body: {0}")]
+    Body(#[source] std::io::Error),
+    #[error("the request body is too large")]
+    TooLarge,
+    #[error(transparent)]
+    Relay(#[from] RelayError),
+    #[error(transparent)]
+    Callback(#[from] CallbackError),
+}
+
+/// Data guard that verifies the callback parameters, and contains the verified email address.
+///
+/// This reads form-encoded parameters from the request body, as sent to the redirect URI with
+/// `ResponseMode::FormPost`, or by `FragmentPage` to the relay endpoint. It uses the managed
+/// `Client`, and `Client::handle_callback`. If the body contains a `redirect_uri`, as sent by
+/// `FragmentPage`, it is checked using `FragmentRelay::check_redirect_uri`.
+///
+/// The body size is limited by the `form` limit.
+#[derive(Clone, Debug)]
+pub struct VerifiedEmail(pub String);
+
+#[::rocket::async_trait]
+impl<'r> FromData<'r> for VerifiedEmail {
+    type Error = VerifiedEmailError;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let client = match req.rocket().state::<Client>() {
+            Some(client) => client,
+            None => {
+                return Outcome::Error((
+                    Status::InternalServerError,
+                    VerifiedEmailError::MissingClient,
+                ))
+            }
+        };
+
+        let limit = req.limits().get("form").unwrap_or(Limits::FORM);
+        let body = match data.open(limit).into_string().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(_) => {
+                return Outcome::Error((Status::PayloadTooLarge, VerifiedEmailError::TooLarge))
+            }
+            Err(err) => return Outcome::Error((Status::BadRequest, VerifiedEmailError::Body(err))),
+        };
+        let payload = FragmentPayload::parse(&body);
+        if let Err(err) = payload.check_redirect_uri(client.fragment_relay()) {
+            return Outcome::Error((Status::BadRequest, err.into()));
+        }
+
+        match client.handle_callback(&payload.params).await {
+            Ok(email) => Outcome::Success(VerifiedEmail(email)),
+            Err(err) if err.is_server_error() => {
+                Outcome::Error((Status::InternalServerError, err.into()))
+            }
+            Err(err) => Outcome::Error((Status::BadRequest, err.into())),
+        }
+    }
+}
+
+/// Responder that redirects the user agent to the broker, using the URL from `Client::start_auth`.
+#[derive(Clone, Debug)]
+pub struct AuthRedirect(pub Url);
+
+impl<'r> Responder<'r, 'static> for AuthRedirect {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        Redirect::to(String::from(self.0)).respond_to(req)
+    }
+}
+
+/// Responder for the redirect URI page with `ResponseMode::Fragment`.
+///
+/// This renders the page from `FragmentRelay::page_html`, which uses JavaScript to send the
+/// parameters in the URL fragment to the relay endpoint, as a form `POST`. The relay endpoint can
+/// then use the `VerifiedEmail` data guard.
+#[derive(Clone, Debug)]
+pub struct FragmentPage {
+    relay_url: Url,
+    script_nonce: Option<String>,
+}
+
+impl FragmentPage {
+    /// Create the page for the relay endpoint of `relay`, usually from `Client::fragment_relay`.
+    pub fn new(relay: &FragmentRelay) -> Self {
+        FragmentPage {
+            relay_url: relay.relay_url().clone(),
+            script_nonce: None,
+        }
+    }
+
+    /// Add a Content Security Policy nonce to the script element.
+    pub fn script_nonce(mut self, nonce: impl Into<String>) -> Self {
+        self.script_nonce = Some(nonce.into());
+        self
+    }
+}
+
+impl<'r> Responder<'r, 'static> for FragmentPage {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        RawHtml(fragment::page_html(
+            &self.relay_url,
+            self.script_nonce.as_deref(),
+        ))
+        .respond_to(req)
+    }
+}
@@ -0,0 +1,85 @@
+use std::path::PathBuf;
+
+use crate::misc::DynErr;
+
+/// Supplies a secret when the `Client` is built, so it doesn't need to be in application code.
+///
+/// Configure one using `Builder::session_secret_from`. The secret is loaded by `Builder::build`,
+/// which fails with `BuildError::Secret` if loading fails. `EnvSecret` and `FileSecret` load
+/// secrets from the environment and from files, such as those mounted by container orchestrators.
+/// Providers for a key management service can block on a request, because `Builder::build` is
+/// typically called once at startup.
+///
+/// This is implemented for closures of the form
+/// `Fn() -> Result<Vec<u8>, Box<dyn Error + Send + Sync>>`.
+pub trait SecretProvider: Send + Sync + 'static {
+    /// Load the secret.
+    fn load_secret(&self) -> Result<Vec<u8>, DynErr>;
+}
+
+impl<F> SecretProvider for F
+where
+    F: Fn() -> Result<Vec<u8>, DynErr> + Send + Sync + 'static,
+{
+    fn load_secret(&self) -> Result<Vec<u8>, DynErr> {
+        self()
+    }
+}
+
+/// A `SecretProvider` that reads an environment variable.
+///
+/// The value is used as is, so should be a long random string. Loading fails if the variable is
+/// not set, or empty.
+#[derive(Clone, Debug)]
+pub struct EnvSecret {
+    name: String,
+}
+
+impl EnvSecret {
+    /// Read the secret from the environment variable `name`.
+    pub fn new(name: impl Into<String>) -> Self {
+        EnvSecret { name: name.into() }
+    }
+}
+
+impl SecretProvider for EnvSecret {
+    fn load_secret(&self) -> Result<Vec<u8>, DynErr> {
+        match std::env::var_os(&self.name) {
+            Some(value) if !value.is_empty() => Ok(value.into_encoded_bytes()),
+            _ => Err(format!("environment variable {} is not set", self.name).into()),
+        }
+    }
+}
+
+/// A `SecretProvider` that reads a file.
+///
+/// The contents are used as is, except that a trailing newline is removed. Loading fails if the
+/// file can't be read, or is empty.
+#[derive(Clone, Debug)]
+pub struct FileSecret {
+    path: PathBuf,
+}
+
+impl FileSecret {
+    /// Read the secret from the file at `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileSecret { path: path.into() }
+    }
+}
+
+impl SecretProvider for FileSecret {
+    fn load_secret(&self) -> Result<Vec<u8>, DynErr> {
+        let mut secret = std::fs::read(&self.path)
+            .map_err(|err| format!("could not read {}: {}", self.path.display(), err))?;
+        if secret.ends_with(b"\n") {
+            secret.pop();
+            if secret.ends_with(b"\r") {
+                secret.pop();
+            }
+        }
+        if secret.is_empty() {
+            return Err(format!("{} is empty", self.path.display()).into());
+        }
+        Ok(secret)
+    }
+}
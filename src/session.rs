@@ -0,0 +1,96 @@
+use crate::binding::DIGEST_LEN;
+
+/// The session data stored by `Client`, wrapping the application data.
+///
+/// Encoded as a flags byte, followed by the fields indicated by the flags, and the application
+/// data. Sessions stored without data decode as empty.
+#[derive(Default)]
+pub(crate) struct SessionEnvelope {
+    /// Digest of the user agent properties. See `Builder::session_binding`.
+    pub binding: Option<[u8; DIGEST_LEN]>,
+    /// The correlation ID set using `AuthOptions::correlation_id`, or generated for the login.
+    pub correlation_id: Option<String>,
+    /// The redirect URI set using `AuthOptions::redirect_uri`.
+    pub redirect_uri: Option<String>,
+    /// The application data set using `AuthOptions::session_data`.
+    pub data: Vec<u8>,
+}
+
+impl SessionEnvelope {
+    const BINDING: u8 = 1;
+    const CORRELATION_ID: u8 = 2;
+    const REDIRECT_URI: u8 = 4;
+
+    /// Whether there is nothing to store, so the session can be created without data.
+    pub fn is_empty(&self) -> bool {
+        self.binding.is_none()
+            && self.correlation_id.is_none()
+            && self.redirect_uri.is_none()
+            && self.data.is_empty()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![0];
+        if let Some(binding) = self.binding {
+            out[0] |= Self::BINDING;
+            out.extend_from_slice(&binding);
+        }
+        if let Some(ref correlation_id) = self.correlation_id {
+            out[0] |= Self::CORRELATION_ID;
+            encode_str(&mut out, correlation_id);
+        }
+        if let Some(ref redirect_uri) = self.redirect_uri {
+            out[0] |= Self::REDIRECT_URI;
+            encode_str(&mut out, redirect_uri);
+        }
+        out.extend_from_slice(&self.data);
+        out
+    }
+
+    /// Decode session data, or return `None` if it is malformed.
+    pub fn decode(bytes: Vec<u8>) -> Option<Self> {
+        let mut envelope = SessionEnvelope::default();
+        let (&flags, mut rest) = match bytes.split_first() {
+            Some(split) => split,
+            None => return Some(envelope),
+        };
+        if flags & !(Self::BINDING | Self::CORRELATION_ID | Self::REDIRECT_URI) != 0 {
+            return None;
+        }
+        if flags & Self::BINDING != 0 {
+            let (binding, tail) = split_at_checked(rest, DIGEST_LEN)?;
+            envelope.binding = Some(binding.try_into().ok()?);
+            rest = tail;
+        }
+        if flags & Self::CORRELATION_ID != 0 {
+            let (correlation_id, tail) = decode_str(rest)?;
+            envelope.correlation_id = Some(correlation_id);
+            rest = tail;
+        }
+        if flags & Self::REDIRECT_URI != 0 {
+            let (redirect_uri, tail) = decode_str(rest)?;
+            envelope.redirect_uri = Some(redirect_uri);
+            rest = tail;
+        }
+        envelope.data = rest.to_vec();
+        Some(envelope)
+    }
+}
+
+/// Append a length-prefixed string.
+fn encode_str(out: &mut Vec<u8>, value: &str) {
+    out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+/// Read a length-prefixed string, and return it with the remaining bytes.
+fn decode_str(bytes: &[u8]) -> Option<(String, &[u8])> {
+    let (len, tail) = split_at_checked(bytes, 4)?;
+    let len = u32::from_be_bytes(len.try_into().ok()?) as usize;
+    let (value, tail) = split_at_checked(tail, len)?;
+    Some((String::from_utf8(value.to_vec()).ok()?, tail))
+}
+
+fn split_at_checked(bytes: &[u8], mid: usize) -> Option<(&[u8], &[u8])> {
+    (mid <= bytes.len()).then(|| bytes.split_at(mid))
+}
@@ -0,0 +1,220 @@
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::misc::{base64url, DynErr};
+
+/// Encoding used by store backends to persist cached documents and session records.
+///
+/// Backends that talk to a remote database can be parameterized with a codec, so that
+/// applications can choose a compact binary encoding to reduce memory and network overhead. The
+/// default is `JsonCodec`. `CborCodec` and `MessagePackCodec` are available with the `codec-cbor`
+/// and `codec-msgpack` crate features respectively.
+pub trait Codec: Send + Sync + 'static {
+    /// Encode a value.
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, DynErr>;
+
+    /// Decode a value.
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, DynErr>;
+}
+
+/// A `Codec` that uses JSON. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, DynErr> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, DynErr> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+/// A `Codec` that uses CBOR.
+#[cfg(feature = "codec-cbor")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CborCodec;
+
+#[cfg(feature = "codec-cbor")]
+impl Codec for CborCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, DynErr> {
+        let mut data = Vec::new();
+        ciborium::ser::into_writer(value, &mut data)?;
+        Ok(data)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, DynErr> {
+        Ok(ciborium::de::from_reader(data)?)
+    }
+}
+
+/// A `Codec` that uses MessagePack.
+#[cfg(feature = "codec-msgpack")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessagePackCodec;
+
+#[cfg(feature = "codec-msgpack")]
+impl Codec for MessagePackCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, DynErr> {
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, DynErr> {
+        Ok(rmp_serde::from_slice(data)?)
+    }
+}
+
+/// A cached document, as persisted by store backends.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CachedDocument {
+    /// The document body, or `None` if fetching resulted in an error.
+    #[serde(with = "binary")]
+    pub data: Option<Vec<u8>>,
+    /// Unix timestamp at which the document expires.
+    pub expires: u64,
+}
+
+/// Session data associated with a nonce, as persisted by store backends.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct SessionRecord {
+    /// The email address the session was created for.
+    pub email: String,
+    /// Opaque application data stored with the session, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "binary")]
+    pub data: Option<Vec<u8>>,
+}
+
+impl SessionRecord {
+    /// Create a session record for the given email address.
+    pub fn new(email: String) -> Self {
+        SessionRecord { email, data: None }
+    }
+
+    /// Attach opaque application data to the session record.
+    pub fn data(mut self, data: Vec<u8>) -> Self {
+        self.data = Some(data);
+        self
+    }
+}
+
+/// Serializes binary data as base64 in human-readable formats, and as raw bytes otherwise.
+mod binary {
+    use super::*;
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(data: &Option<Vec<u8>>, ser: S) -> Result<S::Ok, S::Error> {
+        match data {
+            Some(data) if ser.is_human_readable() => ser.serialize_some(&base64url::encode(data)),
+            Some(data) => ser.serialize_some(&Bytes(data)),
+            None => ser.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<Option<Vec<u8>>, D::Error> {
+        if de.is_human_readable() {
+            let data: Option<String> = Deserialize::deserialize(de)?;
+            data.map(|data| base64url::decode(&data).map_err(Error::custom))
+                .transpose()
+        } else {
+            let data: Option<ByteBuf> = Deserialize::deserialize(de)?;
+            Ok(data.map(|data| data.0))
+        }
+    }
+
+    struct Bytes<'a>(&'a [u8]);
+
+    impl Serialize for Bytes<'_> {
+        fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+            ser.serialize_bytes(self.0)
+        }
+    }
+
+    struct ByteBuf(Vec<u8>);
+
+    impl<'de> Deserialize<'de> for ByteBuf {
+        fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+            struct ByteBufVisitor;
+            impl<'de> serde::de::Visitor<'de> for ByteBufVisitor {
+                type Value = ByteBuf;
+
+                fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    formatter.write_str("a byte array")
+                }
+
+                fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+                    Ok(ByteBuf(v.to_vec()))
+                }
+
+                fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+                    Ok(ByteBuf(v))
+                }
+
+                fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+                where
+                    A: serde::de::SeqAccess<'de>,
+                {
+                    let mut data = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+                    while let Some(byte) = seq.next_element()? {
+                        data.push(byte);
+                    }
+                    Ok(ByteBuf(data))
+                }
+            }
+            de.deserialize_byte_buf(ByteBufVisitor)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<C: Codec>(codec: C) {
+        for data in [None, Some(vec![]), Some(vec![0, 1, 254, 255])] {
+            let doc = CachedDocument {
+                data: data.clone(),
+                expires: 1_600_000_000,
+            };
+            let decoded: CachedDocument = codec.decode(&codec.encode(&doc).unwrap()).unwrap();
+            assert_eq!(decoded.data, doc.data);
+            assert_eq!(decoded.expires, doc.expires);
+
+            let mut record = SessionRecord::new("a@example.com".into());
+            record.data = data;
+            let decoded: SessionRecord = codec.decode(&codec.encode(&record).unwrap()).unwrap();
+            assert_eq!(decoded.email, record.email);
+            assert_eq!(decoded.data, record.data);
+        }
+    }
+
+    #[test]
+    fn json_round_trip() {
+        round_trip(JsonCodec);
+    }
+
+    #[test]
+    fn json_encodes_data_as_base64() {
+        let record = SessionRecord::new("a@example.com".into()).data(vec![0xff, 0xfe]);
+        let encoded = JsonCodec.encode(&record).unwrap();
+        assert_eq!(encoded, br#"{"email":"a@example.com","data":"__4"}"#);
+    }
+
+    #[test]
+    fn json_decodes_record_without_data() {
+        let record: SessionRecord = JsonCodec.decode(br#"{"email":"a@example.com"}"#).unwrap();
+        assert_eq!(record.data, None);
+    }
+
+    #[cfg(feature = "codec-cbor")]
+    #[test]
+    fn cbor_round_trip() {
+        round_trip(CborCodec);
+    }
+
+    #[cfg(feature = "codec-msgpack")]
+    #[test]
+    fn msgpack_round_trip() {
+        round_trip(MessagePackCodec);
+    }
+}
@@ -0,0 +1,485 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use aws_sdk_dynamodb::{
+    primitives::Blob,
+    types::{AttributeValue, ReturnValue},
+    Client as DynamoClient,
+};
+use bytes::Bytes;
+use url::Url;
+
+use crate::misc::{DynErr, DynFut, DynFutRes, DynRes};
+use crate::store::simple::{hex_digest, unix_time, url_hash};
+use crate::{
+    simple_fetch, CachedDocument, Clock, Codec, FetchError, HttpClient, JsonCodec, NonceGenerator,
+    RandomNonces, SessionRecord, Store, SystemClock,
+};
+
+/// Name of the partition key attribute, a string.
+const KEY: &str = "id";
+/// Name of the attribute holding the encoded value, a binary.
+const VALUE: &str = "value";
+/// Name of the expiry attribute, a number holding a Unix timestamp.
+const EXPIRES: &str = "expires";
+
+/// A `Store` implementation that keeps everything in Amazon DynamoDB.
+///
+/// This is available with the `dynamodb-store` crate feature, and is suitable for serverless
+/// deployments, where an in-memory store is lost on every cold start. Values are encoded using the
+/// given `Codec`, which defaults to `JsonCodec`.
+///
+/// Sessions and cached documents are kept in two tables, which may be the same table. Both need a
+/// string partition key named `id`, and no sort key. Items have an `expires` attribute with a Unix
+/// timestamp, which should be enabled as the TTL attribute of the tables, so DynamoDB removes
+/// expired items. Because DynamoDB may take a while to do so, the store also ignores expired
+/// items itself. Key pins and endpoint state are kept in the cache table, the former without
+/// expiry.
+///
+/// Documents are fetched using the given `HttpClient` on cache miss. Sessions are consumed using a
+/// conditional delete, so concurrent workers can't both consume the same session.
+pub struct DynamoStore<C, K = JsonCodec> {
+    dynamo: DynamoClient,
+    client: C,
+    codec: Arc<K>,
+    sessions_table: String,
+    cache_table: String,
+    timeout: Duration,
+    session_lifetime: Duration,
+    nonce_generator: Arc<dyn NonceGenerator>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<C> DynamoStore<C> {
+    /// Create a store using the given DynamoDB client and HTTP client, and table names.
+    ///
+    /// The default timeout for HTTP requests is 30 seconds, and sessions expire after 15 minutes.
+    pub fn new(
+        dynamo: DynamoClient,
+        client: C,
+        sessions_table: impl Into<String>,
+        cache_table: impl Into<String>,
+    ) -> Self {
+        DynamoStore {
+            dynamo,
+            client,
+            codec: Arc::new(JsonCodec),
+            sessions_table: sessions_table.into(),
+            cache_table: cache_table.into(),
+            timeout: Duration::from_secs(30),
+            session_lifetime: Duration::from_secs(15 * 60),
+            nonce_generator: Arc::new(RandomNonces::new()),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl<C, K> DynamoStore<C, K> {
+    /// Use the given `Codec` to encode values.
+    pub fn codec<K2: Codec>(self, codec: K2) -> DynamoStore<C, K2> {
+        DynamoStore {
+            dynamo: self.dynamo,
+            client: self.client,
+            codec: Arc::new(codec),
+            sessions_table: self.sessions_table,
+            cache_table: self.cache_table,
+            timeout: self.timeout,
+            session_lifetime: self.session_lifetime,
+            nonce_generator: self.nonce_generator,
+            clock: self.clock,
+        }
+    }
+
+    /// Configure the timeout for HTTP requests.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Configure how long a session remains valid after `Store::new_nonce`.
+    ///
+    /// This is overridden by the TTL passed to `Store::new_nonce_with_ttl`.
+    pub fn session_lifetime(mut self, lifetime: Duration) -> Self {
+        self.session_lifetime = lifetime;
+        self
+    }
+
+    /// Use the given `Clock` for expiry, instead of the system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Generate nonces for new sessions using `generator`, instead of `RandomNonces`.
+    pub fn nonce_generator(mut self, generator: Arc<dyn NonceGenerator>) -> Self {
+        self.nonce_generator = generator;
+        self
+    }
+
+    /// The DynamoDB client used by this store.
+    pub fn dynamo(&self) -> &DynamoClient {
+        &self.dynamo
+    }
+
+    /// A handle for session operations, which can be moved into a future.
+    fn sessions(&self) -> Table {
+        Table {
+            dynamo: self.dynamo.clone(),
+            name: self.sessions_table.clone(),
+            clock: self.clock.clone(),
+        }
+    }
+
+    /// A handle for cache operations, which can be moved into a future.
+    fn cache(&self) -> Table {
+        Table {
+            dynamo: self.dynamo.clone(),
+            name: self.cache_table.clone(),
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+impl<C, K> DynamoStore<C, K>
+where
+    C: HttpClient + Clone,
+    K: Codec,
+{
+    /// Fetch a document, using the cached result if `use_cache` is set and it is fresh.
+    fn fetch_document(&self, url: Url, use_cache: bool) -> DynFut<Result<Bytes, FetchError>> {
+        let table = self.cache();
+        let client = self.client.clone();
+        let codec = self.codec.clone();
+        let key = cache_key(&url);
+        let timeout = self.timeout;
+        Box::pin(async move {
+            if use_cache {
+                let cached = table.get(&key).await.map_err(FetchError::Store)?;
+                if let Some(cached) = cached {
+                    let doc: CachedDocument = codec.decode(&cached).map_err(FetchError::Store)?;
+                    if table.now() < doc.expires {
+                        return match doc.data {
+                            Some(data) => Ok(data.into()),
+                            None => Err(FetchError::Fetch(Arc::new(
+                                "fetching the document failed recently".into(),
+                            ))),
+                        };
+                    }
+                }
+            }
+
+            let (result, max_age) = simple_fetch(&client, timeout, url).await;
+            let doc = CachedDocument {
+                data: result.as_ref().ok().map(|data| data.to_vec()),
+                expires: table.now().saturating_add(max_age.as_secs()),
+            };
+            let value = codec.encode(&doc).map_err(FetchError::Store)?;
+            table
+                .put(key, value, Some(doc.expires), false)
+                .await
+                .map_err(FetchError::Store)?;
+            result.map_err(|err| FetchError::Fetch(Arc::new(err)))
+        })
+    }
+
+    /// Store a new session with a random nonce, and return the nonce.
+    fn add_session(&self, record: SessionRecord, ttl: Duration) -> DynFutRes<String> {
+        let table = self.sessions();
+        let codec = self.codec.clone();
+        let nonce = self.nonce_generator.generate();
+        Box::pin(async move {
+            let nonce = nonce.await?;
+            add_session(&table, &*codec, ttl, &nonce, &record).await?;
+            Ok(nonce)
+        })
+    }
+}
+
+impl<C, K> Store for DynamoStore<C, K>
+where
+    C: HttpClient + Clone,
+    K: Codec,
+{
+    fn fetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        self.fetch_document(url, true)
+    }
+
+    fn refetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        self.fetch_document(url, false)
+    }
+
+    fn cache_lifetime(&self, url: Url) -> DynFutRes<Option<Duration>> {
+        let table = self.cache();
+        let codec = self.codec.clone();
+        let key = cache_key(&url);
+        Box::pin(async move {
+            let lifetime = match table.get(&key).await? {
+                Some(cached) => codec.decode::<CachedDocument>(&cached)?.expires,
+                None => return Ok(None),
+            };
+            Ok(Some(lifetime.saturating_sub(table.now()))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs))
+        })
+    }
+
+    fn new_nonce(&self, email: String) -> DynFutRes<String> {
+        self.new_nonce_with_ttl(email, self.session_lifetime)
+    }
+
+    fn new_nonce_with_ttl(&self, email: String, ttl: Duration) -> DynFutRes<String> {
+        self.add_session(SessionRecord::new(email), ttl)
+    }
+
+    fn consume_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        let table = self.sessions();
+        let key = session_key(&nonce, &email);
+        Box::pin(async move { Ok(table.take(key).await?.is_some()) })
+    }
+
+    fn new_nonce_with_data(
+        &self,
+        email: String,
+        data: Vec<u8>,
+        ttl: Duration,
+    ) -> DynFutRes<String> {
+        self.add_session(SessionRecord::new(email).data(data), ttl)
+    }
+
+    fn consume_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        let table = self.sessions();
+        let codec = self.codec.clone();
+        let key = session_key(&nonce, &email);
+        Box::pin(async move {
+            match table.take(key).await? {
+                Some(value) => {
+                    let record: SessionRecord = codec.decode(&value)?;
+                    Ok(Some(record.data.unwrap_or_default()))
+                }
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn peek_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        let table = self.sessions();
+        let key = session_key(&nonce, &email);
+        Box::pin(async move { Ok(table.get(&key).await?.is_some()) })
+    }
+
+    fn peek_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        let table = self.sessions();
+        let codec = self.codec.clone();
+        let key = session_key(&nonce, &email);
+        Box::pin(async move {
+            match table.get(&key).await? {
+                Some(value) => {
+                    let record: SessionRecord = codec.decode(&value)?;
+                    Ok(Some(record.data.unwrap_or_default()))
+                }
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn insert_nonce(
+        &self,
+        nonce: String,
+        email: String,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> DynFutRes<()> {
+        let table = self.sessions();
+        let codec = self.codec.clone();
+        let ttl = ttl.unwrap_or(self.session_lifetime);
+        Box::pin(async move {
+            let record = SessionRecord::new(email).data(data);
+            add_session(&table, &*codec, ttl, &nonce, &record).await
+        })
+    }
+
+    fn load_key_pins(&self, origin: String) -> DynFutRes<Option<String>> {
+        let table = self.cache();
+        let key = key_pins_key(&origin);
+        Box::pin(async move {
+            match table.get(&key).await? {
+                Some(value) => Ok(Some(String::from_utf8(value)?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn save_key_pins(&self, origin: String, pins: String) -> DynFutRes<()> {
+        let table = self.cache();
+        let key = key_pins_key(&origin);
+        Box::pin(async move { table.put(key, pins.into_bytes(), None, false).await })
+    }
+
+    fn load_endpoint_state(&self, origin: String) -> DynFutRes<Option<String>> {
+        let table = self.cache();
+        let key = endpoint_state_key(&origin);
+        Box::pin(async move {
+            match table.get(&key).await? {
+                Some(value) => Ok(Some(String::from_utf8(value)?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn save_endpoint_state(&self, origin: String, state: String, ttl: Duration) -> DynFutRes<()> {
+        let table = self.cache();
+        let key = endpoint_state_key(&origin);
+        Box::pin(async move {
+            let expires = table.now().saturating_add(ttl.as_secs());
+            table
+                .put(key, state.into_bytes(), Some(expires), false)
+                .await
+        })
+    }
+}
+
+/// A DynamoDB table with the layout described on `DynamoStore`.
+struct Table {
+    dynamo: DynamoClient,
+    name: String,
+    clock: Arc<dyn Clock>,
+}
+
+impl Table {
+    /// The current time as a Unix timestamp.
+    fn now(&self) -> u64 {
+        unix_time(&*self.clock) as u64
+    }
+
+    /// Read the value of an item that has not expired, using a strongly consistent read.
+    async fn get(&self, key: &str) -> DynRes<Option<Vec<u8>>> {
+        let output = self
+            .dynamo
+            .get_item()
+            .table_name(&self.name)
+            .key(KEY, AttributeValue::S(key.to_owned()))
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(|err| Box::new(err) as DynErr)?;
+        match output.item {
+            Some(item) if !is_expired(&item, self.now()) => value(&item).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Write an item, expiring at the Unix timestamp `expires`, if given.
+    ///
+    /// With `add`, the write fails if an item with the same key exists, even if it has expired.
+    async fn put(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expires: Option<u64>,
+        add: bool,
+    ) -> DynRes<()> {
+        let mut request = self
+            .dynamo
+            .put_item()
+            .table_name(&self.name)
+            .item(KEY, AttributeValue::S(key))
+            .item(VALUE, AttributeValue::B(Blob::new(value)));
+        if let Some(expires) = expires {
+            request = request.item(EXPIRES, AttributeValue::N(expires.to_string()));
+        }
+        if add {
+            request = request
+                .condition_expression("attribute_not_exists(#key)")
+                .expression_attribute_names("#key", KEY);
+        }
+        request
+            .send()
+            .await
+            .map_err(|err| Box::new(err) as DynErr)?;
+        Ok(())
+    }
+
+    /// Delete an item that has not expired, and return its value.
+    ///
+    /// Only a single concurrent call can return the value, because the delete is conditional on
+    /// the item existing.
+    async fn take(&self, key: String) -> DynRes<Option<Vec<u8>>> {
+        let result = self
+            .dynamo
+            .delete_item()
+            .table_name(&self.name)
+            .key(KEY, AttributeValue::S(key))
+            .condition_expression("attribute_exists(#key) AND #expires > :now")
+            .expression_attribute_names("#key", KEY)
+            .expression_attribute_names("#expires", EXPIRES)
+            .expression_attribute_values(":now", AttributeValue::N(self.now().to_string()))
+            .return_values(ReturnValue::AllOld)
+            .send()
+            .await;
+        match result {
+            Ok(output) => match output.attributes {
+                Some(item) => value(&item).map(Some),
+                None => Ok(None),
+            },
+            Err(err)
+                if err
+                    .as_service_error()
+                    .is_some_and(|err| err.is_conditional_check_failed_exception()) =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(Box::new(err)),
+        }
+    }
+}
+
+/// Whether an item has an `expires` attribute in the past.
+fn is_expired(item: &HashMap<String, AttributeValue>, now: u64) -> bool {
+    item.get(EXPIRES)
+        .and_then(|expires| expires.as_n().ok())
+        .and_then(|expires| expires.parse::<u64>().ok())
+        .is_some_and(|expires| expires <= now)
+}
+
+/// The `value` attribute of an item.
+fn value(item: &HashMap<String, AttributeValue>) -> DynRes<Vec<u8>> {
+    match item.get(VALUE).map(AttributeValue::as_b) {
+        Some(Ok(blob)) => Ok(blob.clone().into_inner()),
+        _ => Err("the item has no binary value attribute".into()),
+    }
+}
+
+/// The key for a cached document.
+fn cache_key(url: &Url) -> String {
+    format!("cache:{}", url_hash(url))
+}
+
+/// The key for the key pins of a broker origin. These never expire.
+fn key_pins_key(origin: &str) -> String {
+    format!("pins:{}", hex_digest(origin))
+}
+
+/// The key for the shared state of a broker origin.
+fn endpoint_state_key(origin: &str) -> String {
+    format!("state:{}", hex_digest(origin))
+}
+
+/// The key for a session.
+///
+/// The email is part of the key, so consuming the session is a single conditional delete.
+fn session_key(nonce: &str, email: &str) -> String {
+    format!("session:{}:{}", nonce, hex_digest(email))
+}
+
+/// Store a session, failing if it already exists.
+async fn add_session<K: Codec>(
+    table: &Table,
+    codec: &K,
+    lifetime: Duration,
+    nonce: &str,
+    record: &SessionRecord,
+) -> DynRes<()> {
+    let key = session_key(nonce, &record.email);
+    let value = codec.encode(record)?;
+    let expires = table.now().saturating_add(lifetime.as_secs());
+    table.put(key, value, Some(expires), true).await
+}
@@ -0,0 +1,435 @@
+use std::{sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use url::Url;
+
+use crate::misc::{DynFut, DynFutRes};
+use crate::{FetchError, Store};
+
+/// A `Store` combinator that replicates nonces to a secondary store.
+///
+/// New nonces are generated by the primary store and copied to the secondary store using
+/// `Store::insert_nonce`, together with their TTL and session data. If the primary store fails, the
+/// nonce is generated by the secondary store instead. Nonces are consumed from both stores, and are
+/// accepted if either store had the pair. This way, an outage of a single store does not break
+/// logins that are in progress.
+///
+/// Document fetches use the primary store, and fall back to the secondary store only if the
+/// primary store returns `FetchError::Store`. Key pins and endpoint state are saved to both stores,
+/// and loaded from the primary store, falling back to the secondary store on error.
+///
+/// Note the trade-off: if a store is unavailable while a nonce is consumed, the pair remains in
+/// that store, and could be accepted again once the store recovers. Use stores that expire
+/// nonces to limit this window.
+pub struct FailoverStore<A, B> {
+    primary: Arc<A>,
+    secondary: Arc<B>,
+}
+
+impl<A, B> FailoverStore<A, B> {
+    /// Create a failover store from a primary and secondary store.
+    pub fn new(primary: A, secondary: B) -> Self {
+        FailoverStore {
+            primary: Arc::new(primary),
+            secondary: Arc::new(secondary),
+        }
+    }
+
+    /// The primary store.
+    pub fn primary(&self) -> &A {
+        &self.primary
+    }
+
+    /// The secondary store.
+    pub fn secondary(&self) -> &B {
+        &self.secondary
+    }
+}
+
+impl<A: Store, B: Store> FailoverStore<A, B> {
+    /// Create a nonce in the primary store and replicate it, or fall back to the secondary.
+    fn create_nonce(
+        &self,
+        email: String,
+        data: Option<Vec<u8>>,
+        ttl: Option<Duration>,
+    ) -> DynFutRes<String> {
+        let primary = new_nonce(&*self.primary, email.clone(), data.clone(), ttl);
+        let secondary = self.secondary.clone();
+        Box::pin(async move {
+            match primary.await {
+                Ok(nonce) => {
+                    // Replication is best-effort; the primary store has the pair.
+                    let data = data.unwrap_or_default();
+                    let _ = secondary
+                        .insert_nonce(nonce.clone(), email, data, ttl)
+                        .await;
+                    Ok(nonce)
+                }
+                Err(_) => new_nonce(&*secondary, email, data, ttl).await,
+            }
+        })
+    }
+}
+
+impl<A: Store, B: Store> Store for FailoverStore<A, B> {
+    fn fetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        let primary = self.primary.fetch(url.clone());
+        let secondary = self.secondary.clone();
+        Box::pin(async move {
+            match primary.await {
+                Err(FetchError::Store(_)) => secondary.fetch(url).await,
+                result => result,
+            }
+        })
+    }
+
+    fn refetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        let primary = self.primary.refetch(url.clone());
+        let secondary = self.secondary.clone();
+        Box::pin(async move {
+            match primary.await {
+                Err(FetchError::Store(_)) => secondary.refetch(url).await,
+                result => result,
+            }
+        })
+    }
+
+    fn cache_lifetime(&self, url: Url) -> DynFutRes<Option<Duration>> {
+        let primary = self.primary.cache_lifetime(url.clone());
+        let secondary = self.secondary.clone();
+        Box::pin(async move {
+            match primary.await {
+                Ok(lifetime) => Ok(lifetime),
+                Err(_) => secondary.cache_lifetime(url).await,
+            }
+        })
+    }
+
+    fn new_nonce(&self, email: String) -> DynFutRes<String> {
+        self.create_nonce(email, None, None)
+    }
+
+    fn new_nonce_with_ttl(&self, email: String, ttl: Duration) -> DynFutRes<String> {
+        self.create_nonce(email, None, Some(ttl))
+    }
+
+    fn consume_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        let primary = self.primary.consume_nonce(nonce.clone(), email.clone());
+        let secondary = self.secondary.consume_nonce(nonce, email);
+        Box::pin(async move {
+            match (primary.await, secondary.await) {
+                (Ok(a), Ok(b)) => Ok(a || b),
+                (Ok(found), Err(_)) | (Err(_), Ok(found)) => Ok(found),
+                (Err(err), Err(_)) => Err(err),
+            }
+        })
+    }
+
+    fn new_nonce_with_data(
+        &self,
+        email: String,
+        data: Vec<u8>,
+        ttl: Duration,
+    ) -> DynFutRes<String> {
+        self.create_nonce(email, Some(data), Some(ttl))
+    }
+
+    fn consume_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        let primary = self
+            .primary
+            .consume_nonce_with_data(nonce.clone(), email.clone());
+        let secondary = self.secondary.consume_nonce_with_data(nonce, email);
+        Box::pin(async move {
+            match (primary.await, secondary.await) {
+                (Ok(Some(data)), _) | (_, Ok(Some(data))) => Ok(Some(data)),
+                (Ok(None), _) | (_, Ok(None)) => Ok(None),
+                (Err(err), Err(_)) => Err(err),
+            }
+        })
+    }
+
+    fn insert_nonce(
+        &self,
+        nonce: String,
+        email: String,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> DynFutRes<()> {
+        let primary = self
+            .primary
+            .insert_nonce(nonce.clone(), email.clone(), data.clone(), ttl);
+        let secondary = self.secondary.insert_nonce(nonce, email, data, ttl);
+        Box::pin(async move {
+            match (primary.await, secondary.await) {
+                (Err(err), Err(_)) => Err(err),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn peek_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        let primary = self.primary.peek_nonce(nonce.clone(), email.clone());
+        let secondary = self.secondary.peek_nonce(nonce, email);
+        Box::pin(async move {
+            match (primary.await, secondary.await) {
+                (Ok(a), Ok(b)) => Ok(a || b),
+                (Ok(found), Err(_)) | (Err(_), Ok(found)) => Ok(found),
+                (Err(err), Err(_)) => Err(err),
+            }
+        })
+    }
+
+    fn peek_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        let primary = self
+            .primary
+            .peek_nonce_with_data(nonce.clone(), email.clone());
+        let secondary = self.secondary.peek_nonce_with_data(nonce, email);
+        Box::pin(async move {
+            match (primary.await, secondary.await) {
+                (Ok(Some(data)), _) | (_, Ok(Some(data))) => Ok(Some(data)),
+                (Ok(None), _) | (_, Ok(None)) => Ok(None),
+                (Err(err), Err(_)) => Err(err),
+            }
+        })
+    }
+
+    fn load_key_pins(&self, origin: String) -> DynFutRes<Option<String>> {
+        let primary = self.primary.load_key_pins(origin.clone());
+        let secondary = self.secondary.clone();
+        Box::pin(async move {
+            match primary.await {
+                Ok(pins) => Ok(pins),
+                Err(_) => secondary.load_key_pins(origin).await,
+            }
+        })
+    }
+
+    fn save_key_pins(&self, origin: String, pins: String) -> DynFutRes<()> {
+        let primary = self.primary.save_key_pins(origin.clone(), pins.clone());
+        let secondary = self.secondary.save_key_pins(origin, pins);
+        Box::pin(async move {
+            match (primary.await, secondary.await) {
+                (Err(err), Err(_)) => Err(err),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn load_endpoint_state(&self, origin: String) -> DynFutRes<Option<String>> {
+        let primary = self.primary.load_endpoint_state(origin.clone());
+        let secondary = self.secondary.clone();
+        Box::pin(async move {
+            match primary.await {
+                Ok(state) => Ok(state),
+                Err(_) => secondary.load_endpoint_state(origin).await,
+            }
+        })
+    }
+
+    fn save_endpoint_state(&self, origin: String, state: String, ttl: Duration) -> DynFutRes<()> {
+        let primary = self
+            .primary
+            .save_endpoint_state(origin.clone(), state.clone(), ttl);
+        let secondary = self.secondary.save_endpoint_state(origin, state, ttl);
+        Box::pin(async move {
+            match (primary.await, secondary.await) {
+                (Err(err), Err(_)) => Err(err),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn record_failure(&self, record: String, retention: Duration) -> DynFutRes<()> {
+        let primary = self.primary.record_failure(record.clone(), retention);
+        let secondary = self.secondary.record_failure(record, retention);
+        Box::pin(async move {
+            match (primary.await, secondary.await) {
+                (Err(err), Err(_)) => Err(err),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn load_failures(&self) -> DynFutRes<Vec<String>> {
+        let primary = self.primary.load_failures();
+        let secondary = self.secondary.clone();
+        Box::pin(async move {
+            match primary.await {
+                Ok(records) => Ok(records),
+                Err(_) => secondary.load_failures().await,
+            }
+        })
+    }
+}
+
+/// Call `Store::new_nonce_with_data` if data is given, otherwise `Store::new_nonce_with_ttl` if a
+/// TTL is given, otherwise `Store::new_nonce`.
+fn new_nonce<S: Store>(
+    store: &S,
+    email: String,
+    data: Option<Vec<u8>>,
+    ttl: Option<Duration>,
+) -> DynFutRes<String> {
+    match (data, ttl) {
+        (Some(data), Some(ttl)) => store.new_nonce_with_data(email, data, ttl),
+        (_, Some(ttl)) => store.new_nonce_with_ttl(email, ttl),
+        (_, None) => store.new_nonce(email),
+    }
+}
+
+#[cfg(all(test, feature = "memory-store"))]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::{HttpClient, HttpRequest, HttpResponse, ManualClock, MemoryStore};
+
+    const EMAIL: &str = "a@example.com";
+    const TTL: Duration = Duration::from_secs(60);
+
+    /// An `HttpClient` for stores that are not expected to fetch documents.
+    #[derive(Clone)]
+    struct NoHttp;
+
+    impl HttpClient for NoHttp {
+        fn request(&self, _: HttpRequest) -> DynFutRes<HttpResponse> {
+            Box::pin(async { Err("unexpected request".into()) })
+        }
+    }
+
+    type Memory = MemoryStore<NoHttp>;
+
+    /// A store that is unavailable.
+    struct Down;
+
+    impl Store for Down {
+        fn fetch(&self, _: Url) -> DynFut<Result<Bytes, FetchError>> {
+            Box::pin(async { Err(FetchError::Store("store down".into())) })
+        }
+
+        fn new_nonce(&self, _: String) -> DynFutRes<String> {
+            Box::pin(async { Err("store down".into()) })
+        }
+
+        fn consume_nonce(&self, _: String, _: String) -> DynFutRes<bool> {
+            Box::pin(async { Err("store down".into()) })
+        }
+    }
+
+    fn memory_store(clock: &Arc<ManualClock>) -> Memory {
+        MemoryStore::with_http_client(NoHttp, Duration::from_secs(30)).clock(clock.clone())
+    }
+
+    fn failover() -> (FailoverStore<Memory, Memory>, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new(SystemTime::now()));
+        let store = FailoverStore::new(memory_store(&clock), memory_store(&clock));
+        (store, clock)
+    }
+
+    #[tokio::test]
+    async fn replica_expires() {
+        let (store, clock) = failover();
+        let nonce = store.new_nonce_with_ttl(EMAIL.into(), TTL).await.unwrap();
+        let secondary = store.secondary();
+        assert!(secondary
+            .peek_nonce(nonce.clone(), EMAIL.into())
+            .await
+            .unwrap());
+        clock.advance(TTL);
+        assert!(!secondary.consume_nonce(nonce, EMAIL.into()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn replicates_session_data() {
+        let (store, _) = failover();
+        let data = b"data".to_vec();
+        let nonce = store
+            .new_nonce_with_data(EMAIL.into(), data.clone(), TTL)
+            .await
+            .unwrap();
+        let replica = store
+            .secondary()
+            .consume_nonce_with_data(nonce, EMAIL.into())
+            .await
+            .unwrap();
+        assert_eq!(replica, Some(data));
+    }
+
+    #[tokio::test]
+    async fn replicates_nonces() {
+        let (store, _) = failover();
+        let nonce = store.new_nonce_with_ttl(EMAIL.into(), TTL).await.unwrap();
+        for replica in [store.primary(), store.secondary()] {
+            assert!(replica
+                .peek_nonce(nonce.clone(), EMAIL.into())
+                .await
+                .unwrap());
+        }
+        assert!(store
+            .consume_nonce(nonce.clone(), EMAIL.into())
+            .await
+            .unwrap());
+        for replica in [store.primary(), store.secondary()] {
+            assert!(!replica
+                .peek_nonce(nonce.clone(), EMAIL.into())
+                .await
+                .unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn accepts_nonce_from_either_store() {
+        let (store, _) = failover();
+        let nonce = store.new_nonce_with_ttl(EMAIL.into(), TTL).await.unwrap();
+        assert!(store
+            .primary()
+            .consume_nonce(nonce.clone(), EMAIL.into())
+            .await
+            .unwrap());
+        assert!(store
+            .consume_nonce(nonce.clone(), EMAIL.into())
+            .await
+            .unwrap());
+        assert!(!store.consume_nonce(nonce, EMAIL.into()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_secondary() {
+        let clock = Arc::new(ManualClock::new(SystemTime::now()));
+        let store = FailoverStore::new(Down, memory_store(&clock));
+        let nonce = store.new_nonce_with_ttl(EMAIL.into(), TTL).await.unwrap();
+        assert!(store
+            .secondary()
+            .peek_nonce(nonce.clone(), EMAIL.into())
+            .await
+            .unwrap());
+        assert!(store.consume_nonce(nonce, EMAIL.into()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn fails_when_both_stores_are_down() {
+        let store = FailoverStore::new(Down, Down);
+        assert!(store.new_nonce(EMAIL.into()).await.is_err());
+        assert!(store
+            .consume_nonce("nonce".into(), EMAIL.into())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn fetch_falls_back_only_on_store_errors() {
+        let clock = Arc::new(ManualClock::new(SystemTime::now()));
+        let url: Url = "https://broker.example/jwks.json".parse().unwrap();
+
+        // The secondary store attempts the request, and fails with a fetch error.
+        let store = FailoverStore::new(Down, memory_store(&clock));
+        let err = store.fetch(url.clone()).await.unwrap_err();
+        assert!(matches!(err, FetchError::Fetch(_)));
+
+        // Fetch errors from the primary store are returned as-is.
+        let store = FailoverStore::new(memory_store(&clock), Down);
+        let err = store.fetch(url).await.unwrap_err();
+        assert!(matches!(err, FetchError::Fetch(_)));
+    }
+}
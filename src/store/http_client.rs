@@ -0,0 +1,273 @@
+#[cfg(all(
+    any(feature = "http-hyper", feature = "http-reqwest"),
+    any(feature = "tls-native", feature = "tls-rustls")
+))]
+use std::time::Duration;
+
+use bytes::Bytes;
+#[cfg(any(feature = "http-hyper", feature = "http-reqwest"))]
+use bytes::BytesMut;
+use thiserror::Error;
+
+#[cfg(any(feature = "http-hyper", feature = "http-reqwest"))]
+use crate::misc::DynErr;
+use crate::misc::DynFutRes;
+
+/// The request type passed to an `HttpClient`.
+pub type HttpRequest = http::Request<()>;
+
+/// The response type returned by an `HttpClient`.
+pub type HttpResponse = http::Response<Bytes>;
+
+/// Trait for HTTP clients that can be used by `MemoryStore` and `simple_fetch`.
+///
+/// Implementations are provided for Hyper and reqwest clients, when the `http-hyper` and
+/// `http-reqwest` crate features are enabled respectively. Other HTTP stacks can be plugged in by
+/// implementing this trait.
+pub trait HttpClient: Send + Sync + 'static {
+    /// Perform the request and read the full response body.
+    ///
+    /// Implementations should not check the response status; this is left to the caller.
+    fn request(&self, request: HttpRequest) -> DynFutRes<HttpResponse>;
+}
+
+/// A request extension that limits the size of the response body, in bytes.
+///
+/// `MemoryStore::max_response_size` adds this to requests. The Hyper and reqwest implementations
+/// of `HttpClient` stop reading the body once it exceeds the limit, and fail with
+/// `ResponseTooLarge`. Other implementations may ignore it, in which case the `MemoryStore` rejects
+/// the body after it was read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResponseSizeLimit(pub usize);
+
+/// A response body exceeded its `ResponseSizeLimit`, given in bytes.
+#[derive(Clone, Debug, Error)]
+#[error("the response body exceeds {0} bytes")]
+pub struct ResponseTooLarge(pub usize);
+
+/// Append `chunk` to `buf`, unless that would exceed `limit`.
+#[cfg(any(feature = "http-hyper", feature = "http-reqwest"))]
+fn append_limited(buf: &mut BytesMut, chunk: &[u8], limit: usize) -> Result<(), DynErr> {
+    if buf.len() + chunk.len() > limit {
+        return Err(Box::new(ResponseTooLarge(limit)));
+    }
+    buf.extend_from_slice(chunk);
+    Ok(())
+}
+
+#[cfg(feature = "http-hyper")]
+impl<C> HttpClient for hyper::Client<C>
+where
+    C: hyper::client::connect::Connect + Clone + Send + Sync + 'static,
+{
+    fn request(&self, request: HttpRequest) -> DynFutRes<HttpResponse> {
+        use hyper::body::HttpBody;
+
+        let limit = request.extensions().get::<ResponseSizeLimit>().copied();
+        let request = request.map(|()| hyper::Body::empty());
+        let response = hyper::Client::request(self, request);
+        Box::pin(async move {
+            let (parts, mut body) = response.await?.into_parts();
+            let body = match limit {
+                None => hyper::body::to_bytes(body).await?,
+                Some(ResponseSizeLimit(limit)) => {
+                    let mut buf = BytesMut::new();
+                    while let Some(chunk) = body.data().await {
+                        append_limited(&mut buf, &chunk?, limit)?;
+                    }
+                    buf.freeze()
+                }
+            };
+            Ok(HttpResponse::from_parts(parts, body))
+        })
+    }
+}
+
+#[cfg(feature = "http-reqwest")]
+impl HttpClient for reqwest::Client {
+    fn request(&self, request: HttpRequest) -> DynFutRes<HttpResponse> {
+        let (parts, ()) = request.into_parts();
+        let limit = parts.extensions.get::<ResponseSizeLimit>().copied();
+        let response = self
+            .request(parts.method, parts.uri.to_string())
+            .headers(parts.headers)
+            .send();
+        Box::pin(async move {
+            let mut response = response.await?;
+            let mut builder = http::Response::builder()
+                .status(response.status())
+                .version(response.version());
+            if let Some(headers) = builder.headers_mut() {
+                *headers = response.headers().clone();
+            }
+            let body = match limit {
+                None => response.bytes().await?,
+                Some(ResponseSizeLimit(limit)) => {
+                    let mut buf = BytesMut::new();
+                    while let Some(chunk) = response.chunk().await? {
+                        append_limited(&mut buf, &chunk, limit)?;
+                    }
+                    buf.freeze()
+                }
+            };
+            Ok(builder.body(body)?)
+        })
+    }
+}
+
+/// The HTTP client type used by the default `MemoryStore`.
+///
+/// This depends on the enabled crate features. Hyper is preferred over reqwest, and native-tls is
+/// preferred over rustls, if multiple are enabled.
+#[cfg(all(feature = "http-hyper", feature = "tls-native"))]
+pub type DefaultHttpClient = hyper::Client<hyper_tls::HttpsConnector<crate::ProxyConnector>>;
+
+/// The HTTP client type used by the default `MemoryStore`.
+///
+/// This depends on the enabled crate features. Hyper is preferred over reqwest, and native-tls is
+/// preferred over rustls, if multiple are enabled.
+#[cfg(all(
+    feature = "http-hyper",
+    not(feature = "tls-native"),
+    feature = "tls-rustls"
+))]
+pub type DefaultHttpClient = hyper::Client<hyper_rustls::HttpsConnector<crate::ProxyConnector>>;
+
+/// The HTTP client type used by the default `MemoryStore`.
+///
+/// This depends on the enabled crate features. Hyper is preferred over reqwest, and native-tls is
+/// preferred over rustls, if multiple are enabled.
+#[cfg(all(
+    not(feature = "http-hyper"),
+    feature = "http-reqwest",
+    any(feature = "tls-native", feature = "tls-rustls")
+))]
+pub type DefaultHttpClient = reqwest::Client;
+
+/// Network settings for `default_http_client_with_config`.
+#[cfg(all(
+    any(feature = "http-hyper", feature = "http-reqwest"),
+    any(feature = "tls-native", feature = "tls-rustls")
+))]
+#[derive(Clone, Debug)]
+pub struct HttpClientConfig {
+    proxy: crate::ProxyConfig,
+    connect_timeout: Option<Duration>,
+}
+
+#[cfg(all(
+    any(feature = "http-hyper", feature = "http-reqwest"),
+    any(feature = "tls-native", feature = "tls-rustls")
+))]
+impl HttpClientConfig {
+    /// Create the default configuration, with proxies from the environment and no connect
+    /// timeout.
+    pub fn new() -> Self {
+        HttpClientConfig {
+            proxy: crate::ProxyConfig::from_env(),
+            connect_timeout: None,
+        }
+    }
+
+    /// Use the given proxies, instead of those from the environment.
+    pub fn proxy(mut self, proxy: crate::ProxyConfig) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Fail connections that are not established within `dur`.
+    ///
+    /// When connecting through a proxy, this applies to the connection to the proxy.
+    pub fn connect_timeout(mut self, dur: Duration) -> Self {
+        self.connect_timeout = Some(dur);
+        self
+    }
+}
+
+#[cfg(all(
+    any(feature = "http-hyper", feature = "http-reqwest"),
+    any(feature = "tls-native", feature = "tls-rustls")
+))]
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Create an HTTP client with a default configuration.
+///
+/// Proxies are configured from the environment, using `ProxyConfig::from_env`.
+#[cfg(all(
+    any(feature = "http-hyper", feature = "http-reqwest"),
+    any(feature = "tls-native", feature = "tls-rustls")
+))]
+pub fn default_http_client() -> DefaultHttpClient {
+    default_http_client_with_config(HttpClientConfig::new())
+}
+
+/// Create an HTTP client with a default configuration, using the given proxies.
+#[cfg(all(
+    any(feature = "http-hyper", feature = "http-reqwest"),
+    any(feature = "tls-native", feature = "tls-rustls")
+))]
+pub fn default_http_client_with_proxy(proxy: crate::ProxyConfig) -> DefaultHttpClient {
+    default_http_client_with_config(HttpClientConfig::new().proxy(proxy))
+}
+
+/// Create the connector of the Hyper-based `DefaultHttpClient`, without TLS.
+#[cfg(all(
+    feature = "http-hyper",
+    any(feature = "tls-native", feature = "tls-rustls")
+))]
+fn proxy_connector(config: HttpClientConfig) -> crate::ProxyConnector {
+    let connector = crate::ProxyConnector::new(config.proxy);
+    match config.connect_timeout {
+        Some(dur) => connector.connect_timeout(dur),
+        None => connector,
+    }
+}
+
+/// Create an HTTP client using the given network settings.
+#[cfg(all(feature = "http-hyper", feature = "tls-native"))]
+pub fn default_http_client_with_config(config: HttpClientConfig) -> DefaultHttpClient {
+    let connector = hyper_tls::HttpsConnector::new_with_connector(proxy_connector(config));
+    hyper::Client::builder().build(connector)
+}
+
+/// Create an HTTP client using the given network settings.
+#[cfg(all(
+    feature = "http-hyper",
+    not(feature = "tls-native"),
+    feature = "tls-rustls"
+))]
+pub fn default_http_client_with_config(config: HttpClientConfig) -> DefaultHttpClient {
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .enable_http2()
+        .wrap_connector(proxy_connector(config));
+    hyper::Client::builder().build(connector)
+}
+
+/// Create an HTTP client using the given network settings.
+#[cfg(all(
+    not(feature = "http-hyper"),
+    feature = "http-reqwest",
+    any(feature = "tls-native", feature = "tls-rustls")
+))]
+pub fn default_http_client_with_config(config: HttpClientConfig) -> DefaultHttpClient {
+    let HttpClientConfig {
+        proxy,
+        connect_timeout,
+    } = config;
+    let proxy = reqwest::Proxy::custom(move |url| {
+        let uri: http::Uri = url.as_str().parse().ok()?;
+        proxy.proxy_for(&uri).map(|proxy| proxy.to_string())
+    });
+    let mut builder = reqwest::Client::builder().proxy(proxy);
+    if let Some(dur) = connect_timeout {
+        builder = builder.connect_timeout(dur);
+    }
+    builder.build().expect("could not create HTTP client")
+}
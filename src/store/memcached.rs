@@ -0,0 +1,371 @@
+use std::{sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use url::Url;
+
+use crate::misc::{DynErr, DynFut, DynFutRes, DynRes};
+use crate::store::simple::{hex_digest, unix_time, url_hash};
+use crate::{
+    simple_fetch, CachedDocument, Clock, Codec, FetchError, HttpClient, JsonCodec, NonceGenerator,
+    RandomNonces, SessionRecord, Store, SystemClock,
+};
+
+/// A `Store` implementation that keeps everything in memcached.
+///
+/// This is available with the `memcached-store` crate feature. Both sessions and cached documents
+/// are stored with an expiry time, so memcached takes care of cleaning up. Values are encoded
+/// using the given `Codec`, which defaults to `JsonCodec`.
+///
+/// Documents are fetched using the given `HttpClient` on cache miss. Multiple application
+/// processes can share the same memcached servers, so this store is suitable for applications
+/// running multiple workers. Note that memcached may evict sessions under memory pressure, in
+/// which case the login fails with `VerifyError::InvalidSession`. The same applies to key pins,
+/// which are stored without expiry.
+pub struct MemcachedStore<C, K = JsonCodec> {
+    memcache: memcache::Client,
+    client: C,
+    codec: Arc<K>,
+    prefix: String,
+    timeout: Duration,
+    session_lifetime: Duration,
+    nonce_generator: Arc<dyn NonceGenerator>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<C> MemcachedStore<C> {
+    /// Create a store using the given memcached client and HTTP client.
+    ///
+    /// The default timeout for HTTP requests is 30 seconds, and sessions expire after 15 minutes.
+    /// Keys are prefixed with `portier:`.
+    pub fn new(memcache: memcache::Client, client: C) -> Self {
+        MemcachedStore {
+            memcache,
+            client,
+            codec: Arc::new(JsonCodec),
+            prefix: "portier:".to_owned(),
+            timeout: Duration::from_secs(30),
+            session_lifetime: Duration::from_secs(15 * 60),
+            nonce_generator: Arc::new(RandomNonces::new()),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl<C, K> MemcachedStore<C, K> {
+    /// Use the given `Codec` to encode values.
+    pub fn codec<K2: Codec>(self, codec: K2) -> MemcachedStore<C, K2> {
+        MemcachedStore {
+            memcache: self.memcache,
+            client: self.client,
+            codec: Arc::new(codec),
+            prefix: self.prefix,
+            timeout: self.timeout,
+            session_lifetime: self.session_lifetime,
+            nonce_generator: self.nonce_generator,
+            clock: self.clock,
+        }
+    }
+
+    /// Configure the prefix for all keys, so memcached servers can be shared.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Configure the timeout for HTTP requests.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Configure how long a session remains valid after `Store::new_nonce`.
+    ///
+    /// This is overridden by the TTL passed to `Store::new_nonce_with_ttl`.
+    pub fn session_lifetime(mut self, lifetime: Duration) -> Self {
+        self.session_lifetime = lifetime;
+        self
+    }
+
+    /// Use the given `Clock` for cache expiry, instead of the system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Generate nonces for new sessions using `generator`, instead of `RandomNonces`.
+    pub fn nonce_generator(mut self, generator: Arc<dyn NonceGenerator>) -> Self {
+        self.nonce_generator = generator;
+        self
+    }
+
+    /// The memcached client used by this store.
+    pub fn memcache(&self) -> &memcache::Client {
+        &self.memcache
+    }
+}
+
+impl<C, K> MemcachedStore<C, K>
+where
+    C: HttpClient + Clone,
+    K: Codec,
+{
+    /// Fetch a document, using the cached result if `use_cache` is set and it is fresh.
+    fn fetch_document(&self, url: Url, use_cache: bool) -> DynFut<Result<Bytes, FetchError>> {
+        let memcache = self.memcache.clone();
+        let client = self.client.clone();
+        let codec = self.codec.clone();
+        let key = cache_key(&self.prefix, &url);
+        let timeout = self.timeout;
+        let clock = self.clock.clone();
+        Box::pin(async move {
+            let now = unix_time(&*clock) as u64;
+            if use_cache {
+                let cached: Option<Vec<u8>> = blocking({
+                    let memcache = memcache.clone();
+                    let key = key.clone();
+                    move || memcache.get(&key)
+                })
+                .await
+                .map_err(FetchError::Store)?;
+                if let Some(cached) = cached {
+                    let doc: CachedDocument = codec.decode(&cached).map_err(FetchError::Store)?;
+                    if now < doc.expires {
+                        return match doc.data {
+                            Some(data) => Ok(data.into()),
+                            None => Err(FetchError::Fetch(Arc::new(
+                                "fetching the document failed recently".into(),
+                            ))),
+                        };
+                    }
+                }
+            }
+
+            let (result, max_age) = simple_fetch(&client, timeout, url).await;
+            let doc = CachedDocument {
+                data: result.as_ref().ok().map(|data| data.to_vec()),
+                expires: now.saturating_add(max_age.as_secs()),
+            };
+            let value = codec.encode(&doc).map_err(FetchError::Store)?;
+            let max_age = max_age.as_secs() as u32;
+            blocking(move || memcache.set(&key, value.as_slice(), max_age))
+                .await
+                .map_err(FetchError::Store)?;
+            result.map_err(|err| FetchError::Fetch(Arc::new(err)))
+        })
+    }
+}
+
+impl<C, K> Store for MemcachedStore<C, K>
+where
+    C: HttpClient + Clone,
+    K: Codec,
+{
+    fn fetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        self.fetch_document(url, true)
+    }
+
+    fn refetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        self.fetch_document(url, false)
+    }
+
+    fn cache_lifetime(&self, url: Url) -> DynFutRes<Option<Duration>> {
+        let memcache = self.memcache.clone();
+        let codec = self.codec.clone();
+        let key = cache_key(&self.prefix, &url);
+        let clock = self.clock.clone();
+        Box::pin(async move {
+            let now = unix_time(&*clock) as u64;
+            let cached: Option<Vec<u8>> = blocking(move || memcache.get(&key)).await?;
+            let lifetime = match cached {
+                Some(cached) => codec.decode::<CachedDocument>(&cached)?.expires,
+                None => return Ok(None),
+            };
+            Ok(Some(lifetime.saturating_sub(now))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs))
+        })
+    }
+
+    fn new_nonce(&self, email: String) -> DynFutRes<String> {
+        self.new_nonce_with_ttl(email, self.session_lifetime)
+    }
+
+    fn new_nonce_with_ttl(&self, email: String, ttl: Duration) -> DynFutRes<String> {
+        let memcache = self.memcache.clone();
+        let codec = self.codec.clone();
+        let prefix = self.prefix.clone();
+        let nonce = self.nonce_generator.generate();
+        Box::pin(async move {
+            let nonce = nonce.await?;
+            let record = SessionRecord::new(email);
+            add_session(memcache, &*codec, &prefix, ttl, nonce.clone(), record).await?;
+            Ok(nonce)
+        })
+    }
+
+    fn consume_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        let memcache = self.memcache.clone();
+        let key = session_key(&self.prefix, &nonce, &email);
+        Box::pin(async move { blocking(move || memcache.delete(&key)).await })
+    }
+
+    fn new_nonce_with_data(
+        &self,
+        email: String,
+        data: Vec<u8>,
+        ttl: Duration,
+    ) -> DynFutRes<String> {
+        let memcache = self.memcache.clone();
+        let codec = self.codec.clone();
+        let prefix = self.prefix.clone();
+        let nonce = self.nonce_generator.generate();
+        Box::pin(async move {
+            let nonce = nonce.await?;
+            let record = SessionRecord::new(email).data(data);
+            add_session(memcache, &*codec, &prefix, ttl, nonce.clone(), record).await?;
+            Ok(nonce)
+        })
+    }
+
+    fn consume_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        let memcache = self.memcache.clone();
+        let codec = self.codec.clone();
+        let key = session_key(&self.prefix, &nonce, &email);
+        Box::pin(async move {
+            // Only the delete decides whether the session is consumed, so a concurrent consume
+            // can't return the data twice.
+            let (value, deleted) = blocking(move || {
+                let value: Option<Vec<u8>> = memcache.get(&key)?;
+                Ok((value, memcache.delete(&key)?))
+            })
+            .await?;
+            match value {
+                Some(value) if deleted => {
+                    let record: SessionRecord = codec.decode(&value)?;
+                    Ok(Some(record.data.unwrap_or_default()))
+                }
+                _ => Ok(None),
+            }
+        })
+    }
+
+    fn peek_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        let memcache = self.memcache.clone();
+        let key = session_key(&self.prefix, &nonce, &email);
+        Box::pin(async move {
+            let value: Option<Vec<u8>> = blocking(move || memcache.get(&key)).await?;
+            Ok(value.is_some())
+        })
+    }
+
+    fn peek_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        let memcache = self.memcache.clone();
+        let codec = self.codec.clone();
+        let key = session_key(&self.prefix, &nonce, &email);
+        Box::pin(async move {
+            let value: Option<Vec<u8>> = blocking(move || memcache.get(&key)).await?;
+            match value {
+                Some(value) => {
+                    let record: SessionRecord = codec.decode(&value)?;
+                    Ok(Some(record.data.unwrap_or_default()))
+                }
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn insert_nonce(
+        &self,
+        nonce: String,
+        email: String,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> DynFutRes<()> {
+        let memcache = self.memcache.clone();
+        let codec = self.codec.clone();
+        let prefix = self.prefix.clone();
+        let ttl = ttl.unwrap_or(self.session_lifetime);
+        Box::pin(async move {
+            let record = SessionRecord::new(email).data(data);
+            add_session(memcache, &*codec, &prefix, ttl, nonce, record).await
+        })
+    }
+
+    fn load_key_pins(&self, origin: String) -> DynFutRes<Option<String>> {
+        let memcache = self.memcache.clone();
+        let key = key_pins_key(&self.prefix, &origin);
+        Box::pin(async move { blocking(move || memcache.get(&key)).await })
+    }
+
+    fn save_key_pins(&self, origin: String, pins: String) -> DynFutRes<()> {
+        let memcache = self.memcache.clone();
+        let key = key_pins_key(&self.prefix, &origin);
+        Box::pin(async move { blocking(move || memcache.set(&key, pins.as_str(), 0)).await })
+    }
+
+    fn load_endpoint_state(&self, origin: String) -> DynFutRes<Option<String>> {
+        let memcache = self.memcache.clone();
+        let key = endpoint_state_key(&self.prefix, &origin);
+        Box::pin(async move { blocking(move || memcache.get(&key)).await })
+    }
+
+    fn save_endpoint_state(&self, origin: String, state: String, ttl: Duration) -> DynFutRes<()> {
+        let memcache = self.memcache.clone();
+        let key = endpoint_state_key(&self.prefix, &origin);
+        // An expiry of zero means the value never expires.
+        let expires = ttl.as_secs().max(1) as u32;
+        Box::pin(async move { blocking(move || memcache.set(&key, state.as_str(), expires)).await })
+    }
+}
+
+/// The key for a cached document.
+fn cache_key(prefix: &str, url: &Url) -> String {
+    format!("{}cache:{}", prefix, url_hash(url))
+}
+
+/// The key for the key pins of a broker origin. These never expire.
+fn key_pins_key(prefix: &str, origin: &str) -> String {
+    format!("{}pins:{}", prefix, hex_digest(origin))
+}
+
+/// The key for the shared state of a broker origin.
+fn endpoint_state_key(prefix: &str, origin: &str) -> String {
+    format!("{}state:{}", prefix, hex_digest(origin))
+}
+
+/// The key for a session.
+///
+/// The email is part of the key, so consuming the session is a single atomic delete. It is hashed,
+/// because memcached keys may not contain whitespace and are limited in length.
+fn session_key(prefix: &str, nonce: &str, email: &str) -> String {
+    format!("{}session:{}:{}", prefix, nonce, hex_digest(email))
+}
+
+/// Store a session, failing if it already exists.
+async fn add_session<K: Codec>(
+    memcache: memcache::Client,
+    codec: &K,
+    prefix: &str,
+    lifetime: Duration,
+    nonce: String,
+    record: SessionRecord,
+) -> DynRes<()> {
+    let key = session_key(prefix, &nonce, &record.email);
+    let value = codec.encode(&record)?;
+    let expires = lifetime.as_secs() as u32;
+    blocking(move || memcache.add(&key, value.as_slice(), expires)).await
+}
+
+/// Run a memcached operation on the blocking thread pool.
+///
+/// The `memcache` crate uses blocking I/O.
+async fn blocking<T, F>(f: F) -> DynRes<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, memcache::MemcacheError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .expect("memcached task panicked")
+        .map_err(|err| Box::new(err) as DynErr)
+}
@@ -1,26 +1,134 @@
-use std::sync::Arc;
+#[cfg(feature = "client")]
+use std::{sync::Arc, time::Duration};
 
+#[cfg(feature = "client")]
 use bytes::Bytes;
+#[cfg(feature = "client")]
 use thiserror::Error;
+#[cfg(feature = "client")]
 use url::Url;
 
+#[cfg(feature = "client")]
 use crate::misc::{DynErr, DynFut, DynFutRes};
 
 /// Errors that can result from `Store::fetch`.
+#[cfg(feature = "client")]
 #[derive(Debug, Error)]
 pub enum FetchError {
     #[error(transparent)]
     Store(DynErr),
     #[error(transparent)]
     Fetch(Arc<DynErr>),
+    /// An error annotated with the document that was being fetched.
+    ///
+    /// Stores don't need to return this variant. It is added by `Client` to errors it returns.
+    #[error("{url} ({purpose}): {source}")]
+    Context {
+        purpose: FetchPurpose,
+        url: Url,
+        #[source]
+        source: Box<FetchError>,
+    },
+}
+
+#[cfg(feature = "client")]
+impl FetchError {
+    /// The URL that was being fetched, if known.
+    pub fn url(&self) -> Option<&Url> {
+        match self {
+            FetchError::Context { url, .. } => Some(url),
+            _ => None,
+        }
+    }
+
+    /// The purpose of the document that was being fetched, if known.
+    pub fn purpose(&self) -> Option<FetchPurpose> {
+        match self {
+            FetchError::Context { purpose, .. } => Some(*purpose),
+            _ => None,
+        }
+    }
+
+    /// The error without any context added by `Client`.
+    pub fn without_context(&self) -> &FetchError {
+        match self {
+            FetchError::Context { source, .. } => source.without_context(),
+            err => err,
+        }
+    }
+
+    /// The unexpected HTTP status that caused the fetch to fail, if any.
+    pub fn status_error(&self) -> Option<&HttpStatusError> {
+        match self.without_context() {
+            FetchError::Fetch(err) => err.downcast_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// An unexpected HTTP status code in response to a document fetch.
+///
+/// This is returned by `simple_fetch` inside `FetchError::Fetch`. Custom stores can return it as
+/// well, so that `Client` can recognize an unavailable broker. See
+/// `StartAuthError::BrokerUnavailable`.
+#[cfg(feature = "client")]
+#[derive(Clone, Debug, Error)]
+#[error("unexpected HTTP status code {status}")]
+#[non_exhaustive]
+pub struct HttpStatusError {
+    /// The HTTP status code.
+    pub status: u16,
+    /// The delay from the `Retry-After` header, if present in delay-seconds format.
+    pub retry_after: Option<Duration>,
+}
+
+#[cfg(feature = "client")]
+impl HttpStatusError {
+    /// Create an error for the given HTTP status code and `Retry-After` delay.
+    pub fn new(status: u16, retry_after: Option<Duration>) -> Self {
+        HttpStatusError {
+            status,
+            retry_after,
+        }
+    }
+}
+
+/// The kind of document being fetched, used in `FetchError::Context`.
+#[cfg(feature = "client")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FetchPurpose {
+    /// The OpenID Connect discovery document.
+    Discovery,
+    /// The JWKs document referenced by `jwks_uri` in the discovery document.
+    Keys,
+}
+
+#[cfg(feature = "client")]
+impl std::fmt::Display for FetchPurpose {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FetchPurpose::Discovery => "discovery",
+            FetchPurpose::Keys => "jwks_uri",
+        })
+    }
 }
 
 /// Trait that describes a backing store used by `Client` for two purposes:
 /// - to fetch JSON documents using HTTP GET with additional caching, and
 /// - to generate and manage nonces (numbers used once) used in authentication.
 ///
+/// Stores can optionally also persist key pins, used by `Builder::key_continuity`.
+///
 /// The store is shared between threads by reference, and is itself responsible for synchronizing
 /// access from different threads.
+///
+/// Every `Store` is also a `Fetcher` and a `SessionStore`. Applications that only need to replace
+/// one of these concerns can implement the narrower trait instead, and configure it using
+/// `Builder::fetcher` or `Builder::session_store`.
+///
+/// Crates implementing a store can use `assert_store_impl!` to check the implementation.
+#[cfg(feature = "client")]
 pub trait Store: Send + Sync + 'static {
     /// Requests a document using HTTP GET, and perform caching.
     ///
@@ -29,6 +137,25 @@ pub trait Store: Send + Sync + 'static {
     /// implementation that can be used on cache miss.
     fn fetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>>;
 
+    /// Like `Store::fetch`, but bypass the cache, and replace the cached document with the result.
+    ///
+    /// This is used by `Client` to pick up new broker keys after a key rotation, when a token is
+    /// signed with a key that is not in the cached JWKs document. Implementing it is optional; the
+    /// default implementation returns `Unsupported`.
+    fn refetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        let _ = url;
+        Box::pin(async { Err(FetchError::Store(Box::new(Unsupported("refetch")))) })
+    }
+
+    /// The remaining cache lifetime of a document, or `None` if it is not cached.
+    ///
+    /// This is used by `Client::prefetch`. Implementing it is optional; the default implementation
+    /// returns `Unsupported`.
+    fn cache_lifetime(&self, url: Url) -> DynFutRes<Option<Duration>> {
+        let _ = url;
+        Box::pin(async { Err(Box::new(Unsupported("cache_lifetime")) as DynErr) })
+    }
+
     /// Generate a random nonce and store the pair nonce/email.
     ///
     /// See `generate_nonce` for a default implementation for generating the nonce, but using this
@@ -36,17 +163,576 @@ pub trait Store: Send + Sync + 'static {
     /// URL safe format to prevent unnecessary escaping.
     ///
     /// Implementors should not apply any limits to the amount of active nonces; this is left to
-    /// the application using the `Client`.
+    /// the application using the `Client`, for example using `Builder::rate_limiter`.
     fn new_nonce(&self, email: String) -> DynFutRes<String>;
 
+    /// Like `Store::new_nonce`, but the pair should expire after `ttl`.
+    ///
+    /// This is what `Client` uses, with the TTL configured using `Builder::session_ttl`. Expired
+    /// pairs should no longer be found by `Store::consume_nonce`. Implementing this is optional;
+    /// the default implementation calls `Store::new_nonce`, ignoring the TTL.
+    fn new_nonce_with_ttl(&self, email: String, ttl: Duration) -> DynFutRes<String> {
+        let _ = ttl;
+        self.new_nonce(email)
+    }
+
     /// Check that a nonce/email pair exists and delete it if so.
     ///
     /// This method should return `Ok(true)` if a pair was found, `Ok(false)` if not, and use `Err`
     /// only to indicate problems with the store.
     fn consume_nonce(&self, nonce: String, email: String) -> DynFutRes<bool>;
+
+    /// Like `Store::new_nonce_with_ttl`, but also store opaque application `data` with the pair.
+    ///
+    /// This is used by `Client` when `AuthOptions::session_data` is set, and the data should be
+    /// returned by `Store::consume_nonce_with_data`. Implementing this is optional; the default
+    /// implementation returns `Unsupported`.
+    fn new_nonce_with_data(
+        &self,
+        email: String,
+        data: Vec<u8>,
+        ttl: Duration,
+    ) -> DynFutRes<String> {
+        let _ = (email, data, ttl);
+        Box::pin(async { Err(Box::new(Unsupported("new_nonce_with_data")) as DynErr) })
+    }
+
+    /// Like `Store::consume_nonce`, but return the data stored with the pair.
+    ///
+    /// This method should return `Ok(None)` if the pair was not found, and an empty `Vec` for
+    /// pairs stored without data. This is what `Client` uses. The default implementation calls
+    /// `Store::consume_nonce`, so returns no data.
+    fn consume_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        let consume = self.consume_nonce(nonce, email);
+        Box::pin(async move { Ok(consume.await?.then(Vec::new)) })
+    }
+
+    /// Store a nonce/email pair that was generated elsewhere, with opaque application `data`.
+    ///
+    /// The pair should expire after `ttl`, if given, and `data` should be returned by
+    /// `Store::consume_nonce_with_data` like for `Store::new_nonce_with_data`. This is used by
+    /// store combinators such as `FailoverStore` to replicate nonces between stores. Implementing
+    /// it is optional; the default implementation returns `Unsupported`.
+    fn insert_nonce(
+        &self,
+        nonce: String,
+        email: String,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> DynFutRes<()> {
+        let _ = (nonce, email, data, ttl);
+        Box::pin(async { Err(Box::new(Unsupported("insert_nonce")) as DynErr) })
+    }
+
+    /// Check that a nonce/email pair exists, without deleting it.
+    ///
+    /// This is used by `Client::verify_pending` to verify a login without consuming the session
+    /// yet. Implementing it is optional; the default implementation returns `Unsupported`.
+    fn peek_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        let _ = (nonce, email);
+        Box::pin(async { Err(Box::new(Unsupported("peek_nonce")) as DynErr) })
+    }
+
+    /// Like `Store::peek_nonce`, but return the data stored with the pair.
+    ///
+    /// This method should return `Ok(None)` if the pair was not found, like
+    /// `Store::consume_nonce_with_data`. It is used by `Client::verify_pending` when
+    /// `Builder::session_binding` is enabled. Implementing it is optional; the default
+    /// implementation returns `Unsupported`.
+    fn peek_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        let _ = (nonce, email);
+        Box::pin(async { Err(Box::new(Unsupported("peek_nonce_with_data")) as DynErr) })
+    }
+
+    /// Load the key pins recorded for a broker origin using `Store::save_key_pins`.
+    ///
+    /// This is used by `Builder::key_continuity`. The value is opaque to the store, and should be
+    /// kept indefinitely. Implementing it is optional; the default implementation returns
+    /// `Unsupported`.
+    fn load_key_pins(&self, origin: String) -> DynFutRes<Option<String>> {
+        let _ = origin;
+        Box::pin(async { Err(Box::new(Unsupported("load_key_pins")) as DynErr) })
+    }
+
+    /// Record the key pins for a broker origin, replacing any previous value.
+    ///
+    /// See `Store::load_key_pins`. The default implementation returns `Unsupported`.
+    fn save_key_pins(&self, origin: String, pins: String) -> DynFutRes<()> {
+        let _ = (origin, pins);
+        Box::pin(async { Err(Box::new(Unsupported("save_key_pins")) as DynErr) })
+    }
+
+    /// Load the endpoint state recorded for a broker origin using `Store::save_endpoint_state`.
+    ///
+    /// This is used by `Builder::shared_endpoint_state`, so that workers share the broker health
+    /// and the rate limit on key refetches. The value is opaque to the store. Implementing it is
+    /// optional; the default implementation returns `Unsupported`.
+    fn load_endpoint_state(&self, origin: String) -> DynFutRes<Option<String>> {
+        let _ = origin;
+        Box::pin(async { Err(Box::new(Unsupported("load_endpoint_state")) as DynErr) })
+    }
+
+    /// Record the endpoint state for a broker origin, replacing any previous value.
+    ///
+    /// The value is no longer needed after `ttl`, so the store may discard it. See
+    /// `Store::load_endpoint_state`. The default implementation returns `Unsupported`.
+    fn save_endpoint_state(&self, origin: String, state: String, ttl: Duration) -> DynFutRes<()> {
+        let _ = (origin, state, ttl);
+        Box::pin(async { Err(Box::new(Unsupported("save_endpoint_state")) as DynErr) })
+    }
+
+    /// Append a record of a failed verification.
+    ///
+    /// This is used by `StoreFailureSink`. The record is opaque to the store, and may be discarded
+    /// after `retention`. The store may also discard the oldest records to limit its size.
+    /// Implementing it is optional; the default implementation returns `Unsupported`.
+    fn record_failure(&self, record: String, retention: Duration) -> DynFutRes<()> {
+        let _ = (record, retention);
+        Box::pin(async { Err(Box::new(Unsupported("record_failure")) as DynErr) })
+    }
+
+    /// Load the records appended using `Store::record_failure` that were not discarded, oldest
+    /// first. The default implementation returns `Unsupported`.
+    fn load_failures(&self) -> DynFutRes<Vec<String>> {
+        Box::pin(async { Err(Box::new(Unsupported("load_failures")) as DynErr) })
+    }
 }
 
-#[cfg(feature = "simple-store")]
+#[cfg(feature = "client")]
+impl<T: Store + ?Sized> Store for Arc<T> {
+    fn fetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        (**self).fetch(url)
+    }
+
+    fn refetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        (**self).refetch(url)
+    }
+
+    fn cache_lifetime(&self, url: Url) -> DynFutRes<Option<Duration>> {
+        (**self).cache_lifetime(url)
+    }
+
+    fn new_nonce(&self, email: String) -> DynFutRes<String> {
+        (**self).new_nonce(email)
+    }
+
+    fn new_nonce_with_ttl(&self, email: String, ttl: Duration) -> DynFutRes<String> {
+        (**self).new_nonce_with_ttl(email, ttl)
+    }
+
+    fn consume_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        (**self).consume_nonce(nonce, email)
+    }
+
+    fn new_nonce_with_data(
+        &self,
+        email: String,
+        data: Vec<u8>,
+        ttl: Duration,
+    ) -> DynFutRes<String> {
+        (**self).new_nonce_with_data(email, data, ttl)
+    }
+
+    fn consume_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        (**self).consume_nonce_with_data(nonce, email)
+    }
+
+    fn insert_nonce(
+        &self,
+        nonce: String,
+        email: String,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> DynFutRes<()> {
+        (**self).insert_nonce(nonce, email, data, ttl)
+    }
+
+    fn peek_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        (**self).peek_nonce(nonce, email)
+    }
+
+    fn peek_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        (**self).peek_nonce_with_data(nonce, email)
+    }
+
+    fn load_key_pins(&self, origin: String) -> DynFutRes<Option<String>> {
+        (**self).load_key_pins(origin)
+    }
+
+    fn save_key_pins(&self, origin: String, pins: String) -> DynFutRes<()> {
+        (**self).save_key_pins(origin, pins)
+    }
+
+    fn load_endpoint_state(&self, origin: String) -> DynFutRes<Option<String>> {
+        (**self).load_endpoint_state(origin)
+    }
+
+    fn save_endpoint_state(&self, origin: String, state: String, ttl: Duration) -> DynFutRes<()> {
+        (**self).save_endpoint_state(origin, state, ttl)
+    }
+
+    fn record_failure(&self, record: String, retention: Duration) -> DynFutRes<()> {
+        (**self).record_failure(record, retention)
+    }
+
+    fn load_failures(&self) -> DynFutRes<Vec<String>> {
+        (**self).load_failures()
+    }
+}
+
+/// The document fetching half of a `Store`.
+///
+/// See `Store::fetch` for details. This is implemented for every `Store`.
+#[cfg(feature = "client")]
+pub trait Fetcher: Send + Sync + 'static {
+    /// Requests a document using HTTP GET, and perform caching.
+    fn fetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>>;
+    /// Like `Fetcher::fetch`, but bypass the cache.
+    ///
+    /// See `Store::refetch` for details. The default implementation returns `Unsupported`.
+    fn refetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        let _ = url;
+        Box::pin(async { Err(FetchError::Store(Box::new(Unsupported("refetch")))) })
+    }
+    /// The remaining cache lifetime of a document, or `None` if it is not cached.
+    ///
+    /// See `Store::cache_lifetime` for details. The default implementation returns `Unsupported`.
+    fn cache_lifetime(&self, url: Url) -> DynFutRes<Option<Duration>> {
+        let _ = url;
+        Box::pin(async { Err(Box::new(Unsupported("cache_lifetime")) as DynErr) })
+    }
+}
+
+#[cfg(feature = "client")]
+impl<T: Store + ?Sized> Fetcher for T {
+    fn fetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        Store::fetch(self, url)
+    }
+
+    fn refetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        Store::refetch(self, url)
+    }
+
+    fn cache_lifetime(&self, url: Url) -> DynFutRes<Option<Duration>> {
+        Store::cache_lifetime(self, url)
+    }
+}
+
+/// The session half of a `Store`, managing nonces.
+///
+/// See `Store::new_nonce` and `Store::consume_nonce` for details. This is implemented for every
+/// `Store`.
+#[cfg(feature = "client")]
+pub trait SessionStore: Send + Sync + 'static {
+    /// Generate a random nonce and store the pair nonce/email.
+    fn new_nonce(&self, email: String) -> DynFutRes<String>;
+
+    /// Like `SessionStore::new_nonce`, but the pair should expire after `ttl`.
+    ///
+    /// See `Store::new_nonce_with_ttl` for details. The default implementation calls
+    /// `SessionStore::new_nonce`, ignoring the TTL.
+    fn new_nonce_with_ttl(&self, email: String, ttl: Duration) -> DynFutRes<String> {
+        let _ = ttl;
+        self.new_nonce(email)
+    }
+
+    /// Check that a nonce/email pair exists and delete it if so.
+    fn consume_nonce(&self, nonce: String, email: String) -> DynFutRes<bool>;
+
+    /// Like `SessionStore::new_nonce_with_ttl`, but also store opaque application `data`.
+    ///
+    /// See `Store::new_nonce_with_data` for details. The default implementation returns
+    /// `Unsupported`.
+    fn new_nonce_with_data(
+        &self,
+        email: String,
+        data: Vec<u8>,
+        ttl: Duration,
+    ) -> DynFutRes<String> {
+        let _ = (email, data, ttl);
+        Box::pin(async { Err(Box::new(Unsupported("new_nonce_with_data")) as DynErr) })
+    }
+
+    /// Like `SessionStore::consume_nonce`, but return the data stored with the pair.
+    ///
+    /// See `Store::consume_nonce_with_data` for details. The default implementation calls
+    /// `SessionStore::consume_nonce`, so returns no data.
+    fn consume_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        let consume = self.consume_nonce(nonce, email);
+        Box::pin(async move { Ok(consume.await?.then(Vec::new)) })
+    }
+
+    /// Store a nonce/email pair that was generated elsewhere, with opaque application `data`.
+    ///
+    /// See `Store::insert_nonce` for details. The default implementation returns `Unsupported`.
+    fn insert_nonce(
+        &self,
+        nonce: String,
+        email: String,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> DynFutRes<()> {
+        let _ = (nonce, email, data, ttl);
+        Box::pin(async { Err(Box::new(Unsupported("insert_nonce")) as DynErr) })
+    }
+
+    /// Check that a nonce/email pair exists, without deleting it.
+    ///
+    /// See `Store::peek_nonce` for details. The default implementation returns `Unsupported`.
+    fn peek_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        let _ = (nonce, email);
+        Box::pin(async { Err(Box::new(Unsupported("peek_nonce")) as DynErr) })
+    }
+
+    /// Like `SessionStore::peek_nonce`, but return the data stored with the pair.
+    ///
+    /// See `Store::peek_nonce_with_data` for details. The default implementation returns
+    /// `Unsupported`.
+    fn peek_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        let _ = (nonce, email);
+        Box::pin(async { Err(Box::new(Unsupported("peek_nonce_with_data")) as DynErr) })
+    }
+
+    /// Load the key pins recorded for a broker origin.
+    ///
+    /// See `Store::load_key_pins` for details. The default implementation returns `Unsupported`.
+    fn load_key_pins(&self, origin: String) -> DynFutRes<Option<String>> {
+        let _ = origin;
+        Box::pin(async { Err(Box::new(Unsupported("load_key_pins")) as DynErr) })
+    }
+
+    /// Record the key pins for a broker origin, replacing any previous value.
+    ///
+    /// See `Store::save_key_pins` for details. The default implementation returns `Unsupported`.
+    fn save_key_pins(&self, origin: String, pins: String) -> DynFutRes<()> {
+        let _ = (origin, pins);
+        Box::pin(async { Err(Box::new(Unsupported("save_key_pins")) as DynErr) })
+    }
+
+    /// Load the endpoint state recorded for a broker origin.
+    ///
+    /// See `Store::load_endpoint_state` for details. The default implementation returns
+    /// `Unsupported`.
+    fn load_endpoint_state(&self, origin: String) -> DynFutRes<Option<String>> {
+        let _ = origin;
+        Box::pin(async { Err(Box::new(Unsupported("load_endpoint_state")) as DynErr) })
+    }
+
+    /// Record the endpoint state for a broker origin, replacing any previous value.
+    ///
+    /// See `Store::save_endpoint_state` for details. The default implementation returns
+    /// `Unsupported`.
+    fn save_endpoint_state(&self, origin: String, state: String, ttl: Duration) -> DynFutRes<()> {
+        let _ = (origin, state, ttl);
+        Box::pin(async { Err(Box::new(Unsupported("save_endpoint_state")) as DynErr) })
+    }
+
+    /// Append a record of a failed verification.
+    ///
+    /// See `Store::record_failure` for details. The default implementation returns `Unsupported`.
+    fn record_failure(&self, record: String, retention: Duration) -> DynFutRes<()> {
+        let _ = (record, retention);
+        Box::pin(async { Err(Box::new(Unsupported("record_failure")) as DynErr) })
+    }
+
+    /// Load the records of failed verifications that were not discarded, oldest first.
+    ///
+    /// See `Store::load_failures` for details. The default implementation returns `Unsupported`.
+    fn load_failures(&self) -> DynFutRes<Vec<String>> {
+        Box::pin(async { Err(Box::new(Unsupported("load_failures")) as DynErr) })
+    }
+}
+
+#[cfg(feature = "client")]
+impl<T: Store + ?Sized> SessionStore for T {
+    fn new_nonce(&self, email: String) -> DynFutRes<String> {
+        Store::new_nonce(self, email)
+    }
+
+    fn new_nonce_with_ttl(&self, email: String, ttl: Duration) -> DynFutRes<String> {
+        Store::new_nonce_with_ttl(self, email, ttl)
+    }
+
+    fn consume_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        Store::consume_nonce(self, nonce, email)
+    }
+
+    fn new_nonce_with_data(
+        &self,
+        email: String,
+        data: Vec<u8>,
+        ttl: Duration,
+    ) -> DynFutRes<String> {
+        Store::new_nonce_with_data(self, email, data, ttl)
+    }
+
+    fn consume_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        Store::consume_nonce_with_data(self, nonce, email)
+    }
+
+    fn insert_nonce(
+        &self,
+        nonce: String,
+        email: String,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> DynFutRes<()> {
+        Store::insert_nonce(self, nonce, email, data, ttl)
+    }
+
+    fn peek_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        Store::peek_nonce(self, nonce, email)
+    }
+
+    fn peek_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        Store::peek_nonce_with_data(self, nonce, email)
+    }
+
+    fn load_key_pins(&self, origin: String) -> DynFutRes<Option<String>> {
+        Store::load_key_pins(self, origin)
+    }
+
+    fn save_key_pins(&self, origin: String, pins: String) -> DynFutRes<()> {
+        Store::save_key_pins(self, origin, pins)
+    }
+
+    fn load_endpoint_state(&self, origin: String) -> DynFutRes<Option<String>> {
+        Store::load_endpoint_state(self, origin)
+    }
+
+    fn save_endpoint_state(&self, origin: String, state: String, ttl: Duration) -> DynFutRes<()> {
+        Store::save_endpoint_state(self, origin, state, ttl)
+    }
+
+    fn record_failure(&self, record: String, retention: Duration) -> DynFutRes<()> {
+        Store::record_failure(self, record, retention)
+    }
+
+    fn load_failures(&self) -> DynFutRes<Vec<String>> {
+        Store::load_failures(self)
+    }
+}
+
+/// Assert at compile time that a type implements `Store`, or one of the narrower traits.
+///
+/// This is useful in crates that provide a custom store, to get an error pointing at the store
+/// type when it is missing a required bound, instead of an error at the `Builder` call site.
+///
+/// ```
+/// use portier::{assert_store_impl, ShardedStore};
+///
+/// assert_store_impl!(ShardedStore);
+/// assert_store_impl!(ShardedStore, Fetcher);
+/// assert_store_impl!(ShardedStore, SessionStore);
+/// ```
+#[cfg(feature = "client")]
+#[macro_export]
+macro_rules! assert_store_impl {
+    ($ty:ty) => {
+        $crate::assert_store_impl!($ty, Store);
+    };
+    ($ty:ty, $trait:ident) => {
+        const _: fn() = || {
+            fn store_must_be_send_sync_static<T: Send + Sync + 'static>() {}
+            fn store_must_implement_trait<T: $crate::$trait>() {}
+            store_must_be_send_sync_static::<$ty>();
+            store_must_implement_trait::<$ty>();
+        };
+    };
+}
+
+/// Error returned by optional `Store` methods that a store does not implement.
+#[cfg(feature = "client")]
+#[derive(Debug, Error)]
+#[error("the store does not support {0}")]
+pub struct Unsupported(pub &'static str);
+
+#[cfg(feature = "client")]
+mod codec;
+#[cfg(feature = "client")]
+pub use codec::*;
+
+#[cfg(feature = "client")]
+mod failover;
+#[cfg(feature = "client")]
+pub use failover::*;
+
+#[cfg(feature = "client")]
+mod native;
+#[cfg(feature = "client")]
+pub use native::*;
+
+#[cfg(feature = "client")]
+mod nonces;
+#[cfg(feature = "client")]
+pub use nonces::*;
+
+#[cfg(any(feature = "memory-store", feature = "blocking"))]
+pub(crate) mod sessions;
+
+#[cfg(feature = "client")]
+mod sharded;
+#[cfg(feature = "client")]
+pub use sharded::*;
+
+#[cfg(feature = "client")]
+mod stateless;
+#[cfg(feature = "client")]
+pub use stateless::*;
+
+#[cfg(any(
+    feature = "memory-store",
+    feature = "http-hyper",
+    feature = "http-reqwest"
+))]
+mod http_client;
+#[cfg(any(
+    feature = "memory-store",
+    feature = "http-hyper",
+    feature = "http-reqwest"
+))]
+pub use http_client::*;
+
+#[cfg(all(
+    any(feature = "http-hyper", feature = "http-reqwest"),
+    any(feature = "tls-native", feature = "tls-rustls")
+))]
+mod proxy;
+#[cfg(all(
+    any(feature = "http-hyper", feature = "http-reqwest"),
+    any(feature = "tls-native", feature = "tls-rustls")
+))]
+pub use proxy::*;
+
+#[cfg(feature = "memory-store")]
 mod simple;
-#[cfg(feature = "simple-store")]
+#[cfg(feature = "memory-store")]
 pub use simple::*;
+
+#[cfg(feature = "memcached-store")]
+mod memcached;
+#[cfg(feature = "memcached-store")]
+pub use memcached::*;
+
+#[cfg(feature = "dynamodb-store")]
+mod dynamodb;
+#[cfg(feature = "dynamodb-store")]
+pub use dynamodb::*;
+
+#[cfg(feature = "sled-store")]
+mod sled;
+#[cfg(feature = "sled-store")]
+pub use self::sled::*;
+
+#[cfg(any(
+    feature = "sql-postgres",
+    feature = "sql-mysql",
+    feature = "sql-sqlite"
+))]
+mod sql;
+#[cfg(any(
+    feature = "sql-postgres",
+    feature = "sql-mysql",
+    feature = "sql-sqlite"
+))]
+pub use sql::*;
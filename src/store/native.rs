@@ -0,0 +1,409 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use url::Url;
+
+use crate::misc::{DynErr, DynFut, DynFutRes, DynRes};
+use crate::{FetchError, Store, Unsupported};
+
+/// A `Store` with native async methods.
+///
+/// This is the same as `Store`, but without boxed futures in the method signatures, so that
+/// implementations can simply use `async fn`. Methods behave the same as their `Store`
+/// counterparts, and have the same defaults.
+///
+/// Pass one to `Builder::build_with_store` to use it without boxing the future of each call. It can
+/// also be configured using `Builder::async_store`, or wrapped in `AsyncStoreAdapter` wherever a
+/// `Store` is expected, in which case the adapter boxes the futures.
+///
+/// Every `Store` also implements this trait, so existing implementations can be used with code
+/// written against `AsyncStore`, and can be migrated one method at a time by moving them to an
+/// `AsyncStore` implementation.
+pub trait AsyncStore: Send + Sync + 'static {
+    /// Requests a document using HTTP GET, and perform caching. See `Store::fetch`.
+    fn fetch(&self, url: Url) -> impl Future<Output = Result<Bytes, FetchError>> + Send;
+
+    /// Like `AsyncStore::fetch`, but bypass the cache. See `Store::refetch`.
+    fn refetch(&self, url: Url) -> impl Future<Output = Result<Bytes, FetchError>> + Send {
+        let _ = url;
+        async { Err(FetchError::Store(Box::new(Unsupported("refetch")))) }
+    }
+
+    /// The remaining cache lifetime of a document. See `Store::cache_lifetime`.
+    fn cache_lifetime(&self, url: Url) -> impl Future<Output = DynRes<Option<Duration>>> + Send {
+        let _ = url;
+        async { Err(Box::new(Unsupported("cache_lifetime")) as DynErr) }
+    }
+
+    /// Generate a random nonce and store the pair nonce/email. See `Store::new_nonce`.
+    fn new_nonce(&self, email: String) -> impl Future<Output = DynRes<String>> + Send;
+
+    /// Like `AsyncStore::new_nonce`, but the pair should expire after `ttl`. See
+    /// `Store::new_nonce_with_ttl`.
+    fn new_nonce_with_ttl(
+        &self,
+        email: String,
+        ttl: Duration,
+    ) -> impl Future<Output = DynRes<String>> + Send {
+        let _ = ttl;
+        self.new_nonce(email)
+    }
+
+    /// Check that a nonce/email pair exists and delete it if so. See `Store::consume_nonce`.
+    fn consume_nonce(
+        &self,
+        nonce: String,
+        email: String,
+    ) -> impl Future<Output = DynRes<bool>> + Send;
+
+    /// Like `AsyncStore::new_nonce_with_ttl`, but also store opaque application `data`. See
+    /// `Store::new_nonce_with_data`.
+    fn new_nonce_with_data(
+        &self,
+        email: String,
+        data: Vec<u8>,
+        ttl: Duration,
+    ) -> impl Future<Output = DynRes<String>> + Send {
+        let _ = (email, data, ttl);
+        async { Err(Box::new(Unsupported("new_nonce_with_data")) as DynErr) }
+    }
+
+    /// Like `AsyncStore::consume_nonce`, but return the data stored with the pair. See
+    /// `Store::consume_nonce_with_data`.
+    fn consume_nonce_with_data(
+        &self,
+        nonce: String,
+        email: String,
+    ) -> impl Future<Output = DynRes<Option<Vec<u8>>>> + Send {
+        let consume = self.consume_nonce(nonce, email);
+        async move { Ok(consume.await?.then(Vec::new)) }
+    }
+
+    /// Store a nonce/email pair that was generated elsewhere. See `Store::insert_nonce`.
+    fn insert_nonce(
+        &self,
+        nonce: String,
+        email: String,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> impl Future<Output = DynRes<()>> + Send {
+        let _ = (nonce, email, data, ttl);
+        async { Err(Box::new(Unsupported("insert_nonce")) as DynErr) }
+    }
+
+    /// Check that a nonce/email pair exists, without deleting it. See `Store::peek_nonce`.
+    fn peek_nonce(
+        &self,
+        nonce: String,
+        email: String,
+    ) -> impl Future<Output = DynRes<bool>> + Send {
+        let _ = (nonce, email);
+        async { Err(Box::new(Unsupported("peek_nonce")) as DynErr) }
+    }
+
+    /// Like `AsyncStore::peek_nonce`, but return the data stored with the pair. See
+    /// `Store::peek_nonce_with_data`.
+    fn peek_nonce_with_data(
+        &self,
+        nonce: String,
+        email: String,
+    ) -> impl Future<Output = DynRes<Option<Vec<u8>>>> + Send {
+        let _ = (nonce, email);
+        async { Err(Box::new(Unsupported("peek_nonce_with_data")) as DynErr) }
+    }
+
+    /// Load the key pins recorded for a broker origin. See `Store::load_key_pins`.
+    fn load_key_pins(&self, origin: String) -> impl Future<Output = DynRes<Option<String>>> + Send {
+        let _ = origin;
+        async { Err(Box::new(Unsupported("load_key_pins")) as DynErr) }
+    }
+
+    /// Record the key pins for a broker origin. See `Store::save_key_pins`.
+    fn save_key_pins(
+        &self,
+        origin: String,
+        pins: String,
+    ) -> impl Future<Output = DynRes<()>> + Send {
+        let _ = (origin, pins);
+        async { Err(Box::new(Unsupported("save_key_pins")) as DynErr) }
+    }
+
+    /// Load the endpoint state recorded for a broker origin. See `Store::load_endpoint_state`.
+    fn load_endpoint_state(
+        &self,
+        origin: String,
+    ) -> impl Future<Output = DynRes<Option<String>>> + Send {
+        let _ = origin;
+        async { Err(Box::new(Unsupported("load_endpoint_state")) as DynErr) }
+    }
+
+    /// Record the endpoint state for a broker origin. See `Store::save_endpoint_state`.
+    fn save_endpoint_state(
+        &self,
+        origin: String,
+        state: String,
+        ttl: Duration,
+    ) -> impl Future<Output = DynRes<()>> + Send {
+        let _ = (origin, state, ttl);
+        async { Err(Box::new(Unsupported("save_endpoint_state")) as DynErr) }
+    }
+
+    /// Append a record of a failed verification. See `Store::record_failure`.
+    fn record_failure(
+        &self,
+        record: String,
+        retention: Duration,
+    ) -> impl Future<Output = DynRes<()>> + Send {
+        let _ = (record, retention);
+        async { Err(Box::new(Unsupported("record_failure")) as DynErr) }
+    }
+
+    /// Load the records of failed verifications. See `Store::load_failures`.
+    fn load_failures(&self) -> impl Future<Output = DynRes<Vec<String>>> + Send {
+        async { Err(Box::new(Unsupported("load_failures")) as DynErr) }
+    }
+}
+
+impl<T: Store + ?Sized> AsyncStore for T {
+    fn fetch(&self, url: Url) -> impl Future<Output = Result<Bytes, FetchError>> + Send {
+        Store::fetch(self, url)
+    }
+
+    fn refetch(&self, url: Url) -> impl Future<Output = Result<Bytes, FetchError>> + Send {
+        Store::refetch(self, url)
+    }
+
+    fn cache_lifetime(&self, url: Url) -> impl Future<Output = DynRes<Option<Duration>>> + Send {
+        Store::cache_lifetime(self, url)
+    }
+
+    fn new_nonce(&self, email: String) -> impl Future<Output = DynRes<String>> + Send {
+        Store::new_nonce(self, email)
+    }
+
+    fn new_nonce_with_ttl(
+        &self,
+        email: String,
+        ttl: Duration,
+    ) -> impl Future<Output = DynRes<String>> + Send {
+        Store::new_nonce_with_ttl(self, email, ttl)
+    }
+
+    fn consume_nonce(
+        &self,
+        nonce: String,
+        email: String,
+    ) -> impl Future<Output = DynRes<bool>> + Send {
+        Store::consume_nonce(self, nonce, email)
+    }
+
+    fn new_nonce_with_data(
+        &self,
+        email: String,
+        data: Vec<u8>,
+        ttl: Duration,
+    ) -> impl Future<Output = DynRes<String>> + Send {
+        Store::new_nonce_with_data(self, email, data, ttl)
+    }
+
+    fn consume_nonce_with_data(
+        &self,
+        nonce: String,
+        email: String,
+    ) -> impl Future<Output = DynRes<Option<Vec<u8>>>> + Send {
+        Store::consume_nonce_with_data(self, nonce, email)
+    }
+
+    fn insert_nonce(
+        &self,
+        nonce: String,
+        email: String,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> impl Future<Output = DynRes<()>> + Send {
+        Store::insert_nonce(self, nonce, email, data, ttl)
+    }
+
+    fn peek_nonce(
+        &self,
+        nonce: String,
+        email: String,
+    ) -> impl Future<Output = DynRes<bool>> + Send {
+        Store::peek_nonce(self, nonce, email)
+    }
+
+    fn peek_nonce_with_data(
+        &self,
+        nonce: String,
+        email: String,
+    ) -> impl Future<Output = DynRes<Option<Vec<u8>>>> + Send {
+        Store::peek_nonce_with_data(self, nonce, email)
+    }
+
+    fn load_key_pins(&self, origin: String) -> impl Future<Output = DynRes<Option<String>>> + Send {
+        Store::load_key_pins(self, origin)
+    }
+
+    fn save_key_pins(
+        &self,
+        origin: String,
+        pins: String,
+    ) -> impl Future<Output = DynRes<()>> + Send {
+        Store::save_key_pins(self, origin, pins)
+    }
+
+    fn load_endpoint_state(
+        &self,
+        origin: String,
+    ) -> impl Future<Output = DynRes<Option<String>>> + Send {
+        Store::load_endpoint_state(self, origin)
+    }
+
+    fn save_endpoint_state(
+        &self,
+        origin: String,
+        state: String,
+        ttl: Duration,
+    ) -> impl Future<Output = DynRes<()>> + Send {
+        Store::save_endpoint_state(self, origin, state, ttl)
+    }
+
+    fn record_failure(
+        &self,
+        record: String,
+        retention: Duration,
+    ) -> impl Future<Output = DynRes<()>> + Send {
+        Store::record_failure(self, record, retention)
+    }
+
+    fn load_failures(&self) -> impl Future<Output = DynRes<Vec<String>>> + Send {
+        Store::load_failures(self)
+    }
+}
+
+/// Wraps an `AsyncStore` to implement `Store`.
+///
+/// This is what `Builder::async_store` uses. The store is shared between clones of the adapter.
+pub struct AsyncStoreAdapter<T> {
+    inner: Arc<T>,
+}
+
+impl<T> AsyncStoreAdapter<T> {
+    /// Wrap `store` to implement `Store`.
+    pub fn new(store: T) -> Self {
+        AsyncStoreAdapter {
+            inner: Arc::new(store),
+        }
+    }
+
+    /// The wrapped store.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> Clone for AsyncStoreAdapter<T> {
+    fn clone(&self) -> Self {
+        AsyncStoreAdapter {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: AsyncStore> Store for AsyncStoreAdapter<T> {
+    fn fetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        let inner = self.inner.clone();
+        Box::pin(async move { inner.fetch(url).await })
+    }
+
+    fn refetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        let inner = self.inner.clone();
+        Box::pin(async move { inner.refetch(url).await })
+    }
+
+    fn cache_lifetime(&self, url: Url) -> DynFutRes<Option<Duration>> {
+        let inner = self.inner.clone();
+        Box::pin(async move { inner.cache_lifetime(url).await })
+    }
+
+    fn new_nonce(&self, email: String) -> DynFutRes<String> {
+        let inner = self.inner.clone();
+        Box::pin(async move { inner.new_nonce(email).await })
+    }
+
+    fn new_nonce_with_ttl(&self, email: String, ttl: Duration) -> DynFutRes<String> {
+        let inner = self.inner.clone();
+        Box::pin(async move { inner.new_nonce_with_ttl(email, ttl).await })
+    }
+
+    fn consume_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        let inner = self.inner.clone();
+        Box::pin(async move { inner.consume_nonce(nonce, email).await })
+    }
+
+    fn new_nonce_with_data(
+        &self,
+        email: String,
+        data: Vec<u8>,
+        ttl: Duration,
+    ) -> DynFutRes<String> {
+        let inner = self.inner.clone();
+        Box::pin(async move { inner.new_nonce_with_data(email, data, ttl).await })
+    }
+
+    fn consume_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        let inner = self.inner.clone();
+        Box::pin(async move { inner.consume_nonce_with_data(nonce, email).await })
+    }
+
+    fn insert_nonce(
+        &self,
+        nonce: String,
+        email: String,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> DynFutRes<()> {
+        let inner = self.inner.clone();
+        Box::pin(async move { inner.insert_nonce(nonce, email, data, ttl).await })
+    }
+
+    fn peek_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        let inner = self.inner.clone();
+        Box::pin(async move { inner.peek_nonce(nonce, email).await })
+    }
+
+    fn peek_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        let inner = self.inner.clone();
+        Box::pin(async move { inner.peek_nonce_with_data(nonce, email).await })
+    }
+
+    fn load_key_pins(&self, origin: String) -> DynFutRes<Option<String>> {
+        let inner = self.inner.clone();
+        Box::pin(async move { inner.load_key_pins(origin).await })
+    }
+
+    fn save_key_pins(&self, origin: String, pins: String) -> DynFutRes<()> {
+        let inner = self.inner.clone();
+        Box::pin(async move { inner.save_key_pins(origin, pins).await })
+    }
+
+    fn load_endpoint_state(&self, origin: String) -> DynFutRes<Option<String>> {
+        let inner = self.inner.clone();
+        Box::pin(async move { inner.load_endpoint_state(origin).await })
+    }
+
+    fn save_endpoint_state(&self, origin: String, state: String, ttl: Duration) -> DynFutRes<()> {
+        let inner = self.inner.clone();
+        Box::pin(async move { inner.save_endpoint_state(origin, state, ttl).await })
+    }
+
+    fn record_failure(&self, record: String, retention: Duration) -> DynFutRes<()> {
+        let inner = self.inner.clone();
+        Box::pin(async move { inner.record_failure(record, retention).await })
+    }
+
+    fn load_failures(&self) -> DynFutRes<Vec<String>> {
+        let inner = self.inner.clone();
+        Box::pin(async move { inner.load_failures().await })
+    }
+}
@@ -0,0 +1,107 @@
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::misc::{base64url, wipe, DynErr, DynFutRes};
+
+/// Generates nonces for new login sessions. See `MemoryStore::nonce_generator`.
+///
+/// Nonces must be unpredictable, and should be in some URL-safe format to prevent unnecessary
+/// escaping. This is implemented for closures returning a nonce, so a custom random source can be
+/// plugged in without defining a type.
+pub trait NonceGenerator: Send + Sync + 'static {
+    /// Generate a new nonce.
+    fn generate(&self) -> DynFutRes<String>;
+}
+
+impl<F> NonceGenerator for F
+where
+    F: Fn() -> Result<String, DynErr> + Send + Sync + 'static,
+{
+    fn generate(&self) -> DynFutRes<String> {
+        let res = self();
+        Box::pin(async move { res })
+    }
+}
+
+/// The default `NonceGenerator`, using random data from `SystemRandom`.
+///
+/// Nonces are 128 bits by default, in an URL-safe encoding.
+#[derive(Clone)]
+pub struct RandomNonces {
+    rng: SystemRandom,
+    len: usize,
+}
+
+impl RandomNonces {
+    /// Create a generator of 128-bit nonces.
+    pub fn new() -> Self {
+        RandomNonces {
+            rng: system_random(),
+            len: 16,
+        }
+    }
+
+    /// Generate nonces of `bits` bits, rounded up to whole bytes. The default is 128.
+    pub fn bits(mut self, bits: usize) -> Self {
+        self.len = bits.div_ceil(8);
+        self
+    }
+}
+
+impl Default for RandomNonces {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NonceGenerator for RandomNonces {
+    fn generate(&self) -> DynFutRes<String> {
+        let rng = self.rng.clone();
+        let len = self.len;
+        Box::pin(async move { Ok(random_nonce(rng, len).await) })
+    }
+}
+
+/// Returns 128-bits of secure random data in an URL-safe encoding.
+///
+/// This is a default implementation for use by `Store::new_nonce` to generate nonces (numbers used
+/// once). This function panics if the RNG fails.
+///
+/// The RNG is usually `SystemRandom`. Note that `SystemRandom` may perform lazy initialization,
+/// and it is therefore recommended to do a dummy `SystemRandom::fill` after creating. See
+/// `SystemRandom::new` for details.
+pub async fn generate_nonce(rng: impl SecureRandom + Send + Sync + 'static) -> String {
+    random_nonce(rng, 16).await
+}
+
+/// Returns `len` bytes of secure random data in an URL-safe encoding.
+///
+/// On a Tokio runtime, the RNG is called on a blocking thread.
+async fn random_nonce(rng: impl SecureRandom + Send + Sync + 'static, len: usize) -> String {
+    #[cfg(feature = "tokio")]
+    if let Ok(handle) = tokio::runtime::Handle::try_current() {
+        return handle
+            .spawn_blocking(move || random_string(&rng, len))
+            .await
+            .expect("rng task panicked");
+    }
+    random_string(&rng, len)
+}
+
+/// Returns `len` bytes of secure random data in an URL-safe encoding. Panics if the RNG fails.
+pub(crate) fn random_string(rng: &dyn SecureRandom, len: usize) -> String {
+    let mut data = vec![0; len];
+    rng.fill(&mut data[..])
+        .expect("secure random number generator failed");
+    let nonce = base64url::encode(&data);
+    wipe(&mut data);
+    nonce
+}
+
+/// Create a `SystemRandom`, and flush out any latency from lazy init with a dummy call.
+pub(crate) fn system_random() -> SystemRandom {
+    let rng = SystemRandom::new();
+    let mut dummy = vec![8];
+    rng.fill(&mut dummy)
+        .expect("secure random number generator failed");
+    rng
+}
@@ -0,0 +1,250 @@
+use std::env;
+
+#[cfg(feature = "http-hyper")]
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use http::Uri;
+#[cfg(feature = "http-hyper")]
+use hyper::{
+    client::{
+        connect::{Connected, Connection},
+        HttpConnector,
+    },
+    service::Service,
+};
+#[cfg(feature = "http-hyper")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+#[cfg(feature = "http-hyper")]
+use crate::misc::{DynErr, DynFut};
+
+/// Maximum size of the response to a `CONNECT` request.
+#[cfg(feature = "http-hyper")]
+const MAX_CONNECT_RESPONSE: usize = 8 * 1024;
+
+/// Proxy configuration for the default HTTP client. See `default_http_client_with_proxy`.
+///
+/// Only plain `http` proxies are supported. Requests to `https` URLs are tunneled through the
+/// proxy using `CONNECT`. Proxy credentials are not supported.
+#[derive(Clone, Debug, Default)]
+pub struct ProxyConfig {
+    http: Option<Uri>,
+    https: Option<Uri>,
+    no_proxy: Vec<String>,
+}
+
+impl ProxyConfig {
+    /// Create a configuration without proxies.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the configuration from the environment.
+    ///
+    /// This reads the `http_proxy`, `https_proxy` and `no_proxy` variables, or their uppercase
+    /// forms. Proxy URLs without a scheme are assumed to be `http`, and other invalid values are
+    /// ignored.
+    pub fn from_env() -> Self {
+        let var = |name: &str| {
+            env::var(name)
+                .or_else(|_| env::var(name.to_ascii_uppercase()))
+                .ok()
+                .filter(|value| !value.is_empty())
+        };
+        let mut config = Self::new();
+        config.http = var("http_proxy").and_then(|value| parse_proxy(&value));
+        config.https = var("https_proxy").and_then(|value| parse_proxy(&value));
+        if let Some(value) = var("no_proxy") {
+            config = value.split(',').fold(config, Self::no_proxy);
+        }
+        config
+    }
+
+    /// Use the proxy at `uri` for `http` requests.
+    pub fn http(mut self, uri: Uri) -> Self {
+        self.http = Some(uri);
+        self
+    }
+
+    /// Use the proxy at `uri` for `https` requests.
+    pub fn https(mut self, uri: Uri) -> Self {
+        self.https = Some(uri);
+        self
+    }
+
+    /// Use the proxy at `uri` for all requests.
+    pub fn all(self, uri: Uri) -> Self {
+        self.http(uri.clone()).https(uri)
+    }
+
+    /// Connect directly to `host` and its subdomains, or to all hosts if `host` is `*`.
+    pub fn no_proxy(mut self, host: &str) -> Self {
+        let host = host.trim().trim_start_matches('.');
+        if !host.is_empty() {
+            self.no_proxy.push(host.to_ascii_lowercase());
+        }
+        self
+    }
+
+    /// The proxy to use for a request to `uri`, if any.
+    pub fn proxy_for(&self, uri: &Uri) -> Option<&Uri> {
+        let proxy = match uri.scheme_str() {
+            Some("http") => self.http.as_ref(),
+            Some("https") => self.https.as_ref(),
+            _ => None,
+        }?;
+        let host = uri.host()?.to_ascii_lowercase();
+        let bypass = self.no_proxy.iter().any(|entry| {
+            entry == "*"
+                || host == *entry
+                || host
+                    .strip_suffix(entry.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        });
+        (!bypass).then_some(proxy)
+    }
+}
+
+/// Parse a proxy URL from the environment.
+fn parse_proxy(value: &str) -> Option<Uri> {
+    let uri: Uri = if value.contains("://") {
+        value.parse().ok()?
+    } else {
+        format!("http://{}", value).parse().ok()?
+    };
+    (uri.scheme_str() == Some("http") && uri.host().is_some()).then_some(uri)
+}
+
+/// A Hyper connector that connects through the proxies in a `ProxyConfig`.
+///
+/// This is the transport of the Hyper-based `DefaultHttpClient`, and is wrapped by the TLS
+/// connector, which is why `https` requests are tunneled.
+#[cfg(feature = "http-hyper")]
+#[derive(Clone)]
+pub struct ProxyConnector {
+    http: HttpConnector,
+    config: ProxyConfig,
+}
+
+#[cfg(feature = "http-hyper")]
+impl ProxyConnector {
+    /// Create a connector using the given configuration.
+    pub fn new(config: ProxyConfig) -> Self {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        ProxyConnector { http, config }
+    }
+
+    /// Fail connections that are not established within `dur`.
+    pub fn connect_timeout(mut self, dur: Duration) -> Self {
+        self.http.set_connect_timeout(Some(dur));
+        self
+    }
+}
+
+#[cfg(feature = "http-hyper")]
+impl Service<Uri> for ProxyConnector {
+    type Response = ProxyStream;
+    type Error = DynErr;
+    type Future = DynFut<Result<ProxyStream, DynErr>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), DynErr>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let mut http = self.http.clone();
+        let proxy = self.config.proxy_for(&uri).cloned();
+        Box::pin(async move {
+            let proxy = match proxy {
+                Some(proxy) => proxy,
+                None => {
+                    let stream = http.call(uri).await?;
+                    return Ok(ProxyStream {
+                        inner: stream,
+                        proxied: false,
+                    });
+                }
+            };
+            let mut stream = http.call(proxy).await?;
+            if uri.scheme_str() != Some("https") {
+                return Ok(ProxyStream {
+                    inner: stream,
+                    proxied: true,
+                });
+            }
+
+            let host = uri.host().ok_or("request URL has no host")?;
+            let authority = format!("{}:{}", host, uri.port_u16().unwrap_or(443));
+            let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", authority);
+            stream.write_all(request.as_bytes()).await?;
+            let mut response = Vec::new();
+            while !response.ends_with(b"\r\n\r\n") {
+                if response.len() >= MAX_CONNECT_RESPONSE {
+                    return Err("proxy response too large".into());
+                }
+                if stream.read_u8().await.map(|b| response.push(b)).is_err() {
+                    return Err("proxy closed the connection".into());
+                }
+            }
+            let status = response.split(|&b| b == b' ').nth(1).unwrap_or_default();
+            if status != b"200" {
+                let status = String::from_utf8_lossy(status);
+                return Err(format!("proxy refused to connect, status {}", status).into());
+            }
+            Ok(ProxyStream {
+                inner: stream,
+                proxied: false,
+            })
+        })
+    }
+}
+
+/// A connection made by `ProxyConnector`.
+#[cfg(feature = "http-hyper")]
+pub struct ProxyStream {
+    inner: tokio::net::TcpStream,
+    proxied: bool,
+}
+
+#[cfg(feature = "http-hyper")]
+impl Connection for ProxyStream {
+    fn connected(&self) -> Connected {
+        self.inner.connected().proxy(self.proxied)
+    }
+}
+
+#[cfg(feature = "http-hyper")]
+impl AsyncRead for ProxyStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+#[cfg(feature = "http-hyper")]
+impl AsyncWrite for ProxyStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
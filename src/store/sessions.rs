@@ -0,0 +1,67 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::misc::wipe_str;
+
+/// How often `Sessions` removes expired pairs.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A nonce/email pair. The nonce is wiped when dropped, see `misc::wipe`.
+#[derive(PartialEq, Eq, Hash)]
+pub(crate) struct Pair(pub String, pub String);
+
+impl Drop for Pair {
+    fn drop(&mut self) {
+        wipe_str(&mut self.0);
+    }
+}
+
+/// Active nonce/email pairs, with session data and an optional expiry time.
+#[derive(Default)]
+pub(crate) struct Sessions {
+    pairs: HashMap<Pair, (Vec<u8>, Option<Instant>)>,
+    next_purge: Option<Instant>,
+}
+
+impl Sessions {
+    pub fn insert(&mut self, pair: Pair, data: Vec<u8>, expires: Option<Instant>, now: Instant) {
+        if self.next_purge.map_or(true, |next_purge| now >= next_purge) {
+            self.pairs
+                .retain(|_, (_, expires)| !is_expired(*expires, now));
+            self.next_purge = Some(now + PURGE_INTERVAL);
+        }
+        self.pairs.insert(pair, (data, expires));
+    }
+
+    pub fn remove(&mut self, pair: &Pair, now: Instant) -> Option<Vec<u8>> {
+        match self.pairs.remove(pair) {
+            Some((data, expires)) if !is_expired(expires, now) => Some(data),
+            _ => None,
+        }
+    }
+
+    /// The number of pairs, including expired pairs that were not purged yet.
+    #[cfg(all(test, feature = "blocking"))]
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    #[cfg(feature = "memory-store")]
+    pub fn contains(&self, pair: &Pair, now: Instant) -> bool {
+        self.get(pair, now).is_some()
+    }
+
+    #[cfg(feature = "memory-store")]
+    pub fn get(&self, pair: &Pair, now: Instant) -> Option<&[u8]> {
+        match self.pairs.get(pair) {
+            Some((data, expires)) if !is_expired(*expires, now) => Some(data),
+            _ => None,
+        }
+    }
+}
+
+fn is_expired(expires: Option<Instant>, now: Instant) -> bool {
+    expires.is_some_and(|expires| now >= expires)
+}
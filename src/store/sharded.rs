@@ -0,0 +1,343 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use ring::digest;
+use thiserror::Error;
+use url::Url;
+
+use crate::misc::{DynErr, DynFut, DynFutRes};
+use crate::{FetchError, Store};
+
+/// The key used to route `Store::record_failure`, so that all records end up at the same shard.
+const FAILURES_KEY: &str = "failures";
+
+/// Error returned by a `ShardedStore` that has no shards configured.
+#[derive(Debug, Error)]
+#[error("the sharded store has no shards")]
+pub struct NoShardsError;
+
+/// A `Store` that routes operations across multiple backend stores using consistent hashing.
+///
+/// Session operations are routed by email address, so that `Store::new_nonce` and
+/// `Store::consume_nonce` for the same login always end up at the same shard. Document fetches are
+/// routed by URL. Records of failed verifications are all kept by a single shard.
+///
+/// Shards are identified by name, and can be added and removed at runtime. Because of consistent
+/// hashing, adding or removing a shard only moves a fraction of keys to a different shard. Logins
+/// in progress whose key moved will fail, so shards should preferably be changed during quiet
+/// periods.
+pub struct ShardedStore {
+    vnodes: usize,
+    ring: RwLock<Ring>,
+}
+
+#[derive(Default)]
+struct Ring {
+    shards: BTreeMap<String, Arc<dyn Store>>,
+    /// Sorted points on the ring, mapping to shard names.
+    points: Vec<(u64, String)>,
+}
+
+impl ShardedStore {
+    /// Create a store without any shards, using 64 virtual nodes per shard.
+    pub fn new() -> Self {
+        Self::with_virtual_nodes(64)
+    }
+
+    /// Create a store without any shards, using the given number of virtual nodes per shard.
+    ///
+    /// More virtual nodes result in a more even distribution of keys, at the cost of memory.
+    pub fn with_virtual_nodes(vnodes: usize) -> Self {
+        ShardedStore {
+            vnodes: vnodes.max(1),
+            ring: Default::default(),
+        }
+    }
+
+    /// Add a shard, or replace the store of an existing shard with the same name.
+    pub fn add_shard(&self, name: impl Into<String>, store: Arc<dyn Store>) {
+        let name = name.into();
+        let mut ring = self.ring.write().unwrap();
+        if ring.shards.insert(name.clone(), store).is_none() {
+            for vnode in 0..self.vnodes {
+                let point = hash(format!("{}#{}", name, vnode).as_bytes());
+                ring.points.push((point, name.clone()));
+            }
+            ring.points.sort();
+        }
+    }
+
+    /// Remove a shard, returning its store if it existed.
+    pub fn remove_shard(&self, name: &str) -> Option<Arc<dyn Store>> {
+        let mut ring = self.ring.write().unwrap();
+        let store = ring.shards.remove(name)?;
+        ring.points.retain(|(_, point_name)| point_name != name);
+        Some(store)
+    }
+
+    /// The names of all configured shards.
+    pub fn shard_names(&self) -> Vec<String> {
+        self.ring.read().unwrap().shards.keys().cloned().collect()
+    }
+
+    /// Find the shard responsible for the given key.
+    pub fn shard_for(&self, key: &str) -> Option<Arc<dyn Store>> {
+        let ring = self.ring.read().unwrap();
+        let point = hash(key.as_bytes());
+        let idx = ring.points.partition_point(|(p, _)| *p < point);
+        let (_, name) = ring.points.get(idx).or_else(|| ring.points.first())?;
+        ring.shards.get(name).cloned()
+    }
+}
+
+impl Default for ShardedStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Store for ShardedStore {
+    fn fetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        match self.shard_for(url.as_str()) {
+            Some(shard) => shard.fetch(url),
+            None => Box::pin(async { Err(FetchError::Store(Box::new(NoShardsError))) }),
+        }
+    }
+
+    fn refetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        match self.shard_for(url.as_str()) {
+            Some(shard) => shard.refetch(url),
+            None => Box::pin(async { Err(FetchError::Store(Box::new(NoShardsError))) }),
+        }
+    }
+
+    fn cache_lifetime(&self, url: Url) -> DynFutRes<Option<Duration>> {
+        match self.shard_for(url.as_str()) {
+            Some(shard) => shard.cache_lifetime(url),
+            None => no_shards(),
+        }
+    }
+
+    fn new_nonce(&self, email: String) -> DynFutRes<String> {
+        match self.shard_for(&email) {
+            Some(shard) => shard.new_nonce(email),
+            None => no_shards(),
+        }
+    }
+
+    fn new_nonce_with_ttl(&self, email: String, ttl: Duration) -> DynFutRes<String> {
+        match self.shard_for(&email) {
+            Some(shard) => shard.new_nonce_with_ttl(email, ttl),
+            None => no_shards(),
+        }
+    }
+
+    fn consume_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        match self.shard_for(&email) {
+            Some(shard) => shard.consume_nonce(nonce, email),
+            None => no_shards(),
+        }
+    }
+
+    fn new_nonce_with_data(
+        &self,
+        email: String,
+        data: Vec<u8>,
+        ttl: Duration,
+    ) -> DynFutRes<String> {
+        match self.shard_for(&email) {
+            Some(shard) => shard.new_nonce_with_data(email, data, ttl),
+            None => no_shards(),
+        }
+    }
+
+    fn consume_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        match self.shard_for(&email) {
+            Some(shard) => shard.consume_nonce_with_data(nonce, email),
+            None => no_shards(),
+        }
+    }
+
+    fn insert_nonce(
+        &self,
+        nonce: String,
+        email: String,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> DynFutRes<()> {
+        match self.shard_for(&email) {
+            Some(shard) => shard.insert_nonce(nonce, email, data, ttl),
+            None => no_shards(),
+        }
+    }
+
+    fn peek_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        match self.shard_for(&email) {
+            Some(shard) => shard.peek_nonce(nonce, email),
+            None => no_shards(),
+        }
+    }
+
+    fn peek_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        match self.shard_for(&email) {
+            Some(shard) => shard.peek_nonce_with_data(nonce, email),
+            None => no_shards(),
+        }
+    }
+
+    fn load_key_pins(&self, origin: String) -> DynFutRes<Option<String>> {
+        match self.shard_for(&origin) {
+            Some(shard) => shard.load_key_pins(origin),
+            None => no_shards(),
+        }
+    }
+
+    fn save_key_pins(&self, origin: String, pins: String) -> DynFutRes<()> {
+        match self.shard_for(&origin) {
+            Some(shard) => shard.save_key_pins(origin, pins),
+            None => no_shards(),
+        }
+    }
+
+    fn load_endpoint_state(&self, origin: String) -> DynFutRes<Option<String>> {
+        match self.shard_for(&origin) {
+            Some(shard) => shard.load_endpoint_state(origin),
+            None => no_shards(),
+        }
+    }
+
+    fn save_endpoint_state(&self, origin: String, state: String, ttl: Duration) -> DynFutRes<()> {
+        match self.shard_for(&origin) {
+            Some(shard) => shard.save_endpoint_state(origin, state, ttl),
+            None => no_shards(),
+        }
+    }
+
+    fn record_failure(&self, record: String, retention: Duration) -> DynFutRes<()> {
+        match self.shard_for(FAILURES_KEY) {
+            Some(shard) => shard.record_failure(record, retention),
+            None => no_shards(),
+        }
+    }
+
+    fn load_failures(&self) -> DynFutRes<Vec<String>> {
+        match self.shard_for(FAILURES_KEY) {
+            Some(shard) => shard.load_failures(),
+            None => no_shards(),
+        }
+    }
+}
+
+fn no_shards<T>() -> DynFutRes<T> {
+    Box::pin(async { Err(Box::new(NoShardsError) as DynErr) })
+}
+
+/// Stable hash function used to place keys on the ring.
+fn hash(data: &[u8]) -> u64 {
+    let digest = digest::digest(&digest::SHA256, data);
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest.as_ref()[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    /// A store that returns its own name as the nonce, to identify the shard an operation used.
+    struct Named(&'static str);
+
+    impl Store for Named {
+        fn fetch(&self, _: Url) -> DynFut<Result<Bytes, FetchError>> {
+            let name = self.0;
+            Box::pin(async move { Ok(Bytes::from(name)) })
+        }
+
+        fn new_nonce(&self, _: String) -> DynFutRes<String> {
+            let name = self.0;
+            Box::pin(async move { Ok(name.to_owned()) })
+        }
+
+        fn consume_nonce(&self, nonce: String, _: String) -> DynFutRes<bool> {
+            let name = self.0;
+            Box::pin(async move { Ok(nonce == name) })
+        }
+    }
+
+    fn sharded(names: &[&'static str]) -> ShardedStore {
+        let store = ShardedStore::new();
+        for name in names {
+            store.add_shard(*name, Arc::new(Named(name)));
+        }
+        store
+    }
+
+    fn email(i: usize) -> String {
+        format!("user{}@example.com", i)
+    }
+
+    #[tokio::test]
+    async fn fails_without_shards() {
+        let store = ShardedStore::new();
+        let err = store.new_nonce(email(0)).await.unwrap_err();
+        assert!(err.is::<NoShardsError>());
+        let err = store
+            .fetch("https://broker.example/".parse().unwrap())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FetchError::Store(_)));
+    }
+
+    #[tokio::test]
+    async fn routes_login_to_one_shard() {
+        let store = sharded(&["a", "b", "c"]);
+        for i in 0..100 {
+            let nonce = store.new_nonce(email(i)).await.unwrap();
+            assert!(store.consume_nonce(nonce, email(i)).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn distributes_keys_over_all_shards() {
+        let store = sharded(&["a", "b", "c"]);
+        let mut seen = BTreeSet::new();
+        for i in 0..100 {
+            seen.insert(store.new_nonce(email(i)).await.unwrap());
+        }
+        assert_eq!(seen.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn removing_shard_only_moves_its_keys() {
+        let store = sharded(&["a", "b", "c"]);
+        let mut before = Vec::new();
+        for i in 0..100 {
+            before.push(store.new_nonce(email(i)).await.unwrap());
+        }
+        assert!(store.remove_shard("b").is_some());
+        assert!(store.remove_shard("b").is_none());
+        for (i, shard) in before.into_iter().enumerate() {
+            let after = store.new_nonce(email(i)).await.unwrap();
+            if shard == "b" {
+                assert_ne!(after, "b");
+            } else {
+                assert_eq!(after, shard);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn replacing_shard_keeps_routing() {
+        let store = sharded(&["a", "b"]);
+        let points = store.ring.read().unwrap().points.len();
+        store.add_shard("b", Arc::new(Named("b")));
+        assert_eq!(store.ring.read().unwrap().points.len(), points);
+        assert_eq!(store.shard_names(), vec!["a", "b"]);
+    }
+}
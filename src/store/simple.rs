@@ -1,51 +1,64 @@
 use std::{
-    collections::{HashMap, HashSet},
-    convert::TryFrom,
-    error::Error as StdError,
+    collections::{HashMap, VecDeque},
     sync::{Arc, Mutex as StdMutex},
     time::{Duration, Instant},
 };
 
-use bytes::{BufMut, Bytes, BytesMut};
-use hyper::{
-    body::HttpBody, client::HttpConnector, header::HeaderName, service::Service, Body, StatusCode,
-};
-use hyper_tls::HttpsConnector;
+use bytes::Bytes;
+use http::{header, HeaderMap, HeaderValue, StatusCode};
 use ring::rand::{SecureRandom, SystemRandom};
-use thiserror::Error;
 use tokio::sync::Mutex as TokioMutex;
-use url::Url;
 
-use crate::misc::{base64url, DynErr, DynFut, DynFutRes};
-use crate::{FetchError, Store};
+use url::Url;
 
-type Request = hyper::Request<Body>;
-type Response = hyper::Response<Body>;
-type Client = hyper::Client<HttpsConnector<HttpConnector>>;
+use super::sessions::{Pair, Sessions};
+use crate::misc::{
+    parse_max_age, parse_retry_after, record_span, DynErr, DynFut, DynFutRes, USER_AGENT,
+};
+use crate::{
+    Clock, FetchError, HttpClient, HttpRequest, HttpStatusError, NonceGenerator, RandomNonces,
+    ResponseSizeLimit, ResponseTooLarge, Store, SystemClock,
+};
 
 /// A `Store` implementation that keeps everything in-memory.
 ///
 /// This is the default `Store` implementation if a `Client` is used without explicitely
 /// configuring one.
 ///
-/// Note that the cache in this store only grows. For clients that only talk to a trusted broker
-/// (the default), this is fine, because it can be assumed only a couple of URLs are fetched
-/// periodically.
+/// The cache in this store is bounded to 1000 documents by default. When full, expired documents
+/// are evicted first, then the least recently used. Clients that only talk to a trusted broker
+/// (the default) fetch only a couple of URLs, but brokers talking to many identity providers may
+/// want to tune this using `MemoryStore::cache_capacity`.
+///
+/// Expired documents are revalidated using a conditional request, if the server provided an `ETag`
+/// or `Last-Modified` header, so an unchanged document is not downloaded again. With
+/// `MemoryStore::stale_while_revalidate`, this happens in the background instead.
 ///
 /// This store will only function correctly if the application is a single process. When running
 /// multiple workers, the different processes will not be able to recognize eachothers' sessions.
 ///
 /// Restarting the application process will also cause a complete loss of all sessions. For low
-/// traffic sites, this may be fine, because sessions are short-lived.
+/// traffic sites, this may be fine, because sessions are short-lived. Sessions created using
+/// `Store::new_nonce_with_ttl` expire, and expired sessions are periodically removed.
+///
+/// Records of failed verifications, see `StoreFailureSink`, are limited to the most recent 1000.
 pub struct MemoryStore<C> {
     client: C,
     timeout: Duration,
-    rng: SystemRandom,
-    // Putting a lock on each item is probably not very efficient, but this is designed for usage
-    // from a Relying Party with a single trusted Broker, so will likely only contain two entries:
-    // the discovery document and the keys document.
-    cache: StdMutex<HashMap<Url, Arc<TokioMutex<CacheItem>>>>,
-    nonces: Arc<StdMutex<HashSet<(String, String)>>>,
+    headers: Arc<HeaderMap>,
+    retry: Arc<RetryPolicy>,
+    nonce_generator: Arc<dyn NonceGenerator>,
+    clock: Arc<dyn Clock>,
+    cache_capacity: usize,
+    max_stale: Option<Duration>,
+    max_response_size: Option<usize>,
+    // Each item has its own lock, so concurrent requests for the same document wait for a single
+    // fetch, while the cache lock is only held to look up or evict entries.
+    cache: StdMutex<Cache>,
+    nonces: Arc<StdMutex<Sessions>>,
+    key_pins: StdMutex<HashMap<String, String>>,
+    endpoint_state: StdMutex<HashMap<String, (String, Instant)>>,
+    failures: StdMutex<VecDeque<(String, Instant)>>,
 }
 
 impl<C> MemoryStore<C> {
@@ -53,186 +66,1191 @@ impl<C> MemoryStore<C> {
     ///
     /// If defaults are fine, use the `Default` implementation instead.
     pub fn with_http_client(client: C, timeout: Duration) -> Self {
-        // Dummy RNG call to flush out any latency from lazy init.
-        let rng = SystemRandom::new();
-        let mut dummy = vec![8];
-        rng.fill(&mut dummy)
-            .expect("secure random number generator failed");
-
         MemoryStore {
             client,
             timeout,
-            rng,
+            headers: Default::default(),
+            retry: Default::default(),
+            nonce_generator: Arc::new(RandomNonces::new()),
+            clock: Arc::new(SystemClock),
+            cache_capacity: 1000,
+            max_stale: None,
+            max_response_size: None,
             cache: Default::default(),
             nonces: Default::default(),
+            key_pins: Default::default(),
+            endpoint_state: Default::default(),
+            failures: Default::default(),
         }
     }
+
+    /// Use the given `Clock` for cache expiry, instead of the system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Configure the timeout for each request, replacing the one given on creation.
+    pub fn timeout(mut self, dur: Duration) -> Self {
+        self.timeout = dur;
+        self
+    }
+
+    /// Reject documents larger than `bytes`. By default, the size is not limited.
+    ///
+    /// The limit is passed to the HTTP client as a `ResponseSizeLimit`, so the default clients stop
+    /// downloading once it is exceeded.
+    pub fn max_response_size(mut self, bytes: usize) -> Self {
+        self.max_response_size = Some(bytes);
+        self
+    }
+
+    /// Generate nonces for new sessions using `generator`, instead of `RandomNonces`.
+    pub fn nonce_generator(mut self, generator: Arc<dyn NonceGenerator>) -> Self {
+        self.nonce_generator = generator;
+        self
+    }
+
+    /// Send the given headers with every document fetch.
+    ///
+    /// These are added to the defaults sent by `simple_fetch`, and replace them if the same header
+    /// name is used. This can be used to override the `User-Agent`, or to add headers required by
+    /// a reverse proxy in front of the broker.
+    pub fn default_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = Arc::new(headers);
+        self
+    }
+
+    /// Retry transient fetch failures according to `policy`. The default is not to retry.
+    ///
+    /// See `simple_fetch_with_retry` for the failures that are retried. Without retries, a single
+    /// failed request fails the login, and the error is cached for a few seconds.
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Arc::new(policy);
+        self
+    }
+
+    /// Configure the maximum number of documents to cache. The default is 1000.
+    ///
+    /// The cache always holds at least the document being fetched.
+    pub fn cache_capacity(mut self, entries: usize) -> Self {
+        self.cache_capacity = entries;
+        self
+    }
+
+    /// Serve expired documents while refreshing them in the background, for up to `max_stale`
+    /// after they expire. By default, expired documents are refreshed before they are returned.
+    ///
+    /// This keeps the latency of a refresh out of the request that happens to find the document
+    /// expired, and out of concurrent requests waiting for it. Once a document is more than
+    /// `max_stale` past its expiry, it is refreshed before it is returned again. If a background
+    /// refresh fails, the stale document continues to be served, and the refresh is retried after
+    /// a few seconds.
+    ///
+    /// This requires a Tokio runtime to spawn refresh tasks on.
+    pub fn stale_while_revalidate(mut self, max_stale: Duration) -> Self {
+        self.max_stale = Some(max_stale);
+        self
+    }
 }
 
-impl Default for MemoryStore<Client> {
+#[cfg(all(
+    any(feature = "http-hyper", feature = "http-reqwest"),
+    any(feature = "tls-native", feature = "tls-rustls")
+))]
+impl Default for MemoryStore<crate::DefaultHttpClient> {
     /// Create a store with a default configuration.
     ///
-    /// This create a Hyper client that uses `native-tls` for secure connections, and configures a
+    /// This creates a `DefaultHttpClient` using proxies from the environment, and configures a
     /// timeout of 30-seconds for each request.
     fn default() -> Self {
-        let client = hyper::Client::builder().build(HttpsConnector::new());
-        Self::with_http_client(client, Duration::from_secs(30))
+        Self::with_http_client(crate::default_http_client(), Duration::from_secs(30))
+    }
+}
+
+#[cfg(all(
+    any(feature = "http-hyper", feature = "http-reqwest"),
+    any(feature = "tls-native", feature = "tls-rustls")
+))]
+impl MemoryStore<crate::DefaultHttpClient> {
+    /// Create a store with a default configuration, using the given proxies.
+    ///
+    /// The `Default` implementation reads the proxy configuration from the environment instead.
+    pub fn with_proxy(proxy: crate::ProxyConfig) -> Self {
+        Self::with_http_client(
+            crate::default_http_client_with_proxy(proxy),
+            Duration::from_secs(30),
+        )
     }
 }
 
 impl<C> Store for MemoryStore<C>
 where
-    C: Service<Request, Response = Response> + Clone + Send + Sync + 'static,
-    C::Error: StdError + Send + Sync + 'static,
-    C::Future: Send,
+    C: HttpClient + Clone,
 {
     fn fetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
         let client = self.client.clone();
         let timeout = self.timeout;
-        let item = self
-            .cache
-            .lock()
-            .unwrap()
-            .entry(url.clone())
-            .or_default()
-            .clone();
+        let headers = self.headers.clone();
+        let retry = self.retry.clone();
+        let clock = self.clock.clone();
+        let max_stale = self.max_stale;
+        let max_size = self.max_response_size;
+        let cache_item =
+            self.cache
+                .lock()
+                .unwrap()
+                .item(&url, self.cache_capacity, max_stale, clock.instant());
         Box::pin(async move {
-            let mut item = item.lock().await;
-            if Instant::now() >= item.expires {
-                let (result, max_age) = simple_fetch(client, timeout, url).await;
-                item.result = result.map_err(Arc::new);
-                item.expires = Instant::now() + max_age;
+            let mut item = cache_item.lock().await;
+            let now = clock.instant();
+            let expired = item.is_expired(now);
+            #[cfg(feature = "tracing")]
+            tracing::debug!(%url, hit = !expired, "document cache lookup");
+            if expired && max_stale.is_some_and(|max_stale| item.is_usable_stale(max_stale, now)) {
+                if item.should_refresh(now) {
+                    item.refreshing = true;
+                    let validators = item.validators.clone();
+                    let cache_item = cache_item.clone();
+                    tokio::spawn(async move {
+                        let validators = (!validators.is_empty()).then_some(validators);
+                        let (result, max_age) = fetch_with_retry(
+                            &client,
+                            timeout,
+                            &url,
+                            &headers,
+                            &retry,
+                            validators.as_ref(),
+                            max_size,
+                        )
+                        .await;
+                        let mut item = cache_item.lock().await;
+                        item.refreshing = false;
+                        let now = clock.instant();
+                        match result {
+                            Ok(fetched) => item.update(Ok(fetched), max_age, now),
+                            // Keep serving the stale document, and retry later.
+                            Err(_) => item.refresh_at = Some(now + max_age),
+                        }
+                    });
+                }
+                return item.result.clone().map_err(FetchError::Fetch);
+            }
+            if expired {
+                // Revalidate a previously fetched document, if the server provided validators.
+                let validators = match item.result {
+                    Ok(_) if !item.validators.is_empty() => Some(item.validators.clone()),
+                    _ => None,
+                };
+                let (result, max_age) = fetch_with_retry(
+                    &client,
+                    timeout,
+                    &url,
+                    &headers,
+                    &retry,
+                    validators.as_ref(),
+                    max_size,
+                )
+                .await;
+                item.update(result, max_age, clock.instant());
             }
             item.result.clone().map_err(FetchError::Fetch)
         })
     }
 
+    fn refetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        let client = self.client.clone();
+        let timeout = self.timeout;
+        let headers = self.headers.clone();
+        let retry = self.retry.clone();
+        let clock = self.clock.clone();
+        let max_size = self.max_response_size;
+        let item = self.cache.lock().unwrap().item(
+            &url,
+            self.cache_capacity,
+            self.max_stale,
+            clock.instant(),
+        );
+        Box::pin(async move {
+            let mut item = item.lock().await;
+            let (result, max_age) =
+                fetch_with_retry(&client, timeout, &url, &headers, &retry, None, max_size).await;
+            item.update(result, max_age, clock.instant());
+            item.result.clone().map_err(FetchError::Fetch)
+        })
+    }
+
+    fn cache_lifetime(&self, url: Url) -> DynFutRes<Option<Duration>> {
+        let now = self.clock.instant();
+        let expires = self.cache.lock().unwrap().expires(&url);
+        let res = expires.and_then(|expires| expires.checked_duration_since(now));
+        Box::pin(async move { Ok(res.filter(|lifetime| !lifetime.is_zero())) })
+    }
+
     fn new_nonce(&self, email: String) -> DynFutRes<String> {
-        let rng = self.rng.clone();
+        self.create_nonce(email, Vec::new(), None)
+    }
+
+    fn new_nonce_with_ttl(&self, email: String, ttl: Duration) -> DynFutRes<String> {
+        self.create_nonce(email, Vec::new(), Some(ttl))
+    }
+
+    fn consume_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        let now = self.clock.instant();
+        let res = self.nonces.lock().unwrap().remove(&Pair(nonce, email), now);
+        Box::pin(async move { Ok(res.is_some()) })
+    }
+
+    fn new_nonce_with_data(
+        &self,
+        email: String,
+        data: Vec<u8>,
+        ttl: Duration,
+    ) -> DynFutRes<String> {
+        self.create_nonce(email, data, Some(ttl))
+    }
+
+    fn consume_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        let now = self.clock.instant();
+        let res = self.nonces.lock().unwrap().remove(&Pair(nonce, email), now);
+        Box::pin(async move { Ok(res) })
+    }
+
+    fn insert_nonce(
+        &self,
+        nonce: String,
+        email: String,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> DynFutRes<()> {
+        let now = self.clock.instant();
+        let expires = ttl.map(|ttl| now + ttl);
+        self.nonces
+            .lock()
+            .unwrap()
+            .insert(Pair(nonce, email), data, expires, now);
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn peek_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        let now = self.clock.instant();
+        let res = self
+            .nonces
+            .lock()
+            .unwrap()
+            .contains(&Pair(nonce, email), now);
+        Box::pin(async move { Ok(res) })
+    }
+
+    fn peek_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        let now = self.clock.instant();
+        let res = self
+            .nonces
+            .lock()
+            .unwrap()
+            .get(&Pair(nonce, email), now)
+            .map(<[u8]>::to_vec);
+        Box::pin(async move { Ok(res) })
+    }
+
+    fn load_key_pins(&self, origin: String) -> DynFutRes<Option<String>> {
+        let res = self.key_pins.lock().unwrap().get(&origin).cloned();
+        Box::pin(async move { Ok(res) })
+    }
+
+    fn save_key_pins(&self, origin: String, pins: String) -> DynFutRes<()> {
+        self.key_pins.lock().unwrap().insert(origin, pins);
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn load_endpoint_state(&self, origin: String) -> DynFutRes<Option<String>> {
+        let now = self.clock.instant();
+        let res = match self.endpoint_state.lock().unwrap().get(&origin) {
+            Some((state, expires)) if now < *expires => Some(state.clone()),
+            _ => None,
+        };
+        Box::pin(async move { Ok(res) })
+    }
+
+    fn save_endpoint_state(&self, origin: String, state: String, ttl: Duration) -> DynFutRes<()> {
+        let expires = self.clock.instant() + ttl;
+        self.endpoint_state
+            .lock()
+            .unwrap()
+            .insert(origin, (state, expires));
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn record_failure(&self, record: String, retention: Duration) -> DynFutRes<()> {
+        let expires = self.clock.instant() + retention;
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_FAILURES {
+            failures.pop_front();
+        }
+        failures.push_back((record, expires));
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn load_failures(&self) -> DynFutRes<Vec<String>> {
+        let now = self.clock.instant();
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|(_, expires)| now < *expires);
+        let res = failures.iter().map(|(record, _)| record.clone()).collect();
+        Box::pin(async move { Ok(res) })
+    }
+}
+
+impl<C> MemoryStore<C> {
+    /// Generate a nonce and store the pair with `data`, optionally expiring after `ttl`.
+    fn create_nonce(
+        &self,
+        email: String,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> DynFutRes<String> {
+        let nonce = self.nonce_generator.generate();
+        let clock = self.clock.clone();
         let nonces = self.nonces.clone();
         Box::pin(async move {
-            let nonce = generate_nonce(rng).await;
-            nonces.lock().unwrap().insert((nonce.clone(), email));
+            let nonce = nonce.await?;
+            let now = clock.instant();
+            let expires = ttl.map(|ttl| now + ttl);
+            nonces
+                .lock()
+                .unwrap()
+                .insert(Pair(nonce.clone(), email), data, expires, now);
             Ok(nonce)
         })
     }
+}
 
-    fn consume_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
-        let res = self.nonces.lock().unwrap().remove(&(nonce, email));
-        Box::pin(async move { Ok(res) })
+/// The number of records of failed verifications kept by a `MemoryStore`.
+const MAX_FAILURES: usize = 1000;
+
+/// The document cache, bounded to a maximum number of entries.
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<Url, CacheEntry>,
+    /// Counter used to track the order in which entries were used.
+    tick: u64,
+}
+
+struct CacheEntry {
+    item: Arc<TokioMutex<CacheItem>>,
+    last_used: u64,
+}
+
+impl Cache {
+    /// Get or create the item for `url`, evicting other entries if the cache is full.
+    fn item(
+        &mut self,
+        url: &Url,
+        capacity: usize,
+        max_stale: Option<Duration>,
+        now: Instant,
+    ) -> Arc<TokioMutex<CacheItem>> {
+        self.tick += 1;
+        if let Some(entry) = self.entries.get_mut(url) {
+            entry.last_used = self.tick;
+            return entry.item.clone();
+        }
+
+        if self.entries.len() >= capacity {
+            self.evict(capacity.saturating_sub(1), max_stale, now);
+        }
+        let item = Arc::<TokioMutex<CacheItem>>::default();
+        self.entries.insert(
+            url.clone(),
+            CacheEntry {
+                item: item.clone(),
+                last_used: self.tick,
+            },
+        );
+        item
+    }
+
+    /// The expiry time of the entry for `url`, if it was fetched and is not being fetched now.
+    fn expires(&self, url: &Url) -> Option<Instant> {
+        self.entries.get(url)?.item.try_lock().ok()?.expires
+    }
+
+    /// Evict expired entries, then the least recently used, until at most `target` remain.
+    ///
+    /// Entries that are locked are being fetched, and are never considered expired. Expired
+    /// entries that can still be served stale within `max_stale` are only evicted as least
+    /// recently used.
+    fn evict(&mut self, target: usize, max_stale: Option<Duration>, now: Instant) {
+        self.entries.retain(|_, entry| match entry.item.try_lock() {
+            Ok(item) => {
+                !item.is_expired(now)
+                    || max_stale.is_some_and(|max_stale| item.is_usable_stale(max_stale, now))
+            }
+            Err(_) => true,
+        });
+        while self.entries.len() > target {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(url, _)| url.clone());
+            match oldest {
+                Some(url) => self.entries.remove(&url),
+                None => break,
+            };
+        }
     }
 }
 
 struct CacheItem {
     result: Result<Bytes, Arc<DynErr>>,
-    expires: Instant,
+    /// Expiry time of the result, or `None` if the item was never fetched.
+    expires: Option<Instant>,
+    /// Validators of a successful result, used to revalidate it once expired.
+    validators: Validators,
+    /// Whether a background refresh is in progress.
+    refreshing: bool,
+    /// Earliest time to retry a failed background refresh.
+    refresh_at: Option<Instant>,
 }
 
 impl Default for CacheItem {
     fn default() -> Self {
         CacheItem {
             result: Ok(Bytes::default()),
-            expires: Instant::now(),
+            expires: None,
+            validators: Validators::default(),
+            refreshing: false,
+            refresh_at: None,
         }
     }
 }
 
-#[derive(Debug, Error)]
-#[error("unexpected HTTP status code {0}")]
-struct FetchStatusError(pub StatusCode);
+impl CacheItem {
+    fn is_expired(&self, now: Instant) -> bool {
+        match self.expires {
+            Some(expires) => now >= expires,
+            None => true,
+        }
+    }
+
+    /// Whether the item holds a document that expired less than `max_stale` ago.
+    fn is_usable_stale(&self, max_stale: Duration, now: Instant) -> bool {
+        match (&self.result, self.expires) {
+            (Ok(_), Some(expires)) => now < expires + max_stale,
+            _ => false,
+        }
+    }
+
+    /// Whether to start a background refresh of a stale document.
+    fn should_refresh(&self, now: Instant) -> bool {
+        !self.refreshing && self.refresh_at.map_or(true, |refresh_at| now >= refresh_at)
+    }
+
+    /// Store the result of a fetch, cached for `max_age`.
+    fn update(&mut self, result: Result<Fetched, DynErr>, max_age: Duration, now: Instant) {
+        match result {
+            Ok(Fetched::Body(body, validators)) => {
+                self.result = Ok(body);
+                self.validators = validators;
+            }
+            Ok(Fetched::NotModified(validators)) => self.validators.refresh(validators),
+            Err(err) => {
+                self.result = Err(Arc::new(err));
+                self.validators = Validators::default();
+            }
+        }
+        self.expires = Some(now + max_age);
+        self.refresh_at = None;
+    }
+}
 
 /// Performs a simple GET-request using the given HTTP client, and handles the response.
 ///
 /// This checks the response status, parses the `Cache-Control` header, and reads the response
-/// body. The returned tuple has the max cache duration as the second element.
+/// body. The returned tuple has the max cache duration as the second element. Unexpected status
+/// codes result in an `HttpStatusError`, which is cached for the `Retry-After` delay, up to a
+/// minute, if present.
+///
+/// The request has a `User-Agent` identifying this crate, and `Accept: application/json`. Use
+/// `simple_fetch_with_headers` to send additional headers.
 ///
 /// This is a default implementation for use by `Store::fetch` on cache miss.
 pub async fn simple_fetch<C>(
-    mut client: C,
+    client: &C,
     timeout: Duration,
     url: Url,
 ) -> (Result<Bytes, DynErr>, Duration)
 where
-    C: Service<Request, Response = Response>,
-    C::Error: StdError + Send + Sync + 'static,
+    C: HttpClient + ?Sized,
 {
-    // Error-case default cache lifespan.
-    let mut max_age = Duration::from_secs(3);
+    simple_fetch_with_headers(client, timeout, url, &HeaderMap::new()).await
+}
 
-    let (response, data) = match tokio::time::timeout(timeout, async {
-        let request = hyper::Request::builder()
-            .uri(hyper::Uri::try_from(String::from(url)).unwrap())
-            .body(Body::empty())
-            .unwrap();
-        let mut response = match client.call(request).await {
-            Ok(response) => response,
-            Err(err) => return Err(Box::new(err) as DynErr),
-        };
+/// Like `simple_fetch`, but also sends the given headers.
+///
+/// The headers are added to the defaults, and replace them if the same header name is used.
+pub async fn simple_fetch_with_headers<C>(
+    client: &C,
+    timeout: Duration,
+    url: Url,
+    headers: &HeaderMap,
+) -> (Result<Bytes, DynErr>, Duration)
+where
+    C: HttpClient + ?Sized,
+{
+    simple_fetch_with_retry(client, timeout, url, headers, &RetryPolicy::default()).await
+}
+
+/// Like `simple_fetch_with_headers`, but retries transient failures according to `retry`.
+///
+/// Failed requests, timeouts and the HTTP status codes 429, 500, 502, 503 and 504 are considered
+/// transient, unless the response has a `Retry-After` header. The `timeout` applies to each
+/// attempt. Only the result of the last attempt is returned.
+pub async fn simple_fetch_with_retry<C>(
+    client: &C,
+    timeout: Duration,
+    url: Url,
+    headers: &HeaderMap,
+    retry: &RetryPolicy,
+) -> (Result<Bytes, DynErr>, Duration)
+where
+    C: HttpClient + ?Sized,
+{
+    let (result, max_age) =
+        fetch_with_retry(client, timeout, &url, headers, retry, None, None).await;
+    let result = result.map(|fetched| match fetched {
+        Fetched::Body(body, _) => body,
+        Fetched::NotModified(_) => unreachable!("unconditional request was not modified"),
+    });
+    (result, max_age)
+}
+
+/// Validators from a response, used to make conditional requests.
+#[derive(Clone, Default)]
+struct Validators {
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+}
 
-        if response.status() != 200 {
-            let err = FetchStatusError(response.status());
-            return Err(Box::new(err) as DynErr);
+impl Validators {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        Validators {
+            etag: headers.get(header::ETAG).cloned(),
+            last_modified: headers.get(header::LAST_MODIFIED).cloned(),
         }
+    }
 
-        let size: usize = response
-            .headers()
-            .get(HeaderName::from_static("cache-control"))
-            .and_then(|val| val.to_str().ok())
-            .and_then(|val| val.parse().ok())
-            .unwrap_or_default();
-
-        let mut data = BytesMut::with_capacity(size);
-        let body = response.body_mut();
-        while let Some(chunk) = body.data().await {
-            match chunk {
-                Ok(chunk) => data.put(chunk),
-                Err(err) => return Err(Box::new(err) as DynErr),
-            }
+    fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    /// Replace validators with those sent in a `304 Not Modified` response.
+    ///
+    /// The server may omit validators that did not change, so those are kept.
+    fn refresh(&mut self, new: Validators) {
+        if new.etag.is_some() {
+            self.etag = new.etag;
+        }
+        if new.last_modified.is_some() {
+            self.last_modified = new.last_modified;
         }
+    }
 
-        Ok((response, data))
-    })
-    .await
-    {
-        Ok(Ok(res)) => res,
-        Ok(Err(err)) => return (Err(err), max_age),
-        Err(err) => return (Err(Box::new(err)), max_age),
+    /// Add the conditional request headers for these validators.
+    fn apply(&self, headers: &mut HeaderMap) {
+        if let Some(ref etag) = self.etag {
+            headers.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(ref last_modified) = self.last_modified {
+            headers.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+}
+
+/// A successful response from `fetch_once`.
+enum Fetched {
+    /// The response body, with its validators.
+    Body(Bytes, Validators),
+    /// The server responded to a conditional request with `304 Not Modified`, with any updated
+    /// validators.
+    NotModified(Validators),
+}
+
+/// Like `simple_fetch_with_retry`, but makes a conditional request if `validators` is given.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "simple_fetch",
+        level = "debug",
+        skip_all,
+        fields(url = %url, status = tracing::field::Empty, max_age = tracing::field::Empty)
+    )
+)]
+async fn fetch_with_retry<C>(
+    client: &C,
+    timeout: Duration,
+    url: &Url,
+    headers: &HeaderMap,
+    retry: &RetryPolicy,
+    validators: Option<&Validators>,
+    max_size: Option<usize>,
+) -> (Result<Fetched, DynErr>, Duration)
+where
+    C: HttpClient + ?Sized,
+{
+    let mut attempt = 1;
+    loop {
+        let (result, max_age, transient) =
+            fetch_once(client, timeout, url, headers, validators, max_size).await;
+        if !transient || attempt >= retry.attempts {
+            return (result, max_age);
+        }
+
+        let delay = retry.backoff(attempt);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(attempt, ?delay, "retrying request");
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Perform a single attempt for `fetch_with_retry`.
+///
+/// The returned tuple has the max cache duration as the second element, and whether the failure
+/// is transient as the third.
+async fn fetch_once<C>(
+    client: &C,
+    timeout: Duration,
+    url: &Url,
+    headers: &HeaderMap,
+    validators: Option<&Validators>,
+    max_size: Option<usize>,
+) -> (Result<Fetched, DynErr>, Duration, bool)
+where
+    C: HttpClient + ?Sized,
+{
+    // Error-case default cache lifespan.
+    let mut max_age = Duration::from_secs(3);
+
+    let mut request = HttpRequest::get(url.as_str()).body(()).unwrap();
+    let request_headers = request.headers_mut();
+    request_headers.insert(header::USER_AGENT, HeaderValue::from_static(USER_AGENT));
+    request_headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+    if let Some(validators) = validators {
+        validators.apply(request_headers);
+    }
+    request_headers.extend(headers.clone());
+    if let Some(limit) = max_size {
+        request.extensions_mut().insert(ResponseSizeLimit(limit));
+    }
+    let response = match tokio::time::timeout(timeout, client.request(request)).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(err)) => {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(error = %err, "request failed");
+            // Exceeding the size limit will fail again on retry.
+            let transient = !err.is::<ResponseTooLarge>();
+            return (Err(err), max_age, transient);
+        }
+        Err(err) => {
+            #[cfg(feature = "tracing")]
+            tracing::debug!("request timed out");
+            return (Err(Box::new(err)), max_age, true);
+        }
     };
 
+    let status = response.status();
+    record_span!("status", status.as_u16());
+    let not_modified = status == StatusCode::NOT_MODIFIED && validators.is_some();
+    if status != StatusCode::OK && !not_modified {
+        let retry_after = parse_retry_after(
+            response
+                .headers()
+                .get(header::RETRY_AFTER)
+                .and_then(|val| val.to_str().ok()),
+        )
+        .map(Duration::from_secs);
+        // Don't retry sooner than the server asked for, but back off for a while.
+        if let Some(retry_after) = retry_after {
+            max_age = retry_after.clamp(max_age, Duration::from_secs(60));
+        }
+        let transient = retry_after.is_none()
+            && matches!(
+                status,
+                StatusCode::TOO_MANY_REQUESTS
+                    | StatusCode::INTERNAL_SERVER_ERROR
+                    | StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+                    | StatusCode::GATEWAY_TIMEOUT
+            );
+        let err = HttpStatusError::new(status.as_u16(), retry_after);
+        return (Err(Box::new(err)), max_age, transient);
+    }
+
     // Success-case default and minimum cache lifespan.
     max_age = Duration::from_secs(60);
 
-    if let Some(val) = response
-        .headers()
-        .get(HeaderName::from_static("cache-control"))
-        .and_then(|val| val.to_str().ok())
-        .and_then(|val| {
-            val.split(',')
-                .find_map(|s| s.trim().strip_prefix("max-age="))
-        })
-        .and_then(|val| val.parse().ok())
-    {
+    if let Some(val) = parse_max_age(
+        response
+            .headers()
+            .get(header::CACHE_CONTROL)
+            .and_then(|val| val.to_str().ok()),
+    ) {
         max_age = max_age.max(Duration::from_secs(val));
     }
+    record_span!("max_age", max_age.as_secs());
 
-    (Ok(data.into()), max_age)
+    let validators = Validators::from_headers(response.headers());
+    if not_modified {
+        return (Ok(Fetched::NotModified(validators)), max_age, false);
+    }
+    if let Some(limit) = max_size.filter(|&limit| response.body().len() > limit) {
+        return (Err(Box::new(ResponseTooLarge(limit))), max_age, false);
+    }
+    (
+        Ok(Fetched::Body(response.into_body(), validators)),
+        max_age,
+        false,
+    )
 }
 
-/// Returns 128-bits of secure random data in an URL-safe encoding.
+/// How `simple_fetch_with_retry` retries transient failures. See `MemoryStore::retry`.
 ///
-/// This is a default implementation for use by `Store::new_nonce` to generate nonces (numbers used
-/// once). This function panics if the RNG fails.
-///
-/// The RNG is usually `SystemRandom`. Note that `SystemRandom` may perform lazy initialization,
-/// and it is therefore recommended to do a dummy `SystemRandom::fill` after creating. See
-/// `SystemRandom::new` for details.
-pub async fn generate_nonce(rng: impl SecureRandom + Send + Sync + 'static) -> String {
-    tokio::task::spawn_blocking(move || {
-        let mut data = vec![0; 16];
-        rng.fill(&mut data[..])
+/// The delay before each retry is chosen randomly, up to a limit that starts at
+/// `RetryPolicy::initial_backoff` and doubles for every retry, up to `RetryPolicy::max_backoff`.
+/// The default policy makes a single attempt.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// A policy that makes at most `attempts` attempts, including the first.
+    ///
+    /// The default backoff starts at 100 milliseconds, up to 2 seconds.
+    pub fn new(attempts: u32) -> Self {
+        RetryPolicy {
+            attempts,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+
+    /// Configure the backoff limit of the first retry.
+    pub fn initial_backoff(mut self, dur: Duration) -> Self {
+        self.initial_backoff = dur;
+        self
+    }
+
+    /// Configure the maximum backoff limit.
+    pub fn max_backoff(mut self, dur: Duration) -> Self {
+        self.max_backoff = dur;
+        self
+    }
+
+    /// The delay before retrying after `attempt` attempts, with full jitter.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let limit = self
+            .initial_backoff
+            .checked_mul(1 << (attempt - 1).min(31))
+            .map_or(self.max_backoff, |limit| limit.min(self.max_backoff));
+        let mut random = [0; 4];
+        SystemRandom::new()
+            .fill(&mut random)
             .expect("secure random number generator failed");
-        base64url::encode(&data)
-    })
-    .await
-    .expect("rng task panicked")
+        limit.mul_f64(f64::from(u32::from_be_bytes(random)) / f64::from(u32::MAX))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new(1)
+    }
+}
+
+/// The cache key for a URL, a hex-encoded SHA-256 digest.
+///
+/// URLs can be longer than some backends allow for keys.
+#[cfg(any(
+    feature = "sql-postgres",
+    feature = "sql-mysql",
+    feature = "sql-sqlite",
+    feature = "memcached-store",
+    feature = "dynamodb-store",
+    feature = "sled-store"
+))]
+pub(crate) fn url_hash(url: &Url) -> String {
+    hex_digest(url.as_str())
+}
+
+/// Hex-encoded SHA-256 digest of `value`, for use in keys.
+#[cfg(any(
+    feature = "sql-postgres",
+    feature = "sql-mysql",
+    feature = "sql-sqlite",
+    feature = "memcached-store",
+    feature = "dynamodb-store",
+    feature = "sled-store"
+))]
+pub(crate) fn hex_digest(value: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, value.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The current time as a Unix timestamp, for persisting expiry times.
+#[cfg(any(
+    feature = "sql-postgres",
+    feature = "sql-mysql",
+    feature = "sql-sqlite",
+    feature = "memcached-store",
+    feature = "dynamodb-store",
+    feature = "sled-store"
+))]
+pub(crate) fn unix_time(clock: &dyn Clock) -> i64 {
+    clock
+        .now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or_else(
+            |before| -(before.duration().as_secs() as i64),
+            |elapsed| elapsed.as_secs() as i64,
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::{HttpResponse, ManualClock};
+
+    /// An `HttpClient` that returns queued responses, and records the requests made.
+    #[derive(Clone, Default)]
+    struct FakeHttp {
+        responses: Arc<StdMutex<VecDeque<HttpResponse>>>,
+        requests: Arc<StdMutex<Vec<HttpRequest>>>,
+    }
+
+    impl FakeHttp {
+        fn respond(
+            &self,
+            status: u16,
+            headers: &[(&'static str, &'static str)],
+            body: &'static str,
+        ) {
+            let mut response = HttpResponse::new(Bytes::from_static(body.as_bytes()));
+            *response.status_mut() = StatusCode::from_u16(status).unwrap();
+            for &(name, value) in headers {
+                response
+                    .headers_mut()
+                    .insert(name, HeaderValue::from_static(value));
+            }
+            self.responses.lock().unwrap().push_back(response);
+        }
+
+        /// The value of header `name` in the last request.
+        fn last_header(&self, name: &str) -> Option<HeaderValue> {
+            let requests = self.requests.lock().unwrap();
+            requests.last()?.headers().get(name).cloned()
+        }
+    }
+
+    impl HttpClient for FakeHttp {
+        fn request(&self, request: HttpRequest) -> DynFutRes<HttpResponse> {
+            self.requests.lock().unwrap().push(request);
+            let response = self.responses.lock().unwrap().pop_front();
+            Box::pin(async move { response.ok_or_else(|| "no response queued".into()) })
+        }
+    }
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn store() -> (MemoryStore<FakeHttp>, FakeHttp, Arc<ManualClock>) {
+        let http = FakeHttp::default();
+        let clock = Arc::new(ManualClock::new(SystemTime::now()));
+        let store = MemoryStore::with_http_client(http.clone(), MINUTE).clock(clock.clone());
+        (store, http, clock)
+    }
+
+    fn url() -> Url {
+        Url::parse("https://broker.example/jwks.json").unwrap()
+    }
+
+    #[tokio::test]
+    async fn revalidates_with_validators_from_not_modified() {
+        let (store, http, clock) = store();
+        http.respond(200, &[("etag", "\"a\"")], "{}");
+        store.fetch(url()).await.unwrap();
+
+        clock.advance(MINUTE);
+        http.respond(304, &[("etag", "\"b\"")], "");
+        assert_eq!(store.fetch(url()).await.unwrap(), &b"{}"[..]);
+        assert_eq!(http.last_header("if-none-match").unwrap(), "\"a\"");
+
+        clock.advance(MINUTE);
+        http.respond(304, &[], "");
+        assert_eq!(store.fetch(url()).await.unwrap(), &b"{}"[..]);
+        assert_eq!(http.last_header("if-none-match").unwrap(), "\"b\"");
+    }
+
+    #[tokio::test]
+    async fn takes_lifetime_from_not_modified() {
+        let (store, http, clock) = store();
+        http.respond(200, &[("etag", "\"a\"")], "{}");
+        store.fetch(url()).await.unwrap();
+
+        clock.advance(MINUTE);
+        http.respond(304, &[("cache-control", "max-age=600")], "");
+        store.fetch(url()).await.unwrap();
+        let lifetime = store.cache_lifetime(url()).await.unwrap();
+        assert_eq!(lifetime, Some(Duration::from_secs(600)));
+    }
+
+    fn doc(path: &str) -> Url {
+        Url::parse("https://broker.example/")
+            .unwrap()
+            .join(path)
+            .unwrap()
+    }
+
+    /// Get or create a cache entry, and mark it as fetched until `expires`.
+    fn use_entry(cache: &mut Cache, url: &Url, capacity: usize, now: Instant, expires: Instant) {
+        let item = cache.item(url, capacity, None, now);
+        item.try_lock().unwrap().expires = Some(expires);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = Cache::default();
+        let now = Instant::now();
+        let (a, b, c) = (doc("a"), doc("b"), doc("c"));
+        use_entry(&mut cache, &a, 2, now, now + MINUTE);
+        use_entry(&mut cache, &b, 2, now, now + MINUTE);
+        use_entry(&mut cache, &a, 2, now, now + MINUTE);
+        use_entry(&mut cache, &c, 2, now, now + MINUTE);
+        assert!(cache.entries.contains_key(&a));
+        assert!(!cache.entries.contains_key(&b));
+        assert!(cache.entries.contains_key(&c));
+    }
+
+    #[test]
+    fn evicts_expired_before_least_recently_used() {
+        let mut cache = Cache::default();
+        let now = Instant::now();
+        let (a, b, c) = (doc("a"), doc("b"), doc("c"));
+        use_entry(&mut cache, &a, 2, now, now + 2 * MINUTE);
+        use_entry(&mut cache, &b, 2, now, now + MINUTE);
+        use_entry(&mut cache, &c, 2, now + MINUTE, now + 2 * MINUTE);
+        assert!(cache.entries.contains_key(&a));
+        assert!(!cache.entries.contains_key(&b));
+        assert!(cache.entries.contains_key(&c));
+    }
+
+    #[test]
+    fn keeps_usable_stale_entries() {
+        let mut cache = Cache::default();
+        let now = Instant::now();
+        let (a, b, c, d) = (doc("a"), doc("b"), doc("c"), doc("d"));
+        let max_stale = Some(2 * MINUTE);
+        for (url, expires) in [(&a, now), (&b, now - 3 * MINUTE), (&c, now + MINUTE)] {
+            let item = cache.item(url, 3, max_stale, now - 4 * MINUTE);
+            item.try_lock().unwrap().expires = Some(expires);
+        }
+        let item = cache.item(&d, 3, max_stale, now);
+        item.try_lock().unwrap().expires = Some(now + MINUTE);
+        assert!(cache.entries.contains_key(&a));
+        assert!(!cache.entries.contains_key(&b));
+        assert!(cache.entries.contains_key(&c));
+        assert!(cache.entries.contains_key(&d));
+
+        // Without room, usable stale entries are still evicted as least recently used.
+        let e = doc("e");
+        cache.item(&e, 3, max_stale, now);
+        assert!(!cache.entries.contains_key(&a));
+        assert!(cache.entries.contains_key(&c));
+    }
+
+    #[tokio::test]
+    async fn refetches_evicted_documents() {
+        let (store, http, _) = store();
+        let store = store.cache_capacity(1);
+        http.respond(200, &[], "a");
+        http.respond(200, &[], "b");
+        http.respond(200, &[], "a");
+        assert_eq!(store.fetch(doc("a")).await.unwrap(), &b"a"[..]);
+        assert_eq!(store.fetch(doc("b")).await.unwrap(), &b"b"[..]);
+        assert_eq!(store.fetch(doc("b")).await.unwrap(), &b"b"[..]);
+        assert_eq!(store.fetch(doc("a")).await.unwrap(), &b"a"[..]);
+        assert_eq!(http.requests.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn backoff_stays_within_limit() {
+        let policy = RetryPolicy::new(10)
+            .initial_backoff(Duration::from_millis(100))
+            .max_backoff(Duration::from_secs(1));
+        let limits = [100, 200, 400, 800, 1000, 1000];
+        for (attempt, limit) in (1..).zip(limits) {
+            for _ in 0..100 {
+                assert!(policy.backoff(attempt) <= Duration::from_millis(limit));
+            }
+        }
+        assert!(policy.backoff(u32::MAX) <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn backoff_is_jittered() {
+        let policy = RetryPolicy::new(2).initial_backoff(Duration::from_secs(1));
+        let first = policy.backoff(1);
+        assert!((0..100).any(|_| policy.backoff(1) != first));
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures() {
+        let (store, http, _) = store();
+        let store = store.retry(RetryPolicy::new(3).initial_backoff(Duration::ZERO));
+        http.respond(503, &[], "");
+        http.respond(502, &[], "");
+        http.respond(200, &[], "{}");
+        assert_eq!(store.fetch(url()).await.unwrap(), &b"{}"[..]);
+        assert_eq!(http.requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_attempts() {
+        let (store, http, _) = store();
+        let store = store.retry(RetryPolicy::new(2).initial_backoff(Duration::ZERO));
+        http.respond(503, &[], "");
+        http.respond(503, &[], "");
+        http.respond(200, &[], "{}");
+        assert!(store.fetch(url()).await.is_err());
+        assert_eq!(http.requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_when_server_sets_retry_after() {
+        let (store, http, _) = store();
+        let store = store.retry(RetryPolicy::new(3).initial_backoff(Duration::ZERO));
+        http.respond(503, &[("retry-after", "5")], "");
+        assert!(store.fetch(url()).await.is_err());
+        assert_eq!(http.requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn revalidates_with_last_modified() {
+        let (store, http, clock) = store();
+        let date = "Wed, 21 Oct 2015 07:28:00 GMT";
+        http.respond(200, &[("last-modified", date)], "{}");
+        store.fetch(url()).await.unwrap();
+        assert!(http.last_header("if-modified-since").is_none());
+
+        clock.advance(MINUTE);
+        http.respond(304, &[], "");
+        assert_eq!(store.fetch(url()).await.unwrap(), &b"{}"[..]);
+        assert_eq!(http.last_header("if-modified-since").unwrap(), date);
+    }
+
+    #[tokio::test]
+    async fn replaces_body_when_modified() {
+        let (store, http, clock) = store();
+        http.respond(200, &[("etag", "\"a\"")], "{}");
+        store.fetch(url()).await.unwrap();
+
+        clock.advance(MINUTE);
+        http.respond(200, &[("etag", "\"b\"")], "[]");
+        assert_eq!(store.fetch(url()).await.unwrap(), &b"[]"[..]);
+        assert_eq!(http.last_header("if-none-match").unwrap(), "\"a\"");
+    }
+
+    #[tokio::test]
+    async fn rejects_unsolicited_not_modified() {
+        let (store, http, _) = store();
+        http.respond(304, &[], "");
+        assert!(store.fetch(url()).await.is_err());
+        assert!(http.last_header("if-none-match").is_none());
+    }
+
+    #[tokio::test]
+    async fn does_not_revalidate_after_error() {
+        let (store, http, clock) = store();
+        http.respond(200, &[("etag", "\"a\"")], "{}");
+        store.fetch(url()).await.unwrap();
+
+        clock.advance(MINUTE);
+        http.respond(404, &[], "");
+        assert!(store.fetch(url()).await.is_err());
+
+        clock.advance(MINUTE);
+        http.respond(200, &[], "{}");
+        store.fetch(url()).await.unwrap();
+        assert!(http.last_header("if-none-match").is_none());
+    }
+
+    fn stale_store() -> (MemoryStore<FakeHttp>, FakeHttp, Arc<ManualClock>) {
+        let (store, http, clock) = store();
+        (store.stale_while_revalidate(MINUTE), http, clock)
+    }
+
+    /// Let background refresh tasks run to completion.
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    fn request_count(http: &FakeHttp) -> usize {
+        http.requests.lock().unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn serves_stale_while_refreshing() {
+        let (store, http, clock) = stale_store();
+        http.respond(200, &[], "a");
+        store.fetch(url()).await.unwrap();
+
+        clock.advance(MINUTE);
+        http.respond(200, &[], "b");
+        assert_eq!(store.fetch(url()).await.unwrap(), &b"a"[..]);
+        assert_eq!(store.fetch(url()).await.unwrap(), &b"a"[..]);
+        settle().await;
+        assert_eq!(store.fetch(url()).await.unwrap(), &b"b"[..]);
+        assert_eq!(request_count(&http), 2);
+    }
+
+    #[tokio::test]
+    async fn refreshes_before_serving_when_too_stale() {
+        let (store, http, clock) = stale_store();
+        http.respond(200, &[], "a");
+        store.fetch(url()).await.unwrap();
+
+        clock.advance(2 * MINUTE);
+        http.respond(200, &[], "b");
+        assert_eq!(store.fetch(url()).await.unwrap(), &b"b"[..]);
+    }
+
+    #[tokio::test]
+    async fn keeps_serving_stale_when_refresh_fails() {
+        let (store, http, clock) = stale_store();
+        http.respond(200, &[], "a");
+        store.fetch(url()).await.unwrap();
+
+        clock.advance(MINUTE);
+        http.respond(500, &[], "");
+        assert_eq!(store.fetch(url()).await.unwrap(), &b"a"[..]);
+        settle().await;
+        assert_eq!(store.fetch(url()).await.unwrap(), &b"a"[..]);
+        settle().await;
+        assert_eq!(request_count(&http), 2);
+
+        clock.advance(Duration::from_secs(3));
+        http.respond(200, &[], "b");
+        assert_eq!(store.fetch(url()).await.unwrap(), &b"a"[..]);
+        settle().await;
+        assert_eq!(store.fetch(url()).await.unwrap(), &b"b"[..]);
+        assert_eq!(request_count(&http), 3);
+    }
 }
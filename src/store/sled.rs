@@ -0,0 +1,456 @@
+use std::{sync::Arc, time::Duration};
+
+use ::sled::{Db, Tree};
+use bytes::Bytes;
+use url::Url;
+
+use crate::misc::{DynErr, DynFut, DynFutRes, DynRes};
+use crate::store::simple::{hex_digest, unix_time, url_hash};
+use crate::{
+    simple_fetch, CachedDocument, Clock, Codec, FetchError, HttpClient, JsonCodec, NonceGenerator,
+    RandomNonces, SessionRecord, Store, SystemClock,
+};
+
+/// A `Store` implementation that persists everything to disk using sled.
+///
+/// This is available with the `sled-store` crate feature. It is meant for single-process
+/// applications that restart frequently, where logins in progress should survive a restart or
+/// deploy. It fits between the `MemoryStore` and a shared store like the `SqlStore`. A sled
+/// database can only be opened by one process at a time, so this store is not suitable for
+/// applications running multiple workers. Values are encoded using the given `Codec`, which
+/// defaults to `JsonCodec`.
+///
+/// Sessions and cached documents are kept in separate trees of the database, and expired entries
+/// are ignored. Expired entries are removed when the store is created, and when calling
+/// `SledStore::purge_expired`. Session writes are flushed to disk before returning.
+pub struct SledStore<C, K = JsonCodec> {
+    db: Db,
+    sessions: Tree,
+    cache: Tree,
+    client: C,
+    codec: Arc<K>,
+    timeout: Duration,
+    session_lifetime: Duration,
+    nonce_generator: Arc<dyn NonceGenerator>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<C> SledStore<C> {
+    /// Create a store using the given sled database and HTTP client.
+    ///
+    /// The default timeout for HTTP requests is 30 seconds, and sessions expire after 15 minutes.
+    /// The store uses the trees `portier:sessions` and `portier:cache`, so the database can be
+    /// shared with the application. Expired entries left from a previous run are removed.
+    pub fn new(db: Db, client: C) -> Result<Self, ::sled::Error> {
+        let store = SledStore {
+            sessions: db.open_tree("portier:sessions")?,
+            cache: db.open_tree("portier:cache")?,
+            db,
+            client,
+            codec: Arc::new(JsonCodec),
+            timeout: Duration::from_secs(30),
+            session_lifetime: Duration::from_secs(15 * 60),
+            nonce_generator: Arc::new(RandomNonces::new()),
+            clock: Arc::new(SystemClock),
+        };
+        store.purge_expired()?;
+        Ok(store)
+    }
+}
+
+impl<C, K> SledStore<C, K> {
+    /// Use the given `Codec` to encode values.
+    ///
+    /// Entries written with a different codec can't be read, so this should not change between
+    /// runs of the application.
+    pub fn codec<K2: Codec>(self, codec: K2) -> SledStore<C, K2> {
+        SledStore {
+            db: self.db,
+            sessions: self.sessions,
+            cache: self.cache,
+            client: self.client,
+            codec: Arc::new(codec),
+            timeout: self.timeout,
+            session_lifetime: self.session_lifetime,
+            nonce_generator: self.nonce_generator,
+            clock: self.clock,
+        }
+    }
+
+    /// Configure the timeout for HTTP requests.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Configure how long a session remains valid after `Store::new_nonce`.
+    ///
+    /// This is overridden by the TTL passed to `Store::new_nonce_with_ttl`.
+    pub fn session_lifetime(mut self, lifetime: Duration) -> Self {
+        self.session_lifetime = lifetime;
+        self
+    }
+
+    /// Use the given `Clock` for expiry, instead of the system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Generate nonces for new sessions using `generator`, instead of `RandomNonces`.
+    pub fn nonce_generator(mut self, generator: Arc<dyn NonceGenerator>) -> Self {
+        self.nonce_generator = generator;
+        self
+    }
+
+    /// The sled database used by this store.
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    /// Remove expired sessions and cached documents, and return how many were removed.
+    ///
+    /// Applications that run for a long time may call this periodically to reclaim disk space.
+    pub fn purge_expired(&self) -> Result<usize, ::sled::Error> {
+        let now = unix_time(&*self.clock) as u64;
+        let mut removed = 0;
+        for tree in [&self.sessions, &self.cache] {
+            for entry in tree.iter() {
+                let (key, value) = entry?;
+                if is_expired(&value, now)
+                    && tree
+                        .compare_and_swap(key, Some(value), None as Option<&[u8]>)?
+                        .is_ok()
+                {
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl<C, K> SledStore<C, K>
+where
+    C: HttpClient + Clone,
+    K: Codec,
+{
+    /// Fetch a document, using the cached result if `use_cache` is set and it is fresh.
+    fn fetch_document(&self, url: Url, use_cache: bool) -> DynFut<Result<Bytes, FetchError>> {
+        let cache = self.cache.clone();
+        let client = self.client.clone();
+        let codec = self.codec.clone();
+        let key = cache_key(&url);
+        let timeout = self.timeout;
+        let clock = self.clock.clone();
+        Box::pin(async move {
+            let now = unix_time(&*clock) as u64;
+            if use_cache {
+                let cached = cache.get(&key).map_err(to_dyn).map_err(FetchError::Store)?;
+                if let Some(cached) = cached.as_deref().and_then(|value| payload(value, now)) {
+                    let doc: CachedDocument = codec.decode(cached).map_err(FetchError::Store)?;
+                    return match doc.data {
+                        Some(data) => Ok(data.into()),
+                        None => Err(FetchError::Fetch(Arc::new(
+                            "fetching the document failed recently".into(),
+                        ))),
+                    };
+                }
+            }
+
+            let (result, max_age) = simple_fetch(&client, timeout, url).await;
+            let doc = CachedDocument {
+                data: result.as_ref().ok().map(|data| data.to_vec()),
+                expires: now.saturating_add(max_age.as_secs()),
+            };
+            let value = codec.encode(&doc).map_err(FetchError::Store)?;
+            cache
+                .insert(key, entry(doc.expires, &value))
+                .map_err(to_dyn)
+                .map_err(FetchError::Store)?;
+            result.map_err(|err| FetchError::Fetch(Arc::new(err)))
+        })
+    }
+
+    /// Store a new session with a random nonce, and return the nonce.
+    fn add_session(&self, record: SessionRecord, ttl: Duration) -> DynFutRes<String> {
+        let sessions = self.sessions.clone();
+        let codec = self.codec.clone();
+        let nonce = self.nonce_generator.generate();
+        let clock = self.clock.clone();
+        Box::pin(async move {
+            let nonce = nonce.await?;
+            add_session(&sessions, &*codec, &*clock, ttl, &nonce, &record).await?;
+            Ok(nonce)
+        })
+    }
+
+    /// Remove a session that has not expired, and return its encoded record.
+    fn take_session(&self, nonce: &str, email: &str) -> DynFutRes<Option<Vec<u8>>> {
+        let sessions = self.sessions.clone();
+        let key = session_key(nonce, email);
+        let clock = self.clock.clone();
+        Box::pin(async move {
+            // Only the remove decides whether the session is consumed, so a concurrent consume
+            // can't return the data twice.
+            let value = sessions.remove(key).map_err(to_dyn)?;
+            sessions.flush_async().await.map_err(to_dyn)?;
+            let now = unix_time(&*clock) as u64;
+            Ok(value
+                .as_deref()
+                .and_then(|value| payload(value, now))
+                .map(<[u8]>::to_vec))
+        })
+    }
+}
+
+impl<C, K> Store for SledStore<C, K>
+where
+    C: HttpClient + Clone,
+    K: Codec,
+{
+    fn fetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        self.fetch_document(url, true)
+    }
+
+    fn refetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        self.fetch_document(url, false)
+    }
+
+    fn cache_lifetime(&self, url: Url) -> DynFutRes<Option<Duration>> {
+        let cache = self.cache.clone();
+        let codec = self.codec.clone();
+        let key = cache_key(&url);
+        let clock = self.clock.clone();
+        Box::pin(async move {
+            let now = unix_time(&*clock) as u64;
+            let cached = cache.get(&key).map_err(to_dyn)?;
+            let lifetime = match cached.as_deref().and_then(|value| payload(value, now)) {
+                Some(cached) => codec.decode::<CachedDocument>(cached)?.expires,
+                None => return Ok(None),
+            };
+            Ok(Some(lifetime.saturating_sub(now))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs))
+        })
+    }
+
+    fn new_nonce(&self, email: String) -> DynFutRes<String> {
+        self.new_nonce_with_ttl(email, self.session_lifetime)
+    }
+
+    fn new_nonce_with_ttl(&self, email: String, ttl: Duration) -> DynFutRes<String> {
+        self.add_session(SessionRecord::new(email), ttl)
+    }
+
+    fn consume_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        let fut = self.take_session(&nonce, &email);
+        Box::pin(async move { Ok(fut.await?.is_some()) })
+    }
+
+    fn new_nonce_with_data(
+        &self,
+        email: String,
+        data: Vec<u8>,
+        ttl: Duration,
+    ) -> DynFutRes<String> {
+        self.add_session(SessionRecord::new(email).data(data), ttl)
+    }
+
+    fn consume_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        let codec = self.codec.clone();
+        let fut = self.take_session(&nonce, &email);
+        Box::pin(async move {
+            match fut.await? {
+                Some(value) => {
+                    let record: SessionRecord = codec.decode(&value)?;
+                    Ok(Some(record.data.unwrap_or_default()))
+                }
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn peek_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        let sessions = self.sessions.clone();
+        let key = session_key(&nonce, &email);
+        let clock = self.clock.clone();
+        Box::pin(async move {
+            let now = unix_time(&*clock) as u64;
+            let value = sessions.get(key).map_err(to_dyn)?;
+            Ok(value
+                .as_deref()
+                .and_then(|value| payload(value, now))
+                .is_some())
+        })
+    }
+
+    fn peek_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        let sessions = self.sessions.clone();
+        let codec = self.codec.clone();
+        let key = session_key(&nonce, &email);
+        let clock = self.clock.clone();
+        Box::pin(async move {
+            let now = unix_time(&*clock) as u64;
+            let value = sessions.get(key).map_err(to_dyn)?;
+            match value.as_deref().and_then(|value| payload(value, now)) {
+                Some(value) => {
+                    let record: SessionRecord = codec.decode(value)?;
+                    Ok(Some(record.data.unwrap_or_default()))
+                }
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn insert_nonce(
+        &self,
+        nonce: String,
+        email: String,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> DynFutRes<()> {
+        let sessions = self.sessions.clone();
+        let codec = self.codec.clone();
+        let ttl = ttl.unwrap_or(self.session_lifetime);
+        let clock = self.clock.clone();
+        Box::pin(async move {
+            let record = SessionRecord::new(email).data(data);
+            add_session(&sessions, &*codec, &*clock, ttl, &nonce, &record).await
+        })
+    }
+
+    fn load_key_pins(&self, origin: String) -> DynFutRes<Option<String>> {
+        let cache = self.cache.clone();
+        let key = key_pins_key(&origin);
+        Box::pin(async move {
+            let value = cache.get(key).map_err(to_dyn)?;
+            match value.as_deref().and_then(|value| payload(value, 0)) {
+                Some(pins) => Ok(Some(String::from_utf8(pins.to_vec())?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn save_key_pins(&self, origin: String, pins: String) -> DynFutRes<()> {
+        let cache = self.cache.clone();
+        let key = key_pins_key(&origin);
+        Box::pin(async move {
+            cache
+                .insert(key, entry(NEVER, pins.as_bytes()))
+                .map_err(to_dyn)?;
+            cache.flush_async().await.map_err(to_dyn)?;
+            Ok(())
+        })
+    }
+
+    fn load_endpoint_state(&self, origin: String) -> DynFutRes<Option<String>> {
+        let cache = self.cache.clone();
+        let key = endpoint_state_key(&origin);
+        let clock = self.clock.clone();
+        Box::pin(async move {
+            let now = unix_time(&*clock) as u64;
+            let value = cache.get(key).map_err(to_dyn)?;
+            match value.as_deref().and_then(|value| payload(value, now)) {
+                Some(state) => Ok(Some(String::from_utf8(state.to_vec())?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn save_endpoint_state(&self, origin: String, state: String, ttl: Duration) -> DynFutRes<()> {
+        let cache = self.cache.clone();
+        let key = endpoint_state_key(&origin);
+        let expires = (unix_time(&*self.clock) as u64).saturating_add(ttl.as_secs().max(1));
+        Box::pin(async move {
+            cache
+                .insert(key, entry(expires, state.as_bytes()))
+                .map_err(to_dyn)?;
+            Ok(())
+        })
+    }
+}
+
+/// Expiry timestamp of entries that never expire.
+const NEVER: u64 = 0;
+
+/// Encode an entry as its expiry timestamp followed by the value.
+fn entry(expires: u64, value: &[u8]) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(8 + value.len());
+    entry.extend_from_slice(&expires.to_be_bytes());
+    entry.extend_from_slice(value);
+    entry
+}
+
+/// Whether an encoded entry has expired, or is malformed.
+fn is_expired(entry: &[u8], now: u64) -> bool {
+    payload(entry, now).is_none()
+}
+
+/// The value of an encoded entry, if it has not expired.
+fn payload(entry: &[u8], now: u64) -> Option<&[u8]> {
+    if entry.len() < 8 {
+        return None;
+    }
+    let (expires, value) = entry.split_at(8);
+    match u64::from_be_bytes(expires.try_into().ok()?) {
+        NEVER => Some(value),
+        expires if expires > now => Some(value),
+        _ => None,
+    }
+}
+
+/// The key for a cached document.
+fn cache_key(url: &Url) -> String {
+    format!("cache:{}", url_hash(url))
+}
+
+/// The key for the key pins of a broker origin. These never expire.
+fn key_pins_key(origin: &str) -> String {
+    format!("pins:{}", hex_digest(origin))
+}
+
+/// The key for the shared state of a broker origin.
+fn endpoint_state_key(origin: &str) -> String {
+    format!("state:{}", hex_digest(origin))
+}
+
+/// The key for a session.
+///
+/// The email is part of the key, so consuming the session is a single atomic remove.
+fn session_key(nonce: &str, email: &str) -> String {
+    format!("session:{}:{}", nonce, hex_digest(email))
+}
+
+/// Store a session, failing if an unexpired session with the same nonce exists.
+async fn add_session<K: Codec>(
+    sessions: &Tree,
+    codec: &K,
+    clock: &dyn Clock,
+    lifetime: Duration,
+    nonce: &str,
+    record: &SessionRecord,
+) -> DynRes<()> {
+    let key = session_key(nonce, &record.email);
+    let now = unix_time(clock) as u64;
+    let value = entry(
+        now.saturating_add(lifetime.as_secs()),
+        &codec.encode(record)?,
+    );
+    // An expired entry may still be present, in which case it is replaced.
+    let current = sessions.get(&key).map_err(to_dyn)?;
+    if current.as_deref().is_some_and(|old| !is_expired(old, now)) {
+        return Err("a session with the same nonce already exists".into());
+    }
+    sessions
+        .compare_and_swap(&key, current, Some(value))
+        .map_err(to_dyn)?
+        .map_err(to_dyn)?;
+    sessions.flush_async().await.map_err(to_dyn)?;
+    Ok(())
+}
+
+/// Box a sled error.
+fn to_dyn<E: std::error::Error + Send + Sync + 'static>(err: E) -> DynErr {
+    Box::new(err)
+}
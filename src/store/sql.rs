@@ -0,0 +1,411 @@
+use std::{sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use sqlx::{Database, Pool};
+use url::Url;
+
+use crate::misc::{DynErr, DynFut, DynFutRes};
+use crate::store::simple::{unix_time, url_hash};
+use crate::{
+    simple_fetch, Clock, FetchError, HttpClient, NonceGenerator, RandomNonces, Store, SystemClock,
+    Unsupported,
+};
+
+/// A `Store` implementation that persists to a relational database using sqlx.
+///
+/// PostgreSQL, MySQL and SQLite are supported, using the `sql-postgres`, `sql-mysql` and
+/// `sql-sqlite` crate features respectively. Sessions and cached documents are kept in two
+/// tables, `portier_sessions` and `portier_cache`. Use `SqlStore::migrate` to create these, or
+/// `SqlStore::schema` to integrate the statements with existing migration tooling.
+///
+/// Documents are fetched using the given `HttpClient` on cache miss. Multiple application
+/// processes can share the same database, so this store is suitable for applications running
+/// multiple workers.
+///
+/// Abandoned logins leave rows behind in `portier_sessions`. Call `SqlStore::purge_expired`
+/// periodically to clean these up, along with expired cache entries.
+pub struct SqlStore<DB: Database, C> {
+    pool: Pool<DB>,
+    client: C,
+    timeout: Duration,
+    nonce_generator: Arc<dyn NonceGenerator>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<DB: Database, C> SqlStore<DB, C> {
+    /// Create a store using the given connection pool and HTTP client.
+    ///
+    /// The default timeout for HTTP requests is 30 seconds.
+    pub fn new(pool: Pool<DB>, client: C) -> Self {
+        SqlStore {
+            pool,
+            client,
+            timeout: Duration::from_secs(30),
+            nonce_generator: Arc::new(RandomNonces::new()),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Configure the timeout for HTTP requests.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Use the given `Clock` for cache expiry, instead of the system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Generate nonces for new sessions using `generator`, instead of `RandomNonces`.
+    pub fn nonce_generator(mut self, generator: Arc<dyn NonceGenerator>) -> Self {
+        self.nonce_generator = generator;
+        self
+    }
+
+    /// The connection pool used by this store.
+    pub fn pool(&self) -> &Pool<DB> {
+        &self.pool
+    }
+}
+
+/// SQL statements for a specific database.
+struct Queries {
+    schema: &'static [&'static str],
+    select_cache: &'static str,
+    upsert_cache: &'static str,
+    insert_session: &'static str,
+    delete_session: &'static str,
+    select_session: &'static str,
+    purge_cache: &'static str,
+    purge_sessions: &'static str,
+}
+
+#[cfg(feature = "sql-postgres")]
+const POSTGRES: Queries = Queries {
+    schema: &[
+        "CREATE TABLE IF NOT EXISTS portier_sessions (
+            nonce VARCHAR(255) NOT NULL,
+            email VARCHAR(255) NOT NULL,
+            created BIGINT NOT NULL,
+            expires BIGINT,
+            PRIMARY KEY (nonce, email)
+        )",
+        "CREATE TABLE IF NOT EXISTS portier_cache (
+            url_hash CHAR(64) NOT NULL PRIMARY KEY,
+            data BYTEA,
+            error TEXT,
+            expires BIGINT NOT NULL
+        )",
+    ],
+    select_cache: "SELECT data, error, expires FROM portier_cache WHERE url_hash = $1",
+    upsert_cache: "INSERT INTO portier_cache (url_hash, data, error, expires)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (url_hash) DO UPDATE
+        SET data = excluded.data, error = excluded.error, expires = excluded.expires",
+    insert_session: "INSERT INTO portier_sessions (nonce, email, created, expires)
+        VALUES ($1, $2, $3, $4)",
+    delete_session: "DELETE FROM portier_sessions
+        WHERE nonce = $1 AND email = $2 AND (expires IS NULL OR expires > $3)",
+    select_session: "SELECT 1 FROM portier_sessions
+        WHERE nonce = $1 AND email = $2 AND (expires IS NULL OR expires > $3)",
+    purge_cache: "DELETE FROM portier_cache WHERE expires <= $1",
+    purge_sessions: "DELETE FROM portier_sessions WHERE created <= $1 OR expires <= $2",
+};
+
+#[cfg(feature = "sql-mysql")]
+const MYSQL: Queries = Queries {
+    schema: &[
+        "CREATE TABLE IF NOT EXISTS portier_sessions (
+            nonce VARCHAR(255) NOT NULL,
+            email VARCHAR(255) NOT NULL,
+            created BIGINT NOT NULL,
+            expires BIGINT,
+            PRIMARY KEY (nonce, email)
+        )",
+        "CREATE TABLE IF NOT EXISTS portier_cache (
+            url_hash CHAR(64) NOT NULL PRIMARY KEY,
+            data LONGBLOB,
+            error TEXT,
+            expires BIGINT NOT NULL
+        )",
+    ],
+    select_cache: "SELECT data, error, expires FROM portier_cache WHERE url_hash = ?",
+    upsert_cache: "INSERT INTO portier_cache (url_hash, data, error, expires)
+        VALUES (?, ?, ?, ?)
+        ON DUPLICATE KEY UPDATE
+        data = VALUES(data), error = VALUES(error), expires = VALUES(expires)",
+    insert_session: "INSERT INTO portier_sessions (nonce, email, created, expires)
+        VALUES (?, ?, ?, ?)",
+    delete_session: "DELETE FROM portier_sessions
+        WHERE nonce = ? AND email = ? AND (expires IS NULL OR expires > ?)",
+    select_session: "SELECT 1 FROM portier_sessions
+        WHERE nonce = ? AND email = ? AND (expires IS NULL OR expires > ?)",
+    purge_cache: "DELETE FROM portier_cache WHERE expires <= ?",
+    purge_sessions: "DELETE FROM portier_sessions WHERE created <= ? OR expires <= ?",
+};
+
+#[cfg(feature = "sql-sqlite")]
+const SQLITE: Queries = Queries {
+    schema: &[
+        "CREATE TABLE IF NOT EXISTS portier_sessions (
+            nonce TEXT NOT NULL,
+            email TEXT NOT NULL,
+            created INTEGER NOT NULL,
+            expires INTEGER,
+            PRIMARY KEY (nonce, email)
+        )",
+        "CREATE TABLE IF NOT EXISTS portier_cache (
+            url_hash TEXT NOT NULL PRIMARY KEY,
+            data BLOB,
+            error TEXT,
+            expires INTEGER NOT NULL
+        )",
+    ],
+    select_cache: "SELECT data, error, expires FROM portier_cache WHERE url_hash = ?",
+    upsert_cache: "INSERT INTO portier_cache (url_hash, data, error, expires)
+        VALUES (?, ?, ?, ?)
+        ON CONFLICT (url_hash) DO UPDATE
+        SET data = excluded.data, error = excluded.error, expires = excluded.expires",
+    insert_session: "INSERT INTO portier_sessions (nonce, email, created, expires)
+        VALUES (?, ?, ?, ?)",
+    delete_session: "DELETE FROM portier_sessions
+        WHERE nonce = ? AND email = ? AND (expires IS NULL OR expires > ?)",
+    select_session: "SELECT 1 FROM portier_sessions
+        WHERE nonce = ? AND email = ? AND (expires IS NULL OR expires > ?)",
+    purge_cache: "DELETE FROM portier_cache WHERE expires <= ?",
+    purge_sessions: "DELETE FROM portier_sessions WHERE created <= ? OR expires <= ?",
+};
+
+/// Implements the database-specific parts of `SqlStore`.
+///
+/// This is a macro rather than a generic implementation, because the sqlx trait bounds required
+/// to do this generically are not worth the trouble for three databases.
+macro_rules! impl_sql_store {
+    ($feature:literal, $db:ty, $queries:ident) => {
+        #[cfg(feature = $feature)]
+        impl<C> SqlStore<$db, C> {
+            /// The SQL statements that create the tables used by this store.
+            pub fn schema() -> &'static [&'static str] {
+                $queries.schema
+            }
+
+            /// Create the tables used by this store, if they don't exist.
+            pub async fn migrate(&self) -> Result<(), sqlx::Error> {
+                for statement in $queries.schema {
+                    sqlx::query(statement).execute(&self.pool).await?;
+                }
+                Ok(())
+            }
+
+            /// Delete expired cache entries and sessions, and sessions older than `session_lifetime`.
+            pub async fn purge_expired(
+                &self,
+                session_lifetime: Duration,
+            ) -> Result<(), sqlx::Error> {
+                let now = unix_time(&*self.clock);
+                sqlx::query($queries.purge_cache)
+                    .bind(now)
+                    .execute(&self.pool)
+                    .await?;
+                sqlx::query($queries.purge_sessions)
+                    .bind(now.saturating_sub(session_lifetime.as_secs() as i64))
+                    .bind(now)
+                    .execute(&self.pool)
+                    .await?;
+                Ok(())
+            }
+        }
+
+        #[cfg(feature = $feature)]
+        impl<C> SqlStore<$db, C>
+        where
+            C: HttpClient + Clone,
+        {
+            /// Generate a nonce and store the pair, optionally expiring after `ttl`.
+            fn create_nonce(&self, email: String, ttl: Option<Duration>) -> DynFutRes<String> {
+                let pool = self.pool.clone();
+                let nonce = self.nonce_generator.generate();
+                let clock = self.clock.clone();
+                Box::pin(async move {
+                    let nonce = nonce.await?;
+                    Self::insert_session(&pool, &*clock, nonce.clone(), email, ttl).await?;
+                    Ok(nonce)
+                })
+            }
+
+            /// Store a nonce/email pair, optionally expiring after `ttl`.
+            async fn insert_session(
+                pool: &Pool<$db>,
+                clock: &dyn Clock,
+                nonce: String,
+                email: String,
+                ttl: Option<Duration>,
+            ) -> Result<(), DynErr> {
+                let now = unix_time(clock);
+                let expires = ttl.map(|ttl| now.saturating_add(ttl.as_secs() as i64));
+                sqlx::query($queries.insert_session)
+                    .bind(nonce)
+                    .bind(email)
+                    .bind(now)
+                    .bind(expires)
+                    .execute(pool)
+                    .await
+                    .map_err(|err| Box::new(err) as DynErr)?;
+                Ok(())
+            }
+
+            /// Fetch a document, using the cached result if `use_cache` is set and it is fresh.
+            fn fetch_document(
+                &self,
+                url: Url,
+                use_cache: bool,
+            ) -> DynFut<Result<Bytes, FetchError>> {
+                let pool = self.pool.clone();
+                let client = self.client.clone();
+                let timeout = self.timeout;
+                let clock = self.clock.clone();
+                Box::pin(async move {
+                    let key = url_hash(&url);
+                    let now = unix_time(&*clock);
+                    if use_cache {
+                        let row: Option<(Option<Vec<u8>>, Option<String>, i64)> =
+                            sqlx::query_as($queries.select_cache)
+                                .bind(&key)
+                                .fetch_optional(&pool)
+                                .await
+                                .map_err(|err| FetchError::Store(Box::new(err)))?;
+                        if let Some((data, error, expires)) = row {
+                            if now < expires {
+                                return match data {
+                                    Some(data) => Ok(data.into()),
+                                    None => Err(FetchError::Fetch(Arc::new(
+                                        error.unwrap_or_default().into(),
+                                    ))),
+                                };
+                            }
+                        }
+                    }
+
+                    let (result, max_age) = simple_fetch(&client, timeout, url).await;
+                    let (data, error) = match result {
+                        Ok(ref data) => (Some(data.to_vec()), None),
+                        Err(ref err) => (None, Some(err.to_string())),
+                    };
+                    sqlx::query($queries.upsert_cache)
+                        .bind(&key)
+                        .bind(data)
+                        .bind(error)
+                        .bind(now.saturating_add(max_age.as_secs() as i64))
+                        .execute(&pool)
+                        .await
+                        .map_err(|err| FetchError::Store(Box::new(err)))?;
+                    result.map_err(|err| FetchError::Fetch(Arc::new(err)))
+                })
+            }
+        }
+
+        #[cfg(feature = $feature)]
+        impl<C> Store for SqlStore<$db, C>
+        where
+            C: HttpClient + Clone,
+        {
+            fn fetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+                self.fetch_document(url, true)
+            }
+
+            fn refetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+                self.fetch_document(url, false)
+            }
+
+            fn cache_lifetime(&self, url: Url) -> DynFutRes<Option<Duration>> {
+                let pool = self.pool.clone();
+                let clock = self.clock.clone();
+                Box::pin(async move {
+                    let now = unix_time(&*clock);
+                    let row: Option<(Option<Vec<u8>>, Option<String>, i64)> =
+                        sqlx::query_as($queries.select_cache)
+                            .bind(url_hash(&url))
+                            .fetch_optional(&pool)
+                            .await?;
+                    Ok(row
+                        .map(|(_, _, expires)| expires.saturating_sub(now))
+                        .filter(|secs| *secs > 0)
+                        .map(|secs| Duration::from_secs(secs as u64)))
+                })
+            }
+
+            fn new_nonce(&self, email: String) -> DynFutRes<String> {
+                self.create_nonce(email, None)
+            }
+
+            fn new_nonce_with_ttl(&self, email: String, ttl: Duration) -> DynFutRes<String> {
+                self.create_nonce(email, Some(ttl))
+            }
+
+            fn consume_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+                let pool = self.pool.clone();
+                let clock = self.clock.clone();
+                Box::pin(async move {
+                    let result = sqlx::query($queries.delete_session)
+                        .bind(nonce)
+                        .bind(email)
+                        .bind(unix_time(&*clock))
+                        .execute(&pool)
+                        .await
+                        .map_err(|err| Box::new(err) as DynErr)?;
+                    Ok(result.rows_affected() > 0)
+                })
+            }
+
+            fn peek_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+                let pool = self.pool.clone();
+                let clock = self.clock.clone();
+                Box::pin(async move {
+                    let row = sqlx::query($queries.select_session)
+                        .bind(nonce)
+                        .bind(email)
+                        .bind(unix_time(&*clock))
+                        .fetch_optional(&pool)
+                        .await
+                        .map_err(|err| Box::new(err) as DynErr)?;
+                    Ok(row.is_some())
+                })
+            }
+
+            fn peek_nonce_with_data(
+                &self,
+                nonce: String,
+                email: String,
+            ) -> DynFutRes<Option<Vec<u8>>> {
+                // This store does not keep session data, like `Store::consume_nonce_with_data`.
+                let peek = self.peek_nonce(nonce, email);
+                Box::pin(async move { Ok(peek.await?.then(Vec::new)) })
+            }
+
+            fn insert_nonce(
+                &self,
+                nonce: String,
+                email: String,
+                data: Vec<u8>,
+                ttl: Option<Duration>,
+            ) -> DynFutRes<()> {
+                // This store does not keep session data, see `Store::new_nonce_with_data`.
+                if !data.is_empty() {
+                    let err = Unsupported("new_nonce_with_data");
+                    return Box::pin(async { Err(Box::new(err) as DynErr) });
+                }
+                let pool = self.pool.clone();
+                let clock = self.clock.clone();
+                Box::pin(
+                    async move { Self::insert_session(&pool, &*clock, nonce, email, ttl).await },
+                )
+            }
+        }
+    };
+}
+
+impl_sql_store!("sql-postgres", sqlx::Postgres, POSTGRES);
+impl_sql_store!("sql-mysql", sqlx::MySql, MYSQL);
+impl_sql_store!("sql-sqlite", sqlx::Sqlite, SQLITE);
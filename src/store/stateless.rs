@@ -0,0 +1,232 @@
+use std::{convert::TryInto, sync::Arc, time::Duration};
+
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+
+use super::nonces::system_random;
+use crate::misc::{base64url, wipe, DynErr, DynFutRes};
+use crate::{clock, Clock, SessionStore, SystemClock};
+
+/// Length of the random part of a nonce.
+const RANDOM_LEN: usize = 16;
+
+/// Length of the expiry time in a nonce, a big-endian Unix timestamp.
+const EXPIRES_LEN: usize = 8;
+
+/// Length of the signature at the end of a nonce.
+const TAG_LEN: usize = 32;
+
+/// A `SessionStore` that keeps no server-side state, using signed nonces.
+///
+/// The nonce contains its expiry time, and is signed using HMAC-SHA256 together with the email
+/// address, using a secret key. This allows deployments that can't run a shared store, such as
+/// serverless functions and edge workers, to verify the session without any storage. All
+/// instances must use the same secret. Use `Builder::session_secret` to configure a `Client`
+/// with this session store.
+///
+/// The tradeoff is that sessions can't be consumed: a token can be verified again until the nonce
+/// expires. Keep the session lifetime short, and use `Builder::session_ttl` to configure it. If
+/// an attacker is able to capture a token, they can replay it within this window.
+///
+/// Session data is stored in the nonce itself. It is signed, but not encrypted, so is visible to
+/// the user agent and broker, and adds to the length of the authentication URL.
+///
+/// Rotating the secret invalidates all logins in progress.
+pub struct StatelessSessions {
+    key: hmac::Key,
+    rng: SystemRandom,
+    clock: Arc<dyn Clock>,
+    lifetime: Duration,
+}
+
+impl StatelessSessions {
+    /// Create a session store that signs nonces using `secret`.
+    ///
+    /// The secret should be at least 32 random bytes. Nonces created using
+    /// `SessionStore::new_nonce` without a TTL expire after 15 minutes.
+    pub fn new(secret: &[u8]) -> Self {
+        StatelessSessions {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            rng: system_random(),
+            clock: Arc::new(SystemClock),
+            lifetime: Duration::from_secs(15 * 60),
+        }
+    }
+
+    /// Use the given `Clock` for session expiry, instead of the system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Configure the lifetime of nonces created without a TTL. The default is 15 minutes.
+    pub fn lifetime(mut self, dur: Duration) -> Self {
+        self.lifetime = dur;
+        self
+    }
+
+    /// The current time as a Unix timestamp, or an error if the clock is not reliable.
+    ///
+    /// Nonce expiry depends on the clock, so sessions are refused instead of expiring at the
+    /// wrong time. See `Clock::is_reliable`.
+    fn now(&self) -> Result<u64, DynErr> {
+        clock::unix_now(&*self.clock).ok_or_else(|| "the system clock is not reliable".into())
+    }
+
+    /// Create a signed nonce for `email` carrying `session_data`, that expires after `ttl`.
+    fn sign(&self, email: &str, session_data: &[u8], ttl: Duration) -> Result<String, DynErr> {
+        let now = self.now()?;
+        let mut data = vec![0; RANDOM_LEN + EXPIRES_LEN];
+        self.rng
+            .fill(&mut data[..RANDOM_LEN])
+            .expect("secure random number generator failed");
+        let expires = now.saturating_add(ttl.as_secs());
+        data[RANDOM_LEN..].copy_from_slice(&expires.to_be_bytes());
+        data.extend_from_slice(session_data);
+
+        let tag = hmac::sign(&self.key, &signed_message(&data, email));
+        data.extend_from_slice(tag.as_ref());
+        let nonce = base64url::encode(&data);
+        wipe(&mut data);
+        Ok(nonce)
+    }
+
+    /// Check the signature and expiry of `nonce` for `email`, and return the session data.
+    fn check(&self, nonce: &str, email: &str) -> Result<Option<Vec<u8>>, DynErr> {
+        let now = self.now()?;
+        let Ok(mut nonce) = base64url::decode(nonce) else {
+            return Ok(None);
+        };
+        let result = self.check_decoded(&nonce, email, now);
+        wipe(&mut nonce);
+        Ok(result)
+    }
+
+    /// Like `StatelessSessions::check`, for a decoded nonce.
+    fn check_decoded(&self, nonce: &[u8], email: &str, now: u64) -> Option<Vec<u8>> {
+        if nonce.len() < RANDOM_LEN + EXPIRES_LEN + TAG_LEN {
+            return None;
+        }
+        let (data, tag) = nonce.split_at(nonce.len() - TAG_LEN);
+        hmac::verify(&self.key, &signed_message(data, email), tag).ok()?;
+        let expires: [u8; EXPIRES_LEN] = data[RANDOM_LEN..RANDOM_LEN + EXPIRES_LEN]
+            .try_into()
+            .unwrap();
+        if now >= u64::from_be_bytes(expires) {
+            return None;
+        }
+        Some(data[RANDOM_LEN + EXPIRES_LEN..].to_vec())
+    }
+}
+
+impl SessionStore for StatelessSessions {
+    fn new_nonce(&self, email: String) -> DynFutRes<String> {
+        self.new_nonce_with_ttl(email, self.lifetime)
+    }
+
+    fn new_nonce_with_ttl(&self, email: String, ttl: Duration) -> DynFutRes<String> {
+        let nonce = self.sign(&email, &[], ttl);
+        Box::pin(async move { nonce })
+    }
+
+    fn consume_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        let res = self.check(&nonce, &email).map(|data| data.is_some());
+        Box::pin(async move { res })
+    }
+
+    fn new_nonce_with_data(
+        &self,
+        email: String,
+        data: Vec<u8>,
+        ttl: Duration,
+    ) -> DynFutRes<String> {
+        let nonce = self.sign(&email, &data, ttl);
+        Box::pin(async move { nonce })
+    }
+
+    fn consume_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        let res = self.check(&nonce, &email);
+        Box::pin(async move { res })
+    }
+
+    fn peek_nonce(&self, nonce: String, email: String) -> DynFutRes<bool> {
+        self.consume_nonce(nonce, email)
+    }
+
+    fn peek_nonce_with_data(&self, nonce: String, email: String) -> DynFutRes<Option<Vec<u8>>> {
+        self.consume_nonce_with_data(nonce, email)
+    }
+}
+
+/// The message signed for a nonce: the length-prefixed nonce data followed by the email address.
+///
+/// The length prefix ensures bytes can't move between the session data and the email address.
+fn signed_message(data: &[u8], email: &str) -> Vec<u8> {
+    let mut message = Vec::with_capacity(8 + data.len() + email.len());
+    message.extend_from_slice(&(data.len() as u64).to_be_bytes());
+    message.extend_from_slice(data);
+    message.extend_from_slice(email.as_bytes());
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::ManualClock;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn sessions() -> (StatelessSessions, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new(SystemTime::now()));
+        (StatelessSessions::new(SECRET).clock(clock.clone()), clock)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (sessions, _) = sessions();
+        let nonce = sessions.sign("a@example.com", b"data", TTL).unwrap();
+        let data = sessions.check(&nonce, "a@example.com").unwrap();
+        assert_eq!(data.as_deref(), Some(&b"data"[..]));
+    }
+
+    #[test]
+    fn rejects_tampered_nonce() {
+        let (sessions, _) = sessions();
+        let nonce = sessions.sign("a@example.com", b"data", TTL).unwrap();
+        let mut decoded = base64url::decode(&nonce).unwrap();
+        decoded[RANDOM_LEN + EXPIRES_LEN] ^= 1;
+        let tampered = base64url::encode(&decoded);
+        assert_eq!(sessions.check(&tampered, "a@example.com").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_expired_nonce() {
+        let (sessions, clock) = sessions();
+        let nonce = sessions.sign("a@example.com", b"", TTL).unwrap();
+        clock.advance(TTL);
+        assert_eq!(sessions.check(&nonce, "a@example.com").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_wrong_email() {
+        let (sessions, _) = sessions();
+        let nonce = sessions.sign("a@example.com", b"", TTL).unwrap();
+        assert_eq!(sessions.check(&nonce, "b@example.com").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_moved_boundary() {
+        let (sessions, _) = sessions();
+        let nonce = sessions.sign("a@example.com", b"data", TTL).unwrap();
+        // Move the first byte of the email address into the session data.
+        let mut decoded = base64url::decode(&nonce).unwrap();
+        decoded.insert(decoded.len() - TAG_LEN, b'a');
+        let moved = base64url::encode(&decoded);
+        assert_eq!(sessions.check(&moved, "@example.com").unwrap(), None);
+    }
+}
@@ -0,0 +1,286 @@
+//! Utilities for testing applications that use this crate.
+//!
+//! A `MockBroker` serves discovery and keys documents from an in-process HTTP server, and issues
+//! tokens for login sessions started by a `Client`. The tokens are signed by a `TokenMint`, which
+//! can also be used on its own to sign arbitrary claims, for example to test expired tokens or
+//! tokens with a bad signature. Together, these allow application test suites to exercise
+//! `Client::verify` end-to-end, without running a real broker.
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use portier::{test_utils::MockBroker, Client};
+//!
+//! let broker = MockBroker::start().await?;
+//! let client = Client::builder("http://localhost:8000/verify".parse().unwrap())
+//!     .broker(broker.url().clone())
+//!     .build()
+//!     .unwrap();
+//!
+//! let auth_url = client.start_auth("user@example.com").await.unwrap();
+//! let token = broker.login(&auth_url).unwrap();
+//! assert_eq!(client.verify(&token).await.unwrap(), "user@example.com");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! This module is only available with the `test-utils` feature, and is not intended for use in
+//! production.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    io,
+    net::TcpListener,
+    sync::{Arc, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Response, Server, StatusCode,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+use url::Url;
+
+use crate::{
+    jwk::KeySet,
+    jws::{self, KeyPair},
+    misc::base64url,
+};
+
+/// Lifetime of tokens issued by `MockBroker::login`, in seconds.
+const TOKEN_LIFETIME: u64 = 10 * 60;
+
+/// Signs tokens with a generated Ed25519 key.
+pub struct TokenMint {
+    key: KeyPair,
+}
+
+impl TokenMint {
+    /// Create a mint with a new key, and a random key ID.
+    pub fn new() -> Self {
+        let mut kid = [0; 8];
+        SystemRandom::new()
+            .fill(&mut kid)
+            .expect("secure random number generator failed");
+        Self::with_kid(base64url::encode(&kid))
+    }
+
+    /// Create a mint with a new key, and the given key ID.
+    ///
+    /// Tokens signed by two mints with the same key ID, but different keys, can be used to test
+    /// tokens with a bad signature.
+    pub fn with_kid(kid: impl Into<String>) -> Self {
+        let key = KeyPair::generate_ed25519(kid).expect("could not generate key");
+        TokenMint { key }
+    }
+
+    /// The key ID.
+    pub fn kid(&self) -> &str {
+        self.key.kid()
+    }
+
+    /// The public key, as a JWK.
+    pub fn jwk(&self) -> Value {
+        serde_json::to_value(self.key.public_jwk()).expect("could not serialize key")
+    }
+
+    /// The public key, as a keys document for use with `Validator`.
+    pub fn key_set(&self) -> KeySet {
+        KeySet {
+            keys: vec![self.key.public_jwk()],
+        }
+    }
+
+    /// Sign `claims`, and return the token.
+    ///
+    /// The claims are not checked, so this can be used to create invalid tokens. See `claims` for
+    /// a valid set of claims to start from.
+    pub fn sign(&self, claims: &Value) -> String {
+        jws::sign(claims.to_string().as_bytes(), &self.key).expect("could not sign token")
+    }
+}
+
+impl Default for TokenMint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Create valid claims for a token, issued now and expiring in 10 minutes.
+///
+/// The result can be modified before signing it using `TokenMint::sign`, for example to set an
+/// `exp` in the past.
+pub fn claims(issuer: &str, audience: &str, email: &str, nonce: &str) -> Value {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("current system time is before Unix epoch")
+        .as_secs();
+    json!({
+        "iss": issuer,
+        "aud": audience,
+        "email": email,
+        "email_original": email,
+        "iat": now,
+        "exp": now + TOKEN_LIFETIME,
+        "nonce": nonce,
+    })
+}
+
+/// A broker that serves its documents from an in-process HTTP server.
+///
+/// The server listens on a random port on the loopback interface, and stops when the broker is
+/// dropped. It must be started from within a Tokio runtime.
+///
+/// The same server can act as an untrusted identity provider, for testing broker implementations
+/// that configure their `Client` using `Builder::idp`.
+pub struct MockBroker {
+    url: Url,
+    mint: TokenMint,
+    documents: Documents,
+    task: JoinHandle<()>,
+}
+
+/// Documents served by a `MockBroker`, by path.
+type Documents = Arc<RwLock<HashMap<&'static str, String>>>;
+
+impl MockBroker {
+    /// Start a broker that signs tokens with a new `TokenMint`.
+    pub async fn start() -> io::Result<Self> {
+        Self::with_mint(TokenMint::new()).await
+    }
+
+    /// Start a broker that signs tokens with `mint`.
+    pub async fn with_mint(mint: TokenMint) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        let url: Url = format!("http://{}", listener.local_addr()?)
+            .parse()
+            .expect("could not parse broker URL");
+
+        let origin = url.origin().ascii_serialization();
+        let discovery = json!({
+            "issuer": origin,
+            "jwks_uri": format!("{}/jwks.json", origin),
+            "authorization_endpoint": format!("{}/auth", origin),
+            "response_modes_supported": ["form_post", "fragment"],
+        });
+        let jwks = json!({ "keys": [mint.jwk()] });
+        let documents: Documents = Arc::new(RwLock::new(HashMap::from([
+            ("/.well-known/openid-configuration", discovery.to_string()),
+            ("/jwks.json", jwks.to_string()),
+        ])));
+
+        let served = documents.clone();
+        let server = Server::from_tcp(listener)
+            .map_err(io::Error::other)?
+            .serve(make_service_fn(move |_| {
+                let documents = served.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        let documents = documents.read().unwrap();
+                        let response = match documents.get(req.uri().path()) {
+                            Some(body) => Response::builder()
+                                .header(header::CONTENT_TYPE, "application/json")
+                                .body(Body::from(body.clone())),
+                            None => Response::builder()
+                                .status(StatusCode::NOT_FOUND)
+                                .body(Body::empty()),
+                        };
+                        async move { response }
+                    }))
+                }
+            }));
+        let task = tokio::spawn(async move {
+            let _ = server.await;
+        });
+
+        Ok(MockBroker {
+            url,
+            mint,
+            documents,
+            task,
+        })
+    }
+
+    /// Replace the signing key with `mint`, and serve only its key from now on.
+    ///
+    /// Clients that cached the keys document find the new key by refetching it, which they do at
+    /// most once a minute per broker.
+    pub fn rotate_key(&mut self, mint: TokenMint) {
+        let jwks = json!({ "keys": [mint.jwk()] });
+        self.documents
+            .write()
+            .unwrap()
+            .insert("/jwks.json", jwks.to_string());
+        self.mint = mint;
+    }
+
+    /// The URL of the broker, for use with `Builder::broker`.
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// The mint used to sign tokens.
+    pub fn mint(&self) -> &TokenMint {
+        &self.mint
+    }
+
+    /// Create valid claims for the login started with `auth_url`, as returned by
+    /// `Client::start_auth`.
+    ///
+    /// Returns `None` if the URL is missing parameters.
+    pub fn claims_for(&self, auth_url: &Url) -> Option<Value> {
+        let param = |name: &str| {
+            auth_url
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+        Some(claims(
+            &self.url.origin().ascii_serialization(),
+            &param("client_id")?,
+            &param("login_hint")?,
+            &param("nonce")?,
+        ))
+    }
+
+    /// Create claims for the login started with `auth_url`, where the broker changed the email
+    /// address to `email`.
+    ///
+    /// The `email_original` claim is the address the login was started with. Brokers may do this
+    /// to normalize the address, but untrusted identity providers may not, so a `Client`
+    /// configured using `Builder::idp` rejects these claims with
+    /// `VerifyError::UntrustedServerChangedEmail`.
+    ///
+    /// Returns `None` if the URL is missing parameters.
+    pub fn claims_as(&self, auth_url: &Url, email: &str) -> Option<Value> {
+        let mut claims = self.claims_for(auth_url)?;
+        claims["email"] = email.into();
+        Some(claims)
+    }
+
+    /// Complete the login started with `auth_url`, and return a valid token.
+    ///
+    /// Returns `None` if the URL is missing parameters.
+    pub fn login(&self, auth_url: &Url) -> Option<String> {
+        Some(self.mint.sign(&self.claims_for(auth_url)?))
+    }
+
+    /// Complete the login started with `auth_url` as `email`, and return the token. See
+    /// `MockBroker::claims_as`.
+    ///
+    /// Returns `None` if the URL is missing parameters.
+    pub fn login_as(&self, auth_url: &Url, email: &str) -> Option<String> {
+        Some(self.mint.sign(&self.claims_as(auth_url, email)?))
+    }
+}
+
+impl Drop for MockBroker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,719 @@
+use ring::digest;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    clock,
+    jwk::KeySet,
+    jws,
+    misc::{self, base64url},
+    Clock, SystemClock, VerifyError,
+};
+
+/// The claims of a token that passed validation.
+///
+/// Claims not known to this crate are preserved in `extra`, so that claims added by brokers in the
+/// future are accessible without waiting for a crate release. Known claims may be added as fields
+/// in minor releases, which is why this struct is marked non-exhaustive.
+#[derive(Clone, Debug, Deserialize)]
+#[non_exhaustive]
+pub struct Claims {
+    /// The issuer of the token, an origin.
+    pub iss: String,
+    /// The audience of the token, an origin.
+    pub aud: String,
+    /// The verified (normalized) email address.
+    pub email: String,
+    /// The email address as originally entered by the user, if it differs.
+    pub email_original: Option<String>,
+    /// The subject of the token, which Portier brokers set to the verified email address.
+    pub sub: Option<String>,
+    /// Unix timestamp at which the token was issued.
+    #[serde(deserialize_with = "misc::deserialize_timestamp")]
+    pub iat: u64,
+    /// Unix timestamp at which the token expires.
+    #[serde(deserialize_with = "misc::deserialize_timestamp")]
+    pub exp: u64,
+    /// The nonce of the login session.
+    pub nonce: String,
+    /// Hash of the access token returned alongside the token, if any.
+    pub at_hash: Option<String>,
+    /// Hash of the authorization code returned alongside the token, if any.
+    pub c_hash: Option<String>,
+    /// Any additional claims in the token.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// A token that passed validation, with all of its claims and the raw payload.
+///
+/// Returned by `Client::verify_full` and `Validator::verify_full`.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct VerifiedToken {
+    /// The validated claims.
+    pub claims: Claims,
+    /// The raw JSON payload of the token.
+    pub payload: Vec<u8>,
+    /// Application data attached to the login session using `AuthOptions::session_data`.
+    ///
+    /// This is only set by `Client::verify_full`, and is empty if no data was attached.
+    pub session_data: Vec<u8>,
+    /// The correlation ID of the login, set using `AuthOptions::correlation_id` or generated when
+    /// the login started. See `LoginEvent::correlation_id`.
+    ///
+    /// This is only set by `Client::verify_full`, and is `None` when using a `Validator` directly.
+    pub correlation_id: Option<String>,
+    /// The `alg` from the token header, used to verify `at_hash` and `c_hash`.
+    alg: Option<String>,
+}
+
+impl VerifiedToken {
+    /// The verified (normalized) email address.
+    pub fn email(&self) -> &str {
+        &self.claims.email
+    }
+
+    /// The email address as originally entered by the user.
+    ///
+    /// This is the same as `VerifiedToken::email` if the broker did not provide it.
+    pub fn email_original(&self) -> &str {
+        self.claims
+            .email_original
+            .as_deref()
+            .unwrap_or(&self.claims.email)
+    }
+
+    /// The email address with the capitalization entered by the user, for display.
+    ///
+    /// This is `VerifiedToken::email_original` if it only differs from the normalized address in
+    /// case, or, with the `client` feature, in the encoding of the domain. See `normalize_email`.
+    /// If the broker changed the address in other ways, the normalized address is returned
+    /// instead, so the result always refers to the same mailbox. See `EmailCase`.
+    pub fn display_email(&self) -> &str {
+        let original = self.email_original();
+        if same_mailbox(original, &self.claims.email) {
+            original
+        } else {
+            &self.claims.email
+        }
+    }
+
+    /// The email address according to `case`.
+    pub fn email_with_case(&self, case: EmailCase) -> &str {
+        match case {
+            EmailCase::Normalized => self.email(),
+            EmailCase::Preserve => self.display_email(),
+        }
+    }
+
+    /// Deserialize the raw payload into an application type, to access custom claims.
+    ///
+    /// The type can pick the claims it needs, such as a `preferred_username` added by a custom
+    /// identity provider. Validation of the known claims is unaffected.
+    pub fn claims_as<T: DeserializeOwned>(&self) -> Result<T, VerifyError> {
+        serde_json::from_slice(&self.payload).map_err(VerifyError::InvalidPayload)
+    }
+
+    /// The time at which the token was issued.
+    pub fn issued_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.claims.iat)
+    }
+
+    /// The time at which the token expires.
+    pub fn expires_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.claims.exp)
+    }
+
+    /// Verify the `at_hash` claim against the access token returned alongside the token.
+    ///
+    /// This is only relevant to flows that return an access token together with the ID token, and
+    /// ensures the two can't be spliced across responses. The claim must be present.
+    pub fn verify_at_hash(&self, access_token: &str) -> Result<(), VerifyError> {
+        self.verify_hash("at_hash", self.claims.at_hash.as_deref(), access_token)
+    }
+
+    /// Verify the `c_hash` claim against the authorization code returned alongside the token.
+    ///
+    /// This is only relevant to hybrid flows, and ensures the code and ID token can't be spliced
+    /// across responses. The claim must be present.
+    pub fn verify_c_hash(&self, code: &str) -> Result<(), VerifyError> {
+        self.verify_hash("c_hash", self.claims.c_hash.as_deref(), code)
+    }
+
+    /// Verify a claim containing the left-most half of the hash of `value`.
+    ///
+    /// The hash function is the one used by the signature algorithm. For EdDSA, this is SHA-512,
+    /// as used by Ed25519.
+    fn verify_hash(
+        &self,
+        claim: &'static str,
+        expected: Option<&str>,
+        value: &str,
+    ) -> Result<(), VerifyError> {
+        let expected = expected.ok_or(VerifyError::MissingClaim(claim))?;
+        let alg = self.alg.as_deref().unwrap_or_default();
+        let algorithm = match alg {
+            "RS256" | "PS256" | "ES256" => &digest::SHA256,
+            "RS384" | "PS384" | "ES384" => &digest::SHA384,
+            "RS512" | "PS512" | "ES512" | "EdDSA" => &digest::SHA512,
+            _ => return Err(VerifyError::UnsupportedHashAlg(alg.to_owned())),
+        };
+
+        let hash = digest::digest(algorithm, value.as_bytes());
+        let hash = hash.as_ref();
+        match base64url::decode(expected) {
+            Ok(expected) if expected == hash[..hash.len() / 2] => Ok(()),
+            _ => Err(VerifyError::HashMismatch(claim)),
+        }
+    }
+}
+
+/// Revisions of the Portier specification that affect validation.
+///
+/// Newer revisions may be stricter than older revisions. The default is the oldest revision, so
+/// that upgrading this crate does not silently change behavior; new revisions are opt-in using
+/// `Builder::spec_version` or `Validator::spec_version`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum SpecVersion {
+    /// The initial revision, where `email_original` is optional.
+    #[default]
+    V1,
+    /// The revision that introduced email normalization. Tokens must contain `email_original`,
+    /// and the `email` claim must be normalized.
+    V2,
+}
+
+impl SpecVersion {
+    /// The latest revision supported by this crate.
+    pub const LATEST: SpecVersion = SpecVersion::V2;
+
+    /// The short name of the revision, such as `"v2"`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SpecVersion::V1 => "v1",
+            SpecVersion::V2 => "v2",
+        }
+    }
+}
+
+/// Optional or unknown claims whose handling is configurable. See `Validator::claim_policy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ClaimCheck {
+    /// The token has no `email_original` claim. The default is `ClaimPolicy::Reject` from
+    /// `SpecVersion::V2`, and `ClaimPolicy::Ignore` before that.
+    MissingEmailOriginal,
+    /// The token has no `sub` claim. The default is `ClaimPolicy::Ignore`.
+    MissingSub,
+    /// The token has claims not known to this crate, which are found in `Claims::extra`. The
+    /// default is `ClaimPolicy::Ignore`.
+    UnexpectedClaims,
+}
+
+/// How to handle a token that fails a `ClaimCheck`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClaimPolicy {
+    /// Accept the token.
+    Ignore,
+    /// Accept the token, but report the problem to `Validator::on_claim_warning`.
+    Warn,
+    /// Reject the token with `VerifyError::MissingClaim` or `VerifyError::UnexpectedClaim`.
+    Reject,
+}
+
+/// Policies set using `Validator::claim_policy`, or `None` for the default.
+#[derive(Clone, Copy, Default)]
+struct ClaimPolicies {
+    email_original: Option<ClaimPolicy>,
+    sub: Option<ClaimPolicy>,
+    extra: Option<ClaimPolicy>,
+}
+
+/// Callback set using `Validator::on_claim_warning`.
+pub(crate) type ClaimWarningHook = Arc<dyn Fn(&VerifyError) + Send + Sync>;
+
+/// Whether `original` normalizes to `email`, differing only in case.
+#[cfg(feature = "client")]
+fn same_mailbox(original: &str, email: &str) -> bool {
+    crate::normalize_email(original).is_ok_and(|normalized| normalized == email)
+}
+
+/// Whether `original` lowercases to `email`.
+///
+/// Without the `client` feature, IDNA is not available to normalize domains, so an original
+/// address with a non-ASCII domain does not match its normalized form.
+#[cfg(not(feature = "client"))]
+fn same_mailbox(original: &str, email: &str) -> bool {
+    original.to_lowercase() == email
+}
+
+/// Whether `email` is normalized the way brokers do.
+#[cfg(feature = "client")]
+fn is_normalized(email: &str) -> bool {
+    crate::normalize_email(email).is_ok_and(|normalized| normalized == email)
+}
+
+/// Whether `email` is lowercase. Without the `client` feature, domains are not checked for IDNA.
+#[cfg(not(feature = "client"))]
+fn is_normalized(email: &str) -> bool {
+    email.to_lowercase() == email
+}
+
+/// Which form of the verified email address to return.
+///
+/// Brokers normalize email addresses, which includes lowercasing them. The normalized address is
+/// what the broker verified, and is what applications should use to identify users: compare and
+/// store it as-is, without further case folding. The address with the capitalization entered by
+/// the user is only suitable for display, because the same user may type it differently on the
+/// next login. `VerifiedToken` provides both, regardless of this setting.
+///
+/// Configure this using `Builder::email_case`, which affects `Client::verify` and friends.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EmailCase {
+    /// Return the normalized address, `VerifiedToken::email`.
+    #[default]
+    Normalized,
+    /// Return the address for display, `VerifiedToken::display_email`.
+    Preserve,
+}
+
+/// Validates token signatures and claims, without fetching any documents.
+///
+/// This is what `Client::verify` uses internally, but it can also be used standalone, for example
+/// in API gateways that receive tokens and keys out-of-band. Unlike `Client::verify`, this does
+/// not check the nonce; the caller is responsible for tracking sessions, if needed.
+#[derive(Clone)]
+pub struct Validator {
+    issuer: String,
+    audience: String,
+    trusted: bool,
+    leeway: Duration,
+    spec_version: SpecVersion,
+    allowed_algs: Option<Vec<String>>,
+    claim_policies: ClaimPolicies,
+    on_claim_warning: Option<ClaimWarningHook>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Validator {
+    /// Create a validator for tokens issued by a trusted broker.
+    ///
+    /// The `issuer` is the origin of the broker, and the `audience` is the origin of the relying
+    /// party. Both are compared verbatim to the token claims, so should be in ASCII serialization.
+    pub fn new(issuer: impl Into<String>, audience: impl Into<String>) -> Self {
+        Validator {
+            issuer: issuer.into(),
+            audience: audience.into(),
+            trusted: true,
+            leeway: Duration::from_secs(180),
+            spec_version: SpecVersion::default(),
+            allowed_algs: None,
+            claim_policies: ClaimPolicies::default(),
+            on_claim_warning: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Treat the issuer as an untrusted identity provider.
+    ///
+    /// An untrusted identity provider may not change the email address. See `Builder::idp`.
+    pub fn untrusted(mut self) -> Self {
+        self.trusted = false;
+        self
+    }
+
+    /// Configure the leeway to allow for timestamps in tokens. The default is 3 minutes.
+    pub fn leeway(mut self, dur: Duration) -> Self {
+        self.leeway = dur;
+        self
+    }
+
+    /// Configure the spec revision to validate against. The default is `SpecVersion::V1`.
+    pub fn spec_version(mut self, version: SpecVersion) -> Self {
+        self.spec_version = version;
+        self
+    }
+
+    /// Only accept tokens signed with one of the given JWS algorithms, such as `"EdDSA"`.
+    ///
+    /// By default, any algorithm supported by this crate is accepted. Regardless of this setting,
+    /// `none` is always rejected, and the algorithm must match the key used.
+    pub fn allowed_algs<I, A>(mut self, algs: I) -> Self
+    where
+        I: IntoIterator<Item = A>,
+        A: Into<String>,
+    {
+        self.allowed_algs = Some(algs.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Configure how to handle tokens that fail `check`.
+    ///
+    /// This allows accepting tokens from brokers in transition between spec revisions. For
+    /// example, a validator for `SpecVersion::V2` can accept tokens without `email_original` using
+    /// `ClaimPolicy::Warn`, and report them to `Validator::on_claim_warning`.
+    pub fn claim_policy(mut self, check: ClaimCheck, policy: ClaimPolicy) -> Self {
+        let policies = &mut self.claim_policies;
+        match check {
+            ClaimCheck::MissingEmailOriginal => policies.email_original = Some(policy),
+            ClaimCheck::MissingSub => policies.sub = Some(policy),
+            ClaimCheck::UnexpectedClaims => policies.extra = Some(policy),
+        }
+        self
+    }
+
+    /// Call `f` for every problem with a token accepted using `ClaimPolicy::Warn`.
+    pub fn on_claim_warning(mut self, f: impl Fn(&VerifyError) + Send + Sync + 'static) -> Self {
+        self.on_claim_warning = Some(Arc::new(f));
+        self
+    }
+
+    /// Use the given `Clock` to check token timestamps, instead of the system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Replace the callback for `ClaimPolicy::Warn`, as configured on a `Builder`.
+    #[cfg(feature = "client")]
+    pub(crate) fn with_claim_warning_hook(mut self, hook: Option<ClaimWarningHook>) -> Self {
+        self.on_claim_warning = hook;
+        self
+    }
+
+    /// Replace the issuer, for example with the one from a discovery document.
+    #[cfg(feature = "client")]
+    pub(crate) fn with_issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.issuer = issuer.into();
+        self
+    }
+
+    /// The configured issuer.
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// The configured audience.
+    pub fn audience(&self) -> &str {
+        &self.audience
+    }
+
+    /// Verify the signature of `token` using `keys`, then validate its claims.
+    pub fn verify(&self, token: &str, keys: &KeySet) -> Result<Claims, VerifyError> {
+        Ok(self.verify_full(token, keys)?.claims)
+    }
+
+    /// Like `Validator::verify`, but also return the raw payload.
+    pub fn verify_full(&self, token: &str, keys: &KeySet) -> Result<VerifiedToken, VerifyError> {
+        let payload = jws::verify_with_algs(token, &keys.keys, self.allowed_algs.as_deref())?;
+        let claims: Claims =
+            serde_json::from_slice(&payload).map_err(VerifyError::InvalidPayload)?;
+        let alg = token_header(token).map(|header| header.alg);
+        let mut findings = Vec::new();
+        let mut warnings = Vec::new();
+        self.check_claims(&claims, &mut findings, &mut warnings);
+        if let Some(err) = findings.into_iter().next() {
+            return Err(err);
+        }
+        if let Some(ref on_claim_warning) = self.on_claim_warning {
+            warnings
+                .iter()
+                .for_each(|warning| on_claim_warning(warning));
+        }
+
+        Ok(VerifiedToken {
+            claims,
+            payload,
+            session_data: Vec::new(),
+            correlation_id: None,
+            alg,
+        })
+    }
+
+    /// Validate `token` using `keys` for debugging, and report all problems found.
+    ///
+    /// Unlike `Validator::verify`, this doesn't stop at the first problem. If the signature is
+    /// invalid, the claims are still decoded and checked.
+    pub fn inspect(&self, token: &str, keys: &KeySet) -> Inspection {
+        match jws::verify_with_algs(token, &keys.keys, self.allowed_algs.as_deref()) {
+            Ok(payload) => self.inspect_payload(Some(payload), Vec::new()),
+            Err(err) => self.inspect_unverified(token, err.into()),
+        }
+    }
+
+    /// Like `Validator::inspect`, for when the signature could not be verified because of `err`.
+    pub(crate) fn inspect_unverified(&self, token: &str, err: VerifyError) -> Inspection {
+        self.inspect_payload(unverified_payload(token), vec![err])
+    }
+
+    /// Parse and check the claims in `payload`, adding to `findings`.
+    fn inspect_payload(
+        &self,
+        payload: Option<Vec<u8>>,
+        mut findings: Vec<VerifyError>,
+    ) -> Inspection {
+        let mut warnings = Vec::new();
+        let claims = match payload.map(|payload| serde_json::from_slice::<Claims>(&payload)) {
+            Some(Ok(claims)) => {
+                self.check_claims(&claims, &mut findings, &mut warnings);
+                Some(claims)
+            }
+            Some(Err(err)) => {
+                findings.push(VerifyError::InvalidPayload(err));
+                None
+            }
+            None => None,
+        };
+        Inspection {
+            claims,
+            findings,
+            warnings,
+        }
+    }
+
+    /// Validate `claims`, adding problems to `findings` in the order they are checked, and
+    /// problems accepted using `ClaimPolicy::Warn` to `warnings`.
+    fn check_claims(
+        &self,
+        claims: &Claims,
+        findings: &mut Vec<VerifyError>,
+        warnings: &mut Vec<VerifyError>,
+    ) {
+        if claims.iss != self.issuer {
+            findings.push(VerifyError::IssuerInvalid);
+        }
+        if claims.aud != self.audience {
+            findings.push(VerifyError::AudienceInvalid);
+        }
+
+        // Timestamps can't be checked against a clock that is far off.
+        match clock::unix_now(&*self.clock) {
+            Some(now) => {
+                let exp_stretched = claims
+                    .exp
+                    .checked_add(self.leeway.as_secs())
+                    .unwrap_or(u64::MIN);
+                if exp_stretched < now {
+                    findings.push(VerifyError::TokenExpired);
+                }
+
+                let iat_stretched = claims
+                    .iat
+                    .checked_sub(self.leeway.as_secs())
+                    .unwrap_or(u64::MAX);
+                if now < iat_stretched {
+                    findings.push(VerifyError::IssuedInTheFuture);
+                }
+            }
+            None => findings.push(VerifyError::ClockUnreliable),
+        }
+
+        if claims.email_original.is_none() {
+            let default = (self.spec_version >= SpecVersion::V2).then_some(ClaimPolicy::Reject);
+            let policy = self.claim_policies.email_original.or(default);
+            let err = VerifyError::MissingClaim("email_original");
+            apply_policy(policy, err, findings, warnings);
+        }
+        if claims.sub.is_none() {
+            let err = VerifyError::MissingClaim("sub");
+            apply_policy(self.claim_policies.sub, err, findings, warnings);
+        }
+        for name in claims.extra.keys() {
+            let err = VerifyError::UnexpectedClaim(name.clone());
+            apply_policy(self.claim_policies.extra, err, findings, warnings);
+        }
+        if self.spec_version >= SpecVersion::V2 && !is_normalized(&claims.email) {
+            findings.push(VerifyError::EmailNotNormalized);
+        }
+
+        // If verifying an IdP token, it can't change the email address per spec. The spec assumes
+        // the client is a Broker, in this case, and has already done normalization.
+        if !self.trusted {
+            match claims.email_original {
+                None => {}
+                Some(ref orig) if orig == &claims.email => {}
+                Some(_) => findings.push(VerifyError::UntrustedServerChangedEmail),
+            }
+        }
+    }
+}
+
+/// Add `err` to `findings` or `warnings` according to `policy`, which defaults to ignoring it.
+fn apply_policy(
+    policy: Option<ClaimPolicy>,
+    err: VerifyError,
+    findings: &mut Vec<VerifyError>,
+    warnings: &mut Vec<VerifyError>,
+) {
+    match policy {
+        None | Some(ClaimPolicy::Ignore) => {}
+        Some(ClaimPolicy::Warn) => warnings.push(err),
+        Some(ClaimPolicy::Reject) => findings.push(err),
+    }
+}
+
+/// The result of `Client::inspect` or `Validator::inspect`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct Inspection {
+    /// The claims of the token, if the payload could be decoded.
+    ///
+    /// These may come from a token that failed validation, so must not be trusted unless
+    /// `Inspection::is_valid` returns `true`.
+    pub claims: Option<Claims>,
+    /// The problems found, in the order they were checked.
+    pub findings: Vec<VerifyError>,
+    /// The problems accepted using `ClaimPolicy::Warn`, in the order they were checked.
+    pub warnings: Vec<VerifyError>,
+}
+
+impl Inspection {
+    /// An inspection of a token that could not be validated at all because of `err`.
+    #[cfg(feature = "client")]
+    pub(crate) fn unverified(token: &str, err: VerifyError) -> Self {
+        let claims =
+            unverified_payload(token).and_then(|payload| serde_json::from_slice(&payload).ok());
+        Inspection {
+            claims,
+            findings: vec![err],
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Whether the token passed validation, meaning no problems were found.
+    pub fn is_valid(&self) -> bool {
+        self.claims.is_some() && self.findings.is_empty()
+    }
+}
+
+/// Decode the payload of `token`, without verifying the token.
+fn unverified_payload(token: &str) -> Option<Vec<u8>> {
+    base64url::decode(token.split('.').nth(1)?).ok()
+}
+
+/// Fields of the token header used by this crate.
+#[derive(Deserialize)]
+pub(crate) struct TokenHeader {
+    pub alg: String,
+    #[cfg(feature = "client")]
+    #[serde(default)]
+    pub kid: Option<String>,
+}
+
+/// Read the token header, without verifying the token.
+pub(crate) fn token_header(token: &str) -> Option<TokenHeader> {
+    base64url::with_decoded(token.split('.').next()?, |header| {
+        serde_json::from_slice(header).ok()
+    })
+    .ok()?
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    // Examples from OpenID Connect Core 1.0, Appendix A.
+    const ACCESS_TOKEN: &str = "jHkWEdUXMU1BwAsC4vtUsZwnNvTIxEl0z9K3vx5KF0Y";
+    const CODE: &str = "Qcb0Orv1zh30vL1MPRsbm-diHiMwcLyZvn1arpZv-Jxf_11jnpEX3Tgfvk";
+
+    fn verified(alg: Option<&str>, at_hash: Option<&str>, c_hash: Option<&str>) -> VerifiedToken {
+        let claims = json!({
+            "iss": "https://broker.example",
+            "aud": "https://rp.example",
+            "email": "user@example.com",
+            "iat": 1_600_000_000,
+            "exp": 1_600_000_600,
+            "nonce": "nonce",
+            "at_hash": at_hash,
+            "c_hash": c_hash,
+        });
+        VerifiedToken {
+            claims: serde_json::from_value(claims).unwrap(),
+            payload: Vec::new(),
+            session_data: Vec::new(),
+            correlation_id: None,
+            alg: alg.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn verifies_sha256_hashes() {
+        for alg in ["RS256", "ES256"] {
+            let token = verified(
+                Some(alg),
+                Some("77QmUPtjPfzWtF2AnpK9RQ"),
+                Some("LDktKdoQak3Pk0cnXxCltA"),
+            );
+            token.verify_at_hash(ACCESS_TOKEN).unwrap();
+            token.verify_c_hash(CODE).unwrap();
+        }
+    }
+
+    #[test]
+    fn verifies_eddsa_hashes_with_sha512() {
+        let token = verified(
+            Some("EdDSA"),
+            Some("q7nS86GgvvFaZkzALLWqJYaJIKw2wCDAVfCAsm5CrBM"),
+            Some("E9z1C-c0Az4eTEzE0Nm3OQ3BS2BhMgxuP7x5JAQj1_4"),
+        );
+        token.verify_at_hash(ACCESS_TOKEN).unwrap();
+        token.verify_c_hash(CODE).unwrap();
+    }
+
+    #[test]
+    fn rejects_hash_mismatch() {
+        let token = verified(
+            Some("RS256"),
+            Some("77QmUPtjPfzWtF2AnpK9RQ"),
+            Some("LDktKdoQak3Pk0cnXxCltA"),
+        );
+        assert!(matches!(
+            token.verify_at_hash(CODE),
+            Err(VerifyError::HashMismatch("at_hash"))
+        ));
+        assert!(matches!(
+            token.verify_c_hash(ACCESS_TOKEN),
+            Err(VerifyError::HashMismatch("c_hash"))
+        ));
+        // The hash of another algorithm family does not match either.
+        let token = verified(Some("EdDSA"), Some("77QmUPtjPfzWtF2AnpK9RQ"), None);
+        assert!(matches!(
+            token.verify_at_hash(ACCESS_TOKEN),
+            Err(VerifyError::HashMismatch("at_hash"))
+        ));
+    }
+
+    #[test]
+    fn rejects_missing_hash_claim() {
+        let token = verified(Some("RS256"), None, None);
+        assert!(matches!(
+            token.verify_at_hash(ACCESS_TOKEN),
+            Err(VerifyError::MissingClaim("at_hash"))
+        ));
+        assert!(matches!(
+            token.verify_c_hash(CODE),
+            Err(VerifyError::MissingClaim("c_hash"))
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_hash_alg() {
+        for alg in [Some("HS256"), None] {
+            let token = verified(alg, Some("77QmUPtjPfzWtF2AnpK9RQ"), None);
+            assert!(matches!(
+                token.verify_at_hash(ACCESS_TOKEN),
+                Err(VerifyError::UnsupportedHashAlg(_))
+            ));
+        }
+    }
+}
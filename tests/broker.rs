@@ -0,0 +1,128 @@
+//! Integration test against a real Portier broker.
+//!
+//! This test is opt-in, because it needs a running broker and a way to read the confirmation
+//! emails it sends. See `scripts/broker-test.sh`, which starts both in containers and runs this
+//! test. To run it against existing services, set the following environment variables:
+//!
+//! - `PORTIER_TEST_BROKER`: the broker origin, for example `http://localhost:3333`.
+//! - `PORTIER_TEST_MAILPIT`: the origin of a Mailpit instance receiving the broker email.
+//! - `PORTIER_TEST_EMAIL`: optional, the email address to log in with.
+//!
+//! Without `PORTIER_TEST_BROKER`, the test does nothing.
+#![cfg(all(
+    feature = "memory-store",
+    feature = "http-hyper",
+    feature = "tls-native"
+))]
+
+use std::{env, time::Duration};
+
+use hyper::{body, header, Body, Request};
+use portier::{default_http_client, Client, DefaultHttpClient};
+use url::Url;
+
+/// Perform a request and return the response body as a string, following no redirects.
+async fn request(http: &DefaultHttpClient, request: Request<Body>) -> String {
+    let response = http.request(request).await.expect("request failed");
+    let status = response.status();
+    let body = body::to_bytes(response.into_body())
+        .await
+        .expect("could not read response body");
+    let body = String::from_utf8(body.to_vec()).expect("response body is not UTF-8");
+    assert!(
+        status.is_success(),
+        "unexpected status {}: {}",
+        status,
+        body
+    );
+    body
+}
+
+/// Extract the value of a hidden form input from an HTML page.
+fn extract_input(html: &str, name: &str) -> Option<String> {
+    let marker = format!("name=\"{}\"", name);
+    let start = html.find(&marker)?;
+    let rest = &html[start..];
+    let value = rest.find("value=\"")? + "value=\"".len();
+    let end = rest[value..].find('"')?;
+    Some(rest[value..value + end].to_owned())
+}
+
+/// Poll Mailpit for the latest message to `email`, and extract the confirmation link.
+async fn fetch_confirm_link(http: &DefaultHttpClient, mailpit: &Url, broker: &Url) -> Url {
+    let mut url = mailpit.join("/api/v1/message/latest").unwrap();
+    url.set_query(None);
+    let prefix = broker.join("/confirm").unwrap();
+    for _ in 0..50 {
+        let response = http
+            .get(url.as_str().parse().unwrap())
+            .await
+            .expect("could not query Mailpit");
+        if response.status().is_success() {
+            let body = body::to_bytes(response.into_body()).await.unwrap();
+            let message: serde_json::Value =
+                serde_json::from_slice(&body).expect("invalid Mailpit response");
+            let text = message["Text"].as_str().unwrap_or_default();
+            if let Some(link) = text
+                .split_whitespace()
+                .find(|word| word.starts_with(prefix.as_str()))
+            {
+                return link.parse().expect("invalid confirmation link");
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("no confirmation email arrived");
+}
+
+#[tokio::test]
+async fn full_flow() {
+    let broker: Url = match env::var("PORTIER_TEST_BROKER") {
+        Ok(broker) => broker.parse().expect("invalid PORTIER_TEST_BROKER"),
+        Err(_) => return,
+    };
+    let mailpit: Url = env::var("PORTIER_TEST_MAILPIT")
+        .expect("PORTIER_TEST_MAILPIT is required")
+        .parse()
+        .expect("invalid PORTIER_TEST_MAILPIT");
+    let email = env::var("PORTIER_TEST_EMAIL").unwrap_or_else(|_| "test@example.com".to_owned());
+
+    let redirect_uri = "http://imaginary-client.test/verify".parse().unwrap();
+    let client = Client::builder(redirect_uri)
+        .broker(broker.clone())
+        .build()
+        .expect("could not build Portier client");
+    let http = default_http_client();
+
+    // Start authentication, which fetches the discovery document.
+    let auth_url = client.start_auth(&email).await.expect("start_auth failed");
+
+    // Visit the authentication URL, which makes the broker send the confirmation email.
+    request(
+        &http,
+        Request::get(auth_url.as_str())
+            .header(header::ACCEPT, "text/html")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+
+    // Follow the confirmation link, and take the token from the resulting `form_post` page.
+    let confirm_url = fetch_confirm_link(&http, &mailpit, &broker).await;
+    let page = request(
+        &http,
+        Request::get(confirm_url.as_str())
+            .header(header::ACCEPT, "text/html")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    let token = extract_input(&page, "id_token").expect("no id_token in confirmation response");
+
+    // Verify the token, which fetches the keys document and consumes the nonce.
+    let verified = client.verify(&token).await.expect("verify failed");
+    assert_eq!(verified, email.to_lowercase());
+
+    // The nonce must not be usable twice.
+    assert!(client.verify(&token).await.is_err());
+}
@@ -0,0 +1,94 @@
+//! Tests of the callback wire format.
+#![cfg(feature = "client")]
+
+use portier::{CallbackError, CallbackParams, ErrorKind, FragmentPayload, VerifyError};
+
+#[test]
+fn deserializes_callback_params() {
+    let params: CallbackParams =
+        serde_json::from_str(r#"{"id_token":"abc","state":"xyz","foo":"bar"}"#).unwrap();
+    assert_eq!(params.id_token.as_deref(), Some("abc"));
+    assert_eq!(params.state.as_deref(), Some("xyz"));
+    assert_eq!(params.extra["foo"], "bar");
+    assert_eq!(
+        params,
+        CallbackParams::parse("id_token=abc&state=xyz&foo=bar")
+    );
+    assert_eq!(
+        serde_json::to_value(&params).unwrap(),
+        serde_json::json!({"id_token": "abc", "state": "xyz", "foo": "bar"})
+    );
+}
+
+#[test]
+fn separates_fragment_redirect_uri() {
+    let input = "#id_token=abc&redirect_uri=https%3A%2F%2Fexample.com%2Fverify";
+    let payload = FragmentPayload::parse(input);
+    assert_eq!(
+        payload.redirect_uri.as_deref(),
+        Some("https://example.com/verify")
+    );
+    assert_eq!(payload.params.id_token.as_deref(), Some("abc"));
+    assert!(payload.params.extra.is_empty());
+
+    let json = r#"{"id_token":"abc","redirect_uri":"https://example.com/verify"}"#;
+    assert_eq!(
+        serde_json::from_str::<FragmentPayload>(json).unwrap(),
+        payload
+    );
+}
+
+#[test]
+fn classifies_errors() {
+    let denied = CallbackError::Broker {
+        code: "access_denied".to_owned(),
+        description: None,
+    };
+    assert_eq!(denied.kind(), ErrorKind::UserInput);
+    assert!(!denied.is_retryable());
+
+    let err = CallbackError::Verify(VerifyError::StoreTimeout);
+    assert_eq!(err.kind(), ErrorKind::Storage);
+    assert!(err.is_retryable());
+    assert!(err.is_server_error());
+
+    let err = CallbackError::Verify(VerifyError::TokenExpired);
+    assert_eq!(err.kind(), ErrorKind::Expired);
+    assert!(!err.is_retryable());
+    assert!(!err.is_server_error());
+
+    assert_eq!(VerifyError::AudienceInvalid.kind(), ErrorKind::Protocol);
+    assert_eq!(
+        VerifyError::KeysRejected("no keys".into()).kind(),
+        ErrorKind::Protocol
+    );
+
+    let err = VerifyError::LoginRejected("not allowed".into());
+    assert_eq!(err.kind(), ErrorKind::Policy);
+    assert!(!err.is_retryable());
+}
+
+#[cfg(feature = "simple-store")]
+#[test]
+fn generates_fragment_page() {
+    let client = portier::Client::builder("https://example.com/verify?x=1".parse().unwrap())
+        .response_mode(portier::ResponseMode::Fragment)
+        .build()
+        .unwrap();
+
+    let html = client.fragment_callback_html(Some("r4nd\"<om"));
+    assert!(html.contains(r#"action="https://example.com/verify/relay""#));
+    assert!(html.contains(r#"<script nonce="r4nd&quot;&lt;om">"#));
+    assert!(!client.fragment_callback_html(None).contains("nonce"));
+
+    // What the page script posts: the fragment parameters, plus its own URL.
+    let location = "https://example.com/verify?x=1#id_token=abc.def&state=xyz";
+    let (page, fragment) = location.split_once('#').unwrap();
+    let body = url::form_urlencoded::Serializer::new(fragment.to_owned())
+        .append_pair("redirect_uri", page)
+        .finish();
+    let payload = FragmentPayload::parse(&body);
+    assert_eq!(payload.params.id_token.as_deref(), Some("abc.def"));
+    assert_eq!(payload.params.state.as_deref(), Some("xyz"));
+    payload.check_redirect_uri(client.fragment_relay()).unwrap();
+}
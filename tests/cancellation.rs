@@ -0,0 +1,215 @@
+//! Tests that `Client::verify` is cancellation-safe, and that deadlines are enforced.
+//!
+//! These use a store that consumes sessions in two steps, like a replicated store would, and drop
+//! the `verify` future in between.
+#![cfg(feature = "tokio")]
+
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use portier::{Client, FetchError, Store, VerifyError};
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use serde_json::json;
+use tokio::sync::Notify;
+use url::Url;
+
+type DynFut<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+type DynRes<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+const BROKER: &str = "https://broker.example";
+const CLIENT: &str = "https://rp.example";
+const EMAIL: &str = "user@example.com";
+const NONCE: &str = "test-nonce";
+
+/// A store that serves fixed documents, and consumes sessions in two steps.
+#[derive(Default)]
+struct TwoStepStore {
+    documents: HashMap<String, Bytes>,
+    first: Mutex<HashSet<(String, String)>>,
+    second: Mutex<HashSet<(String, String)>>,
+    started: Notify,
+    release: Notify,
+    done: Notify,
+}
+
+impl TwoStepStore {
+    fn has_session(&self) -> bool {
+        let key = (NONCE.to_owned(), EMAIL.to_owned());
+        self.first.lock().unwrap().contains(&key) || self.second.lock().unwrap().contains(&key)
+    }
+}
+
+#[derive(Clone)]
+struct SharedStore(Arc<TwoStepStore>);
+
+impl Store for SharedStore {
+    fn fetch(&self, url: Url) -> DynFut<Result<Bytes, FetchError>> {
+        let result = self.0.documents.get(url.as_str()).cloned().ok_or_else(|| {
+            FetchError::Fetch(Arc::new(format!("unexpected fetch: {}", url).into()))
+        });
+        Box::pin(async move { result })
+    }
+
+    fn new_nonce(&self, email: String) -> DynFut<DynRes<String>> {
+        let key = (NONCE.to_owned(), email);
+        self.0.first.lock().unwrap().insert(key.clone());
+        self.0.second.lock().unwrap().insert(key);
+        Box::pin(async { Ok(NONCE.to_owned()) })
+    }
+
+    fn consume_nonce(&self, nonce: String, email: String) -> DynFut<DynRes<bool>> {
+        let store = self.0.clone();
+        Box::pin(async move {
+            let key = (nonce, email);
+            let found = store.first.lock().unwrap().remove(&key);
+            store.started.notify_one();
+            store.release.notified().await;
+            let found = store.second.lock().unwrap().remove(&key) || found;
+            store.done.notify_one();
+            Ok(found)
+        })
+    }
+}
+
+/// Create a store and client, and a token for a started login.
+async fn setup(
+    configure: impl FnOnce(portier::Builder) -> portier::Builder,
+) -> (Arc<TwoStepStore>, Client, String) {
+    let rng = SystemRandom::new();
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+    let keypair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+
+    let mut store = TwoStepStore::default();
+    let discovery = json!({
+        "jwks_uri": format!("{}/jwks.json", BROKER),
+        "authorization_endpoint": format!("{}/auth", BROKER),
+    });
+    let jwks = json!({
+        "keys": [{
+            "kid": "test",
+            "kty": "OKP",
+            "alg": "EdDSA",
+            "crv": "Ed25519",
+            "x": URL_SAFE_NO_PAD.encode(keypair.public_key()),
+        }],
+    });
+    store.documents.insert(
+        format!("{}/.well-known/openid-configuration", BROKER),
+        discovery.to_string().into(),
+    );
+    store
+        .documents
+        .insert(format!("{}/jwks.json", BROKER), jwks.to_string().into());
+    let store = Arc::new(store);
+
+    let client = configure(
+        Client::builder(format!("{}/verify", CLIENT).parse().unwrap())
+            .broker(BROKER.parse().unwrap())
+            .store(Arc::new(SharedStore(store.clone()))),
+    )
+    .build()
+    .unwrap();
+    client.start_auth(EMAIL).await.unwrap();
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let header = json!({ "alg": "EdDSA", "kid": "test" });
+    let payload = json!({
+        "iss": BROKER,
+        "aud": CLIENT,
+        "email": EMAIL,
+        "email_original": EMAIL,
+        "iat": now,
+        "exp": now + 600,
+        "nonce": NONCE,
+    });
+    let message = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(header.to_string()),
+        URL_SAFE_NO_PAD.encode(payload.to_string())
+    );
+    let signature = keypair.sign(message.as_bytes());
+    let token = format!("{}.{}", message, URL_SAFE_NO_PAD.encode(signature));
+
+    (store, client, token)
+}
+
+/// Wait for the store to finish consuming the session.
+async fn wait_done(store: &TwoStepStore) {
+    tokio::time::timeout(Duration::from_secs(5), store.done.notified())
+        .await
+        .expect("session was not fully consumed");
+}
+
+#[tokio::test]
+async fn verify_completes_without_cancellation() {
+    let (store, client, token) = setup(|builder| builder).await;
+    store.release.notify_one();
+    assert_eq!(client.verify(&token).await.unwrap(), EMAIL);
+    assert!(!store.has_session());
+}
+
+#[tokio::test]
+async fn dropped_verify_fully_consumes_session() {
+    let (store, client, token) = setup(|builder| builder).await;
+
+    tokio::select! {
+        _ = client.verify(&token) => panic!("verify completed before the store was released"),
+        _ = store.started.notified() => {}
+    }
+
+    // The `verify` future is dropped at this point, halfway through consuming the session.
+    store.release.notify_one();
+    wait_done(&store).await;
+    assert!(!store.has_session());
+
+    // The token cannot be replayed against the remaining half of the session.
+    store.release.notify_one();
+    assert!(matches!(
+        client.verify(&token).await,
+        Err(VerifyError::InvalidSession)
+    ));
+}
+
+#[tokio::test]
+async fn timed_out_verify_fully_consumes_session() {
+    let (store, client, token) =
+        setup(|builder| builder.store_timeout(Duration::from_millis(50))).await;
+
+    assert!(matches!(
+        client.verify(&token).await,
+        Err(VerifyError::StoreTimeout)
+    ));
+
+    store.release.notify_one();
+    wait_done(&store).await;
+    assert!(!store.has_session());
+}
+
+#[tokio::test]
+async fn verify_with_deadline_fails_in_time() {
+    let (store, client, token) = setup(|builder| builder).await;
+    let deadline = Duration::from_millis(50);
+
+    assert!(matches!(
+        client.verify_with_deadline(&token, deadline).await,
+        Err(VerifyError::DeadlineExceeded(d)) if d == deadline
+    ));
+
+    store.release.notify_one();
+    wait_done(&store).await;
+    assert!(!store.has_session());
+}
@@ -0,0 +1,40 @@
+//! Tests of `normalize_email`.
+#![cfg(feature = "client")]
+
+use portier::{normalize_email, EmailError};
+
+#[test]
+fn normalizes_case_and_domain() {
+    assert_eq!(
+        normalize_email("Jane.Doe@Example.COM").unwrap(),
+        "jane.doe@example.com"
+    );
+    assert_eq!(
+        normalize_email("JÖRG@Bücher.example").unwrap(),
+        "jörg@xn--bcher-kva.example"
+    );
+    assert_eq!(
+        normalize_email("\"a@b\"@example.com").unwrap(),
+        "\"a@b\"@example.com"
+    );
+}
+
+#[test]
+fn rejects_invalid_addresses() {
+    assert!(matches!(
+        normalize_email("example.com"),
+        Err(EmailError::MissingAt)
+    ));
+    assert!(matches!(
+        normalize_email("@example.com"),
+        Err(EmailError::EmptyLocalPart)
+    ));
+    assert!(matches!(
+        normalize_email("jane@"),
+        Err(EmailError::InvalidDomain)
+    ));
+    assert!(matches!(
+        normalize_email("jane@[::1]"),
+        Err(EmailError::InvalidDomain)
+    ));
+}
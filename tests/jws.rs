@@ -0,0 +1,195 @@
+//! Tests for JWS verification of ES256 tokens.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use portier::{jwk::KeySet, jws};
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde_json::json;
+
+/// A P-256 public key, and a token with payload `{"hello":"world"}` signed by it.
+const KEY_X: &str = "Ayibo4t5mZrUqi5tI3HWoCx_SsrrIUwECUA4f2vLzyc";
+const KEY_Y: &str = "7JyyT7ijQPiZjiPlT_qUR-3LqlrR5Y27ICuXvbL8T4c";
+const TOKEN: &str = "eyJhbGciOiJFUzI1NiIsImtpZCI6ImVzMjU2LXRlc3QifQ.\
+    eyJoZWxsbyI6IndvcmxkIn0.\
+    UO46d2bOh9v0owjfEB51of62-6m-Sv4r9X5wrtSCUiXCbPWGa3MmnQW09aHXY6j3y7yQMtXhNXUoacsNKFlCIA";
+
+fn key_set(alg: &str, crv: &str, x: &str, y: &str) -> KeySet {
+    let keys = json!({
+        "keys": [{
+            "kid": "es256-test",
+            "kty": "EC",
+            "alg": alg,
+            "crv": crv,
+            "x": x,
+            "y": y,
+        }],
+    });
+    serde_json::from_str(&keys.to_string()).unwrap()
+}
+
+#[test]
+fn verifies_known_good_token() {
+    let keys = key_set("ES256", "P-256", KEY_X, KEY_Y);
+    let payload = jws::verify(TOKEN, &keys.keys).unwrap();
+    assert_eq!(payload, br#"{"hello":"world"}"#);
+}
+
+#[test]
+fn rejects_tampered_payload() {
+    let keys = key_set("ES256", "P-256", KEY_X, KEY_Y);
+    let mut parts: Vec<&str> = TOKEN.split('.').collect();
+    let payload = URL_SAFE_NO_PAD.encode(r#"{"hello":"mallory"}"#);
+    parts[1] = &payload;
+    assert!(matches!(
+        jws::verify(&parts.join("."), &keys.keys),
+        Err(jws::VerifyError::BadSignature)
+    ));
+}
+
+#[test]
+fn rejects_unsupported_curve() {
+    let keys = key_set("ES256", "P-384", KEY_X, KEY_Y);
+    assert!(matches!(
+        jws::verify(TOKEN, &keys.keys),
+        Err(jws::VerifyError::UnsupportedKeyType)
+    ));
+}
+
+#[test]
+fn verifies_freshly_signed_token() {
+    let rng = SystemRandom::new();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+    let keypair =
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+    let public = keypair.public_key().as_ref();
+    let keys = key_set(
+        "ES256",
+        "P-256",
+        &URL_SAFE_NO_PAD.encode(&public[1..33]),
+        &URL_SAFE_NO_PAD.encode(&public[33..]),
+    );
+
+    let message = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(r#"{"alg":"ES256","kid":"es256-test"}"#),
+        URL_SAFE_NO_PAD.encode("payload")
+    );
+    let signature = keypair.sign(&rng, message.as_bytes()).unwrap();
+    let token = format!("{}.{}", message, URL_SAFE_NO_PAD.encode(signature));
+
+    assert_eq!(jws::verify(&token, &keys.keys).unwrap(), b"payload");
+}
+
+#[test]
+fn rejects_alg_none() {
+    let keys = key_set("ES256", "P-256", KEY_X, KEY_Y);
+    let mut parts: Vec<&str> = TOKEN.split('.').collect();
+    let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"none","kid":"es256-test"}"#);
+    parts[0] = &header;
+    assert!(matches!(
+        jws::verify(&parts.join("."), &keys.keys),
+        Err(jws::VerifyError::AlgNotAllowed { alg }) if alg == "none"
+    ));
+}
+
+#[test]
+fn rejects_alg_mismatch() {
+    let keys = key_set("ES256", "P-256", KEY_X, KEY_Y);
+    let mut parts: Vec<&str> = TOKEN.split('.').collect();
+    let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"RS256","kid":"es256-test"}"#);
+    parts[0] = &header;
+    assert!(matches!(
+        jws::verify(&parts.join("."), &keys.keys),
+        Err(jws::VerifyError::AlgMismatch { alg }) if alg == "RS256"
+    ));
+}
+
+#[test]
+fn enforces_allowed_algs() {
+    let keys = key_set("ES256", "P-256", KEY_X, KEY_Y);
+    let allowed = ["ES256".to_owned()];
+    assert!(jws::verify_with_algs(TOKEN, &keys.keys, Some(&allowed)).is_ok());
+    let allowed = ["EdDSA".to_owned()];
+    assert!(matches!(
+        jws::verify_with_algs(TOKEN, &keys.keys, Some(&allowed)),
+        Err(jws::VerifyError::AlgNotAllowed { alg }) if alg == "ES256"
+    ));
+}
+
+#[test]
+fn verifies_signed_token() {
+    let key = jws::KeyPair::generate_ed25519("ed25519-test").unwrap();
+    let token = jws::sign(b"payload", &key).unwrap();
+    assert_eq!(
+        jws::verify(&token, [&key.public_jwk()]).unwrap(),
+        b"payload"
+    );
+
+    // The key can be loaded again, and the public key published as JSON.
+    let key = jws::KeyPair::ed25519_from_pkcs8("ed25519-test", key.pkcs8()).unwrap();
+    let keys = json!({ "keys": [key.public_jwk()] });
+    let keys: KeySet = serde_json::from_str(&keys.to_string()).unwrap();
+    assert_eq!(jws::verify(&token, &keys.keys).unwrap(), b"payload");
+}
+
+/// A `Signer` standing in for a KMS, which holds a key type `jws::KeyPair` doesn't support.
+struct Kms(EcdsaKeyPair);
+
+impl jws::Signer for Kms {
+    fn kid(&self) -> &str {
+        "es256-test"
+    }
+
+    fn alg(&self) -> &str {
+        "ES256"
+    }
+
+    fn sign<'a>(&'a self, message: &'a [u8]) -> jws::SignFuture<'a> {
+        Box::pin(async move {
+            let signature = self
+                .0
+                .sign(&SystemRandom::new(), message)
+                .map_err(|_err| "could not sign")?;
+            Ok(signature.as_ref().to_vec())
+        })
+    }
+}
+
+#[tokio::test]
+async fn signs_with_signer() {
+    let rng = SystemRandom::new();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+    let keypair =
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+    let public = keypair.public_key().as_ref();
+    let keys = key_set(
+        "ES256",
+        "P-256",
+        &URL_SAFE_NO_PAD.encode(&public[1..33]),
+        &URL_SAFE_NO_PAD.encode(&public[33..]),
+    );
+
+    let token = jws::sign_with(b"payload", &Kms(keypair)).await.unwrap();
+    assert_eq!(jws::verify(&token, &keys.keys).unwrap(), b"payload");
+
+    // A local `KeyPair` signs the same way through the trait.
+    let key = jws::KeyPair::generate_ed25519("ed25519-test").unwrap();
+    let token = jws::sign_with(b"payload", &key).await.unwrap();
+    assert_eq!(token, jws::sign(b"payload", &key).unwrap());
+}
+
+#[test]
+fn reports_capabilities() {
+    let capabilities = portier::capabilities();
+    assert_eq!(capabilities.version, env!("CARGO_PKG_VERSION"));
+    assert!(capabilities.algorithms.contains(&"ES256"));
+    assert_eq!(
+        capabilities.features.contains(&"client"),
+        cfg!(feature = "client")
+    );
+    let json = serde_json::to_value(&capabilities).unwrap();
+    assert_eq!(json["spec_version"], "v2");
+    assert!(capabilities.to_string().starts_with("portier "));
+}
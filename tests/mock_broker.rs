@@ -0,0 +1,874 @@
+//! Tests of `Client::verify` against the `MockBroker` from the `test-utils` feature.
+
+use std::{
+    error::Error,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use portier::{
+    jws,
+    test_utils::{self, MockBroker, TokenMint},
+    AuthOptions, Availability, BuildError, CallbackParams, Client, ClientInfo, DiscoveryError,
+    EnvSecret, ErrorCode, HttpClient, HttpRequest, HttpResponse, IssuerCheck, LoginStep,
+    ManualClock, MemoryRateLimiter, MemoryStore, RandomNonces, RateLimitScope, ResponseMode,
+    RetryPolicy, SessionBinding, StartAuthError, Store, StoreFailureSink, UriCanonicalization,
+    VerifyError, VerifyFailure,
+};
+
+type DynFut<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+async fn setup() -> (MockBroker, Client) {
+    let broker = MockBroker::start().await.unwrap();
+    let client = Client::builder("http://localhost:8000/verify".parse().unwrap())
+        .broker(broker.url().clone())
+        .build()
+        .unwrap();
+    (broker, client)
+}
+
+async fn setup_idp() -> (MockBroker, Client) {
+    let idp = MockBroker::start().await.unwrap();
+    let client = Client::builder("http://localhost:8000/verify".parse().unwrap())
+        .idp(idp.url().clone())
+        .build()
+        .unwrap();
+    (idp, client)
+}
+
+#[tokio::test]
+async fn verifies_login() {
+    let (broker, client) = setup().await;
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let token = broker.login(&auth_url).unwrap();
+    assert_eq!(client.verify(&token).await.unwrap(), "user@example.com");
+}
+
+#[tokio::test]
+async fn rejects_expired_token() {
+    let (broker, client) = setup().await;
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let mut claims = broker.claims_for(&auth_url).unwrap();
+    claims["iat"] = 900.into();
+    claims["exp"] = 1000.into();
+    assert!(matches!(
+        client.verify(&broker.mint().sign(&claims)).await,
+        Err(VerifyError::TokenExpired)
+    ));
+}
+
+#[tokio::test]
+async fn rejects_bad_signature() {
+    let (broker, client) = setup().await;
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let claims = broker.claims_for(&auth_url).unwrap();
+    let other = TokenMint::with_kid(broker.mint().kid());
+    assert!(matches!(
+        client.verify(&other.sign(&claims)).await,
+        Err(VerifyError::Signature(_))
+    ));
+}
+
+#[tokio::test]
+async fn exposes_discovery() {
+    let (broker, client) = setup().await;
+    let discovery = client.discovery().await.unwrap();
+    assert_eq!(
+        discovery.issuer.as_deref(),
+        Some(broker.url().origin().ascii_serialization().as_str())
+    );
+    assert!(discovery.supports_response_mode(ResponseMode::Fragment));
+}
+
+/// Build a client for `broker` that uses a discovery document with the given `issuer`.
+fn with_discovery_issuer(broker: &MockBroker, issuer: Option<&str>, check: IssuerCheck) -> Client {
+    let origin = broker.url().origin().ascii_serialization();
+    let mut discovery = serde_json::json!({
+        "jwks_uri": format!("{}/jwks.json", origin),
+        "authorization_endpoint": format!("{}/auth", origin),
+    });
+    if let Some(issuer) = issuer {
+        discovery["issuer"] = issuer.into();
+    }
+    Client::builder("http://localhost:8000/verify".parse().unwrap())
+        .broker(broker.url().clone())
+        .pinned_discovery(discovery.to_string())
+        .issuer_check(check)
+        .build()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn rejects_discovery_issuer_mismatch() {
+    let broker = MockBroker::start().await.unwrap();
+    for check in [IssuerCheck::Verify, IssuerCheck::Require] {
+        let client = with_discovery_issuer(&broker, Some("https://other.example"), check);
+        assert!(matches!(
+            client.start_auth("user@example.com").await,
+            Err(StartAuthError::InvalidDiscovery(DiscoveryError::IssuerMismatch { found, .. }))
+                if found == "https://other.example"
+        ));
+    }
+}
+
+#[tokio::test]
+async fn requires_discovery_issuer() {
+    let broker = MockBroker::start().await.unwrap();
+    let client = with_discovery_issuer(&broker, None, IssuerCheck::Require);
+    assert!(matches!(
+        client.start_auth("user@example.com").await,
+        Err(StartAuthError::InvalidDiscovery(
+            DiscoveryError::MissingIssuer
+        ))
+    ));
+
+    let client = with_discovery_issuer(&broker, None, IssuerCheck::Verify);
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let token = broker.login(&auth_url).unwrap();
+    assert_eq!(client.verify(&token).await.unwrap(), "user@example.com");
+}
+
+#[tokio::test]
+async fn ignores_discovery_issuer() {
+    let broker = MockBroker::start().await.unwrap();
+    let client = with_discovery_issuer(&broker, Some("https://other.example"), IssuerCheck::Ignore);
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+
+    // Tokens are expected from the configured origin, not the discovery issuer.
+    let token = broker.login(&auth_url).unwrap();
+    assert_eq!(client.verify(&token).await.unwrap(), "user@example.com");
+    let mut claims = broker.claims_for(&auth_url).unwrap();
+    claims["iss"] = "https://other.example".into();
+    assert!(client.verify(&broker.mint().sign(&claims)).await.is_err());
+}
+
+#[tokio::test]
+async fn accepts_normalized_email() {
+    let (broker, client) = setup().await;
+    let auth_url = client.start_auth("User@Example.com").await.unwrap();
+    let token = broker.login_as(&auth_url, "user@example.com").unwrap();
+    assert_eq!(client.verify(&token).await.unwrap(), "user@example.com");
+}
+
+#[tokio::test]
+async fn idp_verifies_login() {
+    let (idp, client) = setup_idp().await;
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let token = idp.login(&auth_url).unwrap();
+    assert_eq!(client.verify(&token).await.unwrap(), "user@example.com");
+}
+
+#[tokio::test]
+async fn idp_rejects_changed_email() {
+    let (idp, client) = setup_idp().await;
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let token = idp.login_as(&auth_url, "other@example.com").unwrap();
+    assert!(matches!(
+        client.verify(&token).await,
+        Err(VerifyError::UntrustedServerChangedEmail)
+    ));
+}
+
+#[tokio::test]
+async fn uses_nonce_generator() {
+    let broker = MockBroker::start().await.unwrap();
+    let client = Client::builder("http://localhost:8000/verify".parse().unwrap())
+        .broker(broker.url().clone())
+        .nonce_generator(Arc::new(RandomNonces::new().bits(256)))
+        .build()
+        .unwrap();
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let (_, nonce) = auth_url.query_pairs().find(|(k, _)| k == "nonce").unwrap();
+    assert_eq!(nonce.len(), 43);
+    let token = broker.login(&auth_url).unwrap();
+    assert_eq!(client.verify(&token).await.unwrap(), "user@example.com");
+}
+
+#[tokio::test]
+async fn overrides_redirect_uri() {
+    let (broker, client) = setup().await;
+    let admin: url::Url = "http://localhost:8000/admin/verify".parse().unwrap();
+    let start = |redirect_uri| {
+        let options = AuthOptions {
+            redirect_uri: Some(redirect_uri),
+            ..Default::default()
+        };
+        client.start_auth_with_options("user@example.com", options)
+    };
+    assert!(matches!(
+        start("http://example.com/verify".parse().unwrap()).await,
+        Err(StartAuthError::InvalidRedirectUri)
+    ));
+
+    let auth_url = start(admin.clone()).await.unwrap();
+    let (_, redirect_uri) = auth_url
+        .query_pairs()
+        .find(|(k, _)| k == "redirect_uri")
+        .unwrap();
+    assert_eq!(redirect_uri, admin.as_str());
+    let token = broker.login(&auth_url).unwrap();
+    let info = ClientInfo::default().redirect_uri(admin.clone());
+    let verified = client.verify_with_info(&token, &info).await.unwrap();
+    assert_eq!(verified.email(), "user@example.com");
+
+    let auth_url = start(admin).await.unwrap();
+    let token = broker.login(&auth_url).unwrap();
+    let info = ClientInfo::default().redirect_uri("http://localhost:8000/verify".parse().unwrap());
+    assert!(matches!(
+        client.verify_with_info(&token, &info).await,
+        Err(VerifyError::RedirectUriMismatch)
+    ));
+}
+
+#[tokio::test]
+async fn canonicalizes_redirect_uri() {
+    let broker = MockBroker::start().await.unwrap();
+    let client = Client::builder("HTTP://LocalHost.:8000/verify/?#".parse().unwrap())
+        .broker(broker.url().clone())
+        .uri_canonicalization(UriCanonicalization::Normalized)
+        .build()
+        .unwrap();
+    assert_eq!(
+        client.redirect_uri().as_str(),
+        "http://localhost:8000/verify"
+    );
+    assert_eq!(client.client_id(), "http://localhost:8000");
+
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let token = broker.login(&auth_url).unwrap();
+    let info = ClientInfo::default().redirect_uri("http://localhost:8000/verify/".parse().unwrap());
+    let verified = client.verify_with_info(&token, &info).await.unwrap();
+    assert_eq!(verified.email(), "user@example.com");
+}
+
+#[tokio::test]
+async fn limits_response_size() {
+    let broker = MockBroker::start().await.unwrap();
+    let builder = Client::builder("http://localhost:8000/verify".parse().unwrap())
+        .broker(broker.url().clone())
+        .fetch_timeout(Duration::from_secs(5))
+        .connect_timeout(Duration::from_secs(1));
+
+    let client = builder.clone().max_response_size(64).build().unwrap();
+    let err = client.start_auth("user@example.com").await.unwrap_err();
+    assert!(matches!(err, StartAuthError::FetchDiscovery(_)));
+    assert!(err.to_string().contains("exceeds 64 bytes"));
+
+    let client = builder.max_response_size(64 * 1024).build().unwrap();
+    client.start_auth("user@example.com").await.unwrap();
+
+    // Responses that are too large are not retried.
+    let http = CountingClient::default();
+    let store = MemoryStore::with_http_client(http.clone(), Duration::from_secs(5))
+        .max_response_size(64)
+        .retry(RetryPolicy::new(3).initial_backoff(Duration::from_millis(10)));
+    let client = Client::builder("http://localhost:8000/verify".parse().unwrap())
+        .broker(broker.url().clone())
+        .store(Arc::new(store))
+        .build()
+        .unwrap();
+    let err = client.start_auth("user@example.com").await.unwrap_err();
+    assert!(matches!(err, StartAuthError::FetchDiscovery(_)));
+    assert_eq!(http.requests("/.well-known/openid-configuration"), 1);
+}
+
+/// An `HttpClient` that counts requests by path.
+#[derive(Clone, Default)]
+struct CountingClient {
+    paths: Arc<Mutex<Vec<String>>>,
+}
+
+impl CountingClient {
+    /// The number of requests for `path`.
+    fn requests(&self, path: &str) -> usize {
+        let paths = self.paths.lock().unwrap();
+        paths.iter().filter(|p| *p == path).count()
+    }
+}
+
+impl HttpClient for CountingClient {
+    fn request(
+        &self,
+        request: HttpRequest,
+    ) -> DynFut<Result<HttpResponse, Box<dyn Error + Send + Sync>>> {
+        let path = request.uri().path().to_owned();
+        self.paths.lock().unwrap().push(path);
+        HttpClient::request(&portier::default_http_client(), request)
+    }
+}
+
+#[tokio::test]
+async fn streams_login_events() {
+    let (broker, client) = setup().await;
+    let mut events = client.events();
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let token = broker.login(&auth_url).unwrap();
+    client.verify(&token).await.unwrap();
+
+    let mut received = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        received.push(event);
+    }
+    let steps: Vec<_> = received.iter().map(|event| event.step).collect();
+    assert_eq!(
+        steps,
+        [
+            LoginStep::AuthStarted,
+            LoginStep::Redirected,
+            LoginStep::Verified
+        ]
+    );
+    assert!(received[0].correlation_id.is_some());
+    assert!(received
+        .iter()
+        .all(|event| event.correlation_id == received[0].correlation_id));
+    assert_eq!(
+        received[2].broker.as_deref(),
+        Some(broker.url().origin().ascii_serialization().as_str())
+    );
+}
+
+#[tokio::test]
+async fn shares_correlation_id() {
+    let (broker, client) = setup().await;
+    let mut events = client.events();
+    let mut drain = || {
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            received.push((event.step, event.correlation_id));
+        }
+        received
+    };
+
+    // A generated identifier is kept for the whole login.
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let params = CallbackParams::parse(&format!("id_token={}", broker.login(&auth_url).unwrap()));
+    client.handle_callback(&params).await.unwrap();
+    let received = drain();
+    assert_eq!(received.len(), 4);
+    let id = received[0].1.clone();
+    assert!(id.is_some());
+    assert!(received.iter().all(|(_, event_id)| *event_id == id));
+
+    // A failure before the session is consumed reports the identifier set by the caller.
+    let options = AuthOptions {
+        correlation_id: Some("request-1".to_owned()),
+        ..Default::default()
+    };
+    let auth_url = client
+        .start_auth_with_options("user@example.com", options)
+        .await
+        .unwrap();
+    let claims = broker.claims_for(&auth_url).unwrap();
+    let forged = TokenMint::with_kid(broker.mint().kid()).sign(&claims);
+    let params = CallbackParams::parse(&format!("id_token={forged}"));
+    client.handle_callback(&params).await.unwrap_err();
+    client.verify(&broker.mint().sign(&claims)).await.unwrap();
+    let id = Some("request-1".to_owned());
+    assert_eq!(
+        drain(),
+        [
+            (LoginStep::AuthStarted, id.clone()),
+            (LoginStep::Redirected, id.clone()),
+            (LoginStep::CallbackReceived, id.clone()),
+            (LoginStep::Failed(ErrorCode::InvalidToken), id.clone()),
+            (LoginStep::Verified, id),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn shares_endpoint_state() {
+    let store = Arc::new(MemoryStore::default());
+    let build = |shared| {
+        Client::builder("http://localhost:8000/verify".parse().unwrap())
+            .broker("http://127.0.0.1:1".parse().unwrap())
+            .store(store.clone())
+            .shared_endpoint_state(shared)
+            .build()
+            .unwrap()
+    };
+
+    let first = build(true);
+    for _ in 0..3 {
+        assert!(first.start_auth("user@example.com").await.is_err());
+    }
+    assert!(matches!(
+        first.availability(),
+        Availability::Unavailable { .. }
+    ));
+
+    // A worker with local state only sees its own failure.
+    let local = build(false);
+    assert!(local.start_auth("user@example.com").await.is_err());
+    assert_eq!(local.availability(), Availability::Degraded);
+
+    let second = build(true);
+    assert!(second.start_auth("user@example.com").await.is_err());
+    assert!(matches!(
+        second.availability(),
+        Availability::Unavailable { .. }
+    ));
+}
+
+#[tokio::test]
+async fn loads_session_secret() {
+    let broker = MockBroker::start().await.unwrap();
+    let builder = Client::builder("http://localhost:8000/verify".parse().unwrap())
+        .broker(broker.url().clone());
+
+    let client = builder
+        .clone()
+        .session_secret_from(|| Ok(b"0123456789abcdef0123456789abcdef".to_vec()))
+        .build()
+        .unwrap();
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let token = broker.login(&auth_url).unwrap();
+    assert_eq!(client.verify(&token).await.unwrap(), "user@example.com");
+
+    assert!(matches!(
+        builder
+            .session_secret_from(EnvSecret::new("PORTIER_TEST_UNSET_SECRET"))
+            .build(),
+        Err(BuildError::Secret(_))
+    ));
+}
+
+#[tokio::test]
+async fn deserializes_custom_claims() {
+    #[derive(serde::Deserialize)]
+    struct Custom {
+        email: String,
+        preferred_username: String,
+    }
+
+    let (broker, client) = setup().await;
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let mut claims = broker.claims_for(&auth_url).unwrap();
+    claims["preferred_username"] = "user".into();
+    let (token, custom) = client
+        .verify_with_claims::<Custom>(&broker.mint().sign(&claims))
+        .await
+        .unwrap();
+    assert_eq!(custom.email, "user@example.com");
+    assert_eq!(custom.preferred_username, "user");
+    assert_eq!(token.claims.extra["preferred_username"], "user");
+}
+
+#[tokio::test]
+async fn rejects_mismatched_custom_claims() {
+    #[derive(Debug, serde::Deserialize)]
+    struct Custom {
+        #[allow(dead_code)]
+        preferred_username: String,
+    }
+
+    let broker = MockBroker::start().await.unwrap();
+    let store = Arc::new(MemoryStore::default());
+    let client = Client::builder("http://localhost:8000/verify".parse().unwrap())
+        .broker(broker.url().clone())
+        .store(store.clone())
+        .failure_sink(Arc::new(StoreFailureSink::new(store.clone())))
+        .build()
+        .unwrap();
+    let mut events = client.events();
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let token = broker.login(&auth_url).unwrap();
+
+    assert!(matches!(
+        client.verify_with_claims::<Custom>(&token).await,
+        Err(VerifyError::InvalidPayload(_))
+    ));
+    let mut steps = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        steps.push(event.step);
+    }
+    assert_eq!(
+        steps.last(),
+        Some(&LoginStep::Failed(ErrorCode::InvalidToken))
+    );
+    assert_eq!(store.load_failures().await.unwrap().len(), 1);
+
+    // The session was not consumed, so the token can still be verified.
+    client.verify(&token).await.unwrap();
+}
+
+#[tokio::test]
+async fn runs_self_test() {
+    let (_broker, client) = setup().await;
+    let report = client.self_test(true).await;
+    assert!(report.is_ok(), "{:?}", report);
+    assert_eq!(report.brokers.unwrap().unwrap().len(), 1);
+
+    let client = Client::builder("http://localhost:8000/verify".parse().unwrap())
+        .broker("http://127.0.0.1:1".parse().unwrap())
+        .build()
+        .unwrap();
+    let report = client.self_test(false).await;
+    assert!(report.is_ok());
+    assert!(report.brokers.is_none());
+    let report = client.self_test(true).await;
+    assert!(report.rng.is_ok() && report.store.is_ok());
+    assert!(!report.is_ok());
+}
+
+#[tokio::test]
+async fn verifies_with_pinned_documents() {
+    // Nothing listens on the broker origin, so any fetch fails.
+    let issuer = "http://127.0.0.1:1";
+    let discovery = serde_json::json!({
+        "issuer": issuer,
+        "jwks_uri": format!("{}/jwks.json", issuer),
+        "authorization_endpoint": format!("{}/auth", issuer),
+    });
+    let mint = TokenMint::new();
+    let client = Client::builder("http://localhost:8000/verify".parse().unwrap())
+        .broker(issuer.parse().unwrap())
+        .pinned_discovery(discovery.to_string())
+        .pinned_jwks(mint.key_set())
+        .build()
+        .unwrap();
+
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let (_, nonce) = auth_url.query_pairs().find(|(k, _)| k == "nonce").unwrap();
+    let claims = test_utils::claims(issuer, client.client_id(), "user@example.com", &nonce);
+    assert_eq!(
+        client.verify(&mint.sign(&claims)).await.unwrap(),
+        "user@example.com"
+    );
+
+    let other = TokenMint::with_kid("unpinned");
+    assert!(matches!(
+        client.verify(&other.sign(&claims)).await,
+        Err(VerifyError::KeyNotPinned(kid)) if kid == "unpinned"
+    ));
+}
+
+/// Start a login, complete it at `broker`, and verify the token.
+async fn login(client: &Client, broker: &MockBroker) -> Result<String, VerifyError> {
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    client.verify(&broker.login(&auth_url).unwrap()).await
+}
+
+#[tokio::test]
+async fn refetches_rotated_keys() {
+    let mut broker = MockBroker::start().await.unwrap();
+    let http = CountingClient::default();
+    let clock = Arc::new(ManualClock::new(std::time::SystemTime::now()));
+    let client = Client::builder("http://localhost:8000/verify".parse().unwrap())
+        .broker(broker.url().clone())
+        .store(Arc::new(MemoryStore::with_http_client(
+            http.clone(),
+            Duration::from_secs(5),
+        )))
+        .clock(clock.clone())
+        .build()
+        .unwrap();
+    login(&client, &broker).await.unwrap();
+    assert_eq!(http.requests("/jwks.json"), 1);
+
+    // A token signed with an unknown key triggers a single refetch.
+    broker.rotate_key(TokenMint::new());
+    login(&client, &broker).await.unwrap();
+    assert_eq!(http.requests("/jwks.json"), 2);
+
+    // Refetches are rate limited.
+    broker.rotate_key(TokenMint::new());
+    assert!(matches!(
+        login(&client, &broker).await,
+        Err(VerifyError::Signature(
+            jws::VerifyError::KidNotMatched { .. }
+        ))
+    ));
+    assert_eq!(http.requests("/jwks.json"), 2);
+
+    clock.advance(Duration::from_secs(60));
+    login(&client, &broker).await.unwrap();
+    assert_eq!(http.requests("/jwks.json"), 3);
+}
+
+#[test]
+fn rejects_invalid_pinned_discovery() {
+    assert!(matches!(
+        Client::builder("http://localhost:8000/verify".parse().unwrap())
+            .pinned_discovery("{}")
+            .build(),
+        Err(BuildError::InvalidPinnedDiscovery(_))
+    ));
+}
+
+#[tokio::test]
+async fn limits_login_rate() {
+    let broker = MockBroker::start().await.unwrap();
+    let clock = Arc::new(ManualClock::new(std::time::SystemTime::now()));
+    let limiter = MemoryRateLimiter::new(2, Duration::from_secs(60)).clock(clock.clone());
+    let client = Client::builder("http://localhost:8000/verify".parse().unwrap())
+        .broker(broker.url().clone())
+        .rate_limiter(Arc::new(limiter))
+        .build()
+        .unwrap();
+    let start = |email, key: Option<&str>| {
+        let options = AuthOptions {
+            rate_limit_key: key.map(str::to_owned),
+            ..Default::default()
+        };
+        client.start_auth_with_options(email, options)
+    };
+
+    // The email address is limited after normalization.
+    start("User@Example.com", None).await.unwrap();
+    start("user@example.com", None).await.unwrap();
+    let err = start("user@example.com", None).await.unwrap_err();
+    assert!(matches!(
+        err,
+        StartAuthError::RateLimited {
+            scope: RateLimitScope::Email,
+            retry_after: Some(delay),
+        } if delay == Duration::from_secs(60)
+    ));
+    assert!(!err.is_retryable());
+    clock.advance(Duration::from_secs(60));
+    start("user@example.com", None).await.unwrap();
+
+    // The caller key is limited across email addresses.
+    start("a@example.com", Some("192.0.2.1")).await.unwrap();
+    start("b@example.com", Some("192.0.2.1")).await.unwrap();
+    assert!(matches!(
+        start("c@example.com", Some("192.0.2.1")).await,
+        Err(StartAuthError::RateLimited {
+            scope: RateLimitScope::Caller,
+            ..
+        })
+    ));
+    start("c@example.com", Some("192.0.2.2")).await.unwrap();
+}
+
+#[tokio::test]
+async fn limits_input_length() {
+    let broker = MockBroker::start().await.unwrap();
+    let client = Client::builder("http://localhost:8000/verify".parse().unwrap())
+        .broker(broker.url().clone())
+        .max_email_len(20)
+        .max_token_len(64)
+        .build()
+        .unwrap();
+
+    let err = client
+        .start_auth("someone.long@example.com")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, StartAuthError::EmailTooLong { max: 20 }));
+
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let token = broker.login(&auth_url).unwrap();
+    let err = client.verify(&token).await.unwrap_err();
+    assert!(matches!(err, VerifyError::TokenTooLong { max: 64 }));
+    assert!(!err.is_retryable());
+}
+
+#[tokio::test]
+async fn records_failures() {
+    let broker = MockBroker::start().await.unwrap();
+    let store = Arc::new(MemoryStore::default());
+    let client = Client::builder("http://localhost:8000/verify".parse().unwrap())
+        .broker(broker.url().clone())
+        .store(store.clone())
+        .failure_sink(Arc::new(StoreFailureSink::new(store.clone())))
+        .build()
+        .unwrap();
+
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let claims = broker.claims_for(&auth_url).unwrap();
+    let other = TokenMint::with_kid(broker.mint().kid());
+    assert!(client.verify(&other.sign(&claims)).await.is_err());
+    let token = broker.login(&auth_url).unwrap();
+    client.verify(&token).await.unwrap();
+
+    let records = store.load_failures().await.unwrap();
+    assert_eq!(records.len(), 1);
+    let failure: VerifyFailure = serde_json::from_str(&records[0]).unwrap();
+    assert_eq!(failure.code, ErrorCode::InvalidToken);
+    assert_eq!(failure.kid.as_deref(), Some(broker.mint().kid()));
+    assert_eq!(failure.issuer, claims["iss"].as_str().map(str::to_owned));
+    assert_eq!(failure.token_hash.len(), 16);
+}
+
+/// An `HttpClient` that delays requests to one origin, to simulate a slow endpoint.
+struct SlowOrigin {
+    origin: url::Url,
+    delay: Duration,
+}
+
+impl HttpClient for SlowOrigin {
+    fn request(
+        &self,
+        request: HttpRequest,
+    ) -> DynFut<Result<HttpResponse, Box<dyn Error + Send + Sync>>> {
+        let url: url::Url = request.uri().to_string().parse().unwrap();
+        let delay = if url.origin() == self.origin.origin() {
+            self.delay
+        } else {
+            Duration::ZERO
+        };
+        let response = HttpClient::request(&portier::default_http_client(), request);
+        Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            response.await
+        })
+    }
+}
+
+async fn setup_mirror() -> (MockBroker, MockBroker, Client) {
+    let primary = MockBroker::start().await.unwrap();
+    let mirror = MockBroker::start().await.unwrap();
+    let client = Client::builder("http://localhost:8000/verify".parse().unwrap())
+        .broker(primary.url().clone())
+        .broker_mirror(mirror.url().clone())
+        .build()
+        .unwrap();
+    (primary, mirror, client)
+}
+
+#[tokio::test]
+async fn selects_fastest_mirror() {
+    let (primary, mirror, client) = setup_mirror().await;
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    assert_eq!(auth_url.origin(), primary.url().origin());
+
+    let slow = SlowOrigin {
+        origin: primary.url().clone(),
+        delay: Duration::from_millis(200),
+    };
+    let probes = client.probe_endpoints(&slow, Duration::from_secs(5)).await;
+    assert!(probes.iter().all(|probe| probe.latency.is_some()));
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    assert_eq!(auth_url.origin(), mirror.url().origin());
+    let token = mirror.login(&auth_url).unwrap();
+    assert_eq!(client.verify(&token).await.unwrap(), "user@example.com");
+
+    // Tokens are verified against the endpoint that issued them.
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let mut claims = mirror.claims_for(&auth_url).unwrap();
+    claims["iss"] = primary.url().origin().ascii_serialization().into();
+    assert_eq!(
+        client.verify(&primary.mint().sign(&claims)).await.unwrap(),
+        "user@example.com"
+    );
+}
+
+#[tokio::test]
+async fn fails_over_to_mirror() {
+    let (primary, mirror, client) = setup_mirror().await;
+    drop(primary);
+
+    let http = portier::default_http_client();
+    let probes = client.probe_endpoints(&http, Duration::from_secs(5)).await;
+    assert!(probes[0].latency.is_none());
+    assert!(probes[1].latency.is_some());
+    assert_eq!(client.availability(), Availability::Degraded);
+
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    assert_eq!(auth_url.origin(), mirror.url().origin());
+    let token = mirror.login(&auth_url).unwrap();
+    assert_eq!(client.verify(&token).await.unwrap(), "user@example.com");
+}
+
+#[tokio::test]
+async fn encodes_auth_options() {
+    let (broker, client) = setup().await;
+    let state = "a&b=c #d e";
+    let options = AuthOptions {
+        state: Some(state.to_owned()),
+        ui_locales: vec!["nl-NL".to_owned(), "en".to_owned()],
+        prompt: Some("login consent".to_owned()),
+        ..Default::default()
+    };
+    let auth_url = client
+        .start_auth_with_options("user@example.com", options)
+        .await
+        .unwrap();
+    assert!(auth_url.fragment().is_none());
+    let param = |name: &str| {
+        let values: Vec<_> = auth_url
+            .query_pairs()
+            .filter(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+            .collect();
+        assert_eq!(values.len(), 1, "{}", name);
+        values.into_iter().next().unwrap()
+    };
+    assert_eq!(param("state"), state);
+    assert_eq!(param("ui_locales"), "nl-NL en");
+    assert_eq!(param("prompt"), "login consent");
+    assert!(auth_url.query_pairs().all(|(key, _)| key != "b"));
+
+    let token = broker.login(&auth_url).unwrap();
+    assert_eq!(client.verify(&token).await.unwrap(), "user@example.com");
+}
+
+#[tokio::test]
+async fn commits_pending_login() {
+    let (broker, client) = setup().await;
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let token = broker.login(&auth_url).unwrap();
+
+    let pending = client.verify_pending(&token).await.unwrap();
+    assert_eq!(pending.email(), "user@example.com");
+    // The session is still there until the login is committed.
+    let concurrent = client.verify_pending(&token).await.unwrap();
+    assert_eq!(pending.commit().await.unwrap(), "user@example.com");
+    assert!(matches!(
+        concurrent.commit().await,
+        Err(VerifyError::InvalidSession)
+    ));
+    assert!(matches!(
+        client.verify_pending(&token).await,
+        Err(VerifyError::InvalidSession)
+    ));
+}
+
+#[tokio::test]
+async fn rolls_back_pending_login() {
+    let (broker, client) = setup().await;
+    let auth_url = client.start_auth("user@example.com").await.unwrap();
+    let token = broker.login(&auth_url).unwrap();
+
+    client.verify_pending(&token).await.unwrap().rollback();
+    assert_eq!(client.verify(&token).await.unwrap(), "user@example.com");
+}
+
+#[tokio::test]
+async fn binds_pending_login() {
+    let broker = MockBroker::start().await.unwrap();
+    let client = Client::builder("http://localhost:8000/verify".parse().unwrap())
+        .broker(broker.url().clone())
+        .session_binding(SessionBinding::UserAgent)
+        .build()
+        .unwrap();
+    let info = ClientInfo::new().user_agent("Browser/1.0");
+    let options = AuthOptions {
+        client_info: info.clone(),
+        ..Default::default()
+    };
+    let auth_url = client
+        .start_auth_with_options("user@example.com", options)
+        .await
+        .unwrap();
+    let token = broker.login(&auth_url).unwrap();
+
+    // A mismatch is detected before the session is consumed.
+    let other = ClientInfo::new().user_agent("Other/1.0");
+    assert!(matches!(
+        client.verify_pending_with_info(&token, other).await,
+        Err(VerifyError::SessionBindingMismatch)
+    ));
+    assert!(matches!(
+        client.verify_pending(&token).await,
+        Err(VerifyError::SessionBindingMismatch)
+    ));
+
+    let pending = client.verify_pending_with_info(&token, info).await.unwrap();
+    assert_eq!(pending.commit().await.unwrap(), "user@example.com");
+}
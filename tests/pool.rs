@@ -0,0 +1,62 @@
+//! Tests of `ClientPool`.
+#![cfg(feature = "simple-store")]
+
+use portier::{BuildError, Client, ClientPool};
+
+#[test]
+fn selects_registered_client_by_host() {
+    let template = Client::builder("https://example.com/verify".parse().unwrap());
+    let pool = ClientPool::with_redirect_uris(
+        template,
+        [
+            "https://example.com/verify".parse().unwrap(),
+            "https://www.example.com/auth/verify".parse().unwrap(),
+            "http://localhost:8000/verify".parse().unwrap(),
+        ],
+    )
+    .unwrap();
+    assert_eq!(pool.len(), 3);
+
+    let client = pool.client_for_host("WWW.example.com").unwrap();
+    assert_eq!(
+        client.redirect_uri().as_str(),
+        "https://www.example.com/auth/verify"
+    );
+    assert_eq!(client.client_id(), "https://www.example.com");
+
+    let client = pool.client_for_host("localhost:8000").unwrap();
+    assert_eq!(client.client_id(), "http://localhost:8000");
+
+    assert!(matches!(
+        pool.client_for_host("other.example.com"),
+        Err(BuildError::UnknownOrigin)
+    ));
+    assert!(matches!(
+        pool.client_for("https://other.example.com"),
+        Err(BuildError::UnknownOrigin)
+    ));
+
+    // Removed clients are rebuilt with the registered redirect URI.
+    pool.remove("https://www.example.com");
+    let client = pool.client_for("https://www.example.com").unwrap();
+    assert_eq!(
+        client.redirect_uri().as_str(),
+        "https://www.example.com/auth/verify"
+    );
+}
+
+#[test]
+fn builds_client_for_any_host() {
+    let pool = ClientPool::new(Client::builder(
+        "https://example.com/verify".parse().unwrap(),
+    ));
+    let client = pool.client_for_host("tenant.example.org").unwrap();
+    assert_eq!(
+        client.redirect_uri().as_str(),
+        "https://tenant.example.org/verify"
+    );
+    assert!(matches!(
+        pool.client_for_host("bad/host"),
+        Err(BuildError::InvalidRedirectUri)
+    ));
+}
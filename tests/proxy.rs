@@ -0,0 +1,26 @@
+//! Tests of proxy selection for the default HTTP client.
+#![cfg(feature = "simple-store")]
+
+use portier::ProxyConfig;
+
+#[test]
+fn selects_proxy_by_scheme() {
+    let proxy: http::Uri = "http://proxy.internal:3128".parse().unwrap();
+    let config = ProxyConfig::new().https(proxy.clone());
+    let https = "https://broker.example/.well-known/openid-configuration"
+        .parse()
+        .unwrap();
+    let http = "http://broker.example/".parse().unwrap();
+    assert_eq!(config.proxy_for(&https), Some(&proxy));
+    assert_eq!(config.proxy_for(&http), None);
+}
+
+#[test]
+fn bypasses_no_proxy_hosts() {
+    let proxy: http::Uri = "http://proxy.internal:3128".parse().unwrap();
+    let config = ProxyConfig::new().all(proxy.clone()).no_proxy(".example");
+    let direct = "https://broker.example/".parse().unwrap();
+    let proxied = "https://broker.notexample/".parse().unwrap();
+    assert_eq!(config.proxy_for(&direct), None);
+    assert_eq!(config.proxy_for(&proxied), Some(&proxy));
+}
@@ -0,0 +1,80 @@
+//! Tests of the disk-backed `SledStore`.
+#![cfg(feature = "sled-store")]
+
+use std::{error::Error, future::Future, pin::Pin, time::Duration};
+
+use portier::{HttpClient, HttpRequest, HttpResponse, SledStore, Store};
+
+type Response =
+    Pin<Box<dyn Future<Output = Result<HttpResponse, Box<dyn Error + Send + Sync>>> + Send>>;
+
+/// An `HttpClient` for tests that don't fetch documents.
+#[derive(Clone)]
+struct NoHttp;
+
+impl HttpClient for NoHttp {
+    fn request(&self, _request: HttpRequest) -> Response {
+        Box::pin(async { Err("unexpected request".into()) })
+    }
+}
+
+fn open(path: &std::path::Path) -> SledStore<NoHttp> {
+    let db = sled::open(path).unwrap();
+    SledStore::new(db, NoHttp).unwrap()
+}
+
+#[tokio::test]
+async fn sessions_survive_restart() {
+    let path = std::env::temp_dir().join(format!("portier-sled-{}", std::process::id()));
+
+    let store = open(&path);
+    let email = "john@example.com".to_owned();
+    let nonce = store.new_nonce(email.clone()).await.unwrap();
+    let data_nonce = store
+        .new_nonce_with_data(email.clone(), b"state".to_vec(), Duration::from_secs(60))
+        .await
+        .unwrap();
+    let expired = store
+        .new_nonce_with_ttl(email.clone(), Duration::ZERO)
+        .await
+        .unwrap();
+    store
+        .save_key_pins("https://broker.example".to_owned(), "pins".to_owned())
+        .await
+        .unwrap();
+    drop(store);
+
+    let store = open(&path);
+    assert!(store
+        .peek_nonce(nonce.clone(), email.clone())
+        .await
+        .unwrap());
+    assert!(!store
+        .consume_nonce(nonce.clone(), "jane@example.com".to_owned())
+        .await
+        .unwrap());
+    assert!(store
+        .consume_nonce(nonce.clone(), email.clone())
+        .await
+        .unwrap());
+    assert!(!store.consume_nonce(nonce, email.clone()).await.unwrap());
+    assert_eq!(
+        store
+            .consume_nonce_with_data(data_nonce, email.clone())
+            .await
+            .unwrap(),
+        Some(b"state".to_vec())
+    );
+    assert!(!store.consume_nonce(expired, email).await.unwrap());
+    assert_eq!(
+        store
+            .load_key_pins("https://broker.example".to_owned())
+            .await
+            .unwrap()
+            .as_deref(),
+        Some("pins")
+    );
+    drop(store);
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
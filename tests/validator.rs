@@ -0,0 +1,140 @@
+//! Tests of the configurable claim checks of `Validator`.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use portier::{
+    test_utils::{claims, TokenMint},
+    ClaimCheck, ClaimPolicy, Clock, ErrorCode, ErrorKind, SpecVersion, Validator, VerifyError,
+};
+
+const BROKER: &str = "https://broker.example";
+const CLIENT: &str = "https://rp.example";
+
+/// Sign a token without `email_original`, and with an unknown claim.
+fn transitional_token(mint: &TokenMint) -> String {
+    let mut claims = claims(BROKER, CLIENT, "user@example.com", "nonce");
+    let claims_map = claims.as_object_mut().unwrap();
+    claims_map.remove("email_original");
+    claims_map.insert("amr".into(), "email".into());
+    mint.sign(&claims)
+}
+
+#[test]
+fn ignores_optional_claims_by_default() {
+    let mint = TokenMint::new();
+    let token = transitional_token(&mint);
+    let claims = Validator::new(BROKER, CLIENT)
+        .verify(&token, &mint.key_set())
+        .unwrap();
+    assert_eq!(claims.extra["amr"], "email");
+}
+
+#[test]
+fn rejects_missing_and_unexpected_claims() {
+    let mint = TokenMint::new();
+    let token = transitional_token(&mint);
+    assert!(matches!(
+        Validator::new(BROKER, CLIENT)
+            .spec_version(SpecVersion::V2)
+            .verify(&token, &mint.key_set()),
+        Err(VerifyError::MissingClaim("email_original"))
+    ));
+    assert!(matches!(
+        Validator::new(BROKER, CLIENT)
+            .claim_policy(ClaimCheck::MissingSub, ClaimPolicy::Reject)
+            .verify(&token, &mint.key_set()),
+        Err(VerifyError::MissingClaim("sub"))
+    ));
+    assert!(matches!(
+        Validator::new(BROKER, CLIENT)
+            .claim_policy(ClaimCheck::UnexpectedClaims, ClaimPolicy::Reject)
+            .verify(&token, &mint.key_set()),
+        Err(VerifyError::UnexpectedClaim(name)) if name == "amr"
+    ));
+}
+
+#[test]
+fn warns_about_accepted_claims() {
+    let mint = TokenMint::new();
+    let token = transitional_token(&mint);
+    let warnings = Arc::new(Mutex::new(Vec::new()));
+    let validator = Validator::new(BROKER, CLIENT)
+        .spec_version(SpecVersion::V2)
+        .claim_policy(ClaimCheck::MissingEmailOriginal, ClaimPolicy::Warn)
+        .claim_policy(ClaimCheck::UnexpectedClaims, ClaimPolicy::Warn)
+        .on_claim_warning({
+            let warnings = warnings.clone();
+            move |err| warnings.lock().unwrap().push(err.to_string())
+        });
+
+    validator.verify(&token, &mint.key_set()).unwrap();
+    assert_eq!(
+        *warnings.lock().unwrap(),
+        [
+            "the token is missing the required claim 'email_original'",
+            "the token has the unexpected claim 'amr'",
+        ]
+    );
+
+    let inspection = validator.inspect(&token, &mint.key_set());
+    assert!(inspection.is_valid());
+    assert_eq!(inspection.warnings.len(), 2);
+}
+
+/// A clock that was reset to shortly after the Unix epoch, as on a device without a battery.
+struct ResetClock {
+    trusted: bool,
+}
+
+impl Clock for ResetClock {
+    fn now(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1000)
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn is_reliable(&self, now: SystemTime) -> bool {
+        self.trusted || now > UNIX_EPOCH + Duration::from_secs(1_577_836_800)
+    }
+}
+
+#[test]
+fn rejects_unreliable_clock() {
+    let mint = TokenMint::new();
+    let token = mint.sign(&claims(BROKER, CLIENT, "user@example.com", "nonce"));
+    let verify = |trusted| {
+        Validator::new(BROKER, CLIENT)
+            .clock(Arc::new(ResetClock { trusted }))
+            .verify(&token, &mint.key_set())
+    };
+
+    let err = verify(false).unwrap_err();
+    assert!(matches!(err, VerifyError::ClockUnreliable));
+    assert_eq!(err.kind(), ErrorKind::Environment);
+    assert_eq!(err.code(), ErrorCode::ServiceUnavailable);
+    assert!(matches!(verify(true), Err(VerifyError::IssuedInTheFuture)));
+}
+
+#[cfg(feature = "client")]
+#[test]
+fn rejects_unnormalized_domain() {
+    let mint = TokenMint::new();
+    let validator = Validator::new(BROKER, CLIENT).spec_version(SpecVersion::V2);
+
+    let token = mint.sign(&claims(BROKER, CLIENT, "user@exämple.com", "nonce"));
+    assert!(matches!(
+        validator.verify(&token, &mint.key_set()),
+        Err(VerifyError::EmailNotNormalized)
+    ));
+
+    let mut claims = claims(BROKER, CLIENT, "user@xn--exmple-cua.com", "nonce");
+    claims["email_original"] = "User@Exämple.com".into();
+    let token = mint.sign(&claims);
+    let claims = validator.verify(&token, &mint.key_set()).unwrap();
+    assert_eq!(claims.email, "user@xn--exmple-cua.com");
+}